@@ -0,0 +1,36 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Stamps the binary with what `GET /version` reports about the build
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_owned())
+        .unwrap_or_else(|| String::from("unknown"));
+
+    let built_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    // Cargo sets `CARGO_FEATURE_<NAME>` for every enabled feature
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(name, _)| {
+            name.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=KV_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=KV_BUILT_AT={}", built_at);
+    println!("cargo:rustc-env=KV_FEATURES={}", features.join(","));
+
+    // Rebuilt when the checked out commit changes, rather than on every build
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
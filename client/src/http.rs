@@ -0,0 +1,156 @@
+use hyper::client::HttpConnector;
+use hyper::{header, Body, Client, Method, Request, StatusCode};
+use serde_json::{json, Value};
+
+use crate::{Error, KvClient, Result};
+
+/// Talks to a kv server over HTTP.
+#[derive(Clone)]
+pub struct HttpClient {
+    client: Client<HttpConnector>,
+    base_url: String,
+}
+
+impl HttpClient {
+    /// A client for the server at `base_url`, like `http://localhost:3000`.
+    pub fn new(base_url: impl Into<String>) -> HttpClient {
+        HttpClient {
+            client: Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_owned(),
+        }
+    }
+
+    // The nodes of the cluster the server is in, from `GET /admin/cluster`
+    pub(crate) async fn cluster_nodes(&self) -> Result<Vec<String>> {
+        let (status, body) = self
+            .send(
+                Method::GET,
+                format!("{}/admin/cluster", self.base_url),
+                None,
+            )
+            .await?;
+
+        match status {
+            StatusCode::OK => serde_json::from_value(body["nodes"].clone()).map_err(Error::Decode),
+            status => Err(unexpected(status, body)),
+        }
+    }
+
+    fn key_url(&self, key: &str) -> String {
+        format!("{}/{}", self.base_url, encode_segment(key))
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        url: String,
+        body: Option<Value>,
+    ) -> Result<(StatusCode, Value)> {
+        let request = Request::builder().method(method).uri(url);
+
+        let request = match body {
+            Some(body) => request
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => request.body(Body::empty()),
+        }
+        .map_err(Error::Request)?;
+
+        let response = self.client.request(request).await.map_err(Error::Http)?;
+
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(Error::Http)?;
+
+        // Some responses, like `DELETE /`, have no body at all
+        let body = if body.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_slice(&body).map_err(Error::Decode)?
+        };
+
+        Ok((status, body))
+    }
+}
+
+// The server answers errors with `{ "error": ... }`
+fn unexpected(status: StatusCode, body: Value) -> Error {
+    let message = body["error"].as_str().unwrap_or_default().to_owned();
+
+    Error::Status(status, message)
+}
+
+/// Percent-encodes `key` so it stays a single path segment.
+fn encode_segment(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+
+    for byte in key.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+impl KvClient for HttpClient {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        let (status, body) = self.send(Method::GET, self.key_url(key), None).await?;
+
+        match status {
+            StatusCode::OK => Ok(body["value"].as_str().map(str::to_owned)),
+            StatusCode::NOT_FOUND => Ok(None),
+            status => Err(unexpected(status, body)),
+        }
+    }
+
+    async fn put(&self, key: &str, value: &str) -> Result<()> {
+        let body = json!({ "key": key, "value": value });
+
+        let (status, body) = self
+            .send(Method::PUT, self.key_url(key), Some(body))
+            .await?;
+
+        match status {
+            StatusCode::OK => Ok(()),
+            status => Err(unexpected(status, body)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        let (status, body) = self.send(Method::DELETE, self.key_url(key), None).await?;
+
+        match status {
+            StatusCode::OK => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            status => Err(unexpected(status, body)),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<(String, String)>> {
+        let (status, body) = self
+            .send(Method::GET, format!("{}/", self.base_url), None)
+            .await?;
+
+        match status {
+            StatusCode::OK => serde_json::from_value(body).map_err(Error::Decode),
+            status => Err(unexpected(status, body)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_keys_as_one_segment() {
+        assert_eq!(encode_segment("config-v42"), "config-v42");
+        assert_eq!(encode_segment("config/current"), "config%2Fcurrent");
+        assert_eq!(encode_segment("a b?"), "a%20b%3F");
+    }
+}
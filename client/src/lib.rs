@@ -0,0 +1,91 @@
+//! Client for the kv HTTP API.
+//!
+//! Code written against [`KvClient`] can talk to a running server through [`HttpClient`],
+//! or to a [`MemoryClient`] in unit tests without starting one. [`ShardedClient`] spreads
+//! keys over several servers. Receivers of the server's webhooks can check they came from
+//! it with [`verify_webhook`].
+
+use std::fmt;
+use std::future::Future;
+
+mod http;
+mod memory;
+mod sharded;
+mod webhook;
+
+pub use crate::http::HttpClient;
+pub use crate::memory::MemoryClient;
+pub use crate::sharded::ShardedClient;
+pub use crate::webhook::{
+    verify_webhook, VerifyError, DEFAULT_TOLERANCE, SIGNATURE_HEADER, TIMESTAMP_HEADER,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request couldn't be built, usually because of an invalid base URL.
+    Request(hyper::http::Error),
+    /// The request couldn't be sent or its response read.
+    Http(hyper::Error),
+    /// The server answered with an unexpected status, and the error it gave.
+    Status(hyper::StatusCode, String),
+    /// The response body wasn't the JSON the server is expected to send.
+    Decode(serde_json::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Request(error) => write!(f, "invalid request: {}", error),
+            Error::Http(error) => write!(f, "request failed: {}", error),
+            Error::Status(status, error) => write!(f, "server answered {}: {}", status, error),
+            Error::Decode(error) => write!(f, "invalid response: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The key-value operations of the server.
+pub trait KvClient {
+    /// The value of `key`, `None` when it doesn't exist.
+    fn get(&self, key: &str) -> impl Future<Output = Result<Option<String>>> + Send;
+
+    /// Creates or overwrites `key`.
+    fn put(&self, key: &str, value: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Deletes `key`, returning whether it existed.
+    fn delete(&self, key: &str) -> impl Future<Output = Result<bool>> + Send;
+
+    /// Every key and value, ordered by key.
+    fn list(&self) -> impl Future<Output = Result<Vec<(String, String)>>> + Send;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_client_behaves_like_the_server() {
+        let client = MemoryClient::new();
+
+        assert_eq!(client.get("missing").await.unwrap(), None);
+
+        client.put("b", "2").await.unwrap();
+        client.put("a", "1").await.unwrap();
+        client.put("a", "one").await.unwrap();
+
+        assert_eq!(client.get("a").await.unwrap().as_deref(), Some("one"));
+        assert_eq!(
+            client.list().await.unwrap(),
+            vec![
+                (String::from("a"), String::from("one")),
+                (String::from("b"), String::from("2"))
+            ]
+        );
+
+        assert!(client.delete("a").await.unwrap());
+        assert!(!client.delete("a").await.unwrap());
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::{KvClient, Result};
+
+/// An in-memory stand-in for a server, for testing code written against [`KvClient`].
+#[derive(Default)]
+pub struct MemoryClient {
+    entries: Mutex<BTreeMap<String, String>>,
+}
+
+impl MemoryClient {
+    pub fn new() -> MemoryClient {
+        MemoryClient::default()
+    }
+
+    /// A client starting out with `entries` already stored.
+    pub fn with_entries<I, K, V>(entries: I) -> MemoryClient
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| (key.into(), value.into()))
+            .collect();
+
+        MemoryClient {
+            entries: Mutex::new(entries),
+        }
+    }
+}
+
+impl KvClient for MemoryClient {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.entries.lock().unwrap().get(key).cloned())
+    }
+
+    async fn put(&self, key: &str, value: &str) -> Result<()> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), value.to_owned());
+
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        Ok(self.entries.lock().unwrap().remove(key).is_some())
+    }
+
+    async fn list(&self) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+}
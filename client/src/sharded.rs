@@ -0,0 +1,180 @@
+use sha2::{Digest, Sha256};
+use std::sync::RwLock;
+
+use crate::{HttpClient, KvClient, Result};
+
+// Points each node gets on the ring, so keys spread evenly and a node joining or leaving
+// only moves its share of them
+const POINTS_PER_NODE: usize = 64;
+
+fn hash(data: &str) -> u64 {
+    let digest = Sha256::digest(data.as_bytes());
+
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+struct Ring {
+    nodes: Vec<(String, HttpClient)>,
+    // Sorted by hash, each pointing into `nodes`
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    fn new(urls: Vec<String>) -> Ring {
+        let nodes: Vec<(String, HttpClient)> = urls
+            .into_iter()
+            .map(|url| {
+                let client = HttpClient::new(url.clone());
+
+                (url.trim_end_matches('/').to_owned(), client)
+            })
+            .collect();
+
+        let mut points: Vec<(u64, usize)> = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(index, (url, _))| {
+                (0..POINTS_PER_NODE).map(move |point| (hash(&format!("{}#{}", url, point)), index))
+            })
+            .collect();
+
+        points.sort_unstable();
+
+        Ring { nodes, points }
+    }
+
+    // The first point at or after the key's hash, wrapping around past the last
+    fn node_for(&self, key: &str) -> &(String, HttpClient) {
+        let hash = hash(key);
+
+        let point = match self.points.partition_point(|(point, _)| *point < hash) {
+            point if point == self.points.len() => 0,
+            point => point,
+        };
+
+        &self.nodes[self.points[point].1]
+    }
+}
+
+/// Spreads keys over several servers by consistent hashing, for sharded deployments
+/// without a router in front of them. Each key lives on one node, so `list` merges what
+/// all of them hold.
+pub struct ShardedClient {
+    ring: RwLock<Ring>,
+}
+
+impl ShardedClient {
+    /// A client for the servers at `urls`, like `http://kv-0:3000`.
+    ///
+    /// # Panics
+    ///
+    /// If `urls` is empty.
+    pub fn new<I, U>(urls: I) -> ShardedClient
+    where
+        I: IntoIterator<Item = U>,
+        U: Into<String>,
+    {
+        let urls: Vec<String> = urls.into_iter().map(Into::into).collect();
+
+        assert!(!urls.is_empty(), "a sharded client needs at least one node");
+
+        ShardedClient {
+            ring: RwLock::new(Ring::new(urls)),
+        }
+    }
+
+    /// The URL of the node `key` is routed to.
+    pub fn node_for(&self, key: &str) -> String {
+        self.ring.read().unwrap().node_for(key).0.clone()
+    }
+
+    /// Rebuilds the ring from `GET /admin/cluster` of the first node that answers, after
+    /// nodes were added or removed. A server without `CLUSTER_NODES` set leaves it as is.
+    pub async fn refresh(&self) -> Result<()> {
+        let mut last_error = None;
+
+        for client in self.clients() {
+            match client.cluster_nodes().await {
+                Ok(urls) if urls.is_empty() => return Ok(()),
+                Ok(urls) => {
+                    *self.ring.write().unwrap() = Ring::new(urls);
+
+                    return Ok(());
+                }
+                Err(err) => last_error = Some(err),
+            }
+        }
+
+        Err(last_error.unwrap())
+    }
+
+    // Cloned out so no lock is held across requests
+    fn clients(&self) -> Vec<HttpClient> {
+        let ring = self.ring.read().unwrap();
+
+        ring.nodes
+            .iter()
+            .map(|(_, client)| client.clone())
+            .collect()
+    }
+
+    fn client_for(&self, key: &str) -> HttpClient {
+        self.ring.read().unwrap().node_for(key).1.clone()
+    }
+}
+
+impl KvClient for ShardedClient {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        self.client_for(key).get(key).await
+    }
+
+    async fn put(&self, key: &str, value: &str) -> Result<()> {
+        self.client_for(key).put(key, value).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        self.client_for(key).delete(key).await
+    }
+
+    async fn list(&self) -> Result<Vec<(String, String)>> {
+        let mut entries = Vec::new();
+
+        for client in self.clients() {
+            entries.extend(client.list().await?);
+        }
+
+        entries.sort();
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_moves_keys_of_nodes_leaving() {
+        let three = ShardedClient::new(["http://kv-0", "http://kv-1", "http://kv-2"]);
+        let two = ShardedClient::new(["http://kv-0", "http://kv-1"]);
+
+        let keys: Vec<String> = (0..300).map(|key| format!("key-{}", key)).collect();
+
+        for node in ["http://kv-0", "http://kv-1", "http://kv-2"] {
+            let share = keys
+                .iter()
+                .filter(|key| three.node_for(key) == node)
+                .count();
+
+            assert!(share > 50, "{} only got {} keys", node, share);
+        }
+
+        for key in &keys {
+            let node = three.node_for(key);
+
+            if node != "http://kv-2" {
+                assert_eq!(two.node_for(key), node);
+            }
+        }
+    }
+}
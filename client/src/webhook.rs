@@ -0,0 +1,117 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Header holding the Unix time in seconds a delivery was signed at.
+pub const TIMESTAMP_HEADER: &str = "x-kv-timestamp";
+/// Header holding `sha256=` and the hex HMAC-SHA256 of `<timestamp>.<body>`.
+pub const SIGNATURE_HEADER: &str = "x-kv-signature";
+
+/// How old a delivery [`verify_webhook`] accepts by default.
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+    /// A header is missing or not in the expected format.
+    Malformed,
+    /// The timestamp is further from now than the tolerance, possibly a replay.
+    Expired,
+    /// The signature doesn't match the body, or was made with another secret.
+    Mismatch,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Malformed => write!(f, "missing or malformed signature headers"),
+            VerifyError::Expired => write!(f, "timestamp outside the tolerance"),
+            VerifyError::Mismatch => write!(f, "signature doesn't match"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Checks a webhook delivery against the endpoint's `secret`, given the values of its
+/// [`TIMESTAMP_HEADER`] and [`SIGNATURE_HEADER`] and the raw body.
+///
+/// Deliveries signed more than `tolerance` away from now are refused, so one captured on
+/// the way can't be replayed later.
+pub fn verify_webhook(
+    secret: &str,
+    timestamp: Option<&str>,
+    signature: Option<&str>,
+    body: &[u8],
+    tolerance: Duration,
+) -> Result<(), VerifyError> {
+    let timestamp = timestamp.ok_or(VerifyError::Malformed)?;
+    let signed_at: u64 = timestamp.parse().map_err(|_| VerifyError::Malformed)?;
+
+    let signature = signature
+        .and_then(|signature| signature.strip_prefix("sha256="))
+        .and_then(decode_hex)
+        .ok_or(VerifyError::Malformed)?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    if now.abs_diff(signed_at) > tolerance.as_secs() {
+        return Err(VerifyError::Expired);
+    }
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    // Compares in constant time
+    mac.verify_slice(&signature)
+        .map_err(|_| VerifyError::Mismatch)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_malformed_signatures() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .to_string();
+
+        let verify = |timestamp: Option<&str>, signature: Option<&str>| {
+            verify_webhook("secret", timestamp, signature, b"{}", DEFAULT_TOLERANCE)
+        };
+
+        assert_eq!(verify(None, Some("sha256=00")), Err(VerifyError::Malformed));
+        assert_eq!(verify(Some(&now), Some("00")), Err(VerifyError::Malformed));
+        assert_eq!(
+            verify(Some(&now), Some("sha256=0g")),
+            Err(VerifyError::Malformed)
+        );
+        assert_eq!(
+            verify(Some("0"), Some("sha256=00")),
+            Err(VerifyError::Expired)
+        );
+        assert_eq!(
+            verify(Some(&now), Some("sha256=00")),
+            Err(VerifyError::Mismatch)
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use axum::extract::{Path, State};
+use axum::{http::StatusCode, Json};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::{AppError, AppState};
+
+// Chains longer than this are treated like loops rather than followed any further
+const MAX_DEPTH: usize = 16;
+
+enum Resolved {
+    Key(String),
+    Loop,
+}
+
+/// Follows `key` through any aliases to the key that actually holds the value.
+fn resolve(state: &AppState, rtxn: &heed::RoTxn, key: &str) -> heed::Result<Resolved> {
+    let mut seen = vec![key.to_owned()];
+
+    while let Some(target) = state.aliases.get(rtxn, seen.last().unwrap())? {
+        if seen.iter().any(|key| key == target) || seen.len() > MAX_DEPTH {
+            return Ok(Resolved::Loop);
+        }
+
+        seen.push(target.to_owned());
+    }
+
+    Ok(Resolved::Key(seen.pop().unwrap()))
+}
+
+/// The key `GET /:key` should read, erroring out on an alias loop.
+pub(crate) fn resolve_key(
+    state: &AppState,
+    rtxn: &heed::RoTxn,
+    key: &str,
+) -> Result<String, String> {
+    match resolve(state, rtxn, key).map_err(|err| err.to_string())? {
+        Resolved::Key(key) => Ok(key),
+        Resolved::Loop => Err(format!("alias loop at {}", key)),
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AliasPayload {
+    target: String,
+}
+
+pub(crate) async fn put_alias(
+    State(state): State<Arc<AppState>>,
+    Path(alias): Path<String>,
+    Json(payload): Json<AliasPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let mut wtxn = state.write_txn().unwrap();
+
+    let value = state.aliases.put(&mut wtxn, &alias, &payload.target);
+
+    if value.is_err() {
+        return Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        ));
+    }
+
+    // Check the chain with the new alias in place, dropping the write if it loops back
+    match resolve(&state, &wtxn, &alias) {
+        Ok(Resolved::Key(_)) => {
+            state.commit(wtxn).unwrap();
+
+            // Reads of it now go to the target
+            state.misses.forget(&alias);
+
+            Ok((
+                StatusCode::OK,
+                Json(json!({ "alias": alias, "target": payload.target })),
+            ))
+        }
+        Ok(Resolved::Loop) => Ok((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Alias would create a loop" })),
+        )),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+pub(crate) async fn get_alias(
+    State(state): State<Arc<AppState>>,
+    Path(alias): Path<String>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let rtxn = state.read_txn().unwrap();
+
+    let value = state.aliases.get(&rtxn, &alias);
+
+    match value {
+        Ok(Some(target)) => Ok((
+            StatusCode::OK,
+            Json(json!({ "alias": alias, "target": target })),
+        )),
+        Ok(None) => Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Alias not found" })),
+        )),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+pub(crate) async fn delete_alias(
+    State(state): State<Arc<AppState>>,
+    Path(alias): Path<String>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let mut wtxn = state.write_txn().unwrap();
+
+    let value = state.aliases.delete(&mut wtxn, &alias);
+
+    match value {
+        Ok(true) => {
+            state.commit(wtxn).unwrap();
+
+            Ok((StatusCode::OK, Json(json!({ "alias": alias }))))
+        }
+        Ok(false) => Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Alias not found" })),
+        )),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{send_json, setup_tests};
+    use axum::http::{self, StatusCode};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn reads_resolve_through_aliases() {
+        let mut app = setup_tests().await;
+
+        send_json(
+            &mut app,
+            http::Method::PUT,
+            "/config-v42",
+            json!({ "key": "config-v42", "value": "settings" }),
+        )
+        .await;
+
+        for (alias, target) in [
+            ("config-stable", "config-current"),
+            ("config-current", "config-v42"),
+        ] {
+            let (status, _) = send_json(
+                &mut app,
+                http::Method::PUT,
+                &format!("/alias/{}", alias),
+                json!({ "target": target }),
+            )
+            .await;
+
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let (status, body) =
+            send_json(&mut app, http::Method::GET, "/config-stable", json!(null)).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["value"], "settings");
+    }
+
+    #[tokio::test]
+    async fn loops_are_rejected() {
+        let mut app = setup_tests().await;
+
+        send_json(
+            &mut app,
+            http::Method::PUT,
+            "/alias/loop-a",
+            json!({ "target": "loop-b" }),
+        )
+        .await;
+
+        let (status, _) = send_json(
+            &mut app,
+            http::Method::PUT,
+            "/alias/loop-b",
+            json!({ "target": "loop-a" }),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = send_json(
+            &mut app,
+            http::Method::PUT,
+            "/alias/loop-self",
+            json!({ "target": "loop-self" }),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,149 @@
+use axum::extract::State;
+use axum::{http::StatusCode, Json};
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+
+use crate::{alias, sizes, AppError, AppState};
+
+/// `POST /batch/get`: the values of every key in the JSON array posted that exists, read
+/// in one transaction so they're all as of the same moment. Aliases are followed as by
+/// `GET /:key`, and keys that don't exist are listed under `missing`. A key that could
+/// never be stored is refused with 400, naming it under `key`.
+pub(crate) async fn get_many(
+    State(state): State<Arc<AppState>>,
+    Json(keys): Json<Vec<String>>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    if keys.len() > state.limits.max_keys {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": format!(
+                    "Asked for more than {} keys, the LIST_MAX_KEYS limit, split it up",
+                    state.limits.max_keys
+                )
+            })),
+        ));
+    }
+
+    // A key LMDB can't hold would fail the whole read, so say which one it was up front
+    for key in &keys {
+        if let Some((status, Json(mut body))) = sizes::check_key(key) {
+            body["key"] = Value::from(key.as_str());
+            return Ok((status, Json(body)));
+        }
+    }
+
+    let rtxn = state.read_txn().unwrap();
+
+    let mut entries = Map::new();
+    let mut missing = Vec::new();
+
+    for key in keys {
+        let value = alias::resolve_key(&state, &rtxn, &key).and_then(|resolved| {
+            state
+                .kv
+                .get(&rtxn, &resolved)
+                .map_err(|err| err.to_string())
+        });
+
+        match value {
+            Ok(Some(value)) => {
+                entries.insert(key, Value::from(value));
+            }
+            Ok(None) => missing.push(key),
+            Err(_) => {
+                return Ok((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({ "error": "Internal server error" })),
+                ))
+            }
+        }
+    }
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "entries": entries, "missing": missing })),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request, StatusCode},
+    };
+    use serde_json::{json, Value};
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[tokio::test]
+    async fn gets_many_keys_at_once() {
+        let mut app = setup_tests().await;
+
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/import")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "batch": { "a": "1", "b": "2" } }).to_string(),
+            ))
+            .unwrap();
+        app.ready().await.unwrap().call(request).await.unwrap();
+
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/batch/get")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!(["batch:a", "batch:b", "batch:c"]).to_string(),
+            ))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(
+            body,
+            json!({ "entries": { "batch:a": "1", "batch:b": "2" }, "missing": ["batch:c"] })
+        );
+
+        // More than LIST_MAX_KEYS
+        let keys: Vec<String> = (0..21).map(|key| format!("batch:{}", key)).collect();
+
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/batch/get")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json!(keys).to_string()))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn refuses_keys_that_cant_be_stored() {
+        let mut app = setup_tests().await;
+
+        for bad in ["".to_owned(), "k".repeat(512)] {
+            let request = Request::builder()
+                .method(http::Method::POST)
+                .uri("/batch/get")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(json!(["batch:a", bad]).to_string()))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+
+            assert_eq!(body["key"], bad);
+        }
+    }
+}
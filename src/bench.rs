@@ -0,0 +1,326 @@
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use serde_json::json;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::wait;
+
+/// What `kv bench` runs against which server, from `BENCH_URL`, `BENCH_CONCURRENCY`,
+/// `BENCH_KEYS`, `BENCH_VALUE_BYTES`, `BENCH_READ_PERCENT` and `BENCH_DURATION`.
+pub(crate) struct Config {
+    url: String,
+    // Requests in flight at once, each client waiting for its answer before the next
+    concurrency: usize,
+    keys: usize,
+    value_bytes: usize,
+    // Of every 100 requests, how many are reads rather than writes
+    read_percent: u64,
+    duration: Duration,
+}
+
+fn number_var(name: &str, default: usize) -> Result<usize, String> {
+    match std::env::var(name) {
+        Ok(number) => match number.parse() {
+            Ok(0) | Err(_) => Err(format!(
+                "{} must be a positive number, got {}",
+                name, number
+            )),
+            Ok(number) => Ok(number),
+        },
+        Err(_) => Ok(default),
+    }
+}
+
+impl Config {
+    pub(crate) fn from_env() -> Result<Config, String> {
+        let read_percent = match std::env::var("BENCH_READ_PERCENT") {
+            Ok(percent) => match percent.parse() {
+                Ok(percent) if percent <= 100 => percent,
+                _ => {
+                    return Err(format!(
+                        "BENCH_READ_PERCENT must be from 0 to 100, got {}",
+                        percent
+                    ))
+                }
+            },
+            Err(_) => 90,
+        };
+
+        let duration = match std::env::var("BENCH_DURATION") {
+            Ok(duration) => match wait::parse_duration(&duration) {
+                Some(duration) if !duration.is_zero() => duration,
+                _ => {
+                    return Err(format!(
+                        "BENCH_DURATION must be a duration, got {}",
+                        duration
+                    ))
+                }
+            },
+            Err(_) => Duration::from_secs(10),
+        };
+
+        Ok(Config {
+            url: std::env::var("BENCH_URL")
+                .unwrap_or_else(|_| String::from("http://localhost:3000"))
+                .trim_end_matches('/')
+                .to_owned(),
+            concurrency: number_var("BENCH_CONCURRENCY", 8)?,
+            keys: number_var("BENCH_KEYS", 1000)?,
+            value_bytes: number_var("BENCH_VALUE_BYTES", 100)?,
+            read_percent,
+            duration,
+        })
+    }
+}
+
+/// The latency below which `percent` of `sorted` fall, by nearest rank.
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    match sorted.len() {
+        0 => Duration::ZERO,
+        len => sorted[(len * percent).div_ceil(100).max(1) - 1],
+    }
+}
+
+#[derive(Default)]
+struct Latencies {
+    reads: Vec<Duration>,
+    writes: Vec<Duration>,
+    errors: usize,
+}
+
+struct Report {
+    elapsed: Duration,
+    latencies: Latencies,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |latency: Duration| latency.as_secs_f64() * 1000.0;
+
+        for (name, latencies) in [
+            ("reads", &self.latencies.reads),
+            ("writes", &self.latencies.writes),
+        ] {
+            let mut sorted = latencies.clone();
+            sorted.sort();
+
+            writeln!(
+                f,
+                "{:<7}{:>9} req {:>10.1} req/s  p50 {:.2}ms  p90 {:.2}ms  p99 {:.2}ms  max {:.2}ms",
+                name,
+                sorted.len(),
+                sorted.len() as f64 / self.elapsed.as_secs_f64(),
+                ms(percentile(&sorted, 50)),
+                ms(percentile(&sorted, 90)),
+                ms(percentile(&sorted, 99)),
+                ms(sorted.last().copied().unwrap_or_default()),
+            )?;
+        }
+
+        write!(f, "{:<7}{:>9}", "errors", self.latencies.errors)
+    }
+}
+
+// No need for good randomness to pick keys, just for every client to pick differently
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+struct Target {
+    client: Client<HttpConnector>,
+    url: String,
+    value: String,
+}
+
+impl Target {
+    fn uri(&self, key: usize) -> Result<Uri, String> {
+        format!("{}/bench:{}", self.url, key)
+            .parse()
+            .map_err(|_| format!("BENCH_URL {} isn't a valid URL", self.url))
+    }
+
+    // Whether the server answered with a success, a miss counting as one for reads
+    async fn request(&self, method: Method, key: usize) -> Result<bool, String> {
+        let read = method == Method::GET;
+
+        let body = match method {
+            Method::PUT => Body::from(
+                json!({ "key": format!("bench:{}", key), "value": self.value }).to_string(),
+            ),
+            _ => Body::empty(),
+        };
+
+        let request = Request::builder()
+            .method(method)
+            .uri(self.uri(key)?)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .unwrap();
+
+        match self.client.request(request).await {
+            Ok(response) => {
+                let status = response.status();
+                let success = status.is_success() || (read && status == StatusCode::NOT_FOUND);
+
+                // Read to the end, so the connection can be used again
+                let _ = hyper::body::to_bytes(response.into_body()).await;
+
+                Ok(success)
+            }
+            Err(_) => Ok(false),
+        }
+    }
+}
+
+async fn drive(target: Arc<Target>, config: Arc<Config>, client: usize) -> Latencies {
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+    let spread = (client as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    let mut random = XorShift((seed ^ spread) | 1);
+
+    let mut latencies = Latencies::default();
+    let end = Instant::now() + config.duration;
+
+    while Instant::now() < end {
+        let key = (random.next() % config.keys as u64) as usize;
+        let read = random.next() % 100 < config.read_percent;
+        let method = if read { Method::GET } else { Method::PUT };
+
+        let started = Instant::now();
+        let success = target.request(method, key).await.unwrap_or(false);
+        let latency = started.elapsed();
+
+        match (success, read) {
+            (false, _) => latencies.errors += 1,
+            (true, true) => latencies.reads.push(latency),
+            (true, false) => latencies.writes.push(latency),
+        }
+    }
+
+    latencies
+}
+
+async fn bench(config: Config) -> Result<Report, String> {
+    let config = Arc::new(config);
+    let target = Arc::new(Target {
+        client: Client::new(),
+        url: config.url.clone(),
+        value: "x".repeat(config.value_bytes),
+    });
+
+    // Every key is written once first, so reads don't just measure misses
+    let fills: Vec<_> = (0..config.concurrency)
+        .map(|client| {
+            let (target, config) = (target.clone(), config.clone());
+
+            tokio::spawn(async move {
+                for key in (client..config.keys).step_by(config.concurrency) {
+                    if !target.request(Method::PUT, key).await? {
+                        return Err(format!("writing bench:{} to {} failed", key, target.url));
+                    }
+                }
+
+                Ok(())
+            })
+        })
+        .collect();
+
+    for fill in fills {
+        fill.await.unwrap()?;
+    }
+
+    let started = Instant::now();
+
+    let clients: Vec<_> = (0..config.concurrency)
+        .map(|client| tokio::spawn(drive(target.clone(), config.clone(), client)))
+        .collect();
+
+    let mut latencies = Latencies::default();
+
+    for client in clients {
+        let client = client.await.unwrap();
+
+        latencies.reads.extend(client.reads);
+        latencies.writes.extend(client.writes);
+        latencies.errors += client.errors;
+    }
+
+    Ok(Report {
+        elapsed: started.elapsed(),
+        latencies,
+    })
+}
+
+/// `kv bench`: writes `BENCH_KEYS` keys under `bench:` to the server at `BENCH_URL`,
+/// then reads and writes them at random for `BENCH_DURATION` and prints the throughput
+/// and latency percentiles of each, for comparing releases.
+pub(crate) async fn run(config: Config) -> Result<(), String> {
+    println!(
+        "{} clients, {} keys of {} bytes, {}% reads, for {:?} against {}",
+        config.concurrency,
+        config.keys,
+        config.value_bytes,
+        config.read_percent,
+        config.duration,
+        config.url
+    );
+
+    println!("{}", bench(config).await?);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+
+    #[test]
+    fn picks_percentiles_by_nearest_rank() {
+        let sorted: Vec<Duration> = (1..=10).map(Duration::from_millis).collect();
+
+        assert_eq!(percentile(&sorted, 50), Duration::from_millis(5));
+        assert_eq!(percentile(&sorted, 90), Duration::from_millis(9));
+        assert_eq!(percentile(&sorted, 99), Duration::from_millis(10));
+        assert_eq!(percentile(&sorted, 0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 50), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn benches_a_server() {
+        let app = setup_tests().await;
+
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let url = format!("http://{}", server.local_addr());
+
+        tokio::spawn(server);
+
+        let report = bench(Config {
+            url,
+            concurrency: 2,
+            keys: 10,
+            value_bytes: 16,
+            read_percent: 50,
+            duration: Duration::from_millis(200),
+        })
+        .await
+        .unwrap();
+
+        assert!(!report.latencies.reads.is_empty());
+        assert!(!report.latencies.writes.is_empty());
+        assert_eq!(report.latencies.errors, 0);
+        assert!(report.to_string().contains("p99"));
+    }
+}
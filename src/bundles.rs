@@ -0,0 +1,404 @@
+use axum::extract::{Path, Query, State};
+use axum::{http::StatusCode, Json};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::{name_prefix, AppError, AppState};
+
+// Every published version is kept under `[name length][name][version]`, while
+// `bundle_heads` maps a bundle name to its current version. Versions are immutable, so
+// flipping or rolling back a bundle only ever moves the head.
+
+pub(crate) type Entries = BTreeMap<String, String>;
+
+fn version_key(name: &str, version: &str) -> Vec<u8> {
+    let mut key = name_prefix(name);
+    key.extend_from_slice(version.as_bytes());
+    key
+}
+
+/// Splits `name@version`, a bare name refers to the bundle's current version.
+fn parse_spec(spec: &str) -> (&str, Option<&str>) {
+    match spec.rsplit_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (spec, None),
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PublishPayload {
+    version: String,
+    entries: Entries,
+}
+
+/// Stores a new version of a bundle and makes it the current one in the same write.
+pub(crate) async fn publish(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(payload): Json<PublishPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    // Either would make `name@version` specs read as another bundle's
+    if name.contains('@') || payload.version.is_empty() || payload.version.contains('@') {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Invalid bundle name or version" })),
+        ));
+    }
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    let key = version_key(&name, &payload.version);
+
+    match state.bundles.get(&wtxn, &key) {
+        Ok(Some(_)) => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "Bundle version already exists" })),
+            ))
+        }
+        Ok(None) => {}
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    }
+
+    let value = state
+        .bundles
+        .put(&mut wtxn, &key, &payload.entries)
+        .and_then(|_| state.bundle_heads.put(&mut wtxn, &name, &payload.version));
+
+    match value {
+        Ok(_) => {
+            state.commit(wtxn).unwrap();
+
+            Ok((
+                StatusCode::CREATED,
+                Json(json!({ "name": name, "version": payload.version })),
+            ))
+        }
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+// Resolves the head and reads the entries from the same snapshot
+fn read_bundle(
+    state: &AppState,
+    rtxn: &heed::RoTxn,
+    spec: &str,
+) -> heed::Result<Option<(String, Entries)>> {
+    let (name, version) = parse_spec(spec);
+
+    let version = match version {
+        Some(version) => version.to_owned(),
+        None => match state.bundle_heads.get(rtxn, name)? {
+            Some(head) => head.to_owned(),
+            None => return Ok(None),
+        },
+    };
+
+    Ok(state
+        .bundles
+        .get(rtxn, &version_key(name, &version))?
+        .map(|entries| (version, entries)))
+}
+
+/// Serves `GET /bundles/:name` for the current version and `/bundles/:name@:version`
+/// for a specific one.
+pub(crate) async fn get_bundle(
+    State(state): State<Arc<AppState>>,
+    Path(spec): Path<String>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let rtxn = state.read_txn().unwrap();
+
+    let bundle = read_bundle(&state, &rtxn, &spec);
+
+    match bundle {
+        Ok(Some((version, entries))) => Ok((
+            StatusCode::OK,
+            Json(json!({ "name": parse_spec(&spec).0, "version": version, "entries": entries })),
+        )),
+        Ok(None) => Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Bundle not found" })),
+        )),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+/// Lists the keys added, changed and removed going from one set of entries to another.
+fn diff_entries(from: &Entries, to: &Entries) -> Value {
+    let added: Entries = to
+        .iter()
+        .filter(|(key, _)| !from.contains_key(*key))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+
+    let changed: BTreeMap<&String, Value> = to
+        .iter()
+        .filter_map(|(key, value)| match from.get(key) {
+            Some(previous) if previous != value => {
+                Some((key, json!({ "from": previous, "to": value })))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let removed: Vec<&String> = from.keys().filter(|key| !to.contains_key(*key)).collect();
+
+    json!({ "added": added, "changed": changed, "removed": removed })
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DiffQuery {
+    // Bundle specs, `name@version` or a bare name for the current version
+    from: String,
+    to: String,
+}
+
+pub(crate) async fn diff(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<DiffQuery>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let rtxn = state.read_txn().unwrap();
+
+    let bundles = read_bundle(&state, &rtxn, &query.from)
+        .and_then(|from| Ok((from, read_bundle(&state, &rtxn, &query.to)?)));
+
+    match bundles {
+        Ok((Some((from_version, from)), Some((to_version, to)))) => {
+            let mut diff = diff_entries(&from, &to);
+
+            diff["from"] = json!(format!("{}@{}", parse_spec(&query.from).0, from_version));
+            diff["to"] = json!(format!("{}@{}", parse_spec(&query.to).0, to_version));
+
+            Ok((StatusCode::OK, Json(diff)))
+        }
+        Ok(_) => Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Bundle not found" })),
+        )),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CurrentPayload {
+    version: String,
+}
+
+/// Points the bundle back (or forward) at an already published version.
+pub(crate) async fn set_current(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(payload): Json<CurrentPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let mut wtxn = state.write_txn().unwrap();
+
+    match state
+        .bundles
+        .get(&wtxn, &version_key(&name, &payload.version))
+    {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return Ok((
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "Bundle not found" })),
+            ))
+        }
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    }
+
+    let value = state.bundle_heads.put(&mut wtxn, &name, &payload.version);
+
+    match value {
+        Ok(_) => {
+            state.commit(wtxn).unwrap();
+
+            Ok((
+                StatusCode::OK,
+                Json(json!({ "name": name, "version": payload.version })),
+            ))
+        }
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+/// Drops a bundle along with every version published under it.
+pub(crate) async fn delete_bundle(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let mut wtxn = state.write_txn().unwrap();
+
+    let value = state
+        .bundle_heads
+        .delete(&mut wtxn, &name)
+        .and_then(|deleted| {
+            let versions: Vec<Vec<u8>> = state
+                .bundles
+                .remap_data_type::<heed::types::DecodeIgnore>()
+                .prefix_iter(&wtxn, &name_prefix(&name))?
+                .map(|entry| entry.map(|(key, _)| key.to_vec()))
+                .collect::<heed::Result<_>>()?;
+
+            for key in &versions {
+                state.bundles.delete(&mut wtxn, key)?;
+            }
+
+            Ok(deleted)
+        });
+
+    match value {
+        Ok(true) => {
+            state.commit(wtxn).unwrap();
+
+            Ok((StatusCode::OK, Json(json!({ "name": name }))))
+        }
+        Ok(false) => Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Bundle not found" })),
+        )),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{send_json, setup_tests};
+    use axum::http::{self};
+
+    #[test]
+    fn diffs_entries() {
+        let from = Entries::from([
+            (String::from("kept"), String::from("1")),
+            (String::from("changed"), String::from("old")),
+            (String::from("removed"), String::from("1")),
+        ]);
+        let to = Entries::from([
+            (String::from("kept"), String::from("1")),
+            (String::from("changed"), String::from("new")),
+            (String::from("added"), String::from("1")),
+        ]);
+
+        assert_eq!(
+            diff_entries(&from, &to),
+            json!({
+                "added": { "added": "1" },
+                "changed": { "changed": { "from": "old", "to": "new" } },
+                "removed": ["removed"],
+            })
+        );
+    }
+
+    #[test]
+    fn parses_specs() {
+        assert_eq!(parse_spec("app"), ("app", None));
+        assert_eq!(parse_spec("app@v2"), ("app", Some("v2")));
+    }
+
+    #[tokio::test]
+    async fn publish_and_roll_back() {
+        let mut app = setup_tests().await;
+
+        // Bundles aren't cleared with the keys, so start over from a previous run
+        send_json(
+            &mut app,
+            http::Method::DELETE,
+            "/bundles/rollout",
+            json!(null),
+        )
+        .await;
+
+        for (version, flag) in [("v1", "off"), ("v2", "on")] {
+            let (status, _) = send_json(
+                &mut app,
+                http::Method::POST,
+                "/bundles/rollout",
+                json!({ "version": version, "entries": { "flag": flag } }),
+            )
+            .await;
+
+            assert_eq!(status, StatusCode::CREATED);
+        }
+
+        let (status, _) = send_json(
+            &mut app,
+            http::Method::POST,
+            "/bundles/rollout",
+            json!({ "version": "v1", "entries": {} }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = send_json(
+            &mut app,
+            http::Method::POST,
+            "/bundles/rollout",
+            json!({ "version": "v@3", "entries": {} }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (_, body) =
+            send_json(&mut app, http::Method::GET, "/bundles/rollout", json!(null)).await;
+        assert_eq!(body["version"], "v2");
+        assert_eq!(body["entries"], json!({ "flag": "on" }));
+
+        let (_, body) = send_json(
+            &mut app,
+            http::Method::GET,
+            "/bundles/rollout@v1",
+            json!(null),
+        )
+        .await;
+        assert_eq!(body["entries"], json!({ "flag": "off" }));
+
+        let (status, _) = send_json(
+            &mut app,
+            http::Method::PUT,
+            "/bundles/rollout/current",
+            json!({ "version": "v1" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (_, body) =
+            send_json(&mut app, http::Method::GET, "/bundles/rollout", json!(null)).await;
+        assert_eq!(body["version"], "v1");
+
+        let (status, _) = send_json(
+            &mut app,
+            http::Method::GET,
+            "/bundles/rollout@v3",
+            json!(null),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,141 @@
+use axum::http::{header, HeaderMap, HeaderValue};
+
+use crate::{wait, AppState};
+
+/// Parses `CACHE_MAX_AGE`, comma separated `prefix=seconds` pairs.
+pub(crate) fn parse_policies(config: &str) -> Result<Vec<(String, u64)>, String> {
+    config
+        .split(',')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (prefix, seconds) = pair
+                .rsplit_once('=')
+                .ok_or_else(|| format!("expected prefix=seconds, got {}", pair))?;
+
+            let seconds = seconds
+                .parse()
+                .map_err(|_| format!("expected a number of seconds, got {}", seconds))?;
+
+            Ok((prefix.to_owned(), seconds))
+        })
+        .collect()
+}
+
+// The most specific prefix wins when several match
+fn max_age_for(state: &AppState, key: &str) -> Option<u64> {
+    state
+        .cache_policies
+        .iter()
+        .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, seconds)| *seconds)
+}
+
+/// The caching headers of a read of `key` at `version`.
+///
+/// Keys without a policy only get their `ETag`, which leaves caching them up to the
+/// client. `Age` is always 0, responses are read from the store and never served stale.
+pub(crate) fn headers(state: &AppState, key: &str, version: u64) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    headers.insert(
+        header::ETAG,
+        HeaderValue::from_str(&wait::etag(version)).unwrap(),
+    );
+
+    if let Some(max_age) = max_age_for(state, key) {
+        let cache_control = match max_age {
+            0 => String::from("no-cache"),
+            max_age => format!("public, max-age={}", max_age),
+        };
+
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_str(&cache_control).unwrap(),
+        );
+        headers.insert(header::AGE, HeaderValue::from_static("0"));
+    }
+
+    headers
+}
+
+/// Whether a conditional read with these request headers already has `version`.
+pub(crate) fn not_modified(request: &HeaderMap, version: u64) -> bool {
+    request
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|if_none_match| wait::etag_matches(if_none_match, version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request, StatusCode},
+    };
+    use serde_json::json;
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!(
+            parse_policies("static:=3600,config:=0").unwrap(),
+            vec![
+                (String::from("static:"), 3600),
+                (String::from("config:"), 0)
+            ]
+        );
+        assert!(parse_policies("static:=an hour").is_err());
+        assert!(parse_policies("static:").is_err());
+    }
+
+    #[tokio::test]
+    async fn caches_reads_by_prefix() {
+        let mut app = setup_tests().await;
+
+        let request = Request::builder()
+            .method(http::Method::PUT)
+            .uri("/cached:logo")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "key": "cached:logo", "value": "v" }).to_string(),
+            ))
+            .unwrap();
+
+        app.ready().await.unwrap().call(request).await.unwrap();
+
+        let request = Request::builder()
+            .uri("/cached:logo")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "public, max-age=60"
+        );
+        assert_eq!(response.headers()[header::AGE], "0");
+
+        let etag = response.headers()[header::ETAG].clone();
+
+        // Revalidating with the same version gets an empty 304
+        let request = Request::builder()
+            .uri("/cached:logo")
+            .header(header::IF_NONE_MATCH, etag)
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "public, max-age=60"
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use heed::MdbError;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Random delays and transient failures injected whenever a transaction is opened, only
+/// compiled in with the `chaos` feature.
+///
+/// Configured with `CHAOS_FAILURE_RATE` (0 to 1), `CHAOS_MAX_DELAY_MS` and
+/// `CHAOS_SEED` to replay the same run, it stays out of the way when none are set.
+pub(crate) struct Chaos {
+    failure_rate: f64,
+    max_delay: Duration,
+    // xorshift64 state, good enough to pick what to disrupt
+    rng: Mutex<u64>,
+}
+
+impl Chaos {
+    pub(crate) fn from_env() -> Chaos {
+        let failure_rate = std::env::var("CHAOS_FAILURE_RATE")
+            .map(|rate| rate.parse().unwrap())
+            .unwrap_or(0.0);
+        let max_delay = std::env::var("CHAOS_MAX_DELAY_MS")
+            .map(|delay| Duration::from_millis(delay.parse().unwrap()))
+            .unwrap_or_default();
+        let seed = std::env::var("CHAOS_SEED")
+            .map(|seed| seed.parse().unwrap())
+            .unwrap_or_else(|_| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos() as u64
+            });
+
+        Chaos::new(failure_rate, max_delay, seed)
+    }
+
+    fn new(failure_rate: f64, max_delay: Duration, seed: u64) -> Chaos {
+        Chaos {
+            failure_rate,
+            max_delay,
+            // Zero would get xorshift stuck
+            rng: Mutex::new(seed | 1),
+        }
+    }
+
+    // A number in [0, 1)
+    fn next(&self) -> f64 {
+        let mut state = self.rng.lock().unwrap();
+
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+
+        (*state >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Maybe sleeps and maybe fails, with an error the retry logic treats as transient.
+    pub(crate) fn disrupt(&self) -> heed::Result<()> {
+        if !self.max_delay.is_zero() {
+            thread::sleep(self.max_delay.mul_f64(self.next()));
+        }
+
+        if self.next() < self.failure_rate {
+            tracing::debug!("chaos: failing transaction");
+
+            return Err(heed::Error::Mdb(MdbError::ReadersFull));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::with_retry;
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request, StatusCode},
+    };
+    use serde_json::json;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[test]
+    fn fails_at_the_configured_rate() {
+        let chaos = Chaos::new(0.25, Duration::ZERO, 42);
+
+        let failures = (0..10_000).filter(|_| chaos.disrupt().is_err()).count();
+
+        assert!((2_000..3_000).contains(&failures), "{} failures", failures);
+    }
+
+    #[test]
+    fn retries_absorb_injected_failures() {
+        let chaos = Chaos::new(0.25, Duration::ZERO, 7);
+        let retries = AtomicU64::new(0);
+
+        for _ in 0..100 {
+            with_retry(&retries, || chaos.disrupt()).unwrap();
+        }
+
+        assert!(retries.load(Ordering::Relaxed) > 0);
+    }
+
+    #[tokio::test]
+    async fn writes_survive_a_flaky_store() {
+        std::env::set_var("CHAOS_FAILURE_RATE", "0.2");
+        std::env::set_var("CHAOS_MAX_DELAY_MS", "2");
+        std::env::set_var("CHAOS_SEED", "1");
+
+        let mut app = setup_tests().await;
+
+        std::env::remove_var("CHAOS_FAILURE_RATE");
+        std::env::remove_var("CHAOS_MAX_DELAY_MS");
+        std::env::remove_var("CHAOS_SEED");
+
+        for i in 0..20 {
+            let request = Request::builder()
+                .method(http::Method::PUT)
+                .uri(format!("/chaos-{}", i))
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    json!({ "key": format!("chaos-{}", i), "value": "ok" }).to_string(),
+                ))
+                .unwrap();
+
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+}
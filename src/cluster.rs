@@ -0,0 +1,94 @@
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::{http::StatusCode, Json};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::{immutable, mirror, AppState};
+
+/// `GET /admin/cluster`: the `CLUSTER_NODES` keys are sharded over, which clients hashing
+/// keys to nodes themselves build their ring from. With the admin token it also has this
+/// server's role, `leader` when it copies writes to a `MIRROR_URL` or `standalone`, and
+/// how far behind that mirror is. The shards don't replicate each other, so there's no
+/// lag to report for them.
+pub(crate) async fn nodes(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<Value>) {
+    let mut body = json!({ "nodes": state.cluster_nodes });
+
+    if immutable::admin_override(&state, &headers) {
+        let mirror = mirror::status(&state);
+
+        body["role"] = Value::from(if mirror.is_some() {
+            "leader"
+        } else {
+            "standalone"
+        });
+        body["mirror"] = mirror.unwrap_or(Value::Null);
+    }
+
+    (StatusCode::OK, Json(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::immutable::ADMIN_TOKEN_HEADER;
+    use crate::tests::{get_json, send, setup_tests};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use kv_client::{KvClient, ShardedClient};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn reports_role_to_admins() {
+        let mut app = setup_tests().await;
+
+        let (status, body) = get_json(&mut app, "/admin/cluster").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "nodes": [] }));
+
+        let request = Request::builder()
+            .uri("/admin/cluster")
+            .header(ADMIN_TOKEN_HEADER, "test-admin-token")
+            .body(Body::empty())
+            .unwrap();
+
+        let (_, body) = send(&mut app, request).await;
+
+        assert_eq!(
+            body,
+            json!({ "nodes": [], "role": "standalone", "mirror": null })
+        );
+    }
+
+    #[tokio::test]
+    async fn sharded_client_round_trip() {
+        let app = setup_tests().await;
+
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let url = format!("http://{}", server.local_addr());
+
+        tokio::spawn(server);
+
+        let client = ShardedClient::new([url.clone()]);
+
+        // Without `CLUSTER_NODES` the ring stays as it was
+        client.refresh().await.unwrap();
+
+        assert_eq!(client.node_for("sharded"), url);
+
+        client.put("sharded", "value").await.unwrap();
+
+        assert_eq!(
+            client.get("sharded").await.unwrap().as_deref(),
+            Some("value")
+        );
+        assert_eq!(
+            client.list().await.unwrap(),
+            vec![(String::from("sharded"), String::from("value"))]
+        );
+    }
+}
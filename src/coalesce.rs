@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+use crate::profile;
+
+/// Deduplicates concurrent lookups of the same key, late callers wait for the lookup
+/// already in flight and share its result.
+pub(crate) struct Singleflight<T> {
+    inflight: Mutex<Inflight<T>>,
+}
+
+struct Inflight<T> {
+    // Tells a lookup apart from one started for the same key after it was forgotten
+    next_id: u64,
+    lookups: HashMap<String, (u64, watch::Receiver<Option<T>>)>,
+}
+
+// Takes the leader's lookup out of the map however its future ends, waiters see the
+// sender dropped and look up themselves if it ended without a result
+struct Leader<'a, T> {
+    flight: &'a Singleflight<T>,
+    key: &'a str,
+    id: u64,
+}
+
+impl<T> Drop for Leader<'_, T> {
+    fn drop(&mut self) {
+        let mut inflight = self.flight.inflight.lock().unwrap();
+
+        // Unless it was forgotten and another one took its place
+        if matches!(inflight.lookups.get(self.key), Some((id, _)) if *id == self.id) {
+            inflight.lookups.remove(self.key);
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Singleflight<T> {
+    pub(crate) fn new() -> Self {
+        Singleflight {
+            inflight: Mutex::new(Inflight {
+                next_id: 0,
+                lookups: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Runs `lookup` on the blocking pool unless one for `key` is in flight already.
+    ///
+    /// Also returns whether the result came from another caller's lookup.
+    pub(crate) async fn run<F>(&self, key: &str, lookup: F) -> (T, bool)
+    where
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let leader = {
+            let mut inflight = self.inflight.lock().unwrap();
+
+            match inflight.lookups.get(key) {
+                Some((_, receiver)) => Err(receiver.clone()),
+                None => {
+                    let id = inflight.next_id;
+                    inflight.next_id += 1;
+
+                    let (sender, receiver) = watch::channel(None);
+                    inflight.lookups.insert(key.to_owned(), (id, receiver));
+
+                    Ok((
+                        sender,
+                        Leader {
+                            flight: self,
+                            key,
+                            id,
+                        },
+                    ))
+                }
+            }
+        };
+
+        let (sender, leader) = match leader {
+            Ok(leader) => leader,
+            Err(mut receiver) => {
+                loop {
+                    if let Some(result) = receiver.borrow().clone() {
+                        return (result, true);
+                    }
+
+                    if receiver.changed().await.is_err() {
+                        break;
+                    }
+                }
+
+                // The lookup we waited on died, do our own
+                return (Self::spawn(lookup).await, false);
+            }
+        };
+
+        let result = Self::spawn(lookup).await;
+
+        drop(leader);
+
+        let _ = sender.send(Some(result.clone()));
+
+        (result, false)
+    }
+
+    /// Stops callers from here on sharing the lookup of `key` in flight, once a write to it
+    /// is committed, as it may have read from before the write.
+    pub(crate) fn forget(&self, key: &str) {
+        self.inflight.lock().unwrap().lookups.remove(key);
+    }
+
+    async fn spawn<F>(lookup: F) -> T
+    where
+        F: FnOnce() -> T + Send + 'static,
+    {
+        // Task locals stay behind on this thread
+        let profile = profile::current();
+
+        match tokio::task::spawn_blocking(move || profile::within(profile, lookup)).await {
+            Ok(result) => result,
+            Err(error) => std::panic::resume_unwind(error.into_panic()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        http::{self, Request, StatusCode},
+    };
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_lookups_are_shared() {
+        let flight = Arc::new(Singleflight::new());
+        let lookups = Arc::new(AtomicU32::new(0));
+
+        let callers: Vec<_> = (0..4)
+            .map(|_| {
+                let flight = flight.clone();
+                let lookups = lookups.clone();
+
+                tokio::spawn(async move {
+                    flight
+                        .run("key", move || {
+                            lookups.fetch_add(1, Ordering::SeqCst);
+                            std::thread::sleep(Duration::from_millis(100));
+                            42
+                        })
+                        .await
+                })
+            })
+            .collect();
+
+        let mut shared = 0;
+
+        for caller in callers {
+            let (result, coalesced) = caller.await.unwrap();
+
+            assert_eq!(result, 42);
+
+            if coalesced {
+                shared += 1;
+            }
+        }
+
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+        assert_eq!(shared, 3);
+
+        // Nothing in flight any more, the next call looks up again
+        let (_, coalesced) = flight.run("key", || 7).await;
+
+        assert!(!coalesced);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn forgets_lookups_dropped_midway() {
+        let flight = Singleflight::new();
+
+        let slow = flight.run("key", || {
+            std::thread::sleep(Duration::from_millis(100));
+            1
+        });
+
+        assert!(tokio::time::timeout(Duration::from_millis(10), slow)
+            .await
+            .is_err());
+
+        assert_eq!(flight.run("key", || 2).await, (2, false));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reads_after_a_write_see_it() {
+        let _ = crate::tests::setup_tests().await;
+
+        let state = crate::app_state().unwrap();
+        let mut app = crate::router(state.clone());
+
+        // A lookup from before the write, still in flight once it's committed
+        let stale = tokio::spawn({
+            let state = state.clone();
+
+            async move {
+                state
+                    .reads
+                    .run("coalesced", || {
+                        std::thread::sleep(Duration::from_millis(200));
+                        Ok(Some((String::from("stale"), 0)))
+                    })
+                    .await
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let request = Request::builder()
+            .method(http::Method::PUT)
+            .uri("/coalesced")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "key": "coalesced", "value": "fresh" }).to_string(),
+            ))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = Request::builder()
+            .uri("/coalesced")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["value"], "fresh");
+
+        let _ = stale.await.unwrap();
+    }
+}
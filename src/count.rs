@@ -0,0 +1,272 @@
+use axum::extract::{Query, State};
+use axum::{http::StatusCode, Json};
+use heed::types::{DecodeIgnore, OwnedType, Str};
+use heed::{Database, Env, RwTxn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::{AppError, AppState};
+
+/// Brings the stored counters in line with the configured prefixes at startup.
+///
+/// Only prefixes without a counter yet are scanned, counters of prefixes that are no
+/// longer configured are dropped since writes stop keeping them exact.
+pub(crate) fn rebuild_counters(
+    env: &Env,
+    kv: Database<Str, Str>,
+    counters: Database<Str, OwnedType<u64>>,
+    prefixes: &[String],
+) -> heed::Result<()> {
+    let mut wtxn = env.write_txn()?;
+
+    let stale: Vec<String> = counters
+        .iter(&wtxn)?
+        .filter_map(Result::ok)
+        .map(|(prefix, _)| prefix.to_owned())
+        .filter(|prefix| !prefixes.contains(prefix))
+        .collect();
+
+    for prefix in &stale {
+        counters.delete(&mut wtxn, prefix)?;
+    }
+
+    for prefix in prefixes {
+        if counters.get(&wtxn, prefix)?.is_none() {
+            let count = kv
+                .remap_data_type::<DecodeIgnore>()
+                .prefix_iter(&wtxn, prefix)?
+                .count() as u64;
+
+            counters.put(&mut wtxn, prefix, &count)?;
+        }
+    }
+
+    wtxn.commit()
+}
+
+/// Adds `delta` to the counter of every configured prefix of `key` within a write.
+pub(crate) fn adjust_counters(
+    state: &AppState,
+    wtxn: &mut RwTxn,
+    key: &str,
+    delta: i64,
+) -> heed::Result<()> {
+    for prefix in state
+        .counted_prefixes
+        .iter()
+        .filter(|prefix| key.starts_with(prefix.as_str()))
+    {
+        let count = state.counters.get(wtxn, prefix)?.unwrap_or(0);
+
+        state
+            .counters
+            .put(wtxn, prefix, &count.saturating_add_signed(delta))?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn reset_counters(state: &AppState, wtxn: &mut RwTxn) -> heed::Result<()> {
+    for prefix in &state.counted_prefixes {
+        state.counters.put(wtxn, prefix, &0)?;
+    }
+
+    Ok(())
+}
+
+/// Matches `key` against a glob where `*` is any run of characters and `?` any one.
+pub(crate) fn glob_matches(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+
+    let (mut p, mut k) = (0, 0);
+    // Where the last `*` was seen and how much of the key it had swallowed
+    let mut backtrack = None;
+
+    while k < key.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, k));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            _ => match backtrack {
+                Some((star, swallowed)) => {
+                    p = star + 1;
+                    k = swallowed + 1;
+                    backtrack = Some((star, swallowed + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CountQuery {
+    prefix: Option<String>,
+    pattern: Option<String>,
+}
+
+pub(crate) async fn count(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<CountQuery>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let rtxn = state.read_txn().unwrap();
+
+    let prefix = query.prefix.unwrap_or_default();
+
+    // Maintained counters answer without touching the keys
+    if query.pattern.is_none() && state.counted_prefixes.contains(&prefix) {
+        return match state.counters.get(&rtxn, &prefix) {
+            Ok(count) => Ok((StatusCode::OK, Json(json!({ "count": count.unwrap_or(0) })))),
+            Err(_) => Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            )),
+        };
+    }
+
+    let keys = match state.kv.prefix_iter(&rtxn, &prefix) {
+        Ok(keys) => keys,
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    };
+
+    let count = keys
+        .remap_data_type::<DecodeIgnore>()
+        .filter_map(Result::ok)
+        .filter(|(key, _)| {
+            query
+                .pattern
+                .as_deref()
+                .is_none_or(|pattern| glob_matches(pattern, key))
+        })
+        .count();
+
+    Ok((StatusCode::OK, Json(json!({ "count": count }))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request},
+    };
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob_matches("user:*", "user:42"));
+        assert!(glob_matches("user:*:name", "user:42:name"));
+        assert!(glob_matches("?at", "cat"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("a*b*c", "aXXbYYc"));
+        assert!(!glob_matches("user:*:name", "user:42:email"));
+        assert!(!glob_matches("?at", "at"));
+    }
+
+    #[tokio::test]
+    async fn count_by_prefix_and_pattern() {
+        let mut app = setup_tests().await;
+
+        for key in ["user:1:name", "user:1:email", "user:2:name", "order:1"] {
+            let request = Request::builder()
+                .method(http::Method::POST)
+                .uri("/")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(json!({ "key": key, "value": "x" }).to_string()))
+                .unwrap();
+
+            app.ready().await.unwrap().call(request).await.unwrap();
+        }
+
+        for (uri, expected) in [
+            ("/count", 4),
+            ("/count?prefix=user:", 3),
+            ("/count?prefix=user:&pattern=*:name", 2),
+            ("/count?pattern=*:1*", 3),
+        ] {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+
+            assert_eq!(body, json!({ "count": expected }), "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn maintained_counters_follow_writes() {
+        let mut app = setup_tests().await;
+
+        for (method, uri, body) in [
+            (
+                http::Method::POST,
+                "/",
+                json!({ "key": "counted:a", "value": "x" }),
+            ),
+            (
+                http::Method::POST,
+                "/",
+                json!({ "key": "counted:b", "value": "x" }),
+            ),
+            // Creating an existing key is rejected and must not count twice
+            (
+                http::Method::POST,
+                "/",
+                json!({ "key": "counted:b", "value": "x" }),
+            ),
+            (
+                http::Method::PUT,
+                "/counted:c",
+                json!({ "key": "counted:c", "value": "x" }),
+            ),
+            (
+                http::Method::PUT,
+                "/counted:a",
+                json!({ "key": "counted:a", "value": "y" }),
+            ),
+            (http::Method::DELETE, "/counted:b", json!(null)),
+        ] {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(match body {
+                    Value::Null => Body::empty(),
+                    body => Body::from(body.to_string()),
+                })
+                .unwrap();
+
+            app.ready().await.unwrap().call(request).await.unwrap();
+        }
+
+        // The maintained counter and a full scan have to agree
+        for uri in ["/count?prefix=counted:", "/count?prefix=counted:&pattern=*"] {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+
+            assert_eq!(body, json!({ "count": 2 }), "{}", uri);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+// Five field cron expressions, `minute hour day-of-month month day-of-week`, read in UTC.
+// Each field takes `*`, numbers, ranges like `1-5`, steps like `*/15` or `0-30/10`, and
+// comma separated lists of those. Day of week counts from Sunday as 0, 7 is Sunday too.
+
+const MINUTE_MS: u64 = 60 * 1000;
+const HOUR_MS: u64 = 60 * MINUTE_MS;
+const DAY_MS: u64 = 24 * HOUR_MS;
+
+// How far ahead to look for the next time before deciding an expression never matches, like
+// `0 0 30 2 *`. Enough to get around every leap year and weekday combination.
+const SEARCH_DAYS: u64 = 8 * 366;
+
+/// A parsed cron expression, each field as a bit set of the values it matches.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Cron matches either day field when both are restricted, rather than both. As in
+    // Vixie cron, a field starting with `*`, like `*/2`, isn't restricted
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let mut bits = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse() {
+                Ok(0) | Err(_) => return Err(format!("bad step in {}", part)),
+                Ok(step) => (range, step),
+            },
+            None => (part, 1),
+        };
+
+        let number = |value: &str| match value.parse() {
+            Ok(value) if (min..=max).contains(&value) => Ok(value),
+            _ => Err(format!("{} isn't between {} and {}", value, min, max)),
+        };
+
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (number(start)?, number(end)?),
+            None => {
+                let start = number(range)?;
+                // `5/15` runs from 5 to the end in steps
+                (start, if step > 1 { max } else { start })
+            }
+        };
+
+        if start > end {
+            return Err(format!("{} runs backwards", range));
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Ok(bits)
+}
+
+impl Cron {
+    pub(crate) fn parse(expression: &str) -> Result<Cron, String> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!(
+                "expected 5 fields, minute hour day month weekday, got {}",
+                fields.len()
+            ));
+        };
+
+        let mut weekday_bits = parse_field(weekdays, 0, 7)?;
+
+        // Sunday either way
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits = (weekday_bits | 1) & !(1 << 7);
+        }
+
+        Ok(Cron {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            days_restricted: !days.starts_with('*'),
+            weekdays_restricted: !weekdays.starts_with('*'),
+        })
+    }
+
+    fn matches_day(&self, day: u64, month: u64, weekday: u64) -> bool {
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+
+        let day = self.days & (1 << day) != 0;
+        let weekday = self.weekdays & (1 << weekday) != 0;
+
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first time this matches strictly after `after`, both Unix time in milliseconds,
+    /// or `None` if it never does.
+    pub(crate) fn next_after(&self, after: u64) -> Option<u64> {
+        // The next whole minute
+        let mut time = (after / MINUTE_MS + 1) * MINUTE_MS;
+        let give_up = time + SEARCH_DAYS * DAY_MS;
+
+        while time < give_up {
+            let days = time / DAY_MS;
+            let (_, month, day) = civil_from_days(days);
+            // 1 January 1970 was a Thursday
+            let weekday = (days + 4) % 7;
+
+            if !self.matches_day(day, month, weekday) {
+                time = (days + 1) * DAY_MS;
+                continue;
+            }
+
+            if self.hours & (1 << (time % DAY_MS / HOUR_MS)) == 0 {
+                time = (time / HOUR_MS + 1) * HOUR_MS;
+                continue;
+            }
+
+            if self.minutes & (1 << (time % HOUR_MS / MINUTE_MS)) == 0 {
+                time += MINUTE_MS;
+                continue;
+            }
+
+            return Some(time);
+        }
+
+        None
+    }
+}
+
+// The year, month and day of the month `days` after 1 January 1970, from Howard Hinnant's
+// `civil_from_days`
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-02-28T23:59:30Z, a Wednesday in a leap year
+    const START: u64 = 1_709_164_770_000;
+
+    #[test]
+    fn reads_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(START / DAY_MS), (2024, 2, 28));
+        assert_eq!(civil_from_days(START / DAY_MS + 1), (2024, 2, 29));
+    }
+
+    #[test]
+    fn finds_the_next_time() {
+        let next = |expression: &str| Cron::parse(expression).unwrap().next_after(START);
+
+        assert_eq!(next("* * * * *"), Some(START + 30 * 1000));
+        // Midnight, on the leap day
+        assert_eq!(next("0 0 * * *"), Some(START + 30 * 1000));
+        assert_eq!(
+            next("*/15 9-17 * * 1-5"),
+            Some(START + 30 * 1000 + 9 * HOUR_MS)
+        );
+        // The next Sunday, 3 March
+        assert_eq!(
+            next("30 12 * * 0"),
+            Some(START + 30 * 1000 + 3 * DAY_MS + 12 * HOUR_MS + 30 * MINUTE_MS)
+        );
+        // The 1st or any Friday, whichever comes first
+        assert_eq!(next("0 0 1 * 5"), Some(START + 30 * 1000 + DAY_MS));
+        // Odd days that are also Fridays, the 29th isn't one but the 1st is
+        assert_eq!(next("0 0 */2 * 5"), Some(START + 30 * 1000 + DAY_MS));
+        assert_eq!(next("0 0 30 2 *"), None);
+
+        assert!(Cron::parse("* * *").is_err());
+        assert!(Cron::parse("60 * * * *").is_err());
+        assert!(Cron::parse("*/0 * * * *").is_err());
+    }
+}
@@ -0,0 +1,177 @@
+use axum::extract::State;
+use axum::http::{HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::{metrics, now_millis, AppState};
+
+/// Absolute deadline as Unix time in milliseconds.
+pub(crate) const DEADLINE_HEADER: &str = "x-request-deadline";
+/// Relative deadline in the gRPC format, a number followed by a unit like `250m`.
+pub(crate) const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+
+// The most digits gRPC allows in a timeout
+const MAX_GRPC_TIMEOUT_DIGITS: usize = 8;
+
+/// Parses a `grpc-timeout` value, hours down to nanoseconds.
+fn parse_grpc_timeout(timeout: &str) -> Option<Duration> {
+    let (number, unit) = timeout.split_at(timeout.len().checked_sub(1)?);
+
+    if number.len() > MAX_GRPC_TIMEOUT_DIGITS {
+        return None;
+    }
+
+    let number: u64 = number.parse().ok()?;
+
+    match unit {
+        "H" => Some(Duration::from_secs(number.checked_mul(60 * 60)?)),
+        "M" => Some(Duration::from_secs(number.checked_mul(60)?)),
+        "S" => Some(Duration::from_secs(number)),
+        "m" => Some(Duration::from_millis(number)),
+        "u" => Some(Duration::from_micros(number)),
+        "n" => Some(Duration::from_nanos(number)),
+        _ => None,
+    }
+}
+
+/// How long the client is still waiting for, the sooner of the two headers when both
+/// are sent. `None` without a deadline, an unreadable one is ignored.
+fn remaining(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+    let absolute = header(DEADLINE_HEADER)
+        .and_then(|deadline| deadline.parse::<u64>().ok())
+        .map(|deadline| Duration::from_millis(deadline.saturating_sub(now_millis())));
+    let relative = header(GRPC_TIMEOUT_HEADER).and_then(parse_grpc_timeout);
+
+    absolute.into_iter().chain(relative).min()
+}
+
+fn exceeded(state: &AppState) -> Response {
+    metrics::increment(&state.metrics.deadline_exceeded);
+
+    (
+        StatusCode::GATEWAY_TIMEOUT,
+        Json(json!({ "error": "Deadline exceeded" })),
+    )
+        .into_response()
+}
+
+/// Refuses requests whose deadline has already passed, and drops the handler if it
+/// runs past it.
+///
+/// Handlers never hold a transaction across an `.await`, so a dropped one has either
+/// committed its write before the deadline or not started it. What's left to do once it
+/// has, like writing through to the upstream, runs in a task of its own that isn't
+/// dropped along with it. Streamed bodies like `GET /` are already on their way by then
+/// and aren't cut short.
+pub(crate) async fn enforce<B>(
+    State(state): State<Arc<AppState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let remaining = match remaining(request.headers()) {
+        Some(remaining) => remaining,
+        None => return next.run(request).await,
+    };
+
+    if remaining.is_zero() {
+        return exceeded(&state);
+    }
+
+    // Too far off to say when is as good as no deadline
+    let deadline = match Instant::now().checked_add(remaining) {
+        Some(deadline) => deadline,
+        None => return next.run(request).await,
+    };
+
+    match tokio::time::timeout_at(deadline, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => exceeded(&state),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::body::Body;
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[test]
+    fn parses_grpc_timeouts() {
+        assert_eq!(parse_grpc_timeout("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_grpc_timeout("2S"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_grpc_timeout("2s"), None);
+        assert_eq!(parse_grpc_timeout(""), None);
+        assert_eq!(
+            parse_grpc_timeout("99999999H"),
+            Some(Duration::from_secs(99_999_999 * 60 * 60))
+        );
+        // Longer than gRPC allows, and would overflow
+        assert_eq!(parse_grpc_timeout("18446744073709551615S"), None);
+        assert_eq!(parse_grpc_timeout("18446744073709551615H"), None);
+        assert_eq!(parse_grpc_timeout("123456789M"), None);
+    }
+
+    #[tokio::test]
+    async fn refuses_expired_requests() {
+        let mut app = setup_tests().await;
+
+        let request = Request::builder()
+            .method(axum::http::Method::PUT)
+            .uri("/too-late")
+            .header(axum::http::header::CONTENT_TYPE, "application/json")
+            .header(DEADLINE_HEADER, (now_millis() - 1000).to_string())
+            .body(Body::from(
+                json!({ "key": "too-late", "value": "v" }).to_string(),
+            ))
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        let request = Request::builder()
+            .uri("/too-late")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // A wait gets cut short at the deadline instead of its own timeout
+        let request = Request::builder()
+            .uri("/too-late/wait?timeout=30s")
+            .header(GRPC_TIMEOUT_HEADER, "50m")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        // Deadlines too far off to keep track of are none at all
+        for (header, deadline) in [
+            (GRPC_TIMEOUT_HEADER, String::from("99999999H")),
+            (DEADLINE_HEADER, u64::MAX.to_string()),
+        ] {
+            let request = Request::builder()
+                .uri("/too-late")
+                .header(header, deadline)
+                .body(Body::empty())
+                .unwrap();
+
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use axum::extract::State;
+use axum::{http::StatusCode, Json};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{nested, paging, record_keys, AppError, AppState};
+
+#[derive(Deserialize)]
+pub(crate) struct SyncPayload {
+    #[serde(default)]
+    prefix: String,
+    // The version of each key the client already has
+    #[serde(default)]
+    versions: HashMap<String, u64>,
+}
+
+/// `POST /sync`: the keys under `prefix` that changed since the versions the client sent,
+/// so devices coming back online only download what they're missing. Keys the client has
+/// that are gone come back with a `null` value.
+pub(crate) async fn sync(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<SyncPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let max_keys = state.limits.max_keys;
+
+    let rtxn = state.read_txn().unwrap();
+
+    let changed = nested::read_entries(&state, &rtxn, &payload.prefix, None, None, max_keys + 1)
+        .and_then(|entries| {
+            if entries.len() > max_keys {
+                return Ok(None);
+            }
+
+            let mut changed = Vec::new();
+
+            for (key, value) in &entries {
+                let version = state.versions.get(&rtxn, key)?.unwrap_or(0);
+
+                if payload.versions.get(key) != Some(&version) {
+                    changed.push(json!({ "key": key, "value": value, "version": version }));
+                }
+            }
+
+            for key in payload.versions.keys() {
+                let gone = key.starts_with(&payload.prefix)
+                    && !entries.iter().any(|(existing, _)| existing == key);
+
+                if gone {
+                    let version = state.versions.get(&rtxn, key)?.unwrap_or(0);
+
+                    changed.push(json!({ "key": key, "value": null, "version": version }));
+                }
+            }
+
+            Ok(Some((entries.len(), changed)))
+        });
+
+    match changed {
+        Ok(Some((read, changed))) => {
+            record_keys(read);
+
+            Ok((StatusCode::OK, Json(json!({ "changed": changed }))))
+        }
+        Ok(None) => Ok(paging::too_many_keys(max_keys)),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{send_json, setup_tests};
+    use axum::http::{self, StatusCode};
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn sends_only_what_changed() {
+        let mut app = setup_tests().await;
+
+        for key in ["device:a", "device:b"] {
+            let payload = json!({ "key": key, "value": "1" });
+
+            let (status, _) =
+                send_json(&mut app, http::Method::PUT, &format!("/{}", key), payload).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let (status, all) = send_json(
+            &mut app,
+            http::Method::POST,
+            "/sync",
+            json!({ "prefix": "device:" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let versions: Value = all["changed"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                (
+                    entry["key"].as_str().unwrap().to_owned(),
+                    entry["version"].clone(),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into();
+
+        assert_eq!(versions.as_object().unwrap().len(), 2);
+
+        let payload = json!({ "key": "device:a", "value": "2" });
+        let (status, _) = send_json(&mut app, http::Method::PUT, "/device:a", payload).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send_json(&mut app, http::Method::DELETE, "/device:b", Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, delta) = send_json(
+            &mut app,
+            http::Method::POST,
+            "/sync",
+            json!({ "prefix": "device:", "versions": versions }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let mut changed: Vec<(Value, Value)> = delta["changed"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| (entry["key"].clone(), entry["value"].clone()))
+            .collect();
+        changed.sort_by_key(|(key, _)| key.to_string());
+
+        assert_eq!(
+            changed,
+            vec![
+                (json!("device:a"), json!("2")),
+                (json!("device:b"), Value::Null)
+            ]
+        );
+    }
+}
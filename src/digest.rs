@@ -0,0 +1,352 @@
+use axum::extract::{Query, State};
+use axum::{http::StatusCode, Json};
+use heed::types::{ByteSlice, Str};
+use heed::{Database, Env, RwTxn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use crate::{paging, AppError, AppState};
+
+// With `DIGEST_DELIMITER` set, every prefix of a key up to and including a delimiter, and
+// the empty prefix at the root, has a node in `digests`: the XOR of the hashes of every
+// key and value under it, and how many keys those are. XOR makes a write an update of
+// the nodes along its key's path, whatever order writes come in, so two replicas holding
+// the same keys have the same digests and a replica only descends into the prefixes whose
+// digests differ to find what to repair.
+//
+// Node keys lead with `#`, LMDB refusing the empty key of the root. The delimiter they
+// were built with is kept under `delimiter`, so changing it rebuilds them.
+
+const NODE_MARKER: u8 = b'#';
+const DELIMITER_KEY: &[u8] = b"delimiter";
+
+type Hash = [u8; 32];
+
+fn entry_hash(key: &str, value: &str) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update((key.len() as u64).to_be_bytes());
+    hasher.update(key.as_bytes());
+    hasher.update(value.as_bytes());
+
+    hasher.finalize().into()
+}
+
+fn node_key(prefix: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(1 + prefix.len());
+    key.push(NODE_MARKER);
+    key.extend_from_slice(prefix.as_bytes());
+    key
+}
+
+fn decode_node(node: &[u8]) -> (Hash, u64) {
+    (
+        node[..32].try_into().unwrap(),
+        u64::from_be_bytes(node[32..].try_into().unwrap()),
+    )
+}
+
+fn encode_node(digest: &Hash, keys: u64) -> Vec<u8> {
+    let mut node = digest.to_vec();
+    node.extend_from_slice(&keys.to_be_bytes());
+    node
+}
+
+fn xor(digest: &mut Hash, hash: &Hash) {
+    for (byte, other) in digest.iter_mut().zip(hash) {
+        *byte ^= other;
+    }
+}
+
+pub(crate) fn hex(digest: &Hash) -> String {
+    let mut hex = String::with_capacity(64);
+
+    for byte in digest {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+
+    hex
+}
+
+/// The prefixes of `key` with a node: the root, then each up to a delimiter.
+fn prefixes_of<'a>(key: &'a str, delimiter: &str) -> Vec<&'a str> {
+    let mut prefixes = vec![""];
+
+    prefixes.extend(
+        key.match_indices(delimiter)
+            .map(|(index, _)| &key[..index + delimiter.len()]),
+    );
+
+    prefixes
+}
+
+/// Brings the digests in line with `DIGEST_DELIMITER` at startup, building them from
+/// scratch when it changed and dropping them when it's no longer set.
+pub(crate) fn rebuild_digests(
+    env: &Env,
+    kv: Database<Str, Str>,
+    digests: Database<ByteSlice, ByteSlice>,
+    delimiter: Option<&str>,
+) -> heed::Result<()> {
+    let mut wtxn = env.write_txn()?;
+
+    let built_with = digests.get(&wtxn, DELIMITER_KEY)?.map(<[u8]>::to_vec);
+
+    match delimiter {
+        Some(delimiter) if built_with.as_deref() == Some(delimiter.as_bytes()) => {}
+        Some(delimiter) => {
+            let mut nodes: BTreeMap<&str, (Hash, u64)> = BTreeMap::new();
+
+            for entry in kv.iter(&wtxn)? {
+                let (key, value) = entry?;
+                let hash = entry_hash(key, value);
+
+                for prefix in prefixes_of(key, delimiter) {
+                    let (digest, keys) = nodes.entry(prefix).or_default();
+
+                    xor(digest, &hash);
+                    *keys += 1;
+                }
+            }
+
+            let nodes: Vec<(Vec<u8>, Vec<u8>)> = nodes
+                .iter()
+                .map(|(prefix, (digest, keys))| (node_key(prefix), encode_node(digest, *keys)))
+                .collect();
+
+            digests.clear(&mut wtxn)?;
+
+            for (key, node) in &nodes {
+                digests.put(&mut wtxn, key, node)?;
+            }
+
+            digests.put(&mut wtxn, DELIMITER_KEY, delimiter.as_bytes())?;
+        }
+        None => digests.clear(&mut wtxn)?,
+    }
+
+    wtxn.commit()
+}
+
+/// Updates the digests along `key`'s path for it becoming `value`, `None` for a delete,
+/// within a write. Called before the write itself, to read what it replaces.
+pub(crate) fn record(
+    state: &AppState,
+    wtxn: &mut RwTxn,
+    key: &str,
+    value: Option<&str>,
+) -> heed::Result<()> {
+    let delimiter = match &state.digest_delimiter {
+        Some(delimiter) => delimiter,
+        None => return Ok(()),
+    };
+
+    let old = state.kv.get(wtxn, key)?.map(|old| entry_hash(key, old));
+    let new = value.map(|value| entry_hash(key, value));
+
+    if old == new {
+        return Ok(());
+    }
+
+    for prefix in prefixes_of(key, delimiter) {
+        let node_key = node_key(prefix);
+
+        let (mut digest, mut keys) = match state.digests.get(wtxn, &node_key)? {
+            Some(node) => decode_node(node),
+            None => ([0; 32], 0),
+        };
+
+        if let Some(old) = &old {
+            xor(&mut digest, old);
+            keys -= 1;
+        }
+
+        if let Some(new) = &new {
+            xor(&mut digest, new);
+            keys += 1;
+        }
+
+        if keys == 0 {
+            state.digests.delete(wtxn, &node_key)?;
+        } else {
+            state
+                .digests
+                .put(wtxn, &node_key, &encode_node(&digest, keys))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Empties the digests along with the keys, keeping note of the delimiter.
+pub(crate) fn reset_digests(state: &AppState, wtxn: &mut RwTxn) -> heed::Result<()> {
+    let delimiter = match &state.digest_delimiter {
+        Some(delimiter) => delimiter,
+        None => return Ok(()),
+    };
+
+    state.digests.clear(wtxn)?;
+    state.digests.put(wtxn, DELIMITER_KEY, delimiter.as_bytes())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DigestQuery {
+    #[serde(default)]
+    prefix: String,
+}
+
+fn describe(prefix: &str, digest: &Hash, keys: u64) -> Value {
+    json!({ "prefix": prefix, "digest": hex(digest), "keys": keys })
+}
+
+/// `GET /digest?prefix=`: the digest of the keys under a prefix ending in the delimiter,
+/// and of each prefix one level down. When a prefix's digest differs between replicas but
+/// none of its children's do, the keys directly at its level are the ones that differ.
+pub(crate) async fn digest(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<DigestQuery>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let delimiter = match &state.digest_delimiter {
+        Some(delimiter) => delimiter.as_str(),
+        None => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "Digests aren't enabled, set DIGEST_DELIMITER" })),
+            ))
+        }
+    };
+
+    let prefix = query.prefix.as_str();
+
+    if !prefix.is_empty() && !prefix.ends_with(delimiter) {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": format!("Prefix must be empty or end with {}", delimiter)
+            })),
+        ));
+    }
+
+    let max_keys = state.limits.max_keys;
+
+    let rtxn = state.read_txn().unwrap();
+
+    let nodes = state
+        .digests
+        .prefix_iter(&rtxn, &node_key(prefix))
+        .and_then(|nodes| {
+            let mut level = None;
+            let mut children = Vec::new();
+
+            for node in nodes {
+                let (key, node) = node?;
+                let (digest, keys) = decode_node(node);
+
+                let child = String::from_utf8_lossy(&key[1..]);
+                let rest = &child[prefix.len()..];
+
+                if rest.is_empty() {
+                    level = Some((digest, keys));
+                } else if rest.find(delimiter) == Some(rest.len() - delimiter.len()) {
+                    if children.len() == max_keys {
+                        return Ok(None);
+                    }
+
+                    children.push(describe(&child, &digest, keys));
+                }
+            }
+
+            Ok(Some((level.unwrap_or(([0; 32], 0)), children)))
+        });
+
+    match nodes {
+        Ok(Some(((digest, keys), children))) => {
+            let mut level = describe(prefix, &digest, keys);
+            level["children"] = Value::Array(children);
+
+            Ok((StatusCode::OK, Json(level)))
+        }
+        Ok(None) => Ok(paging::too_many_keys(max_keys)),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request},
+        Router,
+    };
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    async fn put(app: &mut Router, key: &str, value: &str) {
+        let request = Request::builder()
+            .method(http::Method::PUT)
+            .uri(format!("/{}", key))
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "key": key, "value": value }).to_string(),
+            ))
+            .unwrap();
+
+        app.ready().await.unwrap().call(request).await.unwrap();
+    }
+
+    async fn get_digest(app: &mut Router, prefix: &str) -> Value {
+        let request = Request::builder()
+            .uri(format!("/digest?prefix={}", prefix))
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn finds_the_prefixes_of_keys() {
+        assert_eq!(prefixes_of("a:b:c", ":"), vec!["", "a:", "a:b:"]);
+        assert_eq!(prefixes_of("abc", ":"), vec![""]);
+    }
+
+    #[tokio::test]
+    async fn digests_follow_writes() {
+        let mut app = setup_tests().await;
+
+        put(&mut app, "digest:a:x", "1").await;
+        put(&mut app, "digest:a:y", "2").await;
+        put(&mut app, "digest:b", "3").await;
+
+        let before = get_digest(&mut app, "digest:").await;
+
+        assert_eq!(before["keys"], 3);
+        assert_eq!(before["children"][0]["prefix"], "digest:a:");
+        assert_eq!(before["children"][0]["keys"], 2);
+
+        put(&mut app, "digest:b", "4").await;
+
+        let changed = get_digest(&mut app, "digest:").await;
+
+        assert_ne!(changed["digest"], before["digest"]);
+        assert_eq!(changed["children"], before["children"]);
+
+        put(&mut app, "digest:b", "3").await;
+
+        assert_eq!(get_digest(&mut app, "digest:").await, before);
+
+        let root = get_digest(&mut app, "").await;
+
+        assert_eq!(root["digest"], before["digest"]);
+    }
+}
@@ -0,0 +1,178 @@
+use heed::MdbError;
+use std::fmt;
+
+use crate::{app_state, references, startup, AppState};
+
+// LMDB's own, `startup::open_env` doesn't change it
+const MAP_SIZE: u64 = 1 << 20;
+
+// Less of the map than this left free is worth a warning
+const HEADROOM_WARNING: f64 = 0.1;
+
+#[derive(Debug, PartialEq)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self.status {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+        };
+
+        write!(f, "{:<5} {:<14} {}", status, self.name, self.detail)
+    }
+}
+
+/// `kv doctor`: checks the server could start with this environment and prints what it
+/// found, returning whether every check passed so it can be used as an init container.
+pub(crate) fn run() -> bool {
+    let mut checks = Vec::new();
+
+    match app_state() {
+        Ok(state) => {
+            checks.push(Check {
+                name: "configuration",
+                status: Status::Ok,
+                detail: String::from("valid"),
+            });
+            checks.extend(check_env(&state));
+        }
+        Err(err) => checks.push(Check {
+            name: "configuration",
+            status: Status::Fail,
+            detail: err,
+        }),
+    }
+
+    for check in &checks {
+        println!("{}", check);
+    }
+
+    checks.iter().all(|check| check.status != Status::Fail)
+}
+
+fn check_env(state: &AppState) -> Vec<Check> {
+    let path = state.kv_env.path();
+
+    let permissions = match startup::check_writable(path) {
+        Ok(()) => Check {
+            name: "permissions",
+            status: Status::Ok,
+            detail: format!("{} is writable", path.display()),
+        },
+        Err(err) => Check {
+            name: "permissions",
+            status: Status::Fail,
+            detail: err,
+        },
+    };
+
+    let map = match path.join("data.mdb").metadata() {
+        Ok(data) => headroom(data.len(), MAP_SIZE),
+        Err(err) => Check {
+            name: "map headroom",
+            status: Status::Fail,
+            detail: format!("can't read the size of data.mdb: {}", err),
+        },
+    };
+
+    let readers = match state.read_txn() {
+        Ok(_) => Check {
+            name: "reader table",
+            status: Status::Ok,
+            detail: String::from("a reader slot is free"),
+        },
+        Err(heed::Error::Mdb(MdbError::ReadersFull)) => Check {
+            name: "reader table",
+            status: Status::Fail,
+            detail: String::from(
+                "every reader slot is taken, by this or crashed processes still holding them",
+            ),
+        },
+        Err(err) => Check {
+            name: "reader table",
+            status: Status::Fail,
+            detail: format!("can't start a read transaction: {}", err),
+        },
+    };
+
+    let mut checks = vec![permissions, map, readers];
+
+    if !state.references.is_empty() {
+        checks.push(check_references(state));
+    }
+
+    checks
+}
+
+// Writes are checked, this catches the keys referenced by others being deleted since
+fn check_references(state: &AppState) -> Check {
+    let dangling = state
+        .read_txn()
+        .and_then(|rtxn| references::dangling(state, &rtxn));
+
+    match dangling.as_deref() {
+        Ok([]) => Check {
+            name: "references",
+            status: Status::Ok,
+            detail: String::from("every referenced key exists"),
+        },
+        Ok(dangling) => Check {
+            name: "references",
+            status: Status::Warn,
+            detail: format!(
+                "{} keys reference missing ones, like {} to {}",
+                dangling.len(),
+                dangling[0].0,
+                dangling[0].1
+            ),
+        },
+        Err(err) => Check {
+            name: "references",
+            status: Status::Fail,
+            detail: format!("can't read the keys: {}", err),
+        },
+    }
+}
+
+fn headroom(used: u64, map_size: u64) -> Check {
+    let free = map_size.saturating_sub(used);
+    let detail = format!("{} of {} bytes used", used, map_size);
+
+    let status = if free == 0 {
+        Status::Fail
+    } else if (free as f64) < map_size as f64 * HEADROOM_WARNING {
+        Status::Warn
+    } else {
+        Status::Ok
+    };
+
+    Check {
+        name: "map headroom",
+        status,
+        detail,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warns_when_the_map_fills_up() {
+        assert_eq!(headroom(100, 1000).status, Status::Ok);
+        assert_eq!(headroom(950, 1000).status, Status::Warn);
+        assert_eq!(headroom(1000, 1000).status, Status::Fail);
+    }
+}
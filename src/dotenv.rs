@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+
+/// The environment variable name for `key` once `prefix` is stripped: uppercased, with
+/// anything but letters, digits and `_` turned into `_`, and a leading `_` if it would
+/// otherwise start with a digit.
+fn var_name(key: &str, prefix: &str) -> String {
+    let name: String = key
+        .strip_prefix(prefix)
+        .unwrap_or(key)
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+
+    match name.chars().next() {
+        Some('0'..='9') | None => format!("_{}", name),
+        _ => name,
+    }
+}
+
+// Values with anything a shell or dotenv parser would trip over are double quoted
+fn quote(value: &str) -> String {
+    let plain = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_-.,:/@+%".contains(c));
+
+    if plain {
+        return value.to_owned();
+    }
+
+    let mut quoted = String::from("\"");
+
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '$' => quoted.push_str("\\$"),
+            '`' => quoted.push_str("\\`"),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+/// `KEY=value` lines for `entries`, sorted by name. Fails with both keys when two of them
+/// come out as the same name.
+pub(crate) fn render(entries: &[(String, String)], prefix: &str) -> Result<String, String> {
+    let mut vars: BTreeMap<String, (&str, &str)> = BTreeMap::new();
+
+    for (key, value) in entries {
+        if let Some((other, _)) = vars.insert(var_name(key, prefix), (key, value)) {
+            return Err(format!(
+                "{} and {} are both exported as {}",
+                other,
+                key,
+                var_name(key, prefix)
+            ));
+        }
+    }
+
+    Ok(vars
+        .iter()
+        .map(|(name, (_, value))| format!("{}={}\n", name, quote(value)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_env_files() {
+        let entries = [
+            (String::from("app1:db-host"), String::from("localhost")),
+            (String::from("app1:2fa"), String::from("on")),
+            (
+                String::from("app1:greeting"),
+                String::from("say \"hi\" $USER"),
+            ),
+        ];
+
+        assert_eq!(
+            render(&entries, "app1:").unwrap(),
+            "DB_HOST=localhost\nGREETING=\"say \\\"hi\\\" \\$USER\"\n_2FA=on\n"
+        );
+
+        let commands = [(String::from("cmd"), String::from("`id`\r\n"))];
+
+        assert_eq!(render(&commands, "").unwrap(), "CMD=\"\\`id\\`\\r\\n\"\n");
+
+        let clashing = [
+            (String::from("db-host"), String::from("a")),
+            (String::from("db.host"), String::from("b")),
+        ];
+
+        assert_eq!(
+            render(&clashing, ""),
+            Err(String::from(
+                "db-host and db.host are both exported as DB_HOST"
+            ))
+        );
+    }
+}
@@ -0,0 +1,411 @@
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use heed::RoTxn;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::{delete_value, ephemeral, put_value, refuse_locked, sizes, wait, AppError, AppState};
+
+// An election's leader is the value of this key plus its name, held as an ephemeral key so
+// a leader that stops campaigning loses it, and so changes show up on `GET /watch`
+const KEY_PREFIX: &str = "election:";
+
+/// The election whose fencing token a write carries.
+pub(crate) const FENCING_ELECTION_HEADER: &str = "x-fencing-election";
+/// The fencing token the writer got when it was elected.
+pub(crate) const FENCING_TOKEN_HEADER: &str = "x-fencing-token";
+
+fn leader_key(name: &str) -> String {
+    format!("{}{}", KEY_PREFIX, name)
+}
+
+// The leader key's version, which every new leader, resignation and expiry moves on,
+// while a leader campaigning again doesn't
+fn fencing_token(state: &AppState, txn: &RoTxn, key: &str) -> heed::Result<u64> {
+    Ok(state.versions.get(txn, key)?.unwrap_or(0))
+}
+
+/// The fencing token a write carries and the election it's from.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Fence {
+    election: String,
+    token: u64,
+}
+
+impl Fence {
+    /// The fence a request's headers carry, if any, or the response to send when they
+    /// can't be read.
+    pub(crate) fn from_headers(
+        headers: &HeaderMap,
+    ) -> Result<Option<Fence>, (StatusCode, Json<Value>)> {
+        let token = match headers.get(FENCING_TOKEN_HEADER) {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+
+        let bad_request = |error: &str| (StatusCode::BAD_REQUEST, Json(json!({ "error": error })));
+
+        let token = token
+            .to_str()
+            .ok()
+            .and_then(|token| token.parse().ok())
+            .ok_or_else(|| bad_request("X-Fencing-Token must be a number"))?;
+
+        let election = headers
+            .get(FENCING_ELECTION_HEADER)
+            .and_then(|name| name.to_str().ok())
+            .ok_or_else(|| bad_request("X-Fencing-Token needs X-Fencing-Election"))?;
+
+        Ok(Some(Fence {
+            election: election.to_owned(),
+            token,
+        }))
+    }
+
+    /// Whether it's still the current leader's of its election.
+    pub(crate) fn is_current(&self, state: &AppState, txn: &RoTxn) -> heed::Result<bool> {
+        let key = leader_key(&self.election);
+
+        match state.kv.get(txn, &key)? {
+            Some(_) => Ok(fencing_token(state, txn, &key)? == self.token),
+            None => Ok(false),
+        }
+    }
+}
+
+/// The response to send instead when a write carries a fencing token that isn't the
+/// current leader's of its election, from a leader that stalled past its TTL and was
+/// replaced, say, without noticing. Writes without one aren't fenced.
+pub(crate) fn check_fence(
+    state: &AppState,
+    txn: &RoTxn,
+    headers: &HeaderMap,
+) -> Option<(StatusCode, Json<Value>)> {
+    let fence = match Fence::from_headers(headers) {
+        Ok(fence) => fence?,
+        Err(response) => return Some(response),
+    };
+
+    match fence.is_current(state, txn) {
+        Ok(true) => None,
+        Ok(false) => Some((
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "Stale fencing token" })),
+        )),
+        Err(_) => Some(internal_error()),
+    }
+}
+
+fn internal_error() -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({ "error": "Internal server error" })),
+    )
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CampaignPayload {
+    candidate: String,
+    ttl_secs: u64,
+}
+
+/// `POST /elections/:name/campaign`: makes the candidate leader if there is none, for
+/// `ttl_secs`. The leader keeps campaigning within that to stay leader, anyone else gets
+/// a 409 naming the current one. Leaders get a fencing token, greater than any earlier
+/// leader's, to send with their writes so they're refused once it has moved on.
+pub(crate) async fn campaign(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<CampaignPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    if let Some(response) = ephemeral::check_ttl(payload.ttl_secs) {
+        return Ok(response);
+    }
+
+    let key = leader_key(&name);
+
+    if let Some(response) = sizes::check(&state, &key, &payload.candidate) {
+        return Ok(response);
+    }
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    if let Some(response) = refuse_locked(&state, &wtxn, &key, &headers) {
+        return Ok(response);
+    }
+
+    let leader = match state.kv.get(&wtxn, &key) {
+        Ok(leader) => leader.map(str::to_owned),
+        Err(_) => return Ok(internal_error()),
+    };
+
+    match &leader {
+        Some(leader) if *leader != payload.candidate => {
+            return Ok((
+                StatusCode::CONFLICT,
+                Json(json!({ "error": "Another candidate is leader", "leader": leader })),
+            ))
+        }
+        // Re-elected, the deadline alone moves
+        Some(_) => {}
+        None => {
+            if put_value(&state, &mut wtxn, &key, &payload.candidate).is_err() {
+                return Ok(internal_error());
+            }
+        }
+    }
+
+    let held = ephemeral::hold(&state, &mut wtxn, &key, payload.ttl_secs)
+        .and_then(|expires_at| Ok((expires_at, fencing_token(&state, &wtxn, &key)?)));
+
+    let (expires_at, fencing_token) = match held {
+        Ok(held) => held,
+        Err(_) => return Ok(internal_error()),
+    };
+
+    state.commit(wtxn).unwrap();
+
+    if leader.is_none() {
+        wait::notify(&state, &key);
+    }
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "leader": payload.candidate,
+            "expires_at": expires_at,
+            "fencing_token": fencing_token,
+        })),
+    ))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ResignPayload {
+    candidate: String,
+}
+
+/// `POST /elections/:name/resign`: steps the candidate down if it's the leader, leaving
+/// the election open to the next campaign.
+pub(crate) async fn resign(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<ResignPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let key = leader_key(&name);
+
+    if let Some(response) = sizes::check_key(&key) {
+        return Ok(response);
+    }
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    if let Some(response) = refuse_locked(&state, &wtxn, &key, &headers) {
+        return Ok(response);
+    }
+
+    match state.kv.get(&wtxn, &key) {
+        Ok(Some(leader)) if leader == payload.candidate => {}
+        Ok(leader) => {
+            return Ok((
+                StatusCode::CONFLICT,
+                Json(json!({ "error": "Candidate isn't leader", "leader": leader })),
+            ))
+        }
+        Err(_) => return Ok(internal_error()),
+    }
+
+    if delete_value(&state, &mut wtxn, &key).is_err() {
+        return Ok(internal_error());
+    }
+
+    state.commit(wtxn).unwrap();
+
+    wait::notify(&state, &key);
+
+    Ok((StatusCode::OK, Json(json!({ "leader": null }))))
+}
+
+/// `GET /elections/:name`: the current leader and its fencing token, 404 while there is
+/// none.
+pub(crate) async fn leader(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let key = leader_key(&name);
+
+    let rtxn = state.read_txn().unwrap();
+
+    let leader = state.kv.get(&rtxn, &key).and_then(|leader| match leader {
+        Some(leader) => Ok(Some((
+            leader,
+            state.ephemeral.get(&rtxn, &key)?,
+            fencing_token(&state, &rtxn, &key)?,
+        ))),
+        None => Ok(None),
+    });
+
+    match leader {
+        Ok(Some((leader, expires_at, fencing_token))) => Ok((
+            StatusCode::OK,
+            Json(json!({
+                "leader": leader,
+                "expires_at": expires_at,
+                "fencing_token": fencing_token,
+            })),
+        )),
+        Ok(None) => Ok((StatusCode::NOT_FOUND, Json(json!({ "error": "No leader" })))),
+        Err(_) => Ok(internal_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{send, setup_tests};
+    use axum::{
+        body::Body,
+        http::{self, Request},
+    };
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    fn post(uri: &str, payload: Value) -> Request<Body> {
+        Request::builder()
+            .method(http::Method::POST)
+            .uri(uri)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(payload.to_string()))
+            .unwrap()
+    }
+
+    fn get_leader() -> Request<Body> {
+        Request::builder()
+            .uri("/elections/scheduler")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn elects_one_leader_at_a_time() {
+        let mut app = setup_tests().await;
+
+        let campaign = |candidate: &str| {
+            post(
+                "/elections/scheduler/campaign",
+                json!({ "candidate": candidate, "ttl_secs": 30 }),
+            )
+        };
+
+        assert_eq!(send(&mut app, get_leader()).await.0, StatusCode::NOT_FOUND);
+
+        let (status, body) = send(&mut app, campaign("a")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["leader"], "a");
+
+        let (status, body) = send(&mut app, campaign("b")).await;
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["leader"], "a");
+
+        assert_eq!(send(&mut app, campaign("a")).await.0, StatusCode::OK);
+
+        let (status, body) = send(&mut app, get_leader()).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["leader"], "a");
+
+        let resign = |candidate: &str| {
+            post(
+                "/elections/scheduler/resign",
+                json!({ "candidate": candidate }),
+            )
+        };
+
+        assert_eq!(send(&mut app, resign("b")).await.0, StatusCode::CONFLICT);
+        assert_eq!(send(&mut app, resign("a")).await.0, StatusCode::OK);
+
+        let (status, body) = send(&mut app, campaign("b")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["leader"], "b");
+    }
+
+    #[tokio::test]
+    async fn refuses_writes_with_stale_fencing_tokens() {
+        let mut app = setup_tests().await;
+
+        let campaign = |candidate: &str| {
+            post(
+                "/elections/fenced/campaign",
+                json!({ "candidate": candidate, "ttl_secs": 30 }),
+            )
+        };
+
+        let write = |token: &Value| {
+            Request::builder()
+                .method(http::Method::PUT)
+                .uri("/fenced-resource")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .header(FENCING_ELECTION_HEADER, "fenced")
+                .header(FENCING_TOKEN_HEADER, token.to_string())
+                .body(Body::from(
+                    json!({ "key": "fenced-resource", "value": "1" }).to_string(),
+                ))
+                .unwrap()
+        };
+
+        let (_, first) = send(&mut app, campaign("a")).await;
+        let (_, again) = send(&mut app, campaign("a")).await;
+
+        assert_eq!(again["fencing_token"], first["fencing_token"]);
+        assert_eq!(
+            send(&mut app, write(&first["fencing_token"])).await.0,
+            StatusCode::OK
+        );
+
+        let resign = post("/elections/fenced/resign", json!({ "candidate": "a" }));
+
+        assert_eq!(send(&mut app, resign).await.0, StatusCode::OK);
+        assert_eq!(
+            send(&mut app, write(&first["fencing_token"])).await.0,
+            StatusCode::CONFLICT
+        );
+
+        let (_, second) = send(&mut app, campaign("b")).await;
+
+        assert!(second["fencing_token"].as_u64() > first["fencing_token"].as_u64());
+        assert_eq!(
+            send(&mut app, write(&first["fencing_token"])).await.0,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            send(&mut app, write(&second["fencing_token"])).await.0,
+            StatusCode::OK
+        );
+
+        // Creating keys and deleting them all are fenced too
+        let created = json!({ "key": "fenced-new", "value": "1" });
+
+        for (method, body) in [
+            (http::Method::POST, Body::from(created.to_string())),
+            (http::Method::DELETE, Body::empty()),
+        ] {
+            let request = Request::builder()
+                .method(method)
+                .uri("/")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .header(FENCING_ELECTION_HEADER, "fenced")
+                .header(FENCING_TOKEN_HEADER, first["fencing_token"].to_string())
+                .body(body)
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::CONFLICT);
+        }
+    }
+}
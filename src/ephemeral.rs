@@ -0,0 +1,401 @@
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use heed::RwTxn;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    cache, expire_value, hooks, immutable, metrics, now_millis, put_value, references,
+    refuse_locked, sizes, wait, AppError, AppState,
+};
+
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize)]
+pub(crate) struct EphemeralPayload {
+    // Left out by heartbeats, which only push the deadline back
+    value: Option<String>,
+    ttl_secs: u64,
+}
+
+/// The response to send instead when `ttl_secs` can't be used.
+pub(crate) fn check_ttl(ttl_secs: u64) -> Option<(StatusCode, Json<Value>)> {
+    let error = match ttl_secs {
+        0 => "ttl_secs must be at least 1",
+        _ if expires_at(ttl_secs).is_none() => "ttl_secs is too long",
+        _ => return None,
+    };
+
+    Some((StatusCode::BAD_REQUEST, Json(json!({ "error": error }))))
+}
+
+/// Unix time in milliseconds `ttl_secs` from now, `None` when that's past what fits.
+pub(crate) fn expires_at(ttl_secs: u64) -> Option<u64> {
+    ttl_secs.checked_mul(1000)?.checked_add(now_millis())
+}
+
+/// Makes `key` expire `ttl_secs` from now within `wtxn`, returning when.
+pub(crate) fn hold(
+    state: &AppState,
+    wtxn: &mut RwTxn,
+    key: &str,
+    ttl_secs: u64,
+) -> heed::Result<u64> {
+    // Anything too long to say when is as good as never
+    let expires_at = expires_at(ttl_secs).unwrap_or(u64::MAX);
+
+    state.ephemeral.put(wtxn, key, &expires_at)?;
+
+    Ok(expires_at)
+}
+
+// The TTL of the most specific `EXPIRE_AFTER` prefix `key` is under
+fn policy_ttl(state: &AppState, key: &str) -> Option<u64> {
+    state
+        .expiry_policies
+        .iter()
+        .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, ttl_secs)| *ttl_secs)
+}
+
+/// Parses `EXPIRE_AFTER`, comma separated `prefix=seconds` pairs.
+pub(crate) fn policies_from_env() -> Result<Vec<(String, u64)>, String> {
+    let policies = cache::parse_policies(&std::env::var("EXPIRE_AFTER").unwrap_or_default())
+        .map_err(|err| format!("EXPIRE_AFTER: {}", err))?;
+
+    match policies.iter().find(|(_, ttl_secs)| *ttl_secs == 0) {
+        Some((prefix, _)) => Err(format!(
+            "EXPIRE_AFTER: {} must expire after at least 1 second",
+            prefix
+        )),
+        None => Ok(policies),
+    }
+}
+
+/// Makes `key`, just written within `wtxn`, expire when its prefix has a policy saying so.
+/// Every write starts the TTL over.
+pub(crate) fn apply_policy(state: &AppState, wtxn: &mut RwTxn, key: &str) -> heed::Result<()> {
+    match policy_ttl(state, key) {
+        Some(ttl_secs) => hold(state, wtxn, key, ttl_secs).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+/// Gives the keys written before their prefix had a policy a deadline, as if written now.
+pub(crate) fn apply_policies(state: &AppState) -> heed::Result<()> {
+    if state.expiry_policies.is_empty() {
+        return Ok(());
+    }
+
+    let mut wtxn = state.write_txn()?;
+
+    let uncovered: Vec<String> = state
+        .kv
+        .iter(&wtxn)?
+        .filter_map(|entry| match entry {
+            Ok((key, _)) if policy_ttl(state, key).is_some() => Some(Ok(key.to_owned())),
+            Ok(_) => None,
+            Err(err) => Some(Err(err)),
+        })
+        .collect::<heed::Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|key| !matches!(state.ephemeral.get(&wtxn, key), Ok(Some(_))))
+        .collect();
+
+    for key in &uncovered {
+        apply_policy(state, &mut wtxn, key)?;
+    }
+
+    state.commit(wtxn)
+}
+
+/// `GET /admin/expiry`: the `EXPIRE_AFTER` policies, for the admin token only.
+pub(crate) async fn policies(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<Value>) {
+    if !immutable::admin_override(&state, &headers) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Expiry policies can only be listed with the admin token" })),
+        );
+    }
+
+    let policies: Vec<Value> = state
+        .expiry_policies
+        .iter()
+        .map(|(prefix, ttl_secs)| json!({ "prefix": prefix, "ttl_secs": ttl_secs }))
+        .collect();
+
+    (StatusCode::OK, Json(json!({ "policies": policies })))
+}
+
+/// `PUT /ephemeral/:key`: writes `key` to be deleted `ttl_secs` from now unless put here
+/// again first, for registering services that heartbeat to stay listed. Deleting it
+/// otherwise, or letting it expire, makes it an ordinary key again should it come back.
+pub(crate) async fn register(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+    Json(mut payload): Json<EphemeralPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    if let Some(response) = check_ttl(payload.ttl_secs) {
+        return Ok(response);
+    }
+
+    if let Some(value) = &payload.value {
+        payload.value = match hooks::run(&state, &key, value) {
+            Ok(value) => Some(value),
+            Err(response) => return Ok(response),
+        };
+    }
+
+    let checked = match &payload.value {
+        Some(value) => sizes::check(&state, &key, value),
+        None => sizes::check_key(&key),
+    };
+
+    if let Some(response) = checked {
+        return Ok(response);
+    }
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    let refused = refuse_locked(&state, &wtxn, &key, &headers).or_else(|| {
+        let value = payload.value.as_deref()?;
+
+        references::check(&state, &wtxn, &key, value)
+    });
+
+    if let Some(response) = refused {
+        return Ok(response);
+    }
+
+    let value = match &payload.value {
+        Some(value) => put_value(&state, &mut wtxn, &key, value).map(|_| Some(value.clone())),
+        None => state
+            .kv
+            .get(&wtxn, &key)
+            .map(|value| value.map(str::to_owned)),
+    };
+
+    let value = match value {
+        Ok(Some(value)) => value,
+        Ok(None) => {
+            return Ok((
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "Key not found" })),
+            ))
+        }
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    };
+
+    let expires_at = match hold(&state, &mut wtxn, &key, payload.ttl_secs) {
+        Ok(expires_at) => expires_at,
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    };
+
+    state.commit(wtxn).unwrap();
+
+    if payload.value.is_some() {
+        wait::notify(&state, &key);
+    }
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "key": key, "value": value, "expires_at": expires_at })),
+    ))
+}
+
+/// Deletes the ephemeral keys not refreshed before `now`, returning them.
+pub(crate) fn expire(state: &AppState, now: u64) -> heed::Result<Vec<String>> {
+    let mut wtxn = state.write_txn()?;
+
+    let due: Vec<String> = state
+        .ephemeral
+        .iter(&wtxn)?
+        .filter_map(|entry| match entry {
+            Ok((key, expires_at)) if expires_at <= now => Some(Ok(key.to_owned())),
+            Ok(_) => None,
+            Err(err) => Some(Err(err)),
+        })
+        .collect::<heed::Result<_>>()?;
+
+    for key in &due {
+        state.ephemeral.delete(&mut wtxn, key)?;
+        expire_value(state, &mut wtxn, key)?;
+    }
+
+    state.commit(wtxn)?;
+
+    Ok(due)
+}
+
+/// Expires ephemeral keys every [`EXPIRY_INTERVAL`].
+pub(crate) fn spawn_expiry(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let expired = {
+                let state = state.clone();
+
+                tokio::task::spawn_blocking(move || {
+                    expire(&state, now_millis()).map_err(|err| err.to_string())
+                })
+            };
+
+            match expired.await.unwrap() {
+                Ok(keys) if keys.is_empty() => {}
+                Ok(keys) => {
+                    for key in &keys {
+                        wait::notify(&state, key);
+                    }
+
+                    metrics::add(&state.metrics.keys_expired, keys.len() as u64);
+
+                    tracing::info!(keys = keys.len(), "expired ephemeral keys");
+                }
+                Err(err) => tracing::error!(%err, "failed to expire ephemeral keys"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{send, setup_tests};
+    use axum::{
+        body::Body,
+        http::{self, Request},
+    };
+
+    fn register(key: &str, payload: Value) -> Request<Body> {
+        Request::builder()
+            .method(http::Method::PUT)
+            .uri(format!("/ephemeral/{}", key))
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(payload.to_string()))
+            .unwrap()
+    }
+
+    fn get(key: &str) -> Request<Body> {
+        Request::builder()
+            .uri(format!("/{}", key))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn expires_keys_not_refreshed() {
+        let mut app = setup_tests().await;
+
+        let (status, _) = send(&mut app, register("presence:b", json!({ "ttl_secs": 30 }))).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = send(
+            &mut app,
+            register("presence:a", json!({ "value": "up", "ttl_secs": u64::MAX })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = send(
+            &mut app,
+            register("presence:a", json!({ "value": "up", "ttl_secs": 30 })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["value"], "up");
+
+        let (status, body) =
+            send(&mut app, register("presence:a", json!({ "ttl_secs": 60 }))).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["value"], "up");
+
+        let expires_at = body["expires_at"].as_u64().unwrap();
+
+        let state = crate::app_state().unwrap();
+
+        assert_eq!(
+            expire(&state, expires_at - 1).unwrap(),
+            Vec::<String>::new()
+        );
+        assert_eq!(send(&mut app, get("presence:a")).await.0, StatusCode::OK);
+
+        assert_eq!(
+            expire(&state, expires_at).unwrap(),
+            vec![String::from("presence:a")]
+        );
+        assert_eq!(
+            send(&mut app, get("presence:a")).await.0,
+            StatusCode::NOT_FOUND
+        );
+
+        let rtxn = state.read_txn().unwrap();
+        let (_, event) = state.feed.last(&rtxn).unwrap().unwrap();
+        let event = serde_json::to_value(event).unwrap();
+
+        assert_eq!(event["key"], "presence:a");
+        assert_eq!(event["expired"], true);
+    }
+
+    #[tokio::test]
+    async fn expires_keys_under_policies() {
+        let mut app = setup_tests().await;
+
+        let request = Request::builder()
+            .method(http::Method::PUT)
+            .uri("/expiring:a")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "key": "expiring:a", "value": "1" }).to_string(),
+            ))
+            .unwrap();
+
+        assert_eq!(send(&mut app, request).await.0, StatusCode::OK);
+
+        let state = crate::app_state().unwrap();
+
+        assert_eq!(expire(&state, now_millis()).unwrap(), Vec::<String>::new());
+        assert_eq!(
+            expire(&state, now_millis() + 3600 * 1000).unwrap(),
+            vec![String::from("expiring:a")]
+        );
+
+        let request = Request::builder()
+            .uri("/admin/expiry")
+            .header(immutable::ADMIN_TOKEN_HEADER, "test-admin-token")
+            .body(Body::empty())
+            .unwrap();
+
+        let (status, body) = send(&mut app, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body["policies"],
+            json!([{ "prefix": "expiring:", "ttl_secs": 3600 }])
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use axum::extract::{MatchedPath, Query, State};
+use axum::http::{HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{immutable, AppState};
+
+/// Latency and errors injected into every request to one route, set by an admin on
+/// `PUT /admin/faults` so staging users can test their timeouts and retries.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub(crate) struct Fault {
+    #[serde(default)]
+    latency_ms: u64,
+    // Share of requests answered with a 503, from 0 to 1
+    #[serde(default)]
+    error_rate: f64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct FaultPayload {
+    // The route as registered, placeholders and all, like `/:key`
+    route: String,
+    #[serde(flatten)]
+    fault: Fault,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ClearQuery {
+    route: Option<String>,
+}
+
+fn forbidden() -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::FORBIDDEN,
+        Json(json!({ "error": "Faults can only be managed with the admin token" })),
+    )
+}
+
+pub(crate) async fn list(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<Value>) {
+    if !immutable::admin_override(&state, &headers) {
+        return forbidden();
+    }
+
+    let faults = state.faults.lock().unwrap();
+
+    (StatusCode::OK, Json(json!({ "faults": *faults })))
+}
+
+pub(crate) async fn set(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<FaultPayload>,
+) -> (StatusCode, Json<Value>) {
+    if !immutable::admin_override(&state, &headers) {
+        return forbidden();
+    }
+
+    if !(0.0..=1.0).contains(&payload.fault.error_rate) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "error_rate must be between 0 and 1" })),
+        );
+    }
+
+    state
+        .faults
+        .lock()
+        .unwrap()
+        .insert(payload.route.clone(), payload.fault);
+
+    tracing::warn!("injecting {:?} into {}", payload.fault, payload.route);
+
+    (
+        StatusCode::OK,
+        Json(json!({ "route": payload.route, "fault": payload.fault })),
+    )
+}
+
+/// Clears the faults of `?route=`, or all of them.
+pub(crate) async fn clear(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<ClearQuery>,
+) -> (StatusCode, Json<Value>) {
+    if !immutable::admin_override(&state, &headers) {
+        return forbidden();
+    }
+
+    let mut faults = state.faults.lock().unwrap();
+
+    match &query.route {
+        Some(route) => {
+            faults.remove(route);
+        }
+        None => faults.clear(),
+    }
+
+    (StatusCode::OK, Json(json!({ "faults": *faults })))
+}
+
+/// Delays or fails requests to routes with a fault set. The admin routes are left alone,
+/// so faults can always be turned off again.
+pub(crate) async fn inject<B>(
+    State(state): State<Arc<AppState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let fault = request
+        .extensions()
+        .get::<MatchedPath>()
+        .filter(|route| !route.as_str().starts_with("/admin/"))
+        .and_then(|route| state.faults.lock().unwrap().get(route.as_str()).copied());
+
+    let fault = match fault {
+        Some(fault) => fault,
+        None => return next.run(request).await,
+    };
+
+    if fault.latency_ms > 0 {
+        tokio::time::sleep(Duration::from_millis(fault.latency_ms)).await;
+    }
+
+    // A v4 UUID is 122 random bits, the top 53 make an evenly spread number in [0, 1)
+    let roll = (uuid::Uuid::new_v4().as_u128() >> 75) as f64 / (1u64 << 53) as f64;
+
+    if roll < fault.error_rate {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "error": "Injected fault" })),
+        )
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{send, setup_tests};
+    use axum::{body::Body, http};
+
+    fn set_fault(fault: Value, token: &str) -> Request<Body> {
+        Request::builder()
+            .method(http::Method::PUT)
+            .uri("/admin/faults")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(immutable::ADMIN_TOKEN_HEADER, token)
+            .body(Body::from(fault.to_string()))
+            .unwrap()
+    }
+
+    fn get_count() -> Request<Body> {
+        Request::builder()
+            .uri("/count")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn injects_faults_set_by_admins() {
+        let mut app = setup_tests().await;
+
+        let fault = json!({ "route": "/count", "error_rate": 1.0 });
+
+        assert_eq!(
+            send(&mut app, set_fault(fault.clone(), "wrong-token"))
+                .await
+                .0,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(send(&mut app, get_count()).await.0, StatusCode::OK);
+
+        assert_eq!(
+            send(&mut app, set_fault(fault, "test-admin-token")).await.0,
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(&mut app, get_count()).await.0,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        let request = Request::builder()
+            .method(http::Method::DELETE)
+            .uri("/admin/faults?route=/count")
+            .header(immutable::ADMIN_TOKEN_HEADER, "test-admin-token")
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(send(&mut app, request).await.0, StatusCode::OK);
+        assert_eq!(send(&mut app, get_count()).await.0, StatusCode::OK);
+
+        assert_eq!(
+            send(
+                &mut app,
+                set_fault(
+                    json!({ "route": "/count", "error_rate": 2 }),
+                    "test-admin-token"
+                )
+            )
+            .await
+            .0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -0,0 +1,544 @@
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use heed::RwTxn;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::convert::Infallible;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::filter::FilterQuery;
+use crate::{metrics, now_millis, AppError, AppState};
+
+// Events read per transaction when catching a watcher up
+const PAGE_SIZE: usize = 256;
+
+const TRUNCATION_INTERVAL: Duration = Duration::from_secs(60);
+
+// With `CHANGE_FEED` enabled every write is logged under its big endian sequence number,
+// one more than the last, so the log reads in write order and a watcher can pick up
+// after the last sequence number it saw.
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct FeedEvent {
+    key: String,
+    // `None` records a delete
+    value: Option<String>,
+    version: u64,
+    written_at: u64,
+    // Set on the delete of an ephemeral key whose TTL ran out
+    #[serde(default)]
+    expired: bool,
+}
+
+/// Logs the write of `version` of `key` alongside it, if the change feed is enabled.
+pub(crate) fn record(
+    state: &AppState,
+    wtxn: &mut RwTxn,
+    key: &str,
+    version: u64,
+    value: Option<&str>,
+) -> heed::Result<()> {
+    append(
+        state,
+        wtxn,
+        FeedEvent {
+            key: key.to_owned(),
+            value: value.map(str::to_owned),
+            version,
+            written_at: now_millis(),
+            expired: false,
+        },
+    )
+}
+
+/// Logs the delete of an ephemeral `key` whose TTL ran out, sent to watchers as an
+/// `expire` event rather than a `delete`.
+pub(crate) fn record_expiry(
+    state: &AppState,
+    wtxn: &mut RwTxn,
+    key: &str,
+    version: u64,
+) -> heed::Result<()> {
+    append(
+        state,
+        wtxn,
+        FeedEvent {
+            key: key.to_owned(),
+            value: None,
+            version,
+            written_at: now_millis(),
+            expired: true,
+        },
+    )
+}
+
+fn append(state: &AppState, wtxn: &mut RwTxn, event: FeedEvent) -> heed::Result<()> {
+    if !state.feed_enabled {
+        return Ok(());
+    }
+
+    let sequence = match state.feed.last(wtxn)? {
+        Some((last, _)) => sequence_of(last) + 1,
+        None => 1,
+    };
+
+    state.feed.put(wtxn, &sequence.to_be_bytes(), &event)
+}
+
+/// The sequence number of the last event logged as of `rtxn`, 0 before the first.
+pub(crate) fn last_sequence(state: &AppState, rtxn: &heed::RoTxn) -> heed::Result<u64> {
+    Ok(state
+        .feed
+        .last(rtxn)?
+        .map_or(0, |(last, _)| sequence_of(last)))
+}
+
+fn sequence_of(key: &[u8]) -> u64 {
+    u64::from_be_bytes(key.try_into().unwrap())
+}
+
+/// How much of the change feed to keep, read from `CHANGE_FEED_KEEP_EVENTS` and
+/// `CHANGE_FEED_MAX_AGE_HOURS`.
+///
+/// Events are truncated oldest first once outside either limit, but the newest is always
+/// kept so sequence numbers carry on from it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Retention {
+    pub(crate) keep_events: Option<u64>,
+    pub(crate) max_age: Option<Duration>,
+}
+
+impl Retention {
+    pub(crate) fn from_env() -> Result<Retention, String> {
+        let keep_events = match std::env::var("CHANGE_FEED_KEEP_EVENTS") {
+            Ok(events) => match events.parse() {
+                Ok(events) => Some(events),
+                Err(_) => {
+                    return Err(format!(
+                        "CHANGE_FEED_KEEP_EVENTS must be a number, got {}",
+                        events
+                    ))
+                }
+            },
+            Err(_) => None,
+        };
+        let max_age = match std::env::var("CHANGE_FEED_MAX_AGE_HOURS") {
+            Ok(hours) => match hours
+                .parse::<u64>()
+                .ok()
+                .and_then(|hours| hours.checked_mul(60 * 60))
+            {
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => {
+                    return Err(format!(
+                        "CHANGE_FEED_MAX_AGE_HOURS must be a number of hours, got {}",
+                        hours
+                    ))
+                }
+            },
+            Err(_) => None,
+        };
+
+        Ok(Retention {
+            keep_events,
+            max_age,
+        })
+    }
+
+    fn is_unbounded(&self) -> bool {
+        self.keep_events.is_none() && self.max_age.is_none()
+    }
+
+    // `newer` counts the events logged after this one
+    fn keeps(&self, newer: u64, written_at: u64, now: u64) -> bool {
+        newer == 0
+            || (self.keep_events.is_none_or(|keep| newer < keep)
+                && self
+                    .max_age
+                    .is_none_or(|max_age| written_at + max_age.as_millis() as u64 >= now))
+    }
+}
+
+/// Drops the events `retention` no longer keeps, returning how many.
+pub(crate) fn truncate(state: &AppState, retention: Retention) -> heed::Result<u64> {
+    let now = now_millis();
+
+    let mut truncated = Vec::new();
+
+    {
+        let rtxn = state.read_txn()?;
+
+        let mut newer = state.feed.len(&rtxn)?;
+
+        // Oldest first, stopping at the first event still kept since later ones are too
+        for entry in state.feed.iter(&rtxn)? {
+            let (key, event) = entry?;
+
+            newer -= 1;
+
+            if retention.keeps(newer, event.written_at, now) {
+                break;
+            }
+
+            truncated.push(key.to_vec());
+        }
+    }
+
+    let mut wtxn = state.write_txn()?;
+
+    for key in &truncated {
+        state.feed.delete(&mut wtxn, key)?;
+    }
+
+    state.commit(wtxn)?;
+
+    Ok(truncated.len() as u64)
+}
+
+/// Truncates the change feed every [`TRUNCATION_INTERVAL`] when a retention limit is set.
+pub(crate) fn spawn_truncation(state: Arc<AppState>) {
+    if !state.feed_enabled || state.feed_retention.is_unbounded() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TRUNCATION_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let truncated = {
+                let state = state.clone();
+
+                tokio::task::spawn_blocking(move || {
+                    truncate(&state, state.feed_retention).map_err(|err| err.to_string())
+                })
+            };
+
+            match truncated.await.unwrap() {
+                Ok(events) => {
+                    metrics::add(&state.metrics.feed_truncated, events);
+
+                    tracing::info!(events, "truncated change feed");
+                }
+                Err(err) => tracing::error!(%err, "failed to truncate change feed"),
+            }
+        }
+    });
+}
+
+/// Reads up to `limit` events logged after sequence number `after`.
+fn read_after(state: &AppState, after: u64, limit: usize) -> heed::Result<Vec<(u64, FeedEvent)>> {
+    let rtxn = state.read_txn()?;
+
+    let start = (after + 1).to_be_bytes();
+
+    let events = state
+        .feed
+        .range(&rtxn, &(Bound::Included(&start[..]), Bound::Unbounded))?
+        .take(limit)
+        .map(|entry| entry.map(|(key, event)| (sequence_of(key), event)))
+        .collect();
+
+    events
+}
+
+fn to_event(sequence: u64, event: FeedEvent) -> Event {
+    let kind = match (&event.value, event.expired) {
+        (Some(_), _) => "put",
+        (None, true) => "expire",
+        (None, false) => "delete",
+    };
+
+    Event::default()
+        .id(sequence.to_string())
+        .event(kind)
+        .json_data(json!({
+            "key": event.key,
+            "value": event.value,
+            "version": event.version,
+            "written_at": event.written_at,
+        }))
+        .unwrap()
+}
+
+#[derive(Deserialize)]
+pub(crate) struct WatchQuery {
+    // Resume token, the sequence number of the last event seen
+    since: Option<u64>,
+}
+
+/// Streams every write as a server-sent event, with its sequence number as the event id.
+///
+/// A watcher reconnecting with `?since=` or the `Last-Event-ID` header its client resends
+/// gets every event after that one first, so nothing is lost across a reconnect. Without
+/// either it starts at the next write. Each watcher reads the log at its own pace, a slow
+/// one only falls behind.
+///
+/// Resuming from before the oldest event still kept is refused with a 410 and the
+/// `COMPACTED` code, like etcd, since events the watcher never saw are gone.
+///
+/// Only events passing the [`FilterQuery`] in the query are sent.
+pub(crate) async fn watch(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<WatchQuery>,
+    Query(filter): Query<FilterQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    if !state.feed_enabled {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "The change feed isn't enabled" })),
+        )
+            .into_response());
+    }
+
+    let filter = match filter.parse() {
+        Ok(filter) => filter,
+        Err(err) => {
+            return Ok((StatusCode::BAD_REQUEST, Json(json!({ "error": err }))).into_response())
+        }
+    };
+
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse().ok());
+
+    // Subscribe before reading so a write landing in between isn't missed
+    let mut changes = state.changes.subscribe();
+
+    let bounds = {
+        let rtxn = state.read_txn().unwrap();
+
+        state.feed.first(&rtxn).and_then(|first| {
+            let last = state.feed.last(&rtxn)?;
+
+            Ok((
+                first.map(|(first, _)| sequence_of(first)),
+                last.map_or(0, |(last, _)| sequence_of(last)),
+            ))
+        })
+    };
+
+    let (first, last) = match bounds {
+        Ok(bounds) => bounds,
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            )
+                .into_response())
+        }
+    };
+
+    let mut after = match query.since.or(last_event_id) {
+        Some(since) if first.is_some_and(|first| since + 1 < first) => {
+            return Ok((
+                StatusCode::GONE,
+                Json(json!({
+                    "error": "Events after this one have been truncated",
+                    "code": "COMPACTED",
+                    "compact_revision": first,
+                })),
+            )
+                .into_response())
+        }
+        Some(since) => since,
+        None => last,
+    };
+
+    let (sender, receiver) = mpsc::channel::<Result<Event, Infallible>>(PAGE_SIZE);
+    let sse = state.sse;
+
+    // Every event carries its sequence as the id, which the client sends back as
+    // `Last-Event-ID` after reconnecting this long after a drop
+    let _ = sender.try_send(Ok(sse.retry_hint()));
+
+    tokio::spawn(async move {
+        loop {
+            // Catch up on everything logged since the last event sent
+            loop {
+                let events = match read_after(&state, after, PAGE_SIZE) {
+                    Ok(events) => events,
+                    // Ending the stream makes the client reconnect from its last event
+                    Err(_) => return,
+                };
+
+                let caught_up = events.len() < PAGE_SIZE;
+
+                for (sequence, event) in events {
+                    after = sequence;
+
+                    if !filter.matches(&event.key, event.value.as_deref()) {
+                        continue;
+                    }
+
+                    // The watcher went away
+                    if sender.send(Ok(to_event(sequence, event))).await.is_err() {
+                        return;
+                    }
+                }
+
+                if caught_up {
+                    break;
+                }
+            }
+
+            // Any write may have been logged, missed notifications just mean reading again
+            match changes.recv().await {
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
+
+    Ok(Sse::new(ReceiverStream::new(receiver))
+        .keep_alive(sse.keep_alive())
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::{
+        body::{Body, HttpBody},
+        http::{self, Request},
+    };
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[tokio::test]
+    async fn watchers_resume_after_their_last_event() {
+        let mut app = setup_tests().await;
+
+        let state = crate::app_state().unwrap();
+
+        let rtxn = state.read_txn().unwrap();
+        let start = state
+            .feed
+            .last(&rtxn)
+            .unwrap()
+            .map_or(0, |(last, _)| sequence_of(last));
+        drop(rtxn);
+
+        for (key, value) in [("watched-a", "1"), ("watched-b", "2")] {
+            let request = Request::builder()
+                .method(http::Method::PUT)
+                .uri(format!("/{}", key))
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    json!({ "key": key, "value": value }).to_string(),
+                ))
+                .unwrap();
+
+            app.ready().await.unwrap().call(request).await.unwrap();
+        }
+
+        // Resuming after the first write replays just the second one
+        let request = Request::builder()
+            .uri("/watch")
+            .header("last-event-id", (start + 1).to_string())
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut body = response.into_body();
+        let chunk = body.data().await.unwrap().unwrap();
+
+        assert_eq!(chunk, "retry:3000\n\n");
+
+        let chunk = body.data().await.unwrap().unwrap();
+        let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+
+        assert!(chunk.contains(&format!("id:{}", start + 2)));
+        assert!(chunk.contains("event:put"));
+        assert!(chunk.contains("\"key\":\"watched-b\""));
+
+        // And carries on with writes made while watching
+        let request = Request::builder()
+            .method(http::Method::DELETE)
+            .uri("/watched-a")
+            .body(Body::empty())
+            .unwrap();
+
+        app.ready().await.unwrap().call(request).await.unwrap();
+
+        let chunk = body.data().await.unwrap().unwrap();
+        let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+
+        assert!(chunk.contains(&format!("id:{}", start + 3)));
+        assert!(chunk.contains("event:delete"));
+    }
+
+    #[test]
+    fn retention_limits() {
+        let retention = Retention {
+            keep_events: Some(2),
+            max_age: Some(Duration::from_millis(100)),
+        };
+
+        assert!(retention.keeps(0, 0, 1000));
+        assert!(retention.keeps(1, 950, 1000));
+        assert!(!retention.keeps(2, 950, 1000));
+        assert!(!retention.keeps(1, 850, 1000));
+        assert!(Retention::default().keeps(100, 0, 1000));
+    }
+
+    #[tokio::test]
+    async fn resuming_from_truncated_events_is_refused() {
+        let mut app = setup_tests().await;
+
+        for value in ["1", "2"] {
+            let request = Request::builder()
+                .method(http::Method::PUT)
+                .uri("/truncated")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    json!({ "key": "truncated", "value": value }).to_string(),
+                ))
+                .unwrap();
+
+            app.ready().await.unwrap().call(request).await.unwrap();
+        }
+
+        let state = crate::app_state().unwrap();
+
+        let retention = Retention {
+            keep_events: Some(1),
+            max_age: None,
+        };
+
+        assert!(truncate(&state, retention).unwrap() >= 1);
+
+        let rtxn = state.read_txn().unwrap();
+        assert_eq!(state.feed.len(&rtxn).unwrap(), 1);
+        drop(rtxn);
+
+        let request = Request::builder()
+            .uri("/watch?since=0")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::GONE);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["code"], "COMPACTED");
+    }
+}
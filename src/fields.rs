@@ -0,0 +1,81 @@
+use serde_json::{Map, Value};
+
+use crate::AppState;
+
+/// What a listing returns about each key when asked with `?fields=`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Field {
+    Key,
+    Value,
+    Version,
+    // Length of the value in bytes
+    Size,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "key" => Some(Self::Key),
+            "value" => Some(Self::Value),
+            "version" => Some(Self::Version),
+            "size" => Some(Self::Size),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Key => "key",
+            Self::Value => "value",
+            Self::Version => "version",
+            Self::Size => "size",
+        }
+    }
+}
+
+/// Parses a comma separated `?fields=` list like `key,version,size`.
+pub(crate) fn parse(fields: &str) -> Result<Vec<Field>, String> {
+    fields
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .map(|name| Field::parse(name).ok_or_else(|| format!("Unknown field {}", name)))
+        .collect()
+}
+
+/// Builds the object holding just the requested `fields` of `key`.
+pub(crate) fn select(
+    state: &AppState,
+    rtxn: &heed::RoTxn,
+    fields: &[Field],
+    key: &str,
+    value: &str,
+) -> heed::Result<Value> {
+    let mut entry = Map::new();
+
+    for field in fields {
+        let selected = match field {
+            Field::Key => Value::from(key),
+            Field::Value => Value::from(value),
+            Field::Version => Value::from(state.versions.get(rtxn, key)?.unwrap_or(0)),
+            Field::Size => Value::from(value.len()),
+        };
+
+        entry.insert(field.name().to_owned(), selected);
+    }
+
+    Ok(Value::Object(entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_fields() {
+        assert_eq!(
+            parse("key,version,size").unwrap(),
+            vec![Field::Key, Field::Version, Field::Size]
+        );
+        assert_eq!(parse("key,etag").unwrap_err(), "Unknown field etag");
+    }
+}
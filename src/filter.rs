@@ -0,0 +1,242 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::cmp::Ordering;
+
+/// Which writes a watcher or webhook endpoint wants, every condition given has to match.
+///
+/// Read from the `?prefix=&glob=&event=&where=` query of `GET /watch`, or the same
+/// after the `#` of a `WEBHOOK_URLS` entry.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub(crate) struct FilterQuery {
+    prefix: Option<String>,
+    // `*` matches any run of characters and `?` any one
+    glob: Option<String>,
+    // `put` or `delete`
+    event: Option<String>,
+    // Like `$.status == "paid"`, over the new value parsed as JSON
+    #[serde(rename = "where")]
+    predicate: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Filter {
+    prefix: Option<String>,
+    glob: Option<Vec<char>>,
+    deletes: Option<bool>,
+    predicate: Option<Predicate>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug)]
+struct Predicate {
+    path: Vec<Segment>,
+    op: Op,
+    literal: Value,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Field(String),
+    Index(usize),
+}
+
+impl FilterQuery {
+    pub(crate) fn parse(self) -> Result<Filter, String> {
+        let deletes = match self.event.as_deref() {
+            None => None,
+            Some("put") => Some(false),
+            Some("delete") => Some(true),
+            Some(event) => return Err(format!("Unknown event {}", event)),
+        };
+
+        let predicate = self
+            .predicate
+            .as_deref()
+            .map(Predicate::parse)
+            .transpose()?;
+
+        Ok(Filter {
+            prefix: self.prefix,
+            glob: self.glob.map(|glob| glob.chars().collect()),
+            deletes,
+            predicate,
+        })
+    }
+}
+
+impl Filter {
+    /// Whether the write of `value` to `key`, `None` for a delete, passes the filter.
+    pub(crate) fn matches(&self, key: &str, value: Option<&str>) -> bool {
+        self.prefix
+            .as_ref()
+            .is_none_or(|prefix| key.starts_with(prefix.as_str()))
+            && self.glob.as_ref().is_none_or(|glob| {
+                let key: Vec<char> = key.chars().collect();
+
+                glob_matches(glob, &key)
+            })
+            && self
+                .deletes
+                .is_none_or(|deletes| deletes == value.is_none())
+            && self.predicate.as_ref().is_none_or(|predicate| {
+                value
+                    .and_then(|value| serde_json::from_str(value).ok())
+                    .is_some_and(|value| predicate.matches(&value))
+            })
+    }
+}
+
+fn glob_matches(glob: &[char], key: &[char]) -> bool {
+    match glob.split_first() {
+        None => key.is_empty(),
+        Some(('*', rest)) => (0..=key.len()).any(|skip| glob_matches(rest, &key[skip..])),
+        Some(('?', rest)) => !key.is_empty() && glob_matches(rest, &key[1..]),
+        Some((c, rest)) => key.first() == Some(c) && glob_matches(rest, &key[1..]),
+    }
+}
+
+impl Predicate {
+    /// Parses `$.path[0].to.field <op> <JSON literal>`.
+    fn parse(predicate: &str) -> Result<Predicate, String> {
+        let invalid = || format!("Invalid predicate {}", predicate);
+
+        let operators = [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ];
+
+        let (index, token, op) = operators
+            .iter()
+            .filter_map(|(token, op)| predicate.find(token).map(|index| (index, *token, *op)))
+            .min_by_key(|(index, token, _)| (*index, usize::MAX - token.len()))
+            .ok_or_else(invalid)?;
+
+        let path = predicate[..index].trim();
+        let literal =
+            serde_json::from_str(predicate[index + token.len()..].trim()).map_err(|_| invalid())?;
+
+        let mut segments = Vec::new();
+        let mut rest = path.strip_prefix('$').ok_or_else(invalid)?;
+
+        while !rest.is_empty() {
+            if let Some(field) = rest.strip_prefix('.') {
+                let end = field.find(['.', '[']).unwrap_or(field.len());
+
+                if end == 0 {
+                    return Err(invalid());
+                }
+
+                segments.push(Segment::Field(field[..end].to_owned()));
+                rest = &field[end..];
+            } else if let Some(index) = rest.strip_prefix('[') {
+                let end = index.find(']').ok_or_else(invalid)?;
+
+                segments.push(Segment::Index(index[..end].parse().map_err(|_| invalid())?));
+                rest = &index[end + 1..];
+            } else {
+                return Err(invalid());
+            }
+        }
+
+        Ok(Predicate {
+            path: segments,
+            op,
+            literal,
+        })
+    }
+
+    fn matches(&self, value: &Value) -> bool {
+        let found = self
+            .path
+            .iter()
+            .try_fold(value, |value, segment| match segment {
+                Segment::Field(field) => value.get(field),
+                Segment::Index(index) => value.get(index),
+            });
+
+        let found = match found {
+            Some(found) => found,
+            None => return self.op == Op::Ne,
+        };
+
+        let ordering = match (found, &self.literal) {
+            (Value::Number(found), Value::Number(literal)) => {
+                found.as_f64().partial_cmp(&literal.as_f64())
+            }
+            (Value::String(found), Value::String(literal)) => Some(found.cmp(literal)),
+            (found, literal) if found == literal => Some(Ordering::Equal),
+            _ => None,
+        };
+
+        match self.op {
+            Op::Eq => ordering == Some(Ordering::Equal),
+            Op::Ne => ordering != Some(Ordering::Equal),
+            Op::Lt => ordering == Some(Ordering::Less),
+            Op::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            Op::Gt => ordering == Some(Ordering::Greater),
+            Op::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(query: &str) -> Filter {
+        serde_urlencoded::from_str::<FilterQuery>(query)
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn matches_keys_and_events() {
+        let filter_by = filter("prefix=orders:&event=put");
+
+        assert!(filter_by.matches("orders:1", Some("x")));
+        assert!(!filter_by.matches("orders:1", None));
+        assert!(!filter_by.matches("users:1", Some("x")));
+
+        let glob = filter("glob=orders:*:total");
+
+        assert!(glob.matches("orders:42:total", None));
+        assert!(!glob.matches("orders:42:items", None));
+        assert!(filter("glob=a?c").matches("abc", None));
+    }
+
+    #[test]
+    fn matches_value_predicates() {
+        let paid = filter("where=%24.status%20%3D%3D%20%22paid%22");
+
+        assert!(paid.matches("order", Some(r#"{"status":"paid"}"#)));
+        assert!(!paid.matches("order", Some(r#"{"status":"open"}"#)));
+        assert!(!paid.matches("order", Some("not json")));
+        assert!(!paid.matches("order", None));
+
+        let big = filter("where=%24.items[1].qty>=10");
+
+        assert!(big.matches("order", Some(r#"{"items":[{},{"qty":10}]}"#)));
+        assert!(!big.matches("order", Some(r#"{"items":[{"qty":10}]}"#)));
+
+        assert!(FilterQuery {
+            predicate: Some(String::from("status == 1")),
+            ..FilterQuery::default()
+        }
+        .parse()
+        .is_err());
+    }
+}
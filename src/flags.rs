@@ -0,0 +1,250 @@
+use axum::extract::{Path, State};
+use axum::{http::StatusCode, Json};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::{AppError, AppState};
+
+// Flags are stored as JSON documents under this prefix, `flags:checkout` for `checkout`
+const PREFIX: &str = "flags:";
+
+// Context attribute rollouts bucket by unless a rule says otherwise
+const DEFAULT_BUCKET_BY: &str = "key";
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_off() -> Value {
+    Value::Bool(false)
+}
+
+/// A flag document, like
+///
+/// ```json
+/// {
+///     "rules": [
+///         { "attribute": "plan", "in": ["enterprise"], "serve": true },
+///         { "percentage": 20, "serve": true }
+///     ],
+///     "default": false
+/// }
+/// ```
+///
+/// The first rule the context matches decides what's served, `default` when none does.
+#[derive(Deserialize)]
+struct Flag {
+    #[serde(default = "default_true")]
+    enabled: bool,
+    // Served while the flag is disabled
+    #[serde(default = "default_off")]
+    off: Value,
+    #[serde(default = "default_off")]
+    default: Value,
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+#[derive(Deserialize)]
+struct Rule {
+    // Matches contexts whose `attribute` is one of `in`, or any context without one
+    attribute: Option<String>,
+    #[serde(default, rename = "in")]
+    values: Vec<Value>,
+    // And of those, this percentage, by the hash of the `bucket_by` attribute
+    percentage: Option<f64>,
+    bucket_by: Option<String>,
+    serve: Value,
+}
+
+// Where in 0 to 100 the context falls for `flag`, the same every time but different for
+// each flag, so being in one rollout says nothing about being in another
+fn bucket(flag: &str, id: &Value) -> f64 {
+    let id = match id {
+        Value::String(id) => id.clone(),
+        id => id.to_string(),
+    };
+
+    let hash = Sha256::digest(format!("{}:{}", flag, id).as_bytes());
+    let bucket = u64::from_be_bytes(hash[..8].try_into().unwrap()) % 10_000;
+
+    bucket as f64 / 100.0
+}
+
+impl Rule {
+    fn matches(&self, flag: &str, context: &Map<String, Value>) -> bool {
+        if let Some(attribute) = &self.attribute {
+            match context.get(attribute) {
+                Some(value) if self.values.contains(value) => {}
+                _ => return false,
+            }
+        }
+
+        match self.percentage {
+            Some(percentage) => {
+                let bucket_by = self.bucket_by.as_deref().unwrap_or(DEFAULT_BUCKET_BY);
+
+                // Contexts without the attribute can't be bucketed consistently
+                context
+                    .get(bucket_by)
+                    .is_some_and(|id| bucket(flag, id) < percentage)
+            }
+            None => true,
+        }
+    }
+}
+
+impl Flag {
+    // What's served for `context` and why
+    fn evaluate(&self, name: &str, context: &Map<String, Value>) -> (Value, Value) {
+        if !self.enabled {
+            return (self.off.clone(), json!("disabled"));
+        }
+
+        match self
+            .rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(name, context))
+        {
+            Some((index, rule)) => (rule.serve.clone(), json!({ "rule": index })),
+            None => (self.default.clone(), json!("default")),
+        }
+    }
+}
+
+/// `POST /flags/:name/evaluate`: what the flag stored at `flags:<name>` serves for the
+/// context posted, an object of attributes like `{"key": "user-1", "plan": "free"}`.
+pub(crate) async fn evaluate(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(context): Json<Map<String, Value>>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let key = format!("{}{}", PREFIX, name);
+
+    let rtxn = state.read_txn().unwrap();
+
+    let flag = match state.kv.get(&rtxn, &key) {
+        Ok(Some(flag)) => serde_json::from_str::<Flag>(flag),
+        Ok(None) => {
+            return Ok((
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "Flag not found" })),
+            ))
+        }
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    };
+
+    let flag = match flag {
+        Ok(flag) => flag,
+        Err(err) => {
+            return Ok((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(json!({ "error": format!("{} isn't a valid flag: {}", key, err) })),
+            ))
+        }
+    };
+
+    let (value, reason) = flag.evaluate(&name, &context);
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "flag": name, "value": value, "reason": reason })),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request},
+    };
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    fn context(context: Value) -> Map<String, Value> {
+        context.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn serves_the_first_matching_rule() {
+        let flag: Flag = serde_json::from_value(json!({
+            "rules": [
+                { "attribute": "plan", "in": ["enterprise"], "serve": "beta" },
+                { "percentage": 50, "serve": "rollout" }
+            ],
+            "default": "stable"
+        }))
+        .unwrap();
+
+        let evaluate = |context: Map<String, Value>| flag.evaluate("checkout", &context).0;
+
+        assert_eq!(
+            evaluate(context(json!({ "key": "a", "plan": "enterprise" }))),
+            "beta"
+        );
+        assert_eq!(evaluate(context(json!({ "plan": "free" }))), "stable");
+
+        // About half the users are in, each one staying where they are
+        let rolled_out = (0..1000)
+            .filter(|user| evaluate(context(json!({ "key": user }))) == "rollout")
+            .count();
+
+        assert!((400..600).contains(&rolled_out), "{}", rolled_out);
+        assert_eq!(
+            evaluate(context(json!({ "key": 7 }))),
+            evaluate(context(json!({ "key": 7 })))
+        );
+
+        let disabled: Flag = serde_json::from_value(json!({ "enabled": false })).unwrap();
+
+        assert_eq!(
+            disabled.evaluate("checkout", &Map::new()),
+            (json!(false), json!("disabled"))
+        );
+    }
+
+    #[tokio::test]
+    async fn evaluates_stored_flags() {
+        let mut app = setup_tests().await;
+
+        let flag = json!({ "rules": [{ "attribute": "country", "in": ["DE"], "serve": true }] });
+
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "key": "flags:eu-banner", "value": flag.to_string() }).to_string(),
+            ))
+            .unwrap();
+        app.ready().await.unwrap().call(request).await.unwrap();
+
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/flags/eu-banner/evaluate")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({ "country": "DE" }).to_string()))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(
+            body,
+            json!({ "flag": "eu-banner", "value": true, "reason": { "rule": 0 } })
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use axum::body::HttpBody;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::{metrics, AppState};
+
+/// The address given in `METRICS_ADDRESS` to serve the operational routes on instead of
+/// alongside the data API, if any.
+pub(crate) fn address_from_env() -> Result<Option<SocketAddr>, String> {
+    match std::env::var("METRICS_ADDRESS") {
+        Ok(address) => address.parse().map(Some).map_err(|_| {
+            format!(
+                "METRICS_ADDRESS must be an address like 127.0.0.1:9090, got {}",
+                address
+            )
+        }),
+        Err(_) => Ok(None),
+    }
+}
+
+/// `/metrics`, `/healthz` and `/readyz`, for the data API's router or a listener of their own.
+pub(crate) fn routes<B>() -> Router<Arc<AppState>, B>
+where
+    B: HttpBody + Send + 'static,
+{
+    Router::new()
+        // GET /metrics
+        .route("/metrics", get(metrics::metrics))
+        // GET /healthz
+        .route("/healthz", get(healthz))
+        // GET /readyz
+        .route("/readyz", get(readyz))
+}
+
+/// The process is up, whatever state the database is in.
+async fn healthz() -> (StatusCode, Json<Value>) {
+    (StatusCode::OK, Json(json!({ "status": "ok" })))
+}
+
+/// The database can be read, so requests can be served.
+async fn readyz(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Value>) {
+    match state.read_txn() {
+        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ready" }))),
+        Err(_) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "error": "The database can't be read" })),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[tokio::test]
+    async fn serves_health_checks_on_their_own() {
+        let _ = setup_tests().await;
+
+        let mut app = routes().with_state(crate::app_state().unwrap());
+
+        for uri in ["/healthz", "/readyz", "/metrics"] {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        }
+
+        // The data API isn't served alongside them
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,369 @@
+use axum::{http::StatusCode, Json};
+use heed::types::ByteSlice;
+use heed::{RoTxn, RwTxn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{alias, metrics, name_prefix, now_millis, AppState};
+
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(600);
+
+// With `VERSION_HISTORY` enabled every version of a key is kept under
+// `[key length][key][version]`, the big endian version keeping them in write order.
+
+fn entry_key(key: &str, version: u64) -> Vec<u8> {
+    let mut entry = name_prefix(key);
+    entry.extend_from_slice(&version.to_be_bytes());
+    entry
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct HistoryEntry {
+    // `None` records a delete
+    value: Option<String>,
+    written_at: u64,
+}
+
+/// Keeps `value` as `version` of `key` alongside the write, if history is enabled.
+pub(crate) fn record(
+    state: &AppState,
+    wtxn: &mut RwTxn,
+    key: &str,
+    version: u64,
+    value: Option<&str>,
+) -> heed::Result<()> {
+    if !state.history_enabled {
+        return Ok(());
+    }
+
+    let entry = HistoryEntry {
+        value: value.map(str::to_owned),
+        written_at: now_millis(),
+    };
+
+    state.history.put(wtxn, &entry_key(key, version), &entry)
+}
+
+/// How much history to keep, read from `HISTORY_KEEP_VERSIONS` and `HISTORY_MAX_AGE_DAYS`.
+///
+/// A version is pruned once it falls outside either limit, but the newest version of a
+/// key is always kept so reads as of now keep working.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Retention {
+    pub(crate) keep_versions: Option<usize>,
+    pub(crate) max_age: Option<Duration>,
+}
+
+impl Retention {
+    pub(crate) fn from_env() -> Result<Retention, String> {
+        let keep_versions = match std::env::var("HISTORY_KEEP_VERSIONS") {
+            Ok(versions) => match versions.parse() {
+                Ok(versions) => Some(versions),
+                Err(_) => {
+                    return Err(format!(
+                        "HISTORY_KEEP_VERSIONS must be a number, got {}",
+                        versions
+                    ))
+                }
+            },
+            Err(_) => None,
+        };
+        let max_age = match std::env::var("HISTORY_MAX_AGE_DAYS") {
+            Ok(days) => match days
+                .parse::<u64>()
+                .ok()
+                .and_then(|days| days.checked_mul(24 * 60 * 60))
+            {
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => {
+                    return Err(format!(
+                        "HISTORY_MAX_AGE_DAYS must be a number of days, got {}",
+                        days
+                    ))
+                }
+            },
+            Err(_) => None,
+        };
+
+        Ok(Retention {
+            keep_versions,
+            max_age,
+        })
+    }
+
+    fn is_unbounded(&self) -> bool {
+        self.keep_versions.is_none() && self.max_age.is_none()
+    }
+
+    // `newer` counts the versions of the same key written after this one
+    fn keeps(&self, newer: usize, written_at: u64, now: u64) -> bool {
+        newer == 0
+            || (self.keep_versions.is_none_or(|keep| newer < keep)
+                && self
+                    .max_age
+                    .is_none_or(|max_age| written_at + max_age.as_millis() as u64 >= now))
+    }
+}
+
+/// Deletes the history falling outside `state.history_retention`, returning how many
+/// entries were removed and how many bytes of keys and values they took up.
+///
+/// What to drop is worked out in a read transaction so writers are only held up for the
+/// deletes themselves.
+pub(crate) fn prune(state: &AppState) -> heed::Result<(u64, u64)> {
+    let retention = state.history_retention;
+    let now = now_millis();
+
+    let mut pruned = Vec::new();
+    let mut reclaimed = 0;
+
+    {
+        let rtxn = state.read_txn()?;
+
+        // Newest first, so the versions of each key are counted down from its latest
+        let mut current: Option<Vec<u8>> = None;
+        let mut newer = 0;
+
+        for entry in state
+            .history
+            .remap_data_type::<ByteSlice>()
+            .rev_iter(&rtxn)?
+        {
+            let (entry_key, data) = entry?;
+
+            let key = &entry_key[..entry_key.len() - 8];
+
+            if current.as_deref() != Some(key) {
+                current = Some(key.to_vec());
+                newer = 0;
+            }
+
+            let written_at = serde_json::from_slice::<HistoryEntry>(data)
+                .map(|entry| entry.written_at)
+                .unwrap_or(0);
+
+            if !retention.keeps(newer, written_at, now) {
+                reclaimed += (entry_key.len() + data.len()) as u64;
+                pruned.push(entry_key.to_vec());
+            }
+
+            newer += 1;
+        }
+    }
+
+    let mut wtxn = state.write_txn()?;
+
+    for entry_key in &pruned {
+        state.history.delete(&mut wtxn, entry_key)?;
+    }
+
+    state.commit(wtxn)?;
+
+    Ok((pruned.len() as u64, reclaimed))
+}
+
+/// Prunes the history every [`COMPACTION_INTERVAL`] when a retention limit is set.
+pub(crate) fn spawn_compaction(state: Arc<AppState>) {
+    if !state.history_enabled || state.history_retention.is_unbounded() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(COMPACTION_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let pruned = {
+                let state = state.clone();
+
+                tokio::task::spawn_blocking(move || prune(&state).map_err(|err| err.to_string()))
+            };
+
+            match pruned.await.unwrap() {
+                Ok((entries, bytes)) => {
+                    metrics::add(&state.metrics.history_pruned, entries);
+                    metrics::add(&state.metrics.history_reclaimed_bytes, bytes);
+
+                    tracing::info!(entries, bytes, "pruned version history");
+                }
+                Err(err) => tracing::error!(%err, "failed to prune version history"),
+            }
+        }
+    });
+}
+
+/// Parses `as_of`, Unix time in seconds with an optional fraction, into milliseconds.
+fn parse_timestamp(as_of: &str) -> Option<u64> {
+    let seconds: f64 = as_of.parse().ok()?;
+
+    if !seconds.is_finite() || seconds < 0.0 {
+        return None;
+    }
+
+    Some((seconds * 1000.0) as u64)
+}
+
+/// The newest version of `key` written at or before `as_of` and its value, `None` if
+/// that version was a delete.
+fn version_at(
+    state: &AppState,
+    rtxn: &RoTxn,
+    key: &str,
+    as_of: u64,
+) -> heed::Result<Option<(u64, Option<String>)>> {
+    let prefix = name_prefix(key);
+
+    for entry in state.history.rev_prefix_iter(rtxn, &prefix)? {
+        let (entry_key, entry) = entry?;
+
+        if entry.written_at <= as_of {
+            let version = u64::from_be_bytes(entry_key[prefix.len()..].try_into().unwrap());
+
+            return Ok(Some((version, entry.value)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Answers `GET /:key?as_of=` with the value the key held at that time.
+pub(crate) fn get_as_of(state: &AppState, key: &str, as_of: &str) -> (StatusCode, Json<Value>) {
+    if !state.history_enabled {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Version history is not enabled" })),
+        );
+    }
+
+    let as_of = match parse_timestamp(as_of) {
+        Some(as_of) => as_of,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "Invalid as_of timestamp" })),
+            )
+        }
+    };
+
+    let rtxn = state.read_txn().unwrap();
+
+    // Aliases resolve as they are today, only the value is read from the past
+    let target = match alias::resolve_key(state, &rtxn, key) {
+        Ok(target) => target,
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            )
+        }
+    };
+
+    match version_at(state, &rtxn, &target, as_of) {
+        Ok(Some((version, Some(value)))) => (
+            StatusCode::OK,
+            Json(json!({ "key": key, "value": value, "version": version })),
+        ),
+        Ok(_) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Key not found" })),
+        ),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{get_json, setup_tests};
+    use axum::{
+        body::Body,
+        http::{self, Request},
+        Router,
+    };
+    use std::time::Duration;
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    async fn put(app: &mut Router, key: &str, value: &str) {
+        let request = Request::builder()
+            .method(http::Method::PUT)
+            .uri(format!("/{}", key))
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "key": key, "value": value }).to_string(),
+            ))
+            .unwrap();
+
+        app.ready().await.unwrap().call(request).await.unwrap();
+    }
+
+    #[test]
+    fn retention_limits() {
+        let day = 24 * 60 * 60 * 1000;
+        let now = 100 * day;
+
+        let retention = Retention {
+            keep_versions: Some(2),
+            max_age: None,
+        };
+
+        assert!(retention.keeps(1, 0, now));
+        assert!(!retention.keeps(2, now, now));
+
+        let retention = Retention {
+            keep_versions: None,
+            max_age: Some(Duration::from_secs(7 * 24 * 60 * 60)),
+        };
+
+        assert!(retention.keeps(5, now - 6 * day, now));
+        assert!(!retention.keeps(5, now - 8 * day, now));
+
+        // The latest version survives either limit
+        assert!(retention.keeps(0, 0, now));
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("1700000000"), Some(1_700_000_000_000));
+        assert_eq!(parse_timestamp("1700000000.25"), Some(1_700_000_000_250));
+        assert_eq!(parse_timestamp("-1"), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[tokio::test]
+    async fn reads_past_values() {
+        let mut app = setup_tests().await;
+
+        let before = now_millis();
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        put(&mut app, "history-test", "first").await;
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let between = now_millis();
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        put(&mut app, "history-test", "second").await;
+
+        let as_of = |millis: u64| format!("/history-test?as_of={}", millis as f64 / 1000.0);
+
+        let (status, body) = get_json(&mut app, &as_of(between)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["value"], "first");
+
+        let (_, body) = get_json(&mut app, &as_of(now_millis())).await;
+        assert_eq!(body["value"], "second");
+
+        let (status, _) = get_json(&mut app, &as_of(before)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
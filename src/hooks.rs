@@ -0,0 +1,167 @@
+use axum::{http::StatusCode, Json};
+use serde_json::{json, Value};
+
+use crate::{now_millis, AppState};
+
+/// What a write hook does to values written under its prefix before they're stored.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Hook {
+    Lowercase,
+    Uppercase,
+    Trim,
+    // Sets `written_at` in JSON objects to the time of the write, Unix milliseconds
+    Timestamp,
+    // Refuses values containing this
+    Deny(String),
+}
+
+impl Hook {
+    fn parse(name: &str) -> Option<Hook> {
+        match name {
+            "lowercase" => Some(Hook::Lowercase),
+            "uppercase" => Some(Hook::Uppercase),
+            "trim" => Some(Hook::Trim),
+            "timestamp" => Some(Hook::Timestamp),
+            _ => match name.strip_prefix("deny:") {
+                Some(banned) if !banned.is_empty() => Some(Hook::Deny(banned.to_owned())),
+                _ => None,
+            },
+        }
+    }
+
+    // The value to write instead, or why it can't be written
+    fn apply(&self, value: String, now: u64) -> Result<String, String> {
+        match self {
+            Hook::Lowercase => Ok(value.to_lowercase()),
+            Hook::Uppercase => Ok(value.to_uppercase()),
+            Hook::Trim => Ok(value.trim().to_owned()),
+            Hook::Timestamp => match serde_json::from_str(&value) {
+                Ok(Value::Object(mut object)) => {
+                    object.insert(String::from("written_at"), json!(now));
+
+                    Ok(Value::Object(object).to_string())
+                }
+                _ => Err(String::from("Values here must be JSON objects")),
+            },
+            Hook::Deny(banned) if value.contains(banned.as_str()) => {
+                Err(format!("Values here can't contain {}", banned))
+            }
+            Hook::Deny(_) => Ok(value),
+        }
+    }
+}
+
+/// Parses `WRITE_HOOKS`, comma separated `prefix=hook` pairs run in the order given.
+pub(crate) fn parse_hooks(config: &str) -> Result<Vec<(String, Hook)>, String> {
+    config
+        .split(',')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (prefix, name) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected prefix=hook, got {}", pair))?;
+
+            let hook = Hook::parse(name).ok_or_else(|| format!("unknown write hook {}", name))?;
+
+            Ok((prefix.to_owned(), hook))
+        })
+        .collect()
+}
+
+// Every hook `key` is under, in order
+fn apply(hooks: &[(String, Hook)], key: &str, value: &str, now: u64) -> Result<String, String> {
+    hooks
+        .iter()
+        .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+        .try_fold(value.to_owned(), |value, (_, hook)| hook.apply(value, now))
+}
+
+/// The value to write to `key` once its prefix's hooks have run, or the response to send
+/// instead when one refuses it. Run before any other checks, on what would be written.
+pub(crate) fn run(
+    state: &AppState,
+    key: &str,
+    value: &str,
+) -> Result<String, (StatusCode, Json<Value>)> {
+    apply(&state.write_hooks, key, value, now_millis()).map_err(|err| {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({ "error": err })),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request},
+    };
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[test]
+    fn runs_every_hook_in_order() {
+        let hooks =
+            parse_hooks("users:=trim,users:=lowercase,posts:=deny:spam,events:=timestamp").unwrap();
+
+        assert_eq!(
+            apply(&hooks, "users:1", "  Alice@Example.com ", 0).unwrap(),
+            "alice@example.com"
+        );
+        assert!(apply(&hooks, "posts:1", "cheap spam", 0).is_err());
+        assert_eq!(apply(&hooks, "posts:1", "hello", 0).unwrap(), "hello");
+        assert_eq!(
+            apply(&hooks, "events:1", r#"{"kind":"deploy"}"#, 7).unwrap(),
+            r#"{"kind":"deploy","written_at":7}"#
+        );
+        assert!(apply(&hooks, "events:1", "deploy", 7).is_err());
+        assert_eq!(apply(&hooks, "other", " As Is ", 0).unwrap(), " As Is ");
+
+        assert!(parse_hooks("users:=shout").is_err());
+        assert!(parse_hooks("users:=deny:").is_err());
+    }
+
+    #[tokio::test]
+    async fn writes_what_the_hooks_make_of_values() {
+        let mut app = setup_tests().await;
+
+        let put = |value: &str| {
+            Request::builder()
+                .method(http::Method::PUT)
+                .uri("/hooked:email")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    json!({ "key": "hooked:email", "value": value }).to_string(),
+                ))
+                .unwrap()
+        };
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(put(" Alice@Example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["value"], "alice@example.com");
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(put("root@localhost"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
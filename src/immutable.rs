@@ -0,0 +1,163 @@
+use axum::http::HeaderMap;
+use heed::{RoTxn, RwTxn};
+
+use crate::{signing, tokens, AppState};
+
+/// Header carrying `ADMIN_TOKEN` to update or delete immutable keys anyway.
+pub(crate) const ADMIN_TOKEN_HEADER: &str = "x-admin-token";
+
+/// Whether `key` exists and can no longer be changed, either because it was created as
+/// immutable or because it lives under one of `IMMUTABLE_PREFIXES`.
+///
+/// Keys that don't exist yet are never locked, they can still be written once.
+pub(crate) fn is_locked(state: &AppState, txn: &RoTxn, key: &str) -> heed::Result<bool> {
+    if state.kv.get(txn, key)?.is_none() {
+        return Ok(false);
+    }
+
+    let under_prefix = state
+        .immutable_prefixes
+        .iter()
+        .any(|prefix| key.starts_with(prefix.as_str()));
+
+    Ok(under_prefix || state.immutable.get(txn, key)?.is_some())
+}
+
+/// Marks a key created within `wtxn` as write-once.
+pub(crate) fn mark(state: &AppState, wtxn: &mut RwTxn, key: &str) -> heed::Result<()> {
+    state.immutable.put(wtxn, key, &())
+}
+
+/// Whether `given` is `token`, comparing every byte however early they differ, so response
+/// times don't give the admin token away a byte at a time.
+pub(crate) fn is_token(token: &str, given: &[u8]) -> bool {
+    token.len() == given.len()
+        && token
+            .bytes()
+            .zip(given)
+            .fold(0, |differ, (expected, given)| differ | (expected ^ given))
+            == 0
+}
+
+/// Whether the request carries the admin token or one of the API tokens, or was signed
+/// with one of `SIGNING_KEYS`. Without any of them nobody can override.
+pub(crate) fn admin_override(state: &AppState, headers: &HeaderMap) -> bool {
+    if headers.contains_key(signing::SIGNED_BY_HEADER) {
+        return true;
+    }
+
+    match (&state.admin_token, headers.get(ADMIN_TOKEN_HEADER)) {
+        (Some(token), Some(given)) if is_token(token, given.as_bytes()) => true,
+        (_, Some(given)) => tokens::is_valid(state, given.as_bytes()),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_token;
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request, StatusCode},
+        Router,
+    };
+    use serde_json::{json, Value};
+
+    async fn send(
+        app: &mut Router,
+        method: http::Method,
+        uri: &str,
+        admin: bool,
+        body: Value,
+    ) -> StatusCode {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(http::header::CONTENT_TYPE, "application/json");
+
+        if admin {
+            request = request.header(super::ADMIN_TOKEN_HEADER, "test-admin-token");
+        }
+
+        let body = match body {
+            Value::Null => Body::empty(),
+            body => Body::from(body.to_string()),
+        };
+
+        crate::tests::respond(app, request.body(body).unwrap())
+            .await
+            .status()
+    }
+
+    #[test]
+    fn compares_tokens_whole() {
+        assert!(is_token("test-admin-token", b"test-admin-token"));
+        assert!(!is_token("test-admin-token", b"test-admin-tokem"));
+        assert!(!is_token("test-admin-token", b"test-admin"));
+        assert!(!is_token("test-admin-token", b""));
+    }
+
+    #[tokio::test]
+    async fn immutable_keys_need_an_override() {
+        let mut app = setup_tests().await;
+
+        let status = send(
+            &mut app,
+            http::Method::POST,
+            "/",
+            false,
+            json!({ "key": "artifact", "value": "v1", "immutable": true }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let update = json!({ "key": "artifact", "value": "v2" });
+
+        let status = send(
+            &mut app,
+            http::Method::PUT,
+            "/artifact",
+            false,
+            update.clone(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let status = send(
+            &mut app,
+            http::Method::DELETE,
+            "/artifact",
+            false,
+            json!(null),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let status = send(&mut app, http::Method::DELETE, "/", false, json!(null)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let status = send(&mut app, http::Method::PUT, "/artifact", true, update).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn immutable_prefixes_are_written_once() {
+        let mut app = setup_tests().await;
+
+        let body = json!({ "key": "write-once:log", "value": "entry" });
+
+        let status = send(
+            &mut app,
+            http::Method::PUT,
+            "/write-once:log",
+            false,
+            body.clone(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let status = send(&mut app, http::Method::PUT, "/write-once:log", false, body).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+}
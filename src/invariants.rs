@@ -0,0 +1,187 @@
+//! Property tests running random sequences of operations against the API and checking
+//! the store against a `BTreeMap` model of what it should hold.
+
+use axum::{
+    body::Body,
+    http::{self, Request, StatusCode},
+    Router,
+};
+use proptest::prelude::*;
+use serde_json::{json, Value};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tower::Service; // for `call`
+use tower::ServiceExt; // for `ready`
+
+use crate::immutable::ADMIN_TOKEN_HEADER;
+use crate::tests::setup_tests;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Create(String, String),
+    Put(String, String),
+    Delete(String),
+    Get(String),
+    Scan,
+    Count,
+}
+
+// A handful of keys, half of them counted, so operations keep running into each other
+fn key() -> impl Strategy<Value = String> {
+    "(counted:)?[abc]"
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        (key(), "[a-z]{0,4}").prop_map(|(key, value)| Op::Create(key, value)),
+        (key(), "[a-z]{0,4}").prop_map(|(key, value)| Op::Put(key, value)),
+        key().prop_map(Op::Delete),
+        key().prop_map(Op::Get),
+        Just(Op::Scan),
+        Just(Op::Count),
+    ]
+}
+
+async fn send(
+    app: &mut Router,
+    method: http::Method,
+    uri: &str,
+    body: Option<Value>,
+) -> (StatusCode, Option<u64>, Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(ADMIN_TOKEN_HEADER, "test-admin-token");
+
+    let body = match body {
+        Some(body) => Body::from(body.to_string()),
+        None => Body::empty(),
+    };
+
+    let response = app
+        .ready()
+        .await
+        .unwrap()
+        .call(request.body(body).unwrap())
+        .await
+        .unwrap();
+
+    let status = response.status();
+    let version = response
+        .headers()
+        .get(http::header::ETAG)
+        .map(|etag| etag.to_str().unwrap().trim_matches('"').parse().unwrap());
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+
+    (status, version, body)
+}
+
+async fn check(app: &mut Router, ops: Vec<Op>) {
+    send(app, http::Method::DELETE, "/", None).await;
+
+    let mut model = BTreeMap::new();
+    // The last version read of each key, which has to move on after any write to it and
+    // stay put otherwise
+    let mut versions: HashMap<String, u64> = HashMap::new();
+    let mut written = HashSet::new();
+
+    for op in ops {
+        match op {
+            Op::Create(key, value) => {
+                let body = json!({ "key": key, "value": value });
+                let (status, _, _) = send(app, http::Method::POST, "/", Some(body)).await;
+
+                match model.entry(key) {
+                    Entry::Occupied(_) => assert_eq!(status, StatusCode::BAD_REQUEST),
+                    Entry::Vacant(entry) => {
+                        assert_eq!(status, StatusCode::CREATED);
+                        written.insert(entry.key().clone());
+                        entry.insert(value);
+                    }
+                }
+            }
+            Op::Put(key, value) => {
+                let body = json!({ "key": key, "value": value });
+                let (status, _, _) =
+                    send(app, http::Method::PUT, &format!("/{}", key), Some(body)).await;
+
+                assert_eq!(status, StatusCode::OK);
+                written.insert(key.clone());
+                model.insert(key, value);
+            }
+            Op::Delete(key) => {
+                let (status, _, _) =
+                    send(app, http::Method::DELETE, &format!("/{}", key), None).await;
+
+                written.insert(key.clone());
+
+                match model.remove(&key) {
+                    Some(_) => assert_eq!(status, StatusCode::OK),
+                    None => assert_eq!(status, StatusCode::NOT_FOUND),
+                }
+            }
+            Op::Get(key) => {
+                let (status, version, body) =
+                    send(app, http::Method::GET, &format!("/{}", key), None).await;
+
+                match model.get(&key) {
+                    Some(value) => {
+                        assert_eq!(status, StatusCode::OK);
+                        assert_eq!(body["value"], json!(value));
+
+                        let version = version.unwrap();
+
+                        if let Some(seen) = versions.insert(key.clone(), version) {
+                            if written.contains(&key) {
+                                assert!(version > seen);
+                            } else {
+                                assert_eq!(version, seen);
+                            }
+                        }
+
+                        written.remove(&key);
+                    }
+                    None => assert_eq!(status, StatusCode::NOT_FOUND),
+                }
+            }
+            Op::Scan => {
+                let (_, _, body) = send(app, http::Method::GET, "/", None).await;
+
+                let expected: Vec<_> = model.iter().collect();
+
+                assert_eq!(body, json!(expected));
+            }
+            Op::Count => {
+                let (_, _, body) =
+                    send(app, http::Method::GET, "/count?prefix=counted:", None).await;
+
+                let expected = model
+                    .keys()
+                    .filter(|key| key.starts_with("counted:"))
+                    .count();
+
+                assert_eq!(body["count"], json!(expected));
+            }
+        }
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn store_matches_model(ops in prop::collection::vec(op(), 1..40)) {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let mut app = setup_tests().await;
+
+            check(&mut app, ops).await;
+        });
+    }
+}
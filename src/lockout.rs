@@ -0,0 +1,223 @@
+use axum::body::Body;
+use axum::extract::{ConnectInfo, State};
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use crate::{immutable, metrics, now_millis, tokens, AppState};
+
+// Failed attempts a source gets before it's locked out
+const MAX_FAILURES: u32 = 5;
+// The first lockout, each one after doubles it up to `MAX_LOCKOUT_MS`
+const BASE_LOCKOUT_MS: u64 = 30 * 1000;
+const MAX_LOCKOUT_MS: u64 = 60 * 60 * 1000;
+// Sources without a failure for this long are forgotten, lockouts and all
+const FORGET_AFTER_MS: u64 = 24 * 60 * 60 * 1000;
+// How many sources are tracked before the forgotten ones are cleared out
+const MAX_TRACKED: usize = 10_000;
+
+/// A source's failed authentication attempts. Kept in memory only.
+#[derive(Debug, Default)]
+pub(crate) struct Failures {
+    count: u32,
+    // Lockouts so far, each one longer than the last
+    lockouts: u32,
+    locked_until: u64,
+    last_failure: u64,
+}
+
+impl Failures {
+    // Counts a failure at `now`, returning whether it locked the source out
+    fn record(&mut self, now: u64) -> bool {
+        self.count += 1;
+        self.last_failure = now;
+
+        if self.count < MAX_FAILURES {
+            return false;
+        }
+
+        let lockout = BASE_LOCKOUT_MS
+            .saturating_mul(2u64.saturating_pow(self.lockouts))
+            .min(MAX_LOCKOUT_MS);
+
+        self.count = 0;
+        self.lockouts += 1;
+        self.locked_until = now + lockout;
+
+        true
+    }
+}
+
+fn carries_credentials(headers: &HeaderMap) -> bool {
+    headers.contains_key(immutable::ADMIN_TOKEN_HEADER)
+        || headers.contains_key(header::AUTHORIZATION)
+}
+
+// Sent an admin token that's neither `ADMIN_TOKEN` nor an API token
+fn wrong_token(state: &AppState, headers: &HeaderMap) -> bool {
+    match headers.get(immutable::ADMIN_TOKEN_HEADER) {
+        Some(given) => {
+            !state
+                .admin_token
+                .as_deref()
+                .is_some_and(|token| immutable::is_token(token, given.as_bytes()))
+                && !tokens::is_valid(state, given.as_bytes())
+        }
+        None => false,
+    }
+}
+
+/// Locks a source out of authenticating after [`MAX_FAILURES`] wrong tokens or bad
+/// signatures in a row, for longer each time, so guessing credentials is too slow to
+/// get anywhere and shows up in `kv_auth_failures_total` and `kv_auth_lockouts_total`.
+/// Locked out requests with credentials get a 429 until it ends, requests without any
+/// aren't held up, other clients behind the same address keep working.
+///
+/// Sources are told apart by the address they connect from, requests without one, like
+/// those from tests, aren't tracked.
+pub(crate) async fn guard(
+    State(state): State<Arc<AppState>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let source = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip());
+
+    let source = match source {
+        Some(source) if carries_credentials(request.headers()) => source,
+        _ => return next.run(request).await,
+    };
+
+    let now = now_millis();
+
+    let locked_until = state
+        .auth_failures
+        .lock()
+        .unwrap()
+        .get(&source)
+        .map_or(0, |failures| failures.locked_until);
+
+    if locked_until > now {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(
+                header::RETRY_AFTER,
+                (locked_until - now).div_ceil(1000).to_string(),
+            )],
+            Json(json!({ "error": "Too many failed authentication attempts" })),
+        )
+            .into_response();
+    }
+
+    let wrong_token = wrong_token(&state, request.headers());
+
+    let response = next.run(request).await;
+
+    // Signatures are checked further in, a bad one comes back as a 401
+    if wrong_token || response.status() == StatusCode::UNAUTHORIZED {
+        record_failure(&state, source, now);
+    } else if let Some(failures) = state.auth_failures.lock().unwrap().get_mut(&source) {
+        // Only failures in a row count, earlier lockouts still make the next one longer
+        failures.count = 0;
+    }
+
+    response
+}
+
+fn record_failure(state: &AppState, source: IpAddr, now: u64) {
+    metrics::increment(&state.metrics.auth_failures);
+
+    let mut failures = state.auth_failures.lock().unwrap();
+
+    if failures.len() >= MAX_TRACKED {
+        failures.retain(|_, failures| now - failures.last_failure < FORGET_AFTER_MS);
+    }
+
+    let entry = failures.entry(source).or_default();
+
+    if now - entry.last_failure >= FORGET_AFTER_MS {
+        *entry = Failures::default();
+    }
+
+    if entry.record(now) {
+        metrics::increment(&state.metrics.auth_lockouts);
+
+        tracing::warn!(
+            %source,
+            lockouts = entry.lockouts,
+            "locked out after failed authentication attempts"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::Router;
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[test]
+    fn locks_out_for_longer_each_time() {
+        let mut failures = Failures::default();
+
+        for _ in 1..MAX_FAILURES {
+            assert!(!failures.record(1000));
+        }
+
+        assert!(failures.record(1000));
+        assert_eq!(failures.locked_until, 1000 + BASE_LOCKOUT_MS);
+
+        for _ in 1..MAX_FAILURES {
+            failures.record(2000);
+        }
+
+        assert!(failures.record(2000));
+        assert_eq!(failures.locked_until, 2000 + 2 * BASE_LOCKOUT_MS);
+    }
+
+    async fn status(app: &mut Router, token: &str) -> StatusCode {
+        let mut request = Request::builder()
+            .uri("/admin/expiry")
+            .header(immutable::ADMIN_TOKEN_HEADER, token)
+            .body(Body::empty())
+            .unwrap();
+
+        let address: SocketAddr = "192.0.2.7:4000".parse().unwrap();
+        request.extensions_mut().insert(ConnectInfo(address));
+
+        app.ready()
+            .await
+            .unwrap()
+            .call(request)
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn locks_out_sources_guessing_tokens() {
+        let mut app = setup_tests().await;
+
+        for _ in 0..MAX_FAILURES {
+            assert_eq!(status(&mut app, "guess").await, StatusCode::FORBIDDEN);
+        }
+
+        assert_eq!(
+            status(&mut app, "guess").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // Even with the right one, until the lockout ends
+        assert_eq!(
+            status(&mut app, "test-admin-token").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+}
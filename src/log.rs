@@ -0,0 +1,217 @@
+use axum::extract::{Path, Query, State};
+use axum::{http::StatusCode, Json};
+use heed::types::DecodeIgnore;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::{name_prefix, now_millis, paging, record_keys, sizes, AppError, AppState};
+
+// Entries are stored under `[name length][name][appended at][sequence]`, the big endian
+// time in milliseconds keeping them in order and ranges by time a single seek. The
+// sequence tells apart entries appended in the same millisecond.
+
+// Names leave room for the time and sequence after them
+const MAX_NAME_BYTES: usize = sizes::MAX_KEY_BYTES - 4;
+
+fn entry_key(name: &str, at: u64, sequence: u32) -> Vec<u8> {
+    let mut key = name_prefix(name);
+    key.extend_from_slice(&at.to_be_bytes());
+    key.extend_from_slice(&sequence.to_be_bytes());
+    key
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AppendPayload {
+    value: String,
+}
+
+/// `POST /log/:name/append`: adds `value` to the end of the log, timestamped now.
+pub(crate) async fn append(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(payload): Json<AppendPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    if name.len() > MAX_NAME_BYTES {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": format!("Log names can be at most {} bytes", MAX_NAME_BYTES)
+            })),
+        ));
+    }
+
+    if let Some(response) = sizes::check_value(&state, &payload.value) {
+        return Ok(response);
+    }
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    let at = now_millis();
+    let millisecond = entry_key(&name, at, 0);
+
+    // After any entry appended in the same millisecond
+    let last = state
+        .log
+        .remap_data_type::<DecodeIgnore>()
+        .rev_prefix_iter(&wtxn, &millisecond[..millisecond.len() - 4])
+        .unwrap()
+        .next();
+
+    let sequence = match last {
+        Some(Ok((key, _))) => u32::from_be_bytes(key[key.len() - 4..].try_into().unwrap()) + 1,
+        Some(Err(_)) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+        None => 0,
+    };
+
+    state
+        .log
+        .put(&mut wtxn, &entry_key(&name, at, sequence), &payload.value)
+        .unwrap();
+
+    state.commit(wtxn).unwrap();
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "at": at, "value": payload.value })),
+    ))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RangeQuery {
+    // Unix time in milliseconds, from inclusive and to exclusive, either end left open
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+/// `GET /log/:name/range`: the entries appended between `?from=` and `?to=`, oldest first.
+pub(crate) async fn range(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<RangeQuery>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let rtxn = state.read_txn().unwrap();
+
+    let prefix = name_prefix(&name);
+    let start = entry_key(&name, query.from.unwrap_or(0), 0);
+    let to = query.to.unwrap_or(u64::MAX);
+
+    let entries = state.log.range(
+        &rtxn,
+        &(Bound::Included(start.as_slice()), Bound::Unbounded),
+    );
+
+    let entries = match entries {
+        Ok(entries) => entries,
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    };
+
+    let max_keys = state.limits.max_keys;
+
+    let entries: Vec<_> = entries
+        .filter_map(Result::ok)
+        .take_while(|(key, _)| key.starts_with(&prefix))
+        .map(|(key, value)| {
+            let at = u64::from_be_bytes(key[prefix.len()..prefix.len() + 8].try_into().unwrap());
+
+            (at, value)
+        })
+        .take_while(|(at, _)| *at < to)
+        .take(max_keys + 1)
+        .map(|(at, value)| json!({ "at": at, "value": value }))
+        .collect();
+
+    if entries.len() > max_keys {
+        return Ok(paging::too_many_keys(max_keys));
+    }
+
+    record_keys(entries.len());
+
+    Ok((StatusCode::OK, Json(json!(entries))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{send_json, setup_tests};
+    use axum::http::{self};
+
+    #[tokio::test]
+    async fn appends_and_reads_ranges_by_time() {
+        let mut app = setup_tests().await;
+
+        // Logs aren't cleared between tests
+        let name = format!("deploys-{}", uuid::Uuid::new_v4());
+        let append = format!("/log/{}/append", name);
+
+        let mut times = Vec::new();
+
+        for value in ["v1", "v2", "v3"] {
+            let (status, body) = send_json(
+                &mut app,
+                http::Method::POST,
+                &append,
+                json!({ "value": value }),
+            )
+            .await;
+
+            assert_eq!(status, StatusCode::CREATED);
+            times.push(body["at"].as_u64().unwrap());
+        }
+
+        let (status, body) = send_json(
+            &mut app,
+            http::Method::GET,
+            &format!("/log/{}/range", name),
+            json!(null),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body.as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| entry["value"].as_str().unwrap())
+                .collect::<Vec<_>>(),
+            ["v1", "v2", "v3"]
+        );
+
+        // Appends in the same millisecond share a time, the range still tells them apart
+        let (_, body) = send_json(
+            &mut app,
+            http::Method::GET,
+            &format!("/log/{}/range?from={}&to={}", name, times[2], times[2] + 1),
+            json!(null),
+        )
+        .await;
+
+        assert_eq!(body.as_array().unwrap().last().unwrap()["value"], "v3");
+        assert!(body
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|entry| entry["at"] == times[2]));
+
+        let (_, body) = send_json(
+            &mut app,
+            http::Method::GET,
+            &format!("/log/{}/range?to={}", name, times[0]),
+            json!(null),
+        )
+        .await;
+
+        assert_eq!(body, json!([]));
+    }
+}
@@ -1,21 +1,204 @@
-use axum::extract::{MatchedPath, Path};
+use axum::body::{Body, Bytes};
+use axum::error_handling::HandleErrorLayer;
+use axum::extract::{MatchedPath, Path, Query};
+use axum::http::{header, HeaderMap};
 use axum::response::Response;
-use axum::routing::{delete, get, put};
+use axum::routing::{delete, get, patch, put};
 use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::post, Json, Router};
-use heed::{types::Str, Env};
-use heed::{Database, EnvOpenOptions, Error};
+use axum::{middleware, BoxError};
+use heed::types::{ByteSlice, OwnedType, SerdeJson, Str, Unit};
+use heed::{Database, Env, RwTxn};
 use hyper::Request;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::fs;
-use std::sync::Arc;
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tower::ServiceBuilder;
 use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::decompression::{DecompressionBody, RequestDecompressionLayer};
 use tower_http::trace::TraceLayer;
 use tracing::info_span;
 
+mod alias;
+mod batch;
+mod bench;
+mod bundles;
+mod cache;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod cluster;
+mod coalesce;
+mod count;
+mod cron;
+mod deadline;
+mod delta;
+mod digest;
+mod doctor;
+mod dotenv;
+mod elections;
+mod ephemeral;
+mod faults;
+mod feed;
+mod fields;
+mod filter;
+mod flags;
+mod health;
+mod history;
+mod hooks;
+mod immutable;
+#[cfg(test)]
+mod invariants;
+mod lockout;
+mod log;
+mod maintenance;
+mod merge;
+mod metrics;
+mod mirror;
+mod misses;
+mod nested;
+mod paging;
+mod panic;
+mod profile;
+mod pubsub;
+mod queue;
+mod quotas;
+mod redact;
+mod references;
+mod render;
+mod repl;
+mod retry;
+mod schedule;
+#[cfg(feature = "scripting")]
+mod scripts;
+mod seed;
+mod set;
+mod signing;
+mod sizes;
+mod snapshot;
+mod sse;
+mod startup;
+mod sync;
+mod system;
+mod tags;
+mod tokens;
+mod tree;
+mod uploads;
+mod upstream;
+mod version;
+mod wait;
+mod webhooks;
+mod wrapping;
+mod zset;
+
+// Upper bound on the named databases opened in the env, bump it when adding a new one
+const MAX_DBS: u32 = 32;
+
 struct AppState {
     kv_env: Env,
     kv: Database<Str, Str>,
+    zset: Database<ByteSlice, Unit>,
+    zset_scores: Database<ByteSlice, OwnedType<f64>>,
+    queue: Database<ByteSlice, SerdeJson<queue::QueueMessage>>,
+    set: Database<ByteSlice, Unit>,
+    // Append-only logs by name, entries by the time they were appended
+    log: Database<ByteSlice, Str>,
+    // Pub/sub channels live in memory only, they are never persisted
+    channels: Mutex<HashMap<String, broadcast::Sender<String>>>,
+    subscriber_buffer: usize,
+    lag_policy: pubsub::LagPolicy,
+    // Heartbeats and reconnect hints for `/subscribe` and `/watch` streams
+    sse: sse::Config,
+    versions: Database<Str, OwnedType<u64>>,
+    // Unix time in milliseconds each key was last written, for `GET /export?modified_since=`
+    modified: Database<Str, OwnedType<u64>>,
+    // Keys are sent here after every committed write, for anyone waiting on them
+    changes: broadcast::Sender<String>,
+    counters: Database<Str, OwnedType<u64>>,
+    // Prefixes whose number of keys is kept up to date in `counters`
+    counted_prefixes: Vec<String>,
+    // Hashes of every prefix up to a `digest_delimiter`, for replicas to compare
+    digests: Database<ByteSlice, ByteSlice>,
+    digest_delimiter: Option<String>,
+    #[cfg(feature = "scripting")]
+    scripts: Database<Str, Str>,
+    merge_strategies: Vec<(String, merge::Strategy)>,
+    // Key prefixes mapped to the `max-age` reads of them are cached for
+    cache_policies: Vec<(String, u64)>,
+    bundles: Database<ByteSlice, SerdeJson<bundles::Entries>>,
+    bundle_heads: Database<Str, Str>,
+    // Alias names mapped to the key (or further alias) they stand for
+    aliases: Database<Str, Str>,
+    // Keys created as immutable, on top of everything under `immutable_prefixes`
+    immutable: Database<Str, Unit>,
+    immutable_prefixes: Vec<String>,
+    // Unix time in milliseconds each ephemeral key is deleted at unless refreshed first
+    ephemeral: Database<Str, OwnedType<u64>>,
+    // Key prefixes mapped to the TTL every write under them gets, `EXPIRE_AFTER`
+    expiry_policies: Vec<(String, u64)>,
+    // Writes to make later by job ID, run by the scheduler once due
+    scheduled: Database<Str, SerdeJson<schedule::Job>>,
+    admin_token: Option<String>,
+    // Tokens created on `/admin/tokens` by name, and their hashes mapped to their names and
+    // quotas to check them
+    api_tokens: Database<Str, SerdeJson<tokens::ApiToken>>,
+    token_hashes: RwLock<HashMap<String, (String, quotas::Quota)>>,
+    // What each token has used of its quota, in memory only
+    token_usage: Mutex<HashMap<String, quotas::Usage>>,
+    // Failed authentication attempts by the address they came from, also in memory only
+    auth_failures: Mutex<HashMap<IpAddr, lockout::Failures>>,
+    // Access keys requests can be signed with instead, `SIGNING_KEYS`
+    signing: signing::Config,
+    // Values being uploaded in chunks by upload ID, and the chunks by ID and offset
+    uploads: Database<Str, SerdeJson<uploads::Upload>>,
+    upload_chunks: Database<ByteSlice, ByteSlice>,
+    // Values read with `?wrap_ttl=` by the hash of their token, until unwrapped
+    wrapped: Database<Str, SerdeJson<wrapping::Wrapped>>,
+    history: Database<ByteSlice, SerdeJson<history::HistoryEntry>>,
+    history_enabled: bool,
+    history_retention: history::Retention,
+    // Tags attached to each key, indexed by tag in `tagged` for `GET /keys?tag=`
+    tags: Database<Str, SerdeJson<BTreeSet<String>>>,
+    tagged: Database<ByteSlice, Unit>,
+    // Every write in order, for `GET /watch`
+    feed: Database<ByteSlice, SerdeJson<feed::FeedEvent>>,
+    feed_enabled: bool,
+    feed_retention: feed::Retention,
+    // Endpoints every write is delivered to, through the `outbox`
+    webhooks: Vec<webhooks::Endpoint>,
+    outbox: Database<ByteSlice, SerdeJson<webhooks::Delivery>>,
+    webhook_failures: Database<ByteSlice, SerdeJson<webhooks::Delivery>>,
+    // Another server every committed write is copied to, `MIRROR_URL`
+    mirror_url: Option<String>,
+    mirror_lag: Mutex<mirror::Lag>,
+    // The store misses are read from and writes are made to, `UPSTREAM_URL`
+    upstream: Option<upstream::Upstream>,
+    limits: paging::Limits,
+    // How `GET /export?redact=` masks values, `EXPORT_REDACTIONS` and `REDACTION_RULES_DIR`
+    redactions: redact::Redactions,
+    // Key prefixes mapped to what's done to values written under them first, `WRITE_HOOKS`
+    write_hooks: Vec<(String, hooks::Hook)>,
+    // Key prefixes mapped to the prefix of the keys their values have to name, `REFERENCES`
+    references: Vec<(String, String)>,
+    // Values longer than this are refused with a 413, `MAX_VALUE_BYTES`
+    max_value_bytes: Option<usize>,
+    // Concurrent `GET /:key` of the same key share one lookup of its value and version
+    reads: coalesce::Singleflight<Result<Option<(String, u64)>, String>>,
+    // Keys recently read and found missing, `MISS_CACHE_TTL`
+    misses: misses::Misses,
+    metrics: metrics::Metrics,
+    // The nodes sharing the keys between them, for clients to shard over, `CLUSTER_NODES`
+    cluster_nodes: Vec<String>,
+    // Where `/metrics`, `/healthz` and `/readyz` are served instead of the data API's listener
+    metrics_address: Option<SocketAddr>,
+    // Latency and errors injected by route, in memory only so a restart clears them
+    faults: Mutex<HashMap<String, faults::Fault>>,
+    // Set while in maintenance mode, also in memory only
+    maintenance: Mutex<Option<maintenance::Maintenance>>,
+    #[cfg(feature = "chaos")]
+    chaos: chaos::Chaos,
 }
 
 #[tokio::main]
@@ -24,35 +207,335 @@ async fn main() {
 
     let addr = std::env::var("SOCKET_ADDRESS").unwrap_or_else(|_| String::from("0.0.0.0:3000"));
 
+    if std::env::args().nth(1).as_deref() == Some("doctor") {
+        std::process::exit(if doctor::run() { 0 } else { 1 });
+    }
+
+    if std::env::args().nth(1).as_deref() == Some("sync") {
+        let result = match sync::Config::from_env() {
+            Ok(config) => sync::run(config).await,
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = result {
+            tracing::error!("{}", err);
+            std::process::exit(1);
+        }
+    }
+
+    if std::env::args().nth(1).as_deref() == Some("bench") {
+        let result = match bench::Config::from_env() {
+            Ok(config) => bench::run(config).await,
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = result {
+            tracing::error!("{}", err);
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    if std::env::args().nth(1).as_deref() == Some("repl") {
+        repl::run(repl::Config::from_env()).await;
+        return;
+    }
+
+    let state = match app_state() {
+        Ok(state) => state,
+        Err(err) => {
+            tracing::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(path) = seed_path() {
+        match seed::load(&state, &path) {
+            Ok(Some(loaded)) => tracing::info!("seeded {} keys from {}", loaded, path),
+            Ok(None) => tracing::info!("database isn't empty, not seeding from {}", path),
+            Err(err) => {
+                tracing::error!("failed to seed from {}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(metrics_address) = state.metrics_address {
+        let routes = health::routes().with_state(state.clone());
+
+        tracing::info!("serving metrics and health checks on {}", metrics_address);
+
+        tokio::spawn(async move {
+            axum::Server::bind(&metrics_address)
+                .serve(routes.into_make_service())
+                .await
+                .unwrap();
+        });
+    }
+
     tracing::info!("listening on {}", addr);
 
     // Run with hyper
     axum::Server::bind(&addr.parse().unwrap())
-        .serve(app().into_make_service())
+        .serve(router(state).into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
 
-fn app() -> Router {
-    let db_path = std::env::var("DB_PATH").unwrap_or_else(|_| String::from("db/heed.mdb"));
+/// The fixture given with `--seed <file>` or `--seed=<file>`, if any.
+fn seed_path() -> Option<String> {
+    let mut args = std::env::args().skip(1);
 
-    // Create dir
-    fs::create_dir_all(&db_path).unwrap();
+    while let Some(arg) = args.next() {
+        if arg == "--seed" {
+            return args.next();
+        }
 
-    // Create env
-    let env = EnvOpenOptions::new().open(&db_path).unwrap();
+        if let Some(path) = arg.strip_prefix("--seed=") {
+            return Some(path.to_owned());
+        }
+    }
 
-    // We will open the default unamed database
-    let kv: Database<Str, Str> = env.create_database(None).unwrap();
+    None
+}
+
+#[cfg(test)]
+fn app() -> Router {
+    router(app_state().unwrap())
+}
+
+/// Opens the database and everything else the handlers share, from the environment.
+fn app_state() -> Result<Arc<AppState>, String> {
+    let db_path = std::env::var("DB_PATH").unwrap_or_else(|_| String::from("db/heed.mdb"));
+    let counted_prefixes = prefix_list("COUNTED_PREFIXES");
+    let digest_delimiter = std::env::var("DIGEST_DELIMITER")
+        .ok()
+        .filter(|delimiter| !delimiter.is_empty());
+    let immutable_prefixes = prefix_list("IMMUTABLE_PREFIXES");
+    let admin_token = std::env::var("ADMIN_TOKEN").ok();
+    let signing = signing::Config::from_env()?;
+    let history_enabled = std::env::var("VERSION_HISTORY").is_ok_and(|value| value == "true");
+    let feed_enabled = std::env::var("CHANGE_FEED").is_ok_and(|value| value == "true");
+    let feed_retention = feed::Retention::from_env()?;
+    let webhooks = webhooks::endpoints_from_env()?;
+    let mirror_url = mirror::url_from_env()?;
+    let upstream = upstream::Upstream::from_env()?;
+    let misses = misses::Misses::from_env()?;
+    let history_retention = history::Retention::from_env()?;
+    let limits = paging::Limits::from_env()?;
+    let redactions = redact::Redactions::from_env()?;
+    let write_hooks = hooks::parse_hooks(&std::env::var("WRITE_HOOKS").unwrap_or_default())
+        .map_err(|err| format!("WRITE_HOOKS: {}", err))?;
+    let references = references::parse(&std::env::var("REFERENCES").unwrap_or_default())?;
+    let max_value_bytes = sizes::max_value_from_env()?;
+    let (subscriber_buffer, lag_policy) = pubsub::config_from_env()?;
+    let sse = sse::Config::from_env()?;
+    let metrics_address = health::address_from_env()?;
+    let cluster_nodes = prefix_list("CLUSTER_NODES");
+    let merge_strategies =
+        merge::parse_strategies(&std::env::var("MERGE_STRATEGIES").unwrap_or_default())
+            .map_err(|err| format!("MERGE_STRATEGIES: {}", err))?;
+    let cache_policies =
+        cache::parse_policies(&std::env::var("CACHE_MAX_AGE").unwrap_or_default())?;
+    let expiry_policies = ephemeral::policies_from_env()?;
+
+    let env = startup::open_env(&db_path)?;
+
+    let kv = open_kv(&env);
+    let zset = env.create_database(Some("zset")).unwrap();
+    let zset_scores = env.create_database(Some("zset-scores")).unwrap();
+    let queue = env.create_database(Some("queue")).unwrap();
+    let set = env.create_database(Some("set")).unwrap();
+    let log = env.create_database(Some("log")).unwrap();
+    let versions = env.create_database(Some("versions")).unwrap();
+    let modified = env.create_database(Some("modified")).unwrap();
+    let counters = env.create_database(Some("counters")).unwrap();
+    let digests = env.create_database(Some("digests")).unwrap();
+    #[cfg(feature = "scripting")]
+    let scripts = env.create_database(Some("scripts")).unwrap();
+    let bundles = env.create_database(Some("bundles")).unwrap();
+    let bundle_heads = env.create_database(Some("bundle-heads")).unwrap();
+    let aliases = env.create_database(Some("aliases")).unwrap();
+    let immutable = env.create_database(Some("immutable")).unwrap();
+    let ephemeral = env.create_database(Some("ephemeral")).unwrap();
+    let scheduled = env.create_database(Some("scheduled")).unwrap();
+    let api_tokens = env.create_database(Some("api-tokens")).unwrap();
+    let wrapped = env.create_database(Some("wrapped")).unwrap();
+    let uploads = env.create_database(Some("uploads")).unwrap();
+    let upload_chunks = env.create_database(Some("upload-chunks")).unwrap();
+    let history = env.create_database(Some("history")).unwrap();
+    let tags = env.create_database(Some("tags")).unwrap();
+    let tagged = env.create_database(Some("tagged")).unwrap();
+    let feed = env.create_database(Some("feed")).unwrap();
+    let outbox = env.create_database(Some("outbox")).unwrap();
+    let webhook_failures = env.create_database(Some("webhook-failures")).unwrap();
+
+    count::rebuild_counters(&env, kv, counters, &counted_prefixes).unwrap();
+    digest::rebuild_digests(&env, kv, digests, digest_delimiter.as_deref()).unwrap();
+    let token_hashes = tokens::load_hashes(&env, api_tokens).unwrap();
 
     // Create shared state to pass around the db ref
-    let shared_state = Arc::new(AppState { kv_env: env, kv });
+    let shared_state = Arc::new(AppState {
+        kv_env: env,
+        kv,
+        zset,
+        zset_scores,
+        queue,
+        set,
+        log,
+        channels: Mutex::new(HashMap::new()),
+        subscriber_buffer,
+        lag_policy,
+        sse,
+        versions,
+        modified,
+        changes: broadcast::channel(1024).0,
+        counters,
+        counted_prefixes,
+        digests,
+        digest_delimiter,
+        #[cfg(feature = "scripting")]
+        scripts,
+        merge_strategies,
+        cache_policies,
+        bundles,
+        bundle_heads,
+        aliases,
+        immutable,
+        immutable_prefixes,
+        ephemeral,
+        expiry_policies,
+        scheduled,
+        admin_token,
+        api_tokens,
+        token_hashes: RwLock::new(token_hashes),
+        token_usage: Mutex::new(HashMap::new()),
+        auth_failures: Mutex::new(HashMap::new()),
+        signing,
+        uploads,
+        upload_chunks,
+        wrapped,
+        history,
+        history_enabled,
+        history_retention,
+        tags,
+        tagged,
+        feed,
+        feed_enabled,
+        feed_retention,
+        webhooks,
+        outbox,
+        webhook_failures,
+        mirror_url,
+        mirror_lag: Mutex::new(mirror::Lag::new()),
+        upstream,
+        limits,
+        redactions,
+        write_hooks,
+        references,
+        max_value_bytes,
+        reads: coalesce::Singleflight::new(),
+        misses,
+        metrics: metrics::Metrics::default(),
+        cluster_nodes,
+        metrics_address,
+        faults: Mutex::new(HashMap::new()),
+        maintenance: Mutex::new(None),
+        #[cfg(feature = "chaos")]
+        chaos: chaos::Chaos::from_env(),
+    });
+
+    ephemeral::apply_policies(&shared_state).unwrap();
+
+    history::spawn_compaction(shared_state.clone());
+    feed::spawn_truncation(shared_state.clone());
+    webhooks::spawn_delivery(shared_state.clone());
+    mirror::spawn_mirroring(shared_state.clone());
+    ephemeral::spawn_expiry(shared_state.clone());
+    schedule::spawn_scheduler(shared_state.clone());
+    wrapping::spawn_sweep(shared_state.clone());
+    uploads::spawn_sweep(shared_state.clone());
+
+    Ok(shared_state)
+}
+
+fn router(shared_state: Arc<AppState>) -> Router {
+    // Without a listener of their own, the operational routes are served with the rest.
+    // Handlers read request bodies through the decompression layer added last
+    let routes = match shared_state.metrics_address {
+        Some(_) => Router::<Arc<AppState>, DecompressionBody<Body>>::new(),
+        None => health::routes(),
+    };
+
+    #[cfg(feature = "scripting")]
+    let routes = routes.merge(scripts::routes());
 
-    Router::<Arc<AppState>>::new()
+    routes
         // GET /
         .route("/", get(get_all))
+        // GET /version
+        .route("/version", get(version::version))
+        // GET /admin/cluster
+        .route("/admin/cluster", get(cluster::nodes))
+        // GET /admin/diff
+        .route("/admin/diff", get(bundles::diff))
+        // GET /admin/faults
+        .route("/admin/faults", get(faults::list))
+        // PUT /admin/faults
+        .route("/admin/faults", put(faults::set))
+        // DELETE /admin/faults
+        .route("/admin/faults", delete(faults::clear))
+        // GET /admin/expiry
+        .route("/admin/expiry", get(ephemeral::policies))
+        // POST /admin/maintenance
+        .route("/admin/maintenance", post(maintenance::switch))
+        // GET /admin/snapshot
+        .route("/admin/snapshot", get(snapshot::snapshot))
+        // GET /admin/tokens
+        .route("/admin/tokens", get(tokens::list))
+        // POST /admin/tokens
+        .route("/admin/tokens", post(tokens::create))
+        // POST /admin/tokens/:name/rotate
+        .route("/admin/tokens/:name/rotate", post(tokens::rotate))
+        // PUT /admin/tokens/:name/quota
+        .route("/admin/tokens/:name/quota", put(tokens::set_quota))
+        // DELETE /admin/tokens/:name
+        .route("/admin/tokens/:name", delete(tokens::revoke))
+        // GET /admin/webhooks/failures
+        .route("/admin/webhooks/failures", get(webhooks::failures))
+        // GET /count
+        .route("/count", get(count::count))
+        // GET /tree
+        .route("/tree", get(tree::tree))
+        // GET /digest
+        .route("/digest", get(digest::digest))
+        // GET /watch
+        .route("/watch", get(feed::watch))
+        // POST /sync
+        .route("/sync", post(delta::sync))
+        // POST /unwrap
+        .route("/unwrap", post(wrapping::unwrap))
+        // POST /import
+        .route("/import", post(nested::import))
+        // GET /export
+        .route("/export", get(nested::export))
+        // POST /batch/get
+        .route("/batch/get", post(batch::get_many))
+        // GET /keys
+        .route("/keys", get(tags::keys_with_tag))
         // GET /:key
         .route("/:key", get(get_key))
+        // POST /:key/merge
+        .route("/:key/merge", post(merge::merge))
+        // GET /:key/wait
+        .route("/:key/wait", get(wait::wait_for_change))
+        // GET /:key/render
+        .route("/:key/render", get(render::render_key))
+        // GET /:key/tags
+        .route("/:key/tags", get(tags::get_tags))
+        // PUT /:key/tags
+        .route("/:key/tags", put(tags::put_tags))
         // POST /
         .route("/", post(create_key))
         // PUT /:key
@@ -61,8 +544,97 @@ fn app() -> Router {
         .route("/", delete(delete_all))
         // DELETE /:key
         .route("/:key", delete(delete_key))
+        // GET /elections/:name
+        .route("/elections/:name", get(elections::leader))
+        // POST /elections/:name/campaign
+        .route("/elections/:name/campaign", post(elections::campaign))
+        // POST /elections/:name/resign
+        .route("/elections/:name/resign", post(elections::resign))
+        // POST /uploads
+        .route("/uploads", post(uploads::start))
+        // GET /uploads/:id
+        .route("/uploads/:id", get(uploads::status))
+        // PATCH /uploads/:id
+        .route("/uploads/:id", patch(uploads::append))
+        // DELETE /uploads/:id
+        .route("/uploads/:id", delete(uploads::abort))
+        // POST /uploads/:id/finalize
+        .route("/uploads/:id/finalize", post(uploads::finalize))
+        // PUT /ephemeral/:key
+        .route("/ephemeral/:key", put(ephemeral::register))
+        // GET /zset/:name
+        .route("/zset/:name", get(zset::range))
+        // POST /zset/:name
+        .route("/zset/:name", post(zset::add))
+        // GET /zset/:name/:member
+        .route("/zset/:name/:member", get(zset::rank))
+        // DELETE /zset/:name/:member
+        .route("/zset/:name/:member", delete(zset::remove))
+        // POST /queue/:name/push
+        .route("/queue/:name/push", post(queue::push))
+        // POST /queue/:name/pop
+        .route("/queue/:name/pop", post(queue::pop))
+        // DELETE /queue/:name/:id
+        .route("/queue/:name/:id", delete(queue::ack))
+        // POST /flags/:name/evaluate
+        .route("/flags/:name/evaluate", post(flags::evaluate))
+        // GET /schedule
+        .route("/schedule", get(schedule::list))
+        // POST /schedule
+        .route("/schedule", post(schedule::create))
+        // DELETE /schedule/:id
+        .route("/schedule/:id", delete(schedule::cancel))
+        // POST /log/:name/append
+        .route("/log/:name/append", post(log::append))
+        // GET /log/:name/range
+        .route("/log/:name/range", get(log::range))
+        // GET /set/:name
+        .route("/set/:name", get(set::members))
+        // POST /set/:name
+        .route("/set/:name", post(set::add))
+        // GET /set/:name/:member
+        .route("/set/:name/:member", get(set::contains))
+        // DELETE /set/:name/:member
+        .route("/set/:name/:member", delete(set::remove))
+        // GET /alias/:alias
+        .route("/alias/:alias", get(alias::get_alias))
+        // PUT /alias/:alias
+        .route("/alias/:alias", put(alias::put_alias))
+        // DELETE /alias/:alias
+        .route("/alias/:alias", delete(alias::delete_alias))
+        // GET /bundles/:name, or /bundles/:name@:version
+        .route("/bundles/:name", get(bundles::get_bundle))
+        // POST /bundles/:name
+        .route("/bundles/:name", post(bundles::publish))
+        // DELETE /bundles/:name
+        .route("/bundles/:name", delete(bundles::delete_bundle))
+        // PUT /bundles/:name/current
+        .route("/bundles/:name/current", put(bundles::set_current))
+        // POST /publish/:channel
+        .route("/publish/:channel", post(pubsub::publish))
+        // GET /subscribe/:channel
+        .route("/subscribe/:channel", get(pubsub::subscribe))
+        // Slow down or fail routes an admin has set faults for
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            faults::inject,
+        ))
+        // Turn data requests away while in maintenance mode
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            maintenance::refuse,
+        ))
+        // Give up on requests the client stopped waiting for
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            deadline::enforce,
+        ))
         // Add panic recovery
-        .layer(CatchPanicLayer::new())
+        .layer(CatchPanicLayer::custom({
+            let state = shared_state.clone();
+
+            move |payload| panic::handle_panic(&state.metrics, payload)
+        }))
         // Add tracing middleware
         .layer(
             TraceLayer::new_for_http().make_span_with(|request: &Request<_>| {
@@ -76,84 +648,394 @@ fn app() -> Router {
                 info_span!(
                     "http_request",
                     method = ?request.method(),
-                    matched_path
+                    matched_path,
+                    // Filled in by handlers going through more than a key or two
+                    keys = tracing::field::Empty,
                 )
             }),
         )
+        // Accept `Content-Encoding: gzip` and `zstd` request bodies, a body that doesn't
+        // decompress is rejected by whichever extractor reads it
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(|_: BoxError| async {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }))
+                .layer(RequestDecompressionLayer::new()),
+        )
+        // Time requests an admin asks to have profiled with `X-Debug-Profile`
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            profile::record,
+        ))
+        // Hold requests with an API token to its quota, counting bodies as sent
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            quotas::enforce,
+        ))
+        // Check signed requests against the body as sent, before it's decompressed
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            signing::verify,
+        ))
+        // Lock out addresses that keep failing to authenticate
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            lockout::guard,
+        ))
         // Add shared state
         .with_state(shared_state)
 }
 
+/// Reads a comma separated list of key prefixes from the environment variable `name`.
+fn prefix_list(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .filter(|prefix| !prefix.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Notes on the request's span how many keys it read or wrote, so traces of slow requests
+/// show whether they were simply big.
+fn record_keys(count: usize) {
+    tracing::Span::current().record("keys", count);
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Prefix of the composite keys belonging to a named structure (sorted set, queue, ...).
+/// Leading with the name's length keeps one name's keys from running into another's.
+fn name_prefix(name: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(4 + name.len());
+    prefix.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(name.as_bytes());
+    prefix
+}
+
+/// Opens the named `kv` database holding the user keys.
+///
+/// Keys used to live in the unnamed database, but once named databases exist that one
+/// also stores their records, so any legacy keys are moved over the first time around.
+fn open_kv(env: &Env) -> Database<Str, Str> {
+    if let Some(kv) = env.open_database(Some("kv")).unwrap() {
+        return kv;
+    }
+
+    let legacy: Database<Str, Str> = env.open_database(None).unwrap().unwrap();
+
+    let mut wtxn = env.write_txn().unwrap();
+
+    let entries: Vec<(String, String)> = legacy
+        .iter(&wtxn)
+        .unwrap()
+        .filter_map(Result::ok)
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect();
+
+    // Clear first, a legacy key could otherwise collide with the new database record
+    legacy.clear(&mut wtxn).unwrap();
+
+    let kv: Database<Str, Str> = env.create_database_with_txn(Some("kv"), &mut wtxn).unwrap();
+
+    for (key, value) in &entries {
+        kv.put(&mut wtxn, key, value).unwrap();
+    }
+
+    wtxn.commit().unwrap();
+
+    kv
+}
+
+/// Writes `key` within `wtxn`, keeping its prefix counters and version in step, and
+/// returns whether the key was created. Waiters should be notified once committed.
+fn put_value(state: &AppState, wtxn: &mut RwTxn, key: &str, value: &str) -> heed::Result<bool> {
+    let created = state.kv.get(wtxn, key)?.is_none();
+
+    digest::record(state, wtxn, key, Some(value))?;
+
+    state.kv.put(wtxn, key, value)?;
+    state.modified.put(wtxn, key, &now_millis())?;
+
+    if created {
+        count::adjust_counters(state, wtxn, key, 1)?;
+    }
+
+    ephemeral::apply_policy(state, wtxn, key)?;
+
+    let version = wait::bump_version(state, wtxn, key)?;
+
+    history::record(state, wtxn, key, version, Some(value))?;
+    feed::record(state, wtxn, key, version, Some(value))?;
+    webhooks::enqueue(state, wtxn, key, version, Some(value))?;
+
+    Ok(created)
+}
+
+/// Deletes `key` within `wtxn` the same way [`put_value`] writes it, returning whether
+/// it existed.
+fn delete_value(state: &AppState, wtxn: &mut RwTxn, key: &str) -> heed::Result<bool> {
+    let version = match unlink_value(state, wtxn, key)? {
+        Some(version) => version,
+        None => return Ok(false),
+    };
+
+    feed::record(state, wtxn, key, version, None)?;
+
+    Ok(true)
+}
+
+/// [`delete_value`] for an ephemeral key whose TTL ran out, logged to the change feed as
+/// an expiry.
+fn expire_value(state: &AppState, wtxn: &mut RwTxn, key: &str) -> heed::Result<bool> {
+    let version = match unlink_value(state, wtxn, key)? {
+        Some(version) => version,
+        None => return Ok(false),
+    };
+
+    feed::record_expiry(state, wtxn, key, version)?;
+
+    Ok(true)
+}
+
+// All of deleting `key` but logging it to the change feed, returning its new version if
+// it existed
+fn unlink_value(state: &AppState, wtxn: &mut RwTxn, key: &str) -> heed::Result<Option<u64>> {
+    digest::record(state, wtxn, key, None)?;
+
+    if !state.kv.delete(wtxn, key)? {
+        return Ok(None);
+    }
+
+    state.modified.delete(wtxn, key)?;
+    state.immutable.delete(wtxn, key)?;
+    state.ephemeral.delete(wtxn, key)?;
+    tags::clear(state, wtxn, key)?;
+    count::adjust_counters(state, wtxn, key, -1)?;
+
+    let version = wait::bump_version(state, wtxn, key)?;
+
+    history::record(state, wtxn, key, version, None)?;
+    webhooks::enqueue(state, wtxn, key, version, None)?;
+
+    Ok(Some(version))
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    // Comma separated, lists objects of just these instead of `[key, value]` pairs
+    fields: Option<String>,
+}
+
 async fn get_all(
     State(state): State<Arc<AppState>>,
-) -> Result<(StatusCode, Json<Value>), AppError> {
-    let rtxn = state.kv_env.read_txn().unwrap();
-    let values = state.kv.iter(&rtxn).unwrap();
+    Query(query): Query<ListQuery>,
+) -> Result<Response, AppError> {
+    let fields = match query.fields.as_deref().map(fields::parse).transpose() {
+        Ok(fields) => fields,
+        Err(err) => {
+            return Ok((StatusCode::BAD_REQUEST, Json(json!({ "error": err }))).into_response())
+        }
+    };
 
-    let ok_values: Vec<_> = values.filter_map(Result::ok).collect();
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/json")],
+        paging::stream_entries(state.clone(), state.limits.page_size, fields),
+    )
+        .into_response())
+}
 
-    Ok((StatusCode::OK, Json(json!(ok_values))))
+#[derive(Deserialize)]
+struct GetQuery {
+    // Unix time in seconds, reads the value as of then from the version history
+    as_of: Option<String>,
+    // Seconds a one-time token for `POST /unwrap` is answered with instead of the value
+    wrap_ttl: Option<u64>,
 }
 
 async fn get_key(
     State(state): State<Arc<AppState>>,
     Path(key): Path<String>,
-) -> Result<(StatusCode, Json<Value>), AppError> {
-    let rtxn = state.kv_env.read_txn().unwrap();
+    Query(query): Query<GetQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    if let Some(response) = system::read(&state, &key) {
+        return Ok(response);
+    }
+
+    if let Some(as_of) = &query.as_of {
+        return Ok(history::get_as_of(&state, &key, as_of).into_response());
+    }
 
-    let value = state.kv.get(&rtxn, &key);
+    if let Some(ttl_secs) = query.wrap_ttl {
+        return Ok(wrapping::wrap(&state, &key, ttl_secs).into_response());
+    }
 
-    match value {
-        Ok(Some(value)) => Ok((StatusCode::OK, Json(json!({ "key": key, "value": value })))),
-        Ok(None) => Ok((
+    if state.misses.contains(&key) {
+        metrics::increment(&state.metrics.cached_misses);
+
+        return Ok((
             StatusCode::NOT_FOUND,
             Json(json!({ "error": "Key not found" })),
-        )),
+        )
+            .into_response());
+    }
+
+    // Taken before reading, so a write landing after the read still counts
+    let epoch = state.misses.epoch();
+
+    let lookup = {
+        let state = state.clone();
+        let key = key.clone();
+
+        move || {
+            let rtxn = state.read_txn().map_err(|err| err.to_string())?;
+            let key = alias::resolve_key(&state, &rtxn, &key)?;
+
+            state
+                .kv
+                .get(&rtxn, &key)
+                .and_then(|value| {
+                    let version = state.versions.get(&rtxn, &key)?.unwrap_or(0);
+
+                    Ok(value.map(|value| (value.to_owned(), version)))
+                })
+                .map_err(|err| err.to_string())
+        }
+    };
+
+    let (value, coalesced) = state.reads.run(&key, lookup).await;
+
+    if coalesced {
+        metrics::increment(&state.metrics.coalesced_reads);
+    }
+
+    match value {
+        Ok(Some((_, version))) if cache::not_modified(&headers, version) => Ok((
+            StatusCode::NOT_MODIFIED,
+            cache::headers(&state, &key, version),
+        )
+            .into_response()),
+        Ok(Some((value, version))) => Ok(profile::time("serialize", || {
+            (
+                StatusCode::OK,
+                // Lets clients make conditional requests against this version with `If-Match`
+                cache::headers(&state, &key, version),
+                Json(json!({ "key": key, "value": value })),
+            )
+                .into_response()
+        })),
+        Ok(None) => {
+            let response = match &state.upstream {
+                Some(upstream) => upstream::read_through(&state, upstream, &key, &headers).await,
+                None => (
+                    StatusCode::NOT_FOUND,
+                    Json(json!({ "error": "Key not found" })),
+                )
+                    .into_response(),
+            };
+
+            // A shared lookup may have started before `epoch`, and aliases miss when their
+            // target does, which writes to the target don't forget
+            if response.status() == StatusCode::NOT_FOUND && !coalesced && !is_alias(&state, &key) {
+                state.misses.remember(&key, epoch);
+            }
+
+            Ok(response)
+        }
         Err(_) => Ok((
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({ "error": "Internal server error" })),
-        )),
+        )
+            .into_response()),
     }
 }
 
+fn is_alias(state: &AppState, key: &str) -> bool {
+    state
+        .read_txn()
+        .and_then(|rtxn| state.aliases.get(&rtxn, key).map(|target| target.is_some()))
+        // Not remembering the miss is the safe side
+        .unwrap_or(true)
+}
+
 #[derive(Serialize, Deserialize)]
 struct KVPayload {
     key: String,
     value: String,
+    // Only honored by `POST /`, later updates and deletes are refused with a 403
+    #[serde(default)]
+    immutable: bool,
 }
 
 async fn create_key(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<KVPayload>,
+    headers: HeaderMap,
+    Json(mut payload): Json<KVPayload>,
 ) -> Result<(StatusCode, Json<Value>), AppError> {
-    let rtxn = state.kv_env.read_txn().unwrap();
-
-    let value = state.kv.get(&rtxn, &payload.key);
+    payload.value = match hooks::run(&state, &payload.key, &payload.value) {
+        Ok(value) => value,
+        Err(response) => return Ok(response),
+    };
 
-    // Check if the key already exists
-    if let Ok(Some(_)) = value {
-        return Ok((
-            StatusCode::BAD_REQUEST,
-            Json(json!({ "error": "Key already exists" })),
-        ));
+    if let Some(response) = sizes::check(&state, &payload.key, &payload.value)
+        .or_else(|| system::refuse_write(&payload.key))
+    {
+        return Ok(response);
     }
 
-    // If an error occurs during the retrieval process
-    if let Err(_) = value {
-        return Ok((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({ "error": "Internal server error" })),
-        ));
-    }
+    // Scoped so the transaction is gone before waiting on the upstream
+    {
+        // Check and write in the same transaction, so counters never see a key created twice
+        let mut wtxn = state.write_txn().unwrap();
 
-    let mut wtxn = state.kv_env.write_txn().unwrap();
+        if let Some(response) = elections::check_fence(&state, &wtxn, &headers) {
+            return Ok(response);
+        }
 
-    state
-        .kv
-        .put(&mut wtxn, &payload.key, &payload.value)
-        .unwrap();
+        let value = state.kv.get(&wtxn, &payload.key);
 
-    wtxn.commit().unwrap();
+        // Check if the key already exists
+        if let Ok(Some(_)) = value {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "Key already exists" })),
+            ));
+        }
+
+        // If an error occurs during the retrieval process
+        if value.is_err() {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ));
+        }
+
+        if let Some(response) = references::check(&state, &wtxn, &payload.key, &payload.value) {
+            return Ok(response);
+        }
+
+        put_value(&state, &mut wtxn, &payload.key, &payload.value).unwrap();
+
+        if payload.immutable {
+            immutable::mark(&state, &mut wtxn, &payload.key).unwrap();
+        }
+
+        state.commit(wtxn).unwrap();
+    }
+
+    wait::notify(&state, &payload.key);
+
+    if let Some(response) = upstream::write_through(&state, &payload.key).await {
+        return Ok(response);
+    }
 
     Ok((
         StatusCode::CREATED,
@@ -164,57 +1046,240 @@ async fn create_key(
 async fn update_key(
     State(state): State<Arc<AppState>>,
     Path(key): Path<String>,
-    Json(payload): Json<KVPayload>,
+    headers: HeaderMap,
+    Json(mut payload): Json<KVPayload>,
 ) -> Result<(StatusCode, Json<Value>), AppError> {
-    let mut wtxn = state.kv_env.write_txn().unwrap();
+    payload.value = match hooks::run(&state, &key, &payload.value) {
+        Ok(value) => value,
+        Err(response) => return Ok(response),
+    };
 
-    let value = state.kv.put(&mut wtxn, &key, &payload.value);
+    if let Some(response) = sizes::check(&state, &key, &payload.value) {
+        return Ok(response);
+    }
 
-    match value {
-        Ok(_) => {
-            wtxn.commit().unwrap();
+    {
+        let mut wtxn = state.write_txn().unwrap();
 
-            Ok((
-                StatusCode::OK,
-                Json(json!({ "key": key, "value": payload.value })),
-            ))
+        if let Some(response) = refuse_locked(&state, &wtxn, &key, &headers)
+            .or_else(|| references::check(&state, &wtxn, &key, &payload.value))
+        {
+            return Ok(response);
         }
-        Err(_) => Ok((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({ "error": "Internal server error" })),
-        )),
+
+        if put_value(&state, &mut wtxn, &key, &payload.value).is_err() {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ));
+        }
+
+        state.commit(wtxn).unwrap();
     }
+
+    wait::notify(&state, &key);
+
+    if let Some(response) = upstream::write_through(&state, &key).await {
+        return Ok(response);
+    }
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "key": key, "value": payload.value })),
+    ))
 }
 
-async fn delete_all(State(state): State<Arc<AppState>>) -> Result<StatusCode, AppError> {
-    let mut wtxn = state.kv_env.write_txn().unwrap();
+async fn delete_all(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<StatusCode, AppError> {
+    let mut wtxn = state.write_txn().unwrap();
+
+    if let Some((status, _)) = elections::check_fence(&state, &wtxn, &headers) {
+        return Ok(status);
+    }
+
+    let keys: Vec<String> = state
+        .kv
+        .iter(&wtxn)
+        .unwrap()
+        .filter_map(Result::ok)
+        .map(|(key, _)| key.to_owned())
+        .collect();
+
+    record_keys(keys.len());
+
+    if !immutable::admin_override(&state, &headers) {
+        for key in &keys {
+            if immutable::is_locked(&state, &wtxn, key).unwrap() {
+                return Ok(StatusCode::FORBIDDEN);
+            }
+        }
+    }
+
+    for key in &keys {
+        let version = wait::bump_version(&state, &mut wtxn, key).unwrap();
+
+        history::record(&state, &mut wtxn, key, version, None).unwrap();
+        feed::record(&state, &mut wtxn, key, version, None).unwrap();
+        webhooks::enqueue(&state, &mut wtxn, key, version, None).unwrap();
+    }
 
     state.kv.clear(&mut wtxn).unwrap();
+    state.modified.clear(&mut wtxn).unwrap();
+    state.immutable.clear(&mut wtxn).unwrap();
+    state.ephemeral.clear(&mut wtxn).unwrap();
+    state.tags.clear(&mut wtxn).unwrap();
+    state.tagged.clear(&mut wtxn).unwrap();
 
-    wtxn.commit().unwrap();
+    count::reset_counters(&state, &mut wtxn).unwrap();
+    digest::reset_digests(&state, &mut wtxn).unwrap();
+
+    state.commit(wtxn).unwrap();
+
+    for key in &keys {
+        wait::notify(&state, key);
+    }
 
     Ok(StatusCode::OK)
 }
 
+#[derive(Deserialize)]
+struct DeletePayload {
+    expected: String,
+}
+
 async fn delete_key(
     State(state): State<Arc<AppState>>,
     Path(key): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> Result<(StatusCode, Json<serde_json::Value>), AppError> {
-    let mut wtxn = state.kv_env.write_txn().unwrap();
+    // Without a body or `If-Match` header the delete is unconditional
+    let expected = if body.is_empty() {
+        None
+    } else {
+        match serde_json::from_slice::<DeletePayload>(&body) {
+            Ok(payload) => Some(payload.expected),
+            Err(_) => {
+                return Ok((
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({ "error": "Invalid body" })),
+                ))
+            }
+        }
+    };
 
-    let value = state.kv.delete(&mut wtxn, &key);
+    let if_match = headers
+        .get(header::IF_MATCH)
+        .map(|if_match| if_match.to_str().unwrap_or_default());
 
-    match value {
-        Ok(true) => {
-            wtxn.commit().unwrap();
+    if let Some(response) = sizes::check_key(&key) {
+        return Ok(response);
+    }
 
-            Ok((StatusCode::OK, Json(json!({ "key": key }))))
+    {
+        let mut wtxn = state.write_txn().unwrap();
+
+        if let Some(response) = refuse_locked(&state, &wtxn, &key, &headers) {
+            return Ok(response);
         }
-        Ok(false) => Ok((
-            StatusCode::NOT_FOUND,
-            Json(json!({ "error": "Key not found" })),
+
+        // Compare within the write transaction so nothing can change in between
+        if expected.is_some() || if_match.is_some() {
+            let current = state.kv.get(&wtxn, &key).and_then(|value| {
+                let version = state.versions.get(&wtxn, &key)?.unwrap_or(0);
+
+                Ok(value.map(|value| (value.to_owned(), version)))
+            });
+
+            let (value, version) = match current {
+                Ok(Some(current)) => current,
+                Ok(None) => {
+                    return Ok((
+                        StatusCode::NOT_FOUND,
+                        Json(json!({ "error": "Key not found" })),
+                    ))
+                }
+                Err(_) => {
+                    return Ok((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(json!({ "error": "Internal server error" })),
+                    ))
+                }
+            };
+
+            let matches = expected.is_none_or(|expected| expected == value)
+                && if_match.is_none_or(|if_match| wait::etag_matches(if_match, version));
+
+            if !matches {
+                return Ok((
+                    StatusCode::PRECONDITION_FAILED,
+                    Json(json!({ "error": "Key has been modified" })),
+                ));
+            }
+        }
+
+        let deleted = match delete_value(&state, &mut wtxn, &key) {
+            Ok(deleted) => deleted,
+            Err(_) => {
+                return Ok((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({ "error": "Internal server error" })),
+                ))
+            }
+        };
+
+        if deleted {
+            state.commit(wtxn).unwrap();
+
+            wait::notify(&state, &key);
+        } else {
+            drop(wtxn);
+
+            // Not cached here, but the upstream may still have it
+            if state.upstream.is_none() {
+                return Ok((
+                    StatusCode::NOT_FOUND,
+                    Json(json!({ "error": "Key not found" })),
+                ));
+            }
+        }
+    }
+
+    if let Some(response) = upstream::write_through(&state, &key).await {
+        return Ok(response);
+    }
+
+    Ok((StatusCode::OK, Json(json!({ "key": key }))))
+}
+
+/// The response to send instead of changing `key` when it's immutable and the request
+/// doesn't carry the admin override, or a system key or the write carries a stale
+/// fencing token, which nothing overrides.
+fn refuse_locked(
+    state: &AppState,
+    wtxn: &RwTxn,
+    key: &str,
+    headers: &HeaderMap,
+) -> Option<(StatusCode, Json<Value>)> {
+    if let Some(response) =
+        system::refuse_write(key).or_else(|| elections::check_fence(state, wtxn, headers))
+    {
+        return Some(response);
+    }
+
+    if immutable::admin_override(state, headers) {
+        return None;
+    }
+
+    match immutable::is_locked(state, wtxn, key) {
+        Ok(false) => None,
+        Ok(true) => Some((
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Key is immutable" })),
         )),
-        Err(_) => Ok((
+        Err(_) => Some((
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({ "error": "Internal server error" })),
         )),
@@ -256,16 +1321,46 @@ mod tests {
     use tower::Service; // for `call`
     use tower::ServiceExt; // for `oneshot` and `ready`
 
-    async fn setup_tests() -> Router {
+    pub(crate) async fn setup_tests() -> Router {
         // set env var to use a different db
         std::env::set_var("DB_PATH", "db/heed_test.mdb");
+        std::env::set_var("COUNTED_PREFIXES", "counted:");
+        std::env::set_var("DIGEST_DELIMITER", ":");
+        std::env::set_var("MERGE_STRATEGIES", "merge-sum:=sum");
+        std::env::set_var("IMMUTABLE_PREFIXES", "write-once:");
+        std::env::set_var("ADMIN_TOKEN", "test-admin-token");
+        std::env::set_var("SIGNING_KEYS", "test-access=test-secret");
+        std::env::set_var("VERSION_HISTORY", "true");
+        std::env::set_var("CHANGE_FEED", "true");
+        std::env::set_var("CACHE_MAX_AGE", "cached:=60");
+        std::env::set_var("EXPIRE_AFTER", "expiring:=3600");
+        std::env::set_var("LIST_MAX_KEYS", "20");
+        std::env::set_var("MAX_VALUE_BYTES", "4096");
+        std::env::set_var("MISS_CACHE_TTL", "60s");
+        std::env::set_var("REFERENCES", "ref:order:=ref:user:");
+        std::env::set_var(
+            "WRITE_HOOKS",
+            "hooked:=trim,hooked:=lowercase,hooked:=deny:@localhost",
+        );
+        std::env::set_var(
+            "EXPORT_REDACTIONS",
+            "redacted:=partial,redacted:secret=drop",
+        );
+        std::env::set_var(
+            "REDACTION_RULES_DIR",
+            std::env::temp_dir().join("kv-test-redactions"),
+        );
+        // The test database is kept between runs, bound what the logs keep of them
+        std::env::set_var("HISTORY_KEEP_VERSIONS", "10");
+        std::env::set_var("CHANGE_FEED_KEEP_EVENTS", "1000");
 
         let mut app = app();
 
-        // Ensure db is cleared
+        // Ensure db is cleared, immutable keys included
         let request = Request::builder()
             .uri("/")
             .method(http::Method::DELETE)
+            .header(immutable::ADMIN_TOKEN_HEADER, "test-admin-token")
             .body(Body::empty())
             .unwrap();
 
@@ -274,6 +1369,50 @@ mod tests {
         app
     }
 
+    /// Answers `request` with `app`, for tests that look past the status and JSON body.
+    pub(crate) async fn respond(app: &mut Router, request: Request<Body>) -> Response {
+        app.ready().await.unwrap().call(request).await.unwrap()
+    }
+
+    /// Answers `request` with `app`, the body parsed as JSON or `null` when there's none.
+    pub(crate) async fn send(app: &mut Router, request: Request<Body>) -> (StatusCode, Value) {
+        let response = respond(app, request).await;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    /// Sends `body` as JSON, or no body at all for `null`.
+    pub(crate) async fn send_json(
+        app: &mut Router,
+        method: http::Method,
+        uri: &str,
+        body: Value,
+    ) -> (StatusCode, Value) {
+        let body = match body {
+            Value::Null => Body::empty(),
+            body => Body::from(body.to_string()),
+        };
+
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .unwrap();
+
+        send(app, request).await
+    }
+
+    pub(crate) async fn get_json(app: &mut Router, uri: &str) -> (StatusCode, Value) {
+        send(
+            app,
+            Request::builder().uri(uri).body(Body::empty()).unwrap(),
+        )
+        .await
+    }
+
     // You can use `ready()` and `call()` to avoid using `clone()`
     // in multiple request
     #[tokio::test]
@@ -474,4 +1613,133 @@ mod tests {
 
         assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
+
+    #[tokio::test]
+    async fn conditional_delete() {
+        let mut app = setup_tests().await;
+
+        // Create key
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({"key": "conditional", "value": "bar"}).to_string(),
+            ))
+            .unwrap();
+
+        app.ready().await.unwrap().call(request).await.unwrap();
+
+        // Delete with a stale expected value
+        let request = Request::builder()
+            .method(http::Method::DELETE)
+            .uri("/conditional")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({"expected": "baz"}).to_string()))
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+        // Delete with a stale version
+        let request = Request::builder()
+            .uri("/conditional")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let etag = response.headers()[http::header::ETAG].clone();
+
+        let request = Request::builder()
+            .method(http::Method::DELETE)
+            .uri("/conditional")
+            .header(http::header::IF_MATCH, "\"0\"")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+        // Delete with matching preconditions
+        let request = Request::builder()
+            .method(http::Method::DELETE)
+            .uri("/conditional")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::IF_MATCH, etag)
+            .body(Body::from(json!({"expected": "bar"}).to_string()))
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn http_client_round_trip() {
+        use kv_client::{HttpClient, KvClient};
+
+        let app = setup_tests().await;
+
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let client = HttpClient::new(format!("http://{}", server.local_addr()));
+
+        tokio::spawn(server);
+
+        client.put("client/key", "value").await.unwrap();
+
+        assert_eq!(
+            client.get("client/key").await.unwrap().as_deref(),
+            Some("value")
+        );
+        assert_eq!(
+            client.list().await.unwrap(),
+            vec![(String::from("client/key"), String::from("value"))]
+        );
+
+        assert!(client.delete("client/key").await.unwrap());
+        assert_eq!(client.get("client/key").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn decompresses_request_bodies() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let mut app = setup_tests().await;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(
+                json!({"key": "gzipped", "value": "bar"})
+                    .to_string()
+                    .as_bytes(),
+            )
+            .unwrap();
+
+        let request = Request::builder()
+            .method(http::Method::PUT)
+            .uri("/gzipped")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::CONTENT_ENCODING, "gzip")
+            .body(Body::from(encoder.finish().unwrap()))
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = Request::builder()
+            .method(http::Method::PUT)
+            .uri("/gzipped")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::CONTENT_ENCODING, "br")
+            .body(Body::from("not brotli"))
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
 }
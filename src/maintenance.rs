@@ -0,0 +1,171 @@
+use axum::extract::{MatchedPath, State};
+use axum::http::{header, HeaderMap, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::{immutable, wrapping, AppState};
+
+// Seconds clients are told to wait when the admin didn't say
+const DEFAULT_RETRY_AFTER: u64 = 60;
+
+// Reporting on the server stays possible during maintenance, as does ending it
+const CONTROL_PLANE: [&str; 4] = ["/metrics", "/healthz", "/readyz", "/version"];
+
+/// Set with `POST /admin/maintenance` while backups, compaction or restores run, so
+/// requests don't interleave with them.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub(crate) struct Maintenance {
+    #[serde(default)]
+    allow_reads: bool,
+    #[serde(default = "default_retry_after")]
+    retry_after: u64,
+}
+
+fn default_retry_after() -> u64 {
+    DEFAULT_RETRY_AFTER
+}
+
+#[derive(Deserialize)]
+pub(crate) struct MaintenancePayload {
+    enabled: bool,
+    #[serde(flatten)]
+    maintenance: Maintenance,
+}
+
+pub(crate) async fn switch(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<MaintenancePayload>,
+) -> (StatusCode, Json<Value>) {
+    if !immutable::admin_override(&state, &headers) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Maintenance mode can only be switched with the admin token" })),
+        );
+    }
+
+    let maintenance = payload.enabled.then_some(payload.maintenance);
+
+    *state.maintenance.lock().unwrap() = maintenance;
+
+    match maintenance {
+        Some(maintenance) => tracing::warn!("entering maintenance mode: {:?}", maintenance),
+        None => tracing::info!("leaving maintenance mode"),
+    }
+
+    (StatusCode::OK, Json(json!({ "maintenance": maintenance })))
+}
+
+/// Answers data requests with a 503 while in maintenance mode, reads too unless allowed.
+pub(crate) async fn refuse<B>(
+    State(state): State<Arc<AppState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let maintenance = *state.maintenance.lock().unwrap();
+
+    let maintenance = match maintenance {
+        Some(maintenance) => maintenance,
+        None => return next.run(request).await,
+    };
+
+    let control_plane = request
+        .extensions()
+        .get::<MatchedPath>()
+        .is_some_and(|route| {
+            route.as_str().starts_with("/admin/") || CONTROL_PLANE.contains(&route.as_str())
+        });
+
+    let read =
+        matches!(*request.method(), Method::GET | Method::HEAD) && !wrapping::wraps(request.uri());
+
+    if control_plane || (read && maintenance.allow_reads) {
+        return next.run(request).await;
+    }
+
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, maintenance.retry_after.to_string())],
+        Json(json!({ "error": "Down for maintenance" })),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::{body::Body, http, Router};
+
+    async fn send(app: &mut Router, method: Method, uri: &str) -> Response {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+
+        crate::tests::respond(app, request).await
+    }
+
+    async fn switch(app: &mut Router, payload: Value) -> StatusCode {
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/admin/maintenance")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(immutable::ADMIN_TOKEN_HEADER, "test-admin-token")
+            .body(Body::from(payload.to_string()))
+            .unwrap();
+
+        crate::tests::respond(app, request).await.status()
+    }
+
+    #[tokio::test]
+    async fn refuses_data_requests_during_maintenance() {
+        let mut app = setup_tests().await;
+
+        let payload = json!({ "enabled": true, "allow_reads": true, "retry_after": 30 });
+
+        assert_eq!(switch(&mut app, payload).await, StatusCode::OK);
+
+        let response = send(&mut app, Method::DELETE, "/maintained").await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "30");
+
+        let response = send(&mut app, Method::GET, "/maintained").await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Wrapping writes the token
+        let response = send(&mut app, Method::GET, "/maintained?wrap_ttl=60").await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        assert_eq!(
+            switch(&mut app, json!({ "enabled": true })).await,
+            StatusCode::OK
+        );
+
+        let response = send(&mut app, Method::GET, "/maintained").await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "60");
+
+        let response = send(&mut app, Method::GET, "/healthz").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        assert_eq!(
+            switch(&mut app, json!({ "enabled": false })).await,
+            StatusCode::OK
+        );
+
+        let response = send(&mut app, Method::DELETE, "/maintained").await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,277 @@
+use axum::extract::{Path, State};
+use axum::http::HeaderMap;
+use axum::{http::StatusCode, Json};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::{hooks, put_value, references, refuse_locked, sizes, wait, AppError, AppState};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Strategy {
+    Append,
+    Max,
+    Min,
+    Sum,
+    Json,
+}
+
+impl Strategy {
+    fn parse(name: &str) -> Option<Strategy> {
+        match name {
+            "append" => Some(Strategy::Append),
+            "max" => Some(Strategy::Max),
+            "min" => Some(Strategy::Min),
+            "sum" => Some(Strategy::Sum),
+            "json" => Some(Strategy::Json),
+            _ => None,
+        }
+    }
+
+    /// Combines the stored value with the incoming one, `None` when they can't be merged.
+    fn apply(self, current: &str, incoming: &str) -> Option<String> {
+        match self {
+            Strategy::Append => Some(format!("{}{}", current, incoming)),
+            Strategy::Max | Strategy::Min | Strategy::Sum => {
+                // Stay in integers when both sides are, so sums don't pick up float noise
+                if let (Ok(current), Ok(incoming)) =
+                    (current.parse::<i64>(), incoming.parse::<i64>())
+                {
+                    let merged = match self {
+                        Strategy::Max => current.max(incoming),
+                        Strategy::Min => current.min(incoming),
+                        _ => current.checked_add(incoming)?,
+                    };
+
+                    return Some(merged.to_string());
+                }
+
+                let current = current.parse::<f64>().ok()?;
+                let incoming = incoming.parse::<f64>().ok()?;
+
+                let merged = match self {
+                    Strategy::Max => current.max(incoming),
+                    Strategy::Min => current.min(incoming),
+                    _ => current + incoming,
+                };
+
+                Some(merged.to_string())
+            }
+            Strategy::Json => {
+                let mut current: Value = serde_json::from_str(current).ok()?;
+                let incoming: Value = serde_json::from_str(incoming).ok()?;
+
+                deep_merge(&mut current, incoming);
+
+                Some(current.to_string())
+            }
+        }
+    }
+}
+
+// Objects are merged key by key, anything else is replaced by the incoming value
+fn deep_merge(current: &mut Value, incoming: Value) {
+    match (current, incoming) {
+        (Value::Object(current), Value::Object(incoming)) => {
+            for (key, value) in incoming {
+                match current.get_mut(&key) {
+                    Some(existing) => deep_merge(existing, value),
+                    None => {
+                        current.insert(key, value);
+                    }
+                }
+            }
+        }
+        (current, incoming) => *current = incoming,
+    }
+}
+
+/// Parses `MERGE_STRATEGIES`, a comma separated list of `prefix=strategy` pairs.
+pub(crate) fn parse_strategies(config: &str) -> Result<Vec<(String, Strategy)>, String> {
+    config
+        .split(',')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (prefix, name) = pair
+                .rsplit_once('=')
+                .ok_or_else(|| format!("expected prefix=strategy, got {}", pair))?;
+
+            let strategy =
+                Strategy::parse(name).ok_or_else(|| format!("unknown merge strategy {}", name))?;
+
+            Ok((prefix.to_owned(), strategy))
+        })
+        .collect()
+}
+
+// The most specific prefix wins when several match
+fn strategy_for(state: &AppState, key: &str) -> Option<Strategy> {
+    state
+        .merge_strategies
+        .iter()
+        .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, strategy)| *strategy)
+}
+
+#[derive(Deserialize)]
+pub(crate) struct MergePayload {
+    value: String,
+}
+
+pub(crate) async fn merge(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<MergePayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let strategy = match strategy_for(&state, &key) {
+        Some(strategy) => strategy,
+        None => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "No merge strategy configured for key" })),
+            ))
+        }
+    };
+
+    if let Some(response) = sizes::check(&state, &key, &payload.value) {
+        return Ok(response);
+    }
+
+    // Reading and writing in one transaction is what makes the merge atomic
+    let mut wtxn = state.write_txn().unwrap();
+
+    if let Some(response) = refuse_locked(&state, &wtxn, &key, &headers) {
+        return Ok(response);
+    }
+
+    let current = match state.kv.get(&wtxn, &key) {
+        Ok(current) => current.map(str::to_owned),
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    };
+
+    let merged = match current {
+        Some(current) => match strategy.apply(&current, &payload.value) {
+            Some(merged) => merged,
+            None => {
+                return Ok((
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({ "error": "Values can't be merged" })),
+                ))
+            }
+        },
+        None => payload.value,
+    };
+
+    let merged = match hooks::run(&state, &key, &merged) {
+        Ok(merged) => merged,
+        Err(response) => return Ok(response),
+    };
+
+    if let Some(response) = sizes::check_value(&state, &merged)
+        .or_else(|| references::check(&state, &wtxn, &key, &merged))
+    {
+        return Ok(response);
+    }
+
+    let value = put_value(&state, &mut wtxn, &key, &merged);
+
+    match value {
+        Ok(_) => {
+            state.commit(wtxn).unwrap();
+
+            wait::notify(&state, &key);
+
+            Ok((StatusCode::OK, Json(json!({ "key": key, "value": merged }))))
+        }
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request},
+    };
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[test]
+    fn strategies() {
+        assert_eq!(Strategy::Append.apply("ab", "cd").unwrap(), "abcd");
+        assert_eq!(Strategy::Max.apply("3", "7").unwrap(), "7");
+        assert_eq!(Strategy::Min.apply("3", "-7").unwrap(), "-7");
+        assert_eq!(Strategy::Sum.apply("3", "4").unwrap(), "7");
+        assert_eq!(Strategy::Sum.apply("0.5", "1").unwrap(), "1.5");
+        assert_eq!(Strategy::Sum.apply("3", "four"), None);
+
+        let merged = Strategy::Json
+            .apply(r#"{"a":{"b":1,"c":2},"d":[1]}"#, r#"{"a":{"c":3},"d":[2]}"#)
+            .unwrap();
+
+        assert_eq!(
+            serde_json::from_str::<Value>(&merged).unwrap(),
+            json!({ "a": { "b": 1, "c": 3 }, "d": [2] })
+        );
+    }
+
+    #[test]
+    fn parses_configuration() {
+        assert_eq!(
+            parse_strategies("hits:=sum,log:=append").unwrap(),
+            vec![
+                (String::from("hits:"), Strategy::Sum),
+                (String::from("log:"), Strategy::Append)
+            ]
+        );
+        assert!(parse_strategies("hits:=avg").is_err());
+        assert!(parse_strategies("hits:").is_err());
+    }
+
+    #[tokio::test]
+    async fn merges_into_stored_value() {
+        let mut app = setup_tests().await;
+
+        for (value, expected) in [("5", "5"), ("10", "15")] {
+            let request = Request::builder()
+                .method(http::Method::POST)
+                .uri("/merge-sum:total/merge")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(json!({ "value": value }).to_string()))
+                .unwrap();
+
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+
+            assert_eq!(body["value"], expected);
+        }
+
+        // Keys outside a configured prefix can't be merged
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/unmerged/merge")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({ "value": "1" }).to_string()))
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
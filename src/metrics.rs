@@ -0,0 +1,179 @@
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::AppState;
+
+/// Counters exposed in the Prometheus text format on `GET /metrics`.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) coalesced_reads: AtomicU64,
+    pub(crate) cached_misses: AtomicU64,
+    pub(crate) transaction_retries: AtomicU64,
+    pub(crate) panics: AtomicU64,
+    pub(crate) history_pruned: AtomicU64,
+    pub(crate) history_reclaimed_bytes: AtomicU64,
+    pub(crate) deadline_exceeded: AtomicU64,
+    pub(crate) subscriber_dropped_messages: AtomicU64,
+    pub(crate) subscriber_disconnects: AtomicU64,
+    pub(crate) feed_truncated: AtomicU64,
+    pub(crate) keys_expired: AtomicU64,
+    pub(crate) mirrored_writes: AtomicU64,
+    pub(crate) mirror_failures: AtomicU64,
+    pub(crate) upstream_reads: AtomicU64,
+    pub(crate) upstream_failures: AtomicU64,
+    pub(crate) rate_limited: AtomicU64,
+    pub(crate) auth_failures: AtomicU64,
+    pub(crate) auth_lockouts: AtomicU64,
+}
+
+pub(crate) fn increment(counter: &AtomicU64) {
+    add(counter, 1);
+}
+
+pub(crate) fn add(counter: &AtomicU64, amount: u64) {
+    counter.fetch_add(amount, Ordering::Relaxed);
+}
+
+impl Metrics {
+    fn counters(&self) -> [(&str, &str, &AtomicU64); 18] {
+        [
+            (
+                "kv_coalesced_reads_total",
+                "Reads answered by sharing a concurrent lookup of the same key",
+                &self.coalesced_reads,
+            ),
+            (
+                "kv_cached_misses_total",
+                "Reads of missing keys answered from MISS_CACHE_TTL's cache",
+                &self.cached_misses,
+            ),
+            (
+                "kv_transaction_retries_total",
+                "Transactions retried after a transient LMDB error",
+                &self.transaction_retries,
+            ),
+            (
+                "kv_panics_total",
+                "Requests whose handler panicked",
+                &self.panics,
+            ),
+            (
+                "kv_history_pruned_total",
+                "Versions removed from the history by compaction",
+                &self.history_pruned,
+            ),
+            (
+                "kv_history_reclaimed_bytes_total",
+                "Bytes of keys and values removed from the history by compaction",
+                &self.history_reclaimed_bytes,
+            ),
+            (
+                "kv_deadline_exceeded_total",
+                "Requests refused or cut short because the client's deadline passed",
+                &self.deadline_exceeded,
+            ),
+            (
+                "kv_subscriber_dropped_messages_total",
+                "Pub/sub messages subscribers missed by falling too far behind",
+                &self.subscriber_dropped_messages,
+            ),
+            (
+                "kv_subscriber_disconnects_total",
+                "Subscribers disconnected for falling too far behind",
+                &self.subscriber_disconnects,
+            ),
+            (
+                "kv_feed_truncated_total",
+                "Events removed from the change feed by truncation",
+                &self.feed_truncated,
+            ),
+            (
+                "kv_keys_expired_total",
+                "Ephemeral keys deleted for not being refreshed within their TTL",
+                &self.keys_expired,
+            ),
+            (
+                "kv_mirrored_writes_total",
+                "Writes copied to MIRROR_URL",
+                &self.mirrored_writes,
+            ),
+            (
+                "kv_mirror_failures_total",
+                "Writes that couldn't be copied to MIRROR_URL",
+                &self.mirror_failures,
+            ),
+            (
+                "kv_upstream_reads_total",
+                "Misses read through to UPSTREAM_URL and cached",
+                &self.upstream_reads,
+            ),
+            (
+                "kv_upstream_failures_total",
+                "Reads and writes UPSTREAM_URL failed to answer",
+                &self.upstream_failures,
+            ),
+            (
+                "kv_rate_limited_total",
+                "Requests refused for going over their API token's quota",
+                &self.rate_limited,
+            ),
+            (
+                "kv_auth_failures_total",
+                "Requests with a wrong admin or API token, or a bad signature",
+                &self.auth_failures,
+            ),
+            (
+                "kv_auth_lockouts_total",
+                "Addresses locked out after failing to authenticate too many times",
+                &self.auth_lockouts,
+            ),
+        ]
+    }
+
+    /// The current value of the counter called `name`, as on `GET /metrics`.
+    pub(crate) fn get(&self, name: &str) -> Option<u64> {
+        self.counters()
+            .into_iter()
+            .find(|(counter_name, _, _)| *counter_name == name)
+            .map(|(_, _, counter)| counter.load(Ordering::Relaxed))
+    }
+
+    fn render(&self) -> String {
+        let mut output = String::new();
+
+        for (name, help, counter) in self.counters() {
+            writeln!(output, "# HELP {} {}", name, help).unwrap();
+            writeln!(output, "# TYPE {} counter", name).unwrap();
+            writeln!(output, "{} {}", name, counter.load(Ordering::Relaxed)).unwrap();
+        }
+
+        output
+    }
+}
+
+pub(crate) async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::default();
+
+        increment(&metrics.coalesced_reads);
+
+        assert!(metrics
+            .render()
+            .contains("# TYPE kv_coalesced_reads_total counter\nkv_coalesced_reads_total 1\n"));
+    }
+}
@@ -0,0 +1,283 @@
+use hyper::client::HttpConnector;
+use hyper::{header, Body, Client, Method, Request, StatusCode};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{metrics, now_millis, AppState};
+
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The `MIRROR_URL` writes are copied to, if any.
+pub(crate) fn url_from_env() -> Result<Option<String>, String> {
+    match std::env::var("MIRROR_URL") {
+        Ok(url) if url.starts_with("http://") => Ok(Some(url.trim_end_matches('/').to_owned())),
+        Ok(url) => Err(format!("MIRROR_URL must be an http:// URL, got {}", url)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// How far behind `MIRROR_URL` is, for `GET /admin/cluster`.
+pub(crate) struct Lag {
+    // Writes committed but not mirrored yet, as of the last one mirrored
+    pending: u64,
+    // Unix time in milliseconds the mirror last had every write
+    caught_up_at: u64,
+    // When the mirror last failed to take a write, and why
+    last_error: Option<(u64, String)>,
+}
+
+impl Lag {
+    pub(crate) fn new() -> Lag {
+        Lag {
+            pending: 0,
+            caught_up_at: now_millis(),
+            last_error: None,
+        }
+    }
+
+    fn report(&self, url: &str, now: u64) -> Value {
+        let seconds = match self.pending {
+            0 => 0,
+            _ => now.saturating_sub(self.caught_up_at) / 1000,
+        };
+
+        json!({
+            "url": url,
+            "lag": { "writes": self.pending, "seconds": seconds },
+            "last_error": self.last_error.as_ref().map(|(at, error)| {
+                json!({ "at": at, "error": error })
+            }),
+        })
+    }
+}
+
+/// The mirror writes are copied to and how far behind it is, `None` without one.
+pub(crate) fn status(state: &AppState) -> Option<Value> {
+    let url = state.mirror_url.as_ref()?;
+
+    Some(state.mirror_lag.lock().unwrap().report(url, now_millis()))
+}
+
+// Percent-encodes `key` so it stays a single path segment on the mirror
+pub(crate) fn encode_segment(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+
+    for byte in key.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+/// Copies `key` as it is now to the mirror at `url`, a put of its value or a delete if
+/// it's gone. Sending the latest value rather than each write means a burst of writes to
+/// one key costs a single request that lands in the same place.
+pub(crate) async fn mirror_key(
+    state: &AppState,
+    client: &Client<HttpConnector>,
+    url: &str,
+    key: &str,
+) -> Result<(), String> {
+    let value = {
+        let rtxn = state.read_txn().map_err(|err| err.to_string())?;
+
+        let value = state
+            .kv
+            .get(&rtxn, key)
+            .map_err(|err| err.to_string())?
+            .map(str::to_owned);
+
+        value
+    };
+
+    let request = Request::builder().uri(format!("{}/{}", url, encode_segment(key)));
+
+    let request = match &value {
+        Some(value) => request
+            .method(Method::PUT)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "key": key, "value": value }).to_string(),
+            )),
+        None => request.method(Method::DELETE).body(Body::empty()),
+    }
+    .map_err(|err| err.to_string())?;
+
+    let response = tokio::time::timeout(MIRROR_TIMEOUT, client.request(request))
+        .await
+        .map_err(|_| String::from("timed out"))?
+        .map_err(|err| err.to_string())?;
+
+    match response.status() {
+        // Already gone from the mirror too
+        StatusCode::NOT_FOUND if value.is_none() => Ok(()),
+        status if status.is_success() => Ok(()),
+        status => Err(format!("answered {}", status)),
+    }
+}
+
+/// Copies every committed write to `MIRROR_URL` in the background, for shadow testing or
+/// moving to a new server live. Nothing waits on the mirror: writes it fails to take, or
+/// that came too fast to keep up with, are logged and counted in `kv_mirror_failures_total`
+/// but not retried.
+pub(crate) fn spawn_mirroring(state: Arc<AppState>) {
+    let url = match &state.mirror_url {
+        Some(url) => url.clone(),
+        None => return,
+    };
+
+    let mut changes = state.changes.subscribe();
+
+    tokio::spawn(async move {
+        let client = Client::new();
+
+        loop {
+            let key = match changes.recv().await {
+                Ok(key) => key,
+                Err(RecvError::Lagged(missed)) => {
+                    metrics::add(&state.metrics.mirror_failures, missed);
+
+                    state.mirror_lag.lock().unwrap().last_error = Some((
+                        now_millis(),
+                        format!("fell behind, {} writes not mirrored", missed),
+                    ));
+
+                    tracing::warn!(missed, "mirroring fell behind, writes not mirrored");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            let result = mirror_key(&state, &client, &url, &key).await;
+
+            let mut lag = state.mirror_lag.lock().unwrap();
+
+            // What's still queued for this receiver is what the mirror hasn't got yet
+            lag.pending = changes.len() as u64;
+
+            if lag.pending == 0 {
+                lag.caught_up_at = now_millis();
+            }
+
+            match result {
+                Ok(()) => metrics::increment(&state.metrics.mirrored_writes),
+                Err(err) => {
+                    metrics::increment(&state.metrics.mirror_failures);
+
+                    tracing::warn!(key, %err, "failed to mirror write");
+
+                    lag.last_error = Some((now_millis(), format!("{}: {}", key, err)));
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::extract::Path;
+    use axum::routing::put;
+    use axum::Router;
+    use std::sync::Mutex;
+
+    #[test]
+    fn reports_lag() {
+        let mut lag = Lag {
+            pending: 0,
+            caught_up_at: 1_000,
+            last_error: None,
+        };
+
+        assert_eq!(
+            lag.report("http://mirror", 9_000),
+            json!({
+                "url": "http://mirror",
+                "lag": { "writes": 0, "seconds": 0 },
+                "last_error": null,
+            })
+        );
+
+        lag.pending = 3;
+        lag.last_error = Some((8_000, String::from("a: timed out")));
+
+        assert_eq!(
+            lag.report("http://mirror", 9_000),
+            json!({
+                "url": "http://mirror",
+                "lag": { "writes": 3, "seconds": 8 },
+                "last_error": { "at": 8_000, "error": "a: timed out" },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn mirrors_puts_and_deletes() {
+        let _ = setup_tests().await;
+
+        let state = crate::app_state().unwrap();
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let receiver = Router::new().route(
+            "/:key",
+            put({
+                let received = received.clone();
+
+                move |Path(key): Path<String>, body: String| async move {
+                    received
+                        .lock()
+                        .unwrap()
+                        .push(format!("PUT {} {}", key, body));
+                }
+            })
+            .delete({
+                let received = received.clone();
+
+                move |Path(key): Path<String>| async move {
+                    received.lock().unwrap().push(format!("DELETE {}", key));
+                }
+            }),
+        );
+
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(receiver.into_make_service());
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let mut wtxn = state.write_txn().unwrap();
+        crate::put_value(&state, &mut wtxn, "mirrored/a", "1").unwrap();
+        wtxn.commit().unwrap();
+
+        let client = Client::new();
+
+        mirror_key(&state, &client, &url, "mirrored/a")
+            .await
+            .unwrap();
+        mirror_key(&state, &client, &url, "mirrored/b")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![
+                String::from(r#"PUT mirrored/a {"key":"mirrored/a","value":"1"}"#),
+                String::from("DELETE mirrored/b"),
+            ]
+        );
+
+        // Nothing listens on port 9
+        assert!(
+            mirror_key(&state, &client, "http://127.0.0.1:9", "mirrored/a")
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::wait;
+
+// Clients asking for random keys could otherwise grow it without end
+const MAX_MISSES: usize = 10_000;
+
+/// Keys recently found missing, so asking for them again is answered without reading the
+/// database, `MISS_CACHE_TTL`. Writes forget the keys they make.
+pub(crate) struct Misses {
+    ttl: Option<Duration>,
+    cached: Mutex<Cached>,
+}
+
+#[derive(Default)]
+struct Cached {
+    // Bumped by every write, so a lookup that raced one isn't remembered
+    epoch: u64,
+    // When each miss was found
+    keys: HashMap<String, Instant>,
+}
+
+impl Misses {
+    fn new(ttl: Option<Duration>) -> Misses {
+        Misses {
+            ttl,
+            cached: Mutex::new(Cached::default()),
+        }
+    }
+
+    /// The `MISS_CACHE_TTL` configured, off without one.
+    pub(crate) fn from_env() -> Result<Misses, String> {
+        let ttl = match std::env::var("MISS_CACHE_TTL") {
+            Ok(ttl) => match wait::parse_duration(&ttl) {
+                Some(ttl) if !ttl.is_zero() => Some(ttl),
+                _ => return Err(format!("MISS_CACHE_TTL must be a duration, got {}", ttl)),
+            },
+            Err(_) => None,
+        };
+
+        Ok(Misses::new(ttl))
+    }
+
+    /// To pass to [`Misses::remember`] once the lookup taken after calling it misses.
+    pub(crate) fn epoch(&self) -> u64 {
+        self.cached.lock().unwrap().epoch
+    }
+
+    /// Whether `key` was found missing within the TTL.
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return false,
+        };
+
+        let mut cached = self.cached.lock().unwrap();
+
+        match cached.keys.get(key) {
+            Some(found) if found.elapsed() < ttl => true,
+            Some(_) => {
+                cached.keys.remove(key);
+
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Remembers `key` missing, unless something was written since `epoch`.
+    pub(crate) fn remember(&self, key: &str, epoch: u64) {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return,
+        };
+
+        let mut cached = self.cached.lock().unwrap();
+
+        if cached.epoch != epoch {
+            return;
+        }
+
+        if cached.keys.len() >= MAX_MISSES {
+            cached.keys.retain(|_, found| found.elapsed() < ttl);
+
+            if cached.keys.len() >= MAX_MISSES {
+                return;
+            }
+        }
+
+        cached.keys.insert(key.to_owned(), Instant::now());
+    }
+
+    /// Forgets `key` was missing, once a write to it is committed.
+    pub(crate) fn forget(&self, key: &str) {
+        if self.ttl.is_none() {
+            return;
+        }
+
+        let mut cached = self.cached.lock().unwrap();
+
+        cached.epoch += 1;
+        cached.keys.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request, StatusCode},
+    };
+    use serde_json::json;
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[test]
+    fn remembers_misses_until_written() {
+        let misses = Misses::new(Some(Duration::from_secs(60)));
+
+        let epoch = misses.epoch();
+        misses.remember("absent", epoch);
+
+        assert!(misses.contains("absent"));
+
+        misses.forget("absent");
+
+        assert!(!misses.contains("absent"));
+
+        // A lookup that started before a write could have missed what it wrote
+        let epoch = misses.epoch();
+        misses.forget("other");
+        misses.remember("absent", epoch);
+
+        assert!(!misses.contains("absent"));
+
+        let expired = Misses::new(Some(Duration::from_millis(1)));
+
+        expired.remember("absent", expired.epoch());
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert!(!expired.contains("absent"));
+
+        let off = Misses::new(None);
+
+        off.remember("absent", off.epoch());
+
+        assert!(!off.contains("absent"));
+    }
+
+    #[tokio::test]
+    async fn answers_repeated_misses_until_written() {
+        let mut app = setup_tests().await;
+
+        for _ in 0..2 {
+            let request = Request::builder()
+                .uri("/missing-for-now")
+                .body(Body::empty())
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+
+        let request = Request::builder()
+            .uri("/__system%2Fmetrics%2Fkv_cached_misses_total")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["value"], "1");
+
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "key": "missing-for-now", "value": "here" }).to_string(),
+            ))
+            .unwrap();
+        app.ready().await.unwrap().call(request).await.unwrap();
+
+        let request = Request::builder()
+            .uri("/missing-for-now")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,511 @@
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap};
+use axum::response::{IntoResponse, Response};
+use axum::{http::StatusCode, Json};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::{
+    dotenv, hooks, paging, put_value, record_keys, references, refuse_locked, sizes, wait,
+    AppError, AppState,
+};
+
+#[derive(Deserialize)]
+pub(crate) struct NestedQuery {
+    #[serde(default)]
+    prefix: String,
+    delimiter: Option<String>,
+}
+
+impl NestedQuery {
+    // Path segments are joined with `:` unless asked otherwise
+    fn delimiter(&self) -> Result<&str, (StatusCode, Json<Value>)> {
+        match self.delimiter.as_deref() {
+            Some("") => Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "Delimiter can't be empty" })),
+            )),
+            Some(delimiter) => Ok(delimiter),
+            None => Ok(":"),
+        }
+    }
+}
+
+/// Turns `{"a": {"b": 1}}` into `("a:b", "1")`, keeping strings as they are and writing
+/// any other leaf, arrays included, as JSON.
+fn flatten(path: &str, value: &Value, delimiter: &str, entries: &mut Vec<(String, String)>) {
+    let join = |segment: &str| match path {
+        "" => segment.to_owned(),
+        path => format!("{}{}{}", path, delimiter, segment),
+    };
+
+    match value {
+        Value::Object(object) => {
+            for (segment, value) in object {
+                flatten(&join(segment), value, delimiter, entries);
+            }
+        }
+        Value::String(value) => entries.push((path.to_owned(), value.clone())),
+        value => entries.push((path.to_owned(), value.to_string())),
+    }
+}
+
+/// The reverse of [`flatten`], except values stay strings. Fails with the key that can't
+/// be nested, when another key is also a path through it.
+fn nest(entries: &[(String, String)], delimiter: &str) -> Result<Value, String> {
+    let mut root = Map::new();
+
+    for (key, value) in entries {
+        let mut segments: Vec<&str> = key.split(delimiter).collect();
+        let leaf = segments.pop().unwrap();
+
+        let mut node = &mut root;
+
+        for segment in segments {
+            node = match node
+                .entry(segment)
+                .or_insert_with(|| Value::Object(Map::new()))
+            {
+                Value::Object(object) => object,
+                _ => return Err(key.clone()),
+            };
+        }
+
+        if node.contains_key(leaf) {
+            return Err(key.clone());
+        }
+
+        node.insert(leaf.to_owned(), Value::String(value.clone()));
+    }
+
+    Ok(Value::Object(root))
+}
+
+// Reads at most `limit` of the keys under `prefix` following `after` with their values,
+// only those written at or after `modified_since` if given
+pub(crate) fn read_entries(
+    state: &AppState,
+    rtxn: &heed::RoTxn,
+    prefix: &str,
+    after: Option<&str>,
+    modified_since: Option<u64>,
+    limit: usize,
+) -> heed::Result<Vec<(String, String)>> {
+    let owned = |entry: heed::Result<(&str, &str)>| {
+        entry.map(|(key, value)| (key.to_owned(), value.to_owned()))
+    };
+
+    // Keys last written before times were kept have none, and count as modified
+    let modified = |entry: &heed::Result<(&str, &str)>| match (entry, modified_since) {
+        (Ok((key, _)), Some(since)) => !matches!(
+            state.modified.get(rtxn, key),
+            Ok(Some(modified_at)) if modified_at < since
+        ),
+        _ => true,
+    };
+
+    // LMDB refuses empty keys, even just to seek to, and `after` itself was already read
+    let start = match (after, prefix) {
+        (Some(after), _) => Bound::Included(after),
+        (None, "") => Bound::Unbounded,
+        (None, prefix) => Bound::Included(prefix),
+    };
+
+    state
+        .kv
+        .range(rtxn, &(start, Bound::Unbounded))?
+        .skip_while(|entry| matches!(entry, Ok((key, _)) if Some(*key) == after))
+        .take_while(|entry| !matches!(entry, Ok((key, _)) if !key.starts_with(prefix)))
+        .filter(modified)
+        .take(limit)
+        .map(owned)
+        .collect()
+}
+
+/// `POST /import`: writes every leaf of a JSON object as a key in one transaction, named
+/// by its path under `?prefix=` joined with `?delimiter=`.
+pub(crate) async fn import(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<NestedQuery>,
+    headers: HeaderMap,
+    Json(payload): Json<Map<String, Value>>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let delimiter = match query.delimiter() {
+        Ok(delimiter) => delimiter,
+        Err(response) => return Ok(response),
+    };
+
+    let mut entries = Vec::new();
+    flatten(
+        &query.prefix,
+        &Value::Object(payload),
+        delimiter,
+        &mut entries,
+    );
+
+    for (key, value) in &mut entries {
+        *value = match hooks::run(&state, key, value) {
+            Ok(value) => value,
+            Err(response) => return Ok(response),
+        };
+
+        if let Some(response) = sizes::check(&state, key, value) {
+            return Ok(response);
+        }
+    }
+
+    record_keys(entries.len());
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    for (key, value) in &entries {
+        if let Some(response) = refuse_locked(&state, &wtxn, key, &headers) {
+            return Ok(response);
+        }
+
+        if put_value(&state, &mut wtxn, key, value).is_err() {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ));
+        }
+    }
+
+    // Once they're all written, so entries can reference each other
+    for (key, value) in &entries {
+        if let Some(response) = references::check(&state, &wtxn, key, value) {
+            return Ok(response);
+        }
+    }
+
+    state.commit(wtxn).unwrap();
+
+    for (key, _) in &entries {
+        wait::notify(&state, key);
+    }
+
+    Ok((StatusCode::OK, Json(json!({ "imported": entries.len() }))))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct FormatQuery {
+    // `json`, the default, or `dotenv`
+    format: Option<String>,
+    // Masks values by the `EXPORT_REDACTIONS` rules for `true`, or those of the rules file
+    // in `REDACTION_RULES_DIR` it names
+    redact: Option<String>,
+    // Unix time in milliseconds, leaves out keys last written before it
+    modified_since: Option<u64>,
+}
+
+/// `GET /export`: the keys under `?prefix=` nested back into an object by `?delimiter=`,
+/// or with `?format=dotenv` as `KEY=value` lines named after the keys less the prefix.
+/// `?redact=prefixes.yaml` masks them first by the rules in that file, or `?redact=true` by
+/// `EXPORT_REDACTIONS`, for sharing production data with staging or analytics.
+/// `?modified_since=` only exports the keys written since, for incremental backups.
+/// Keys are read `LIST_PAGE_SIZE` at a time, each page in a read transaction of its own,
+/// so writes landing mid-export may or may not be in it.
+pub(crate) async fn export(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<NestedQuery>,
+    Query(format): Query<FormatQuery>,
+) -> Result<Response, AppError> {
+    let delimiter = match query.delimiter() {
+        Ok(delimiter) => delimiter,
+        Err(response) => return Ok(response.into_response()),
+    };
+
+    let dotenv = match format.format.as_deref() {
+        None | Some("json") => false,
+        Some("dotenv") => true,
+        Some(format) => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": format!("Unknown format {}", format) })),
+            )
+                .into_response())
+        }
+    };
+
+    let redactions = match format.redact.as_deref() {
+        None | Some("false") => None,
+        Some(name) => match state.redactions.select(name) {
+            Ok(rules) => Some(rules),
+            Err(err) => {
+                return Ok((StatusCode::BAD_REQUEST, Json(json!({ "error": err }))).into_response())
+            }
+        },
+    };
+
+    let max_keys = state.limits.max_keys;
+    let page_size = state.limits.page_size;
+
+    // A page per read transaction, like `GET /`, so a large export doesn't pin old pages
+    let mut entries = Vec::new();
+
+    loop {
+        let after = entries
+            .last()
+            .map(|(key, _): &(String, String)| key.clone());
+
+        let page = state.read_txn().and_then(|rtxn| {
+            read_entries(
+                &state,
+                &rtxn,
+                &query.prefix,
+                after.as_deref(),
+                format.modified_since,
+                page_size,
+            )
+        });
+
+        match page {
+            Ok(page) => {
+                let more = page.len() == page_size;
+                entries.extend(page);
+
+                if !more || entries.len() > max_keys {
+                    break;
+                }
+            }
+            Err(_) => {
+                return Ok((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({ "error": "Internal server error" })),
+                )
+                    .into_response())
+            }
+        }
+    }
+
+    if entries.len() > max_keys {
+        return Ok(paging::too_many_keys(max_keys).into_response());
+    }
+
+    if let Some(rules) = &redactions {
+        entries = state.redactions.apply(rules, entries);
+    }
+
+    record_keys(entries.len());
+
+    if dotenv {
+        return match dotenv::render(&entries, &query.prefix) {
+            Ok(lines) => Ok((
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+                lines,
+            )
+                .into_response()),
+            Err(err) => Ok((StatusCode::CONFLICT, Json(json!({ "error": err }))).into_response()),
+        };
+    }
+
+    match nest(&entries, delimiter) {
+        Ok(nested) => Ok((StatusCode::OK, Json(nested)).into_response()),
+        Err(key) => Ok((
+            StatusCode::CONFLICT,
+            Json(json!({
+                "error": format!("{} is both a value and a path to others", key),
+            })),
+        )
+            .into_response()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{send, setup_tests};
+    use axum::{
+        body::Body,
+        http::{self, Request},
+    };
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[test]
+    fn flattens_and_nests() {
+        let mut entries = Vec::new();
+        flatten(
+            "",
+            &json!({ "a": { "b": 1, "c": { "d": "x" } }, "e": [true] }),
+            ":",
+            &mut entries,
+        );
+
+        assert_eq!(
+            entries,
+            vec![
+                (String::from("a:b"), String::from("1")),
+                (String::from("a:c:d"), String::from("x")),
+                (String::from("e"), String::from("[true]")),
+            ]
+        );
+        assert_eq!(
+            nest(&entries, ":").unwrap(),
+            json!({ "a": { "b": "1", "c": { "d": "x" } }, "e": "[true]" })
+        );
+
+        let clashing = [
+            (String::from("a"), String::from("1")),
+            (String::from("a:b"), String::from("2")),
+        ];
+
+        assert_eq!(nest(&clashing, ":"), Err(String::from("a:b")));
+    }
+
+    #[tokio::test]
+    async fn imports_and_exports_config_trees() {
+        let mut app = setup_tests().await;
+
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/import?prefix=nested&delimiter=.")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "db": { "host": "localhost", "port": 5432 } }).to_string(),
+            ))
+            .unwrap();
+
+        let (status, body) = send(&mut app, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["imported"], 2);
+
+        let request = Request::builder()
+            .uri("/nested.db.port")
+            .body(Body::empty())
+            .unwrap();
+
+        let (_, body) = send(&mut app, request).await;
+
+        assert_eq!(body["value"], "5432");
+
+        let request = Request::builder()
+            .uri("/export?prefix=nested.&delimiter=.")
+            .body(Body::empty())
+            .unwrap();
+
+        let (status, body) = send(&mut app, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({ "nested": { "db": { "host": "localhost", "port": "5432" } } })
+        );
+
+        let request = Request::builder()
+            .uri("/export?prefix=nested.&format=dotenv")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        assert_eq!(body, "DB_HOST=localhost\nDB_PORT=5432\n");
+    }
+
+    #[tokio::test]
+    async fn exports_redacted() {
+        let mut app = setup_tests().await;
+
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/import")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "redacted": { "card": "4111111111111111", "secret": "hunter2" } })
+                    .to_string(),
+            ))
+            .unwrap();
+
+        assert_eq!(send(&mut app, request).await.0, StatusCode::OK);
+
+        let request = Request::builder()
+            .uri("/export?prefix=redacted:&redact=true")
+            .body(Body::empty())
+            .unwrap();
+
+        let (status, body) = send(&mut app, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "redacted": { "card": "************1111" } }));
+
+        let dir = std::env::var("REDACTION_RULES_DIR").unwrap();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            std::path::Path::new(&dir).join("prefixes.yaml"),
+            "\"redacted:card\": drop\n",
+        )
+        .unwrap();
+
+        let request = Request::builder()
+            .uri("/export?prefix=redacted:&redact=prefixes.yaml")
+            .body(Body::empty())
+            .unwrap();
+
+        let (status, body) = send(&mut app, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "redacted": { "secret": "hunter2" } }));
+    }
+
+    #[tokio::test]
+    async fn exports_keys_modified_since() {
+        let mut app = setup_tests().await;
+
+        let import = |payload: Value| {
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/import")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(payload.to_string()))
+                .unwrap()
+        };
+
+        let request = import(json!({ "since": { "old": "1" } }));
+        assert_eq!(send(&mut app, request).await.0, StatusCode::OK);
+
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let since = crate::now_millis();
+
+        let request = import(json!({ "since": { "new": "2" } }));
+        assert_eq!(send(&mut app, request).await.0, StatusCode::OK);
+
+        let request = Request::builder()
+            .uri(format!("/export?prefix=since:&modified_since={}", since))
+            .body(Body::empty())
+            .unwrap();
+
+        let (status, body) = send(&mut app, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "since": { "new": "2" } }));
+    }
+
+    #[tokio::test]
+    async fn reads_entries_a_page_at_a_time() {
+        let _ = setup_tests().await;
+
+        let state = crate::app_state().unwrap();
+
+        let mut wtxn = state.write_txn().unwrap();
+        for key in ["paged:a", "paged:a0", "paged:b", "paged;"] {
+            crate::put_value(&state, &mut wtxn, key, "value").unwrap();
+        }
+        wtxn.commit().unwrap();
+
+        let rtxn = state.read_txn().unwrap();
+
+        let first = read_entries(&state, &rtxn, "paged:", None, None, 2).unwrap();
+        assert_eq!(first.len(), 2);
+
+        let rest = read_entries(&state, &rtxn, "paged:", Some(&first[1].0), None, 2).unwrap();
+        assert_eq!(rest, vec![(String::from("paged:b"), String::from("value"))]);
+    }
+}
@@ -0,0 +1,195 @@
+use axum::body::{Bytes, StreamBody};
+use axum::{http::StatusCode, Json};
+use serde_json::{json, Value};
+use std::ops::Bound;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::fields::{self, Field};
+use crate::AppState;
+
+const DEFAULT_PAGE_SIZE: usize = 1000;
+const DEFAULT_MAX_KEYS: usize = 10_000;
+
+/// How much listings read at once, from the environment.
+pub(crate) struct Limits {
+    // Keys read per transaction when streaming `GET /`, `LIST_PAGE_SIZE`
+    pub(crate) page_size: usize,
+    // Most keys a listing built in memory (`/keys`, `/tree`) holds before it's refused
+    // rather than truncated, `LIST_MAX_KEYS`
+    pub(crate) max_keys: usize,
+}
+
+impl Limits {
+    pub(crate) fn from_env() -> Result<Limits, String> {
+        Ok(Limits {
+            page_size: limit_var("LIST_PAGE_SIZE", DEFAULT_PAGE_SIZE)?,
+            max_keys: limit_var("LIST_MAX_KEYS", DEFAULT_MAX_KEYS)?,
+        })
+    }
+}
+
+fn limit_var(name: &str, default: usize) -> Result<usize, String> {
+    match std::env::var(name) {
+        Ok(limit) => match limit.parse() {
+            Ok(0) | Err(_) => Err(format!("{} must be a positive number, got {}", name, limit)),
+            Ok(limit) => Ok(limit),
+        },
+        Err(_) => Ok(default),
+    }
+}
+
+/// The error for a listing with more than `LIST_MAX_KEYS` keys in it.
+pub(crate) fn too_many_keys(max_keys: usize) -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({
+            "error": format!(
+                "Listing has more than {} keys, the LIST_MAX_KEYS limit, narrow it down",
+                max_keys
+            )
+        })),
+    )
+}
+
+pub(crate) type EntryStream = StreamBody<ReceiverStream<Result<Bytes, String>>>;
+
+/// Reads up to `limit` keys following `after` in a read transaction of its own, each
+/// along with its entry in the listing: the requested `fields`, or a `[key, value]` pair.
+pub(crate) fn read_page(
+    state: &AppState,
+    after: Option<&str>,
+    limit: usize,
+    fields: Option<&[Field]>,
+) -> heed::Result<Vec<(String, Value)>> {
+    let rtxn = state.read_txn()?;
+
+    // heed turns an excluded start bound into the next key up by bumping its last byte,
+    // which would skip `a0` after `a`, so start at `after` itself and skip over it
+    let start = match after {
+        Some(after) => Bound::Included(after),
+        None => Bound::Unbounded,
+    };
+
+    let page = state
+        .kv
+        .range(&rtxn, &(start, Bound::Unbounded))?
+        .skip_while(|entry| matches!(entry, Ok((key, _)) if Some(*key) == after))
+        .take(limit)
+        .map(|entry| {
+            let (key, value) = entry?;
+
+            let entry = match fields {
+                Some(fields) => fields::select(state, &rtxn, fields, key, value)?,
+                None => json!([key, value]),
+            };
+
+            Ok((key.to_owned(), entry))
+        })
+        .collect();
+
+    page
+}
+
+/// Streams every key as a JSON array of their entries, a page at a time.
+///
+/// Each page gets a short read transaction and the next one picks up after its last
+/// key, so a large listing doesn't pin old pages in the map while writes continue. The
+/// flip side is that it isn't one snapshot, writes landing mid-stream may or may not
+/// show up.
+pub(crate) fn stream_entries(
+    state: Arc<AppState>,
+    page_size: usize,
+    fields: Option<Vec<Field>>,
+) -> EntryStream {
+    let (sender, receiver) = mpsc::channel(2);
+
+    tokio::task::spawn_blocking(move || {
+        let mut after: Option<String> = None;
+        let mut separator = "[";
+
+        loop {
+            let mut page = match read_page(&state, after.as_deref(), page_size, fields.as_deref()) {
+                Ok(page) => page,
+                Err(err) => {
+                    // Too late for an error status, cutting the body short tells the client
+                    let _ = sender.blocking_send(Err(err.to_string()));
+                    return;
+                }
+            };
+
+            let mut chunk = String::new();
+
+            for (_, entry) in &page {
+                chunk.push_str(separator);
+                chunk.push_str(&entry.to_string());
+                separator = ",";
+            }
+
+            // The client went away
+            if !chunk.is_empty() && sender.blocking_send(Ok(chunk.into())).is_err() {
+                return;
+            }
+
+            if page.len() < page_size {
+                break;
+            }
+
+            after = page.pop().map(|(key, _)| key);
+        }
+
+        let end = if separator == "[" { "[]" } else { "]" };
+        let _ = sender.blocking_send(Ok(Bytes::from_static(end.as_bytes())));
+    });
+
+    StreamBody::new(ReceiverStream::new(receiver))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::response::IntoResponse;
+
+    #[tokio::test]
+    async fn streams_across_pages() {
+        let _ = setup_tests().await;
+
+        let state = crate::app_state().unwrap();
+
+        let mut wtxn = state.write_txn().unwrap();
+        for key in ["page-a", "page-a0", "page-b"] {
+            crate::put_value(&state, &mut wtxn, key, "value").unwrap();
+        }
+        wtxn.commit().unwrap();
+
+        let first = read_page(&state, None, 2, None).unwrap();
+        assert_eq!(first.len(), 2);
+
+        let rest = read_page(&state, Some(&first[1].0), 2, None).unwrap();
+        assert_eq!(
+            rest,
+            vec![(String::from("page-b"), json!(["page-b", "value"]))]
+        );
+
+        let fields = [Field::Key, Field::Size];
+        let rest = read_page(&state, Some(&first[1].0), 2, Some(&fields)).unwrap();
+        assert_eq!(rest[0].1, json!({ "key": "page-b", "size": 5 }));
+
+        for page_size in [1, 2, 3, 4] {
+            let body = stream_entries(state.clone(), page_size, None).into_response();
+            let body = hyper::body::to_bytes(body.into_body()).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+
+            assert_eq!(
+                body,
+                json!([
+                    ["page-a", "value"],
+                    ["page-a0", "value"],
+                    ["page-b", "value"]
+                ])
+            );
+        }
+    }
+}
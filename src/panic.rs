@@ -0,0 +1,55 @@
+use axum::response::{IntoResponse, Response};
+use axum::{http::StatusCode, Json};
+use serde_json::json;
+use std::any::Any;
+
+use crate::metrics::{increment, Metrics};
+
+/// Turns a panicking handler into the usual JSON error, with a request ID to find the
+/// logged panic by.
+///
+/// This runs inside the request's span, so the log line carries its method and route.
+pub(crate) fn handle_panic(metrics: &Metrics, payload: Box<dyn Any + Send + 'static>) -> Response {
+    let message = if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.as_str()
+    } else {
+        "unknown panic payload"
+    };
+
+    let request_id = uuid::Uuid::new_v4().to_string();
+
+    increment(&metrics.panics);
+
+    tracing::error!(%request_id, panic = message, "handler panicked");
+
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({ "error": "Internal server error", "request_id": request_id })),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn responds_with_a_request_id() {
+        let metrics = Metrics::default();
+
+        let response = handle_panic(&metrics, Box::new(String::from("boom")));
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(metrics.panics.load(Ordering::Relaxed), 1);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["error"], "Internal server error");
+        assert!(body["request_id"].is_string());
+    }
+}
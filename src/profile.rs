@@ -0,0 +1,159 @@
+use axum::extract::State;
+use axum::http::{HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{immutable, AppState};
+
+/// Asks for the time a request spent where to be sent back in `Server-Timing`. Only
+/// honored along with the admin token, anyone else's is ignored.
+pub(crate) const PROFILE_HEADER: &str = "x-debug-profile";
+
+/// Where a profiled request's time went, by what it was spent on, in the order first seen.
+#[derive(Default)]
+pub(crate) struct Profile {
+    timings: Vec<(&'static str, Duration)>,
+}
+
+pub(crate) type SharedProfile = Arc<Mutex<Profile>>;
+
+tokio::task_local! {
+    // The profile of the request being handled, if it asked for one
+    static PROFILE: SharedProfile;
+}
+
+impl Profile {
+    fn add(&mut self, name: &'static str, spent: Duration) {
+        match self.timings.iter_mut().find(|(timing, _)| *timing == name) {
+            Some((_, total)) => *total += spent,
+            None => self.timings.push((name, spent)),
+        }
+    }
+
+    // Like `write_lock;dur=0.120, commit;dur=1.004`, in milliseconds
+    fn server_timing(&self, total: Duration) -> String {
+        let mut header = String::new();
+
+        for (name, spent) in self.timings.iter().chain([&("total", total)]) {
+            if !header.is_empty() {
+                header.push_str(", ");
+            }
+
+            write!(header, "{};dur={:.3}", name, spent.as_secs_f64() * 1000.0).unwrap();
+        }
+
+        header
+    }
+}
+
+/// Runs `f`, adding the time it took to `name` in the current request's profile.
+pub(crate) fn time<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+    let profile = match current() {
+        Some(profile) => profile,
+        None => return f(),
+    };
+
+    let start = Instant::now();
+    let result = f();
+
+    profile.lock().unwrap().add(name, start.elapsed());
+
+    result
+}
+
+/// The current request's profile, to carry along to [`within`] on another thread.
+pub(crate) fn current() -> Option<SharedProfile> {
+    PROFILE.try_with(Arc::clone).ok()
+}
+
+/// Runs `f` as part of `profile`, for work handed off to the blocking pool.
+pub(crate) fn within<T>(profile: Option<SharedProfile>, f: impl FnOnce() -> T) -> T {
+    match profile {
+        Some(profile) => PROFILE.sync_scope(profile, f),
+        None => f(),
+    }
+}
+
+/// Profiles requests sent with `X-Debug-Profile` by an admin, for finding out why one
+/// particular request is slow in production. Streamed bodies like `GET /` are still being
+/// sent when the header goes out, so only the time until then is in it.
+pub(crate) async fn record<B>(
+    State(state): State<Arc<AppState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !request.headers().contains_key(PROFILE_HEADER)
+        || !immutable::admin_override(&state, request.headers())
+    {
+        return next.run(request).await;
+    }
+
+    let profile = SharedProfile::default();
+    let start = Instant::now();
+
+    let mut response = PROFILE.scope(profile.clone(), next.run(request)).await;
+
+    let timing = profile.lock().unwrap().server_timing(start.elapsed());
+
+    if let Ok(timing) = HeaderValue::from_str(&timing) {
+        response.headers_mut().insert("server-timing", timing);
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::immutable::ADMIN_TOKEN_HEADER;
+    use crate::tests::setup_tests;
+    use axum::{body::Body, http::StatusCode};
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[tokio::test]
+    async fn breaks_down_admin_requests_asking_for_it() {
+        let mut app = setup_tests().await;
+
+        let request = |admin: bool| {
+            let request = Request::builder()
+                .uri("/profiled")
+                .header(PROFILE_HEADER, "1");
+
+            match admin {
+                true => request.header(ADMIN_TOKEN_HEADER, "test-admin-token"),
+                false => request,
+            }
+            .body(Body::empty())
+            .unwrap()
+        };
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(request(true))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let timing = response.headers()["server-timing"].to_str().unwrap();
+
+        assert!(timing.contains("read_begin;dur="), "{}", timing);
+        assert!(timing.contains("total;dur="), "{}", timing);
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(request(false))
+            .await
+            .unwrap();
+
+        assert!(!response.headers().contains_key("server-timing"));
+    }
+}
@@ -0,0 +1,238 @@
+use axum::extract::{Path, State};
+use axum::response::sse::{Event, Sse};
+use axum::{http::StatusCode, Json};
+use futures_core::Stream;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+
+use crate::metrics;
+use crate::{AppError, AppState};
+
+// How many messages a subscriber can fall behind before it starts missing them, unless
+// set with `SUBSCRIBER_BUFFER`
+const DEFAULT_CHANNEL_CAPACITY: usize = 64;
+
+/// What happens to a subscriber that falls more than the channel's buffer behind.
+///
+/// Each channel only buffers its last messages however far behind its subscribers are,
+/// so a stalled one can't make it grow, the policy just picks how it finds out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LagPolicy {
+    // Skip the messages it missed and carry on from the oldest one still buffered
+    DropOldest,
+    // End its stream, so it knows to resubscribe and catch up some other way
+    Disconnect,
+}
+
+/// The buffer size and lag policy of subscribers, from `SUBSCRIBER_BUFFER` and
+/// `SUBSCRIBER_LAG_POLICY`.
+pub(crate) fn config_from_env() -> Result<(usize, LagPolicy), String> {
+    let capacity = match std::env::var("SUBSCRIBER_BUFFER") {
+        Ok(capacity) => match capacity.parse() {
+            Ok(0) | Err(_) => {
+                return Err(format!(
+                    "SUBSCRIBER_BUFFER must be a positive number, got {}",
+                    capacity
+                ))
+            }
+            Ok(capacity) => capacity,
+        },
+        Err(_) => DEFAULT_CHANNEL_CAPACITY,
+    };
+
+    let policy = match std::env::var("SUBSCRIBER_LAG_POLICY").as_deref() {
+        Ok("drop-oldest") | Err(_) => LagPolicy::DropOldest,
+        Ok("disconnect") => LagPolicy::Disconnect,
+        Ok(policy) => {
+            return Err(format!(
+                "SUBSCRIBER_LAG_POLICY must be drop-oldest or disconnect, got {}",
+                policy
+            ))
+        }
+    };
+
+    Ok((capacity, policy))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PublishPayload {
+    message: String,
+}
+
+pub(crate) async fn publish(
+    State(state): State<Arc<AppState>>,
+    Path(channel): Path<String>,
+    Json(payload): Json<PublishPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let mut channels = state.channels.lock().unwrap();
+
+    // Messages are transient, nobody listening means there is nothing to deliver
+    let receivers = match channels
+        .get(&channel)
+        .map(|sender| sender.send(payload.message))
+    {
+        Some(Ok(receivers)) => receivers,
+        Some(Err(_)) => {
+            // Every subscriber went away, stop tracking the channel
+            channels.remove(&channel);
+            0
+        }
+        None => 0,
+    };
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "channel": channel, "receivers": receivers })),
+    ))
+}
+
+pub(crate) async fn subscribe(
+    State(state): State<Arc<AppState>>,
+    Path(channel): Path<String>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = state
+        .channels
+        .lock()
+        .unwrap()
+        .entry(channel)
+        .or_insert_with(|| broadcast::channel(state.subscriber_buffer).0)
+        .subscribe();
+
+    let policy = state.lag_policy;
+    let sse = state.sse;
+
+    // Messages aren't kept, so there's nothing to resume and no event ids to send
+    let stream = tokio_stream::once(Ok(sse.retry_hint())).chain(events(receiver, policy, state));
+
+    Sse::new(stream).keep_alive(sse.keep_alive())
+}
+
+fn events(
+    receiver: broadcast::Receiver<String>,
+    policy: LagPolicy,
+    state: Arc<AppState>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    BroadcastStream::new(receiver)
+        .map_while(move |message| match message {
+            Ok(message) => Some(Some(Event::default().data(message))),
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                let metrics = &state.metrics;
+
+                metrics::add(&metrics.subscriber_dropped_messages, missed);
+
+                match policy {
+                    LagPolicy::DropOldest => Some(None),
+                    LagPolicy::Disconnect => {
+                        metrics::increment(&metrics.subscriber_disconnects);
+                        None
+                    }
+                }
+            }
+        })
+        .filter_map(|event| event.map(Ok))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::{
+        body::{Body, HttpBody},
+        http::{self, Request, StatusCode},
+        Router,
+    };
+    use serde_json::json;
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    async fn publish(app: &mut Router, channel: &str, message: &str) -> Value {
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri(format!("/publish/{}", channel))
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({ "message": message }).to_string()))
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn publish_without_subscribers() {
+        let mut app = setup_tests().await;
+
+        let body = publish(&mut app, "nobody-listening", "hello").await;
+
+        assert_eq!(
+            body,
+            json!({ "channel": "nobody-listening", "receivers": 0 })
+        );
+    }
+
+    #[tokio::test]
+    async fn subscriber_receives_published_messages() {
+        let mut app = setup_tests().await;
+
+        let request = Request::builder()
+            .uri("/subscribe/greetings")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = publish(&mut app, "greetings", "hello").await;
+
+        assert_eq!(body["receivers"], 1);
+
+        let mut stream = response.into_body();
+        let chunk = stream.data().await.unwrap().unwrap();
+
+        assert_eq!(chunk, "retry:3000\n\n");
+
+        let chunk = stream.data().await.unwrap().unwrap();
+
+        assert_eq!(chunk, "data:hello\n\n");
+    }
+
+    #[tokio::test]
+    async fn lagging_subscribers_follow_the_policy() {
+        let _ = setup_tests().await;
+
+        let state = crate::app_state().unwrap();
+
+        for policy in [LagPolicy::DropOldest, LagPolicy::Disconnect] {
+            let (sender, receiver) = broadcast::channel(2);
+
+            for message in ["a", "b", "c", "d"] {
+                sender.send(message.to_owned()).unwrap();
+            }
+            drop(sender);
+
+            let events: Vec<_> = events(receiver, policy, state.clone()).collect().await;
+
+            match policy {
+                LagPolicy::DropOldest => assert_eq!(events.len(), 2),
+                LagPolicy::Disconnect => assert!(events.is_empty()),
+            }
+        }
+
+        assert!(
+            state
+                .metrics
+                .subscriber_disconnects
+                .load(std::sync::atomic::Ordering::Relaxed)
+                >= 1
+        );
+    }
+}
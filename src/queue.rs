@@ -0,0 +1,350 @@
+use axum::extract::{Path, Query, State};
+use axum::{http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::{ephemeral, name_prefix, now_millis, sizes, AppError, AppState};
+
+// Messages are stored under `[name length][name][sequence]`, the big endian sequence
+// number keeping them in push order.
+
+const MAX_NAME_BYTES: usize = sizes::MAX_KEY_BYTES - 4;
+
+fn check_name(name: &str) -> Option<(StatusCode, Json<Value>)> {
+    if name.len() > MAX_NAME_BYTES {
+        return Some((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": format!("Queue names can be at most {} bytes", MAX_NAME_BYTES)
+            })),
+        ));
+    }
+
+    None
+}
+
+fn message_key(name: &str, id: u64) -> Vec<u8> {
+    let mut key = name_prefix(name);
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct QueueMessage {
+    value: String,
+    // Set while a popped message waits to be acknowledged, it is handed out again after that
+    invisible_until: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PushPayload {
+    value: String,
+}
+
+pub(crate) async fn push(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(payload): Json<PushPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    if let Some(response) = check_name(&name) {
+        return Ok(response);
+    }
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    let prefix = name_prefix(&name);
+
+    // Continue after the newest message still in the queue
+    let last = state
+        .queue
+        .remap_data_type::<heed::types::DecodeIgnore>()
+        .rev_prefix_iter(&wtxn, &prefix)
+        .unwrap()
+        .next();
+
+    let id = match last {
+        Some(Ok((key, _))) => u64::from_be_bytes(key[prefix.len()..].try_into().unwrap()) + 1,
+        Some(Err(_)) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+        None => 0,
+    };
+
+    let message = QueueMessage {
+        value: payload.value,
+        invisible_until: None,
+    };
+
+    state
+        .queue
+        .put(&mut wtxn, &message_key(&name, id), &message)
+        .unwrap();
+
+    state.commit(wtxn).unwrap();
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "id": id, "value": message.value })),
+    ))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PopQuery {
+    // Seconds the message stays reserved for the caller before it can be popped again
+    visibility_timeout: Option<u64>,
+}
+
+pub(crate) async fn pop(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<PopQuery>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    if let Some(response) = check_name(&name) {
+        return Ok(response);
+    }
+
+    let invisible_until = match query.visibility_timeout.map(ephemeral::expires_at) {
+        Some(Some(until)) => Some(until),
+        Some(None) => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "visibility_timeout is too long" })),
+            ))
+        }
+        None => None,
+    };
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    let prefix = name_prefix(&name);
+    let now = now_millis();
+
+    // Oldest message that isn't currently reserved by another consumer
+    let next = state
+        .queue
+        .prefix_iter(&wtxn, &prefix)
+        .unwrap()
+        .filter_map(Result::ok)
+        .find(|(_, message)| message.invisible_until.is_none_or(|until| until <= now))
+        .map(|(key, message)| (key.to_vec(), message));
+
+    let (key, mut message) = match next {
+        Some(next) => next,
+        None => {
+            return Ok((
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "Queue is empty" })),
+            ))
+        }
+    };
+
+    let id = u64::from_be_bytes(key[prefix.len()..].try_into().unwrap());
+
+    match invisible_until {
+        Some(until) => {
+            message.invisible_until = Some(until);
+            state.queue.put(&mut wtxn, &key, &message).unwrap();
+        }
+        None => {
+            state.queue.delete(&mut wtxn, &key).unwrap();
+        }
+    }
+
+    state.commit(wtxn).unwrap();
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "id": id, "value": message.value })),
+    ))
+}
+
+pub(crate) async fn ack(
+    State(state): State<Arc<AppState>>,
+    Path((name, id)): Path<(String, u64)>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    if let Some(response) = check_name(&name) {
+        return Ok(response);
+    }
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    let value = state.queue.delete(&mut wtxn, &message_key(&name, id));
+
+    match value {
+        Ok(true) => {
+            state.commit(wtxn).unwrap();
+
+            Ok((StatusCode::OK, Json(json!({ "id": id }))))
+        }
+        Ok(false) => Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Message not found" })),
+        )),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{send_json, setup_tests};
+    use axum::{
+        http::{self},
+        Router,
+    };
+
+    async fn drain(app: &mut Router, name: &str) {
+        let uri = format!("/queue/{}/pop", name);
+
+        while send_json(app, http::Method::POST, &uri, json!(null))
+            .await
+            .0
+            == StatusCode::OK
+        {}
+    }
+
+    #[tokio::test]
+    async fn push_and_pop_in_order() {
+        let mut app = setup_tests().await;
+
+        drain(&mut app, "fifo-test").await;
+
+        for value in ["one", "two"] {
+            let (status, _) = send_json(
+                &mut app,
+                http::Method::POST,
+                "/queue/fifo-test/push",
+                json!({ "value": value }),
+            )
+            .await;
+
+            assert_eq!(status, StatusCode::CREATED);
+        }
+
+        let (_, body) = send_json(
+            &mut app,
+            http::Method::POST,
+            "/queue/fifo-test/pop",
+            json!(null),
+        )
+        .await;
+        assert_eq!(body["value"], "one");
+
+        let (_, body) = send_json(
+            &mut app,
+            http::Method::POST,
+            "/queue/fifo-test/pop",
+            json!(null),
+        )
+        .await;
+        assert_eq!(body["value"], "two");
+
+        let (status, _) = send_json(
+            &mut app,
+            http::Method::POST,
+            "/queue/fifo-test/pop",
+            json!(null),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn reserved_messages_are_skipped_until_acked() {
+        let mut app = setup_tests().await;
+
+        drain(&mut app, "visibility-test").await;
+
+        for value in ["first", "second"] {
+            send_json(
+                &mut app,
+                http::Method::POST,
+                "/queue/visibility-test/push",
+                json!({ "value": value }),
+            )
+            .await;
+        }
+
+        let (_, reserved) = send_json(
+            &mut app,
+            http::Method::POST,
+            "/queue/visibility-test/pop?visibility_timeout=60",
+            json!(null),
+        )
+        .await;
+        assert_eq!(reserved["value"], "first");
+
+        // The reserved message is still queued but hidden from other consumers
+        let (_, body) = send_json(
+            &mut app,
+            http::Method::POST,
+            "/queue/visibility-test/pop",
+            json!(null),
+        )
+        .await;
+        assert_eq!(body["value"], "second");
+
+        let (status, _) = send_json(
+            &mut app,
+            http::Method::DELETE,
+            &format!("/queue/visibility-test/{}", reserved["id"]),
+            json!(null),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = send_json(
+            &mut app,
+            http::Method::POST,
+            "/queue/visibility-test/pop",
+            json!(null),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn refuses_visibility_timeouts_too_long_to_track() {
+        let mut app = setup_tests().await;
+
+        let (status, _) = send_json(
+            &mut app,
+            http::Method::POST,
+            "/queue/overflow-test/pop?visibility_timeout=18446744073709551615",
+            json!(null),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn refuses_names_too_long_to_store() {
+        let mut app = setup_tests().await;
+        let name = "q".repeat(500);
+
+        let (status, _) = send_json(
+            &mut app,
+            http::Method::POST,
+            &format!("/queue/{}/push", name),
+            json!({ "value": "first" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = send_json(
+            &mut app,
+            http::Method::POST,
+            &format!("/queue/{}/pop", name),
+            json!(null),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
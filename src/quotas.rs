@@ -0,0 +1,249 @@
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::{immutable, metrics, now_millis, tokens, AppState};
+
+pub(crate) const LIMIT_HEADER: &str = "x-ratelimit-limit";
+pub(crate) const REMAINING_HEADER: &str = "x-ratelimit-remaining";
+pub(crate) const BYTES_REMAINING_HEADER: &str = "x-quota-bytes-remaining";
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Requests left this second and bytes left today, for the limits that are set
+type Left = (Option<u64>, Option<u64>);
+
+/// What requests carrying one API token may send, set when it's created or on
+/// `PUT /admin/tokens/:name/quota`. Unset limits don't apply.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub(crate) struct Quota {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    requests_per_sec: Option<u64>,
+    // Request bodies, counted from midnight UTC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bytes_per_day: Option<u64>,
+}
+
+/// What a token has used of its quota so far. Kept in memory only, a restart starts
+/// every token over.
+#[derive(Debug, Default)]
+pub(crate) struct Usage {
+    second: u64,
+    requests: u64,
+    day: u64,
+    bytes: u64,
+}
+
+impl Usage {
+    // Counts a request of `size` bytes at `now`, Unix seconds, returning what's left, or
+    // why it's over quota and how many seconds until it's not
+    fn take(&mut self, quota: &Quota, now: u64, size: u64) -> Result<Left, (&'static str, u64)> {
+        if self.second != now {
+            self.second = now;
+            self.requests = 0;
+        }
+
+        if self.day != now / SECS_PER_DAY {
+            self.day = now / SECS_PER_DAY;
+            self.bytes = 0;
+        }
+
+        if let Some(limit) = quota.requests_per_sec {
+            if self.requests >= limit {
+                return Err(("Request rate limit exceeded", 1));
+            }
+        }
+
+        if let Some(limit) = quota.bytes_per_day {
+            if self.bytes + size > limit {
+                return Err((
+                    "Daily byte quota exceeded",
+                    SECS_PER_DAY - now % SECS_PER_DAY,
+                ));
+            }
+        }
+
+        self.requests += 1;
+        self.bytes += size;
+
+        Ok((
+            quota.requests_per_sec.map(|limit| limit - self.requests),
+            quota.bytes_per_day.map(|limit| limit - self.bytes),
+        ))
+    }
+}
+
+fn too_many_requests(state: &AppState, error: &str, retry_after: u64) -> Response {
+    metrics::increment(&state.metrics.rate_limited);
+
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [("retry-after", retry_after.to_string())],
+        Json(json!({ "error": error })),
+    )
+        .into_response()
+}
+
+/// Holds requests sent with an API token to its quota, by token so teams sharing a server
+/// can't crowd each other out. Requests over it get a 429 with `Retry-After`, the others
+/// say in `X-RateLimit-Remaining` and `X-Quota-Bytes-Remaining` how much is left.
+pub(crate) async fn enforce(
+    State(state): State<Arc<AppState>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let token = request
+        .headers()
+        .get(immutable::ADMIN_TOKEN_HEADER)
+        .and_then(|given| tokens::lookup(&state, given.as_bytes()));
+
+    let (name, quota) = match token {
+        Some((name, quota)) if quota != Quota::default() => (name, quota),
+        _ => return next.run(request).await,
+    };
+
+    let (parts, body) = request.into_parts();
+
+    // Counting a body means reading it all first, only done for tokens with a byte quota
+    let (body, size) = match quota.bytes_per_day {
+        Some(_) => match hyper::body::to_bytes(body).await {
+            Ok(body) => {
+                let size = body.len() as u64;
+
+                (Body::from(body), size)
+            }
+            Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+        },
+        None => (body, 0),
+    };
+
+    let taken = state
+        .token_usage
+        .lock()
+        .unwrap()
+        .entry(name)
+        .or_default()
+        .take(&quota, now_millis() / 1000, size);
+
+    let (requests_left, bytes_left) = match taken {
+        Ok(left) => left,
+        Err((error, retry_after)) => return too_many_requests(&state, error, retry_after),
+    };
+
+    let mut response = next.run(Request::from_parts(parts, body)).await;
+    let headers = response.headers_mut();
+
+    if let (Some(limit), Some(left)) = (quota.requests_per_sec, requests_left) {
+        headers.insert(LIMIT_HEADER, HeaderValue::from(limit));
+        headers.insert(REMAINING_HEADER, HeaderValue::from(left));
+    }
+
+    if let Some(left) = bytes_left {
+        headers.insert(BYTES_REMAINING_HEADER, HeaderValue::from(left));
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::{http, Router};
+    use serde_json::Value;
+
+    async fn send(
+        app: &mut Router,
+        method: http::Method,
+        uri: &str,
+        token: &str,
+        body: Value,
+    ) -> Response {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(immutable::ADMIN_TOKEN_HEADER, token)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        crate::tests::respond(app, request).await
+    }
+
+    #[test]
+    fn counts_requests_per_second_and_bytes_per_day() {
+        let quota = Quota {
+            requests_per_sec: Some(2),
+            bytes_per_day: Some(10),
+        };
+        let mut usage = Usage::default();
+
+        let day = 20_000 * SECS_PER_DAY;
+
+        assert_eq!(usage.take(&quota, day, 4), Ok((Some(1), Some(6))));
+        assert_eq!(usage.take(&quota, day, 4), Ok((Some(0), Some(2))));
+        assert_eq!(usage.take(&quota, day, 0).unwrap_err().1, 1);
+
+        assert_eq!(usage.take(&quota, day + 1, 2), Ok((Some(1), Some(0))));
+        assert_eq!(
+            usage.take(&quota, day + 2, 1).unwrap_err(),
+            ("Daily byte quota exceeded", SECS_PER_DAY - 2)
+        );
+
+        assert_eq!(
+            usage.take(&quota, day + SECS_PER_DAY, 10),
+            Ok((Some(1), Some(0)))
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_over_quota() {
+        let mut app = setup_tests().await;
+
+        let name = format!("team-{}", uuid::Uuid::new_v4());
+
+        let response = send(
+            &mut app,
+            http::Method::POST,
+            "/admin/tokens",
+            "test-admin-token",
+            json!({ "name": name, "bytes_per_day": 100 }),
+        )
+        .await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let created: Value = serde_json::from_slice(&body).unwrap();
+        let token = created["token"].as_str().unwrap().to_owned();
+
+        let put = |value: &str| json!({ "key": "quota:a", "value": value });
+
+        let response = send(&mut app, http::Method::PUT, "/quota:a", &token, put("1")).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let remaining: u64 = response.headers()[BYTES_REMAINING_HEADER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        assert_eq!(remaining, 100 - put("1").to_string().len() as u64);
+
+        let response = send(
+            &mut app,
+            http::Method::PUT,
+            "/quota:a",
+            &token,
+            put(&"x".repeat(100)),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key("retry-after"));
+    }
+}
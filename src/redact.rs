@@ -0,0 +1,336 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::borrow::Cow;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::digest;
+
+// Characters `partial` leaves readable at the end of a value
+const PARTIAL_VISIBLE: usize = 4;
+
+/// How `GET /export?redact=` masks the values under a prefix.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Rule {
+    // The value's HMAC-SHA256 keyed by `REDACTION_SECRET`, so equal values still match up
+    // across keys and exports, but guessed values can't be checked against it without the key
+    Hash,
+    // Leaves the key out altogether
+    Drop,
+    // Masks all but the last few characters, like `************1234`
+    Partial,
+}
+
+impl Rule {
+    fn parse(name: &str) -> Option<Rule> {
+        match name {
+            "hash" => Some(Rule::Hash),
+            "drop" => Some(Rule::Drop),
+            "partial" => Some(Rule::Partial),
+            _ => None,
+        }
+    }
+
+    // `None` when the key is dropped
+    fn mask(self, value: &str, secret: &str) -> Option<String> {
+        match self {
+            Rule::Hash => Some(hash(value, secret)),
+            Rule::Drop => None,
+            Rule::Partial => {
+                let chars = value.chars().count();
+                // Short values would be given away whole
+                let visible = if chars > PARTIAL_VISIBLE * 2 {
+                    PARTIAL_VISIBLE
+                } else {
+                    0
+                };
+
+                Some(
+                    value
+                        .chars()
+                        .enumerate()
+                        .map(|(index, char)| if index < chars - visible { '*' } else { char })
+                        .collect(),
+                )
+            }
+        }
+    }
+}
+
+fn hash(value: &str, secret: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(value.as_bytes());
+
+    digest::hex(&mac.finalize().into_bytes().into())
+}
+
+/// Parses `EXPORT_REDACTIONS`, a comma separated list of `prefix=rule` pairs.
+fn parse_rules(config: &str) -> Result<Vec<(String, Rule)>, String> {
+    config
+        .split(',')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (prefix, name) = pair
+                .rsplit_once('=')
+                .ok_or_else(|| format!("expected prefix=rule, got {}", pair))?;
+
+            let rule = Rule::parse(name).ok_or_else(|| format!("unknown redaction {}", name))?;
+
+            Ok((prefix.to_owned(), rule))
+        })
+        .collect()
+}
+
+// Strips matching quotes around a YAML scalar, if any
+fn unquote(scalar: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = scalar
+            .strip_prefix(quote)
+            .and_then(|rest| rest.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+
+    scalar
+}
+
+/// Parses a rules file, a YAML mapping of prefixes to rules on a line each like
+/// `"user:card:": partial`. Prefixes ending in `:` have to be quoted, as in YAML.
+fn parse_yaml(contents: &str) -> Result<Vec<(String, Rule)>, String> {
+    let mut rules = Vec::new();
+
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') || line == "---" {
+            continue;
+        }
+
+        // A quoted prefix ends at its closing quote, a plain one at the first `: `
+        let split = match line.chars().next() {
+            Some(quote @ ('"' | '\'')) => line[1..]
+                .find(quote)
+                .map(|end| end + 2)
+                .filter(|&end| line[end..].starts_with(':')),
+            _ => line.find(": "),
+        };
+
+        let (prefix, name) = match split {
+            Some(end) => (unquote(&line[..end]), &line[end + 1..]),
+            None => return Err(format!("line {}: expected prefix: rule", number + 1)),
+        };
+
+        let name = unquote(name.split(" #").next().unwrap().trim());
+        let rule = Rule::parse(name)
+            .ok_or_else(|| format!("line {}: unknown redaction {}", number + 1, name))?;
+
+        rules.push((prefix.to_owned(), rule));
+    }
+
+    Ok(rules)
+}
+
+/// The `EXPORT_REDACTIONS` rules and the `REDACTION_SECRET` `hash` is keyed by.
+pub(crate) struct Redactions {
+    rules: Vec<(String, Rule)>,
+    secret: Option<String>,
+    // Where the rules files `?redact=` can name instead are, `REDACTION_RULES_DIR`
+    dir: Option<PathBuf>,
+}
+
+impl Redactions {
+    pub(crate) fn from_env() -> Result<Redactions, String> {
+        let rules = parse_rules(&std::env::var("EXPORT_REDACTIONS").unwrap_or_default())
+            .map_err(|err| format!("EXPORT_REDACTIONS: {}", err))?;
+
+        let secret = std::env::var("REDACTION_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty());
+
+        if secret.is_none() && rules.iter().any(|(_, rule)| *rule == Rule::Hash) {
+            return Err(String::from(
+                "EXPORT_REDACTIONS: hash needs REDACTION_SECRET to key it",
+            ));
+        }
+
+        Ok(Redactions {
+            rules,
+            secret,
+            dir: std::env::var("REDACTION_RULES_DIR").ok().map(PathBuf::from),
+        })
+    }
+
+    /// The rules `?redact=` picks: `true` for `EXPORT_REDACTIONS`, or else the name of a
+    /// file in `REDACTION_RULES_DIR`, read again for every export so edits apply at once.
+    pub(crate) fn select(&self, name: &str) -> Result<Cow<'_, [(String, Rule)]>, String> {
+        if name == "true" {
+            // Rather than letting an export meant to be safe to share go out as is
+            if self.rules.is_empty() {
+                return Err(String::from("No redaction rules, set EXPORT_REDACTIONS"));
+            }
+
+            return Ok(Cow::Borrowed(&self.rules));
+        }
+
+        let dir = self
+            .dir
+            .as_ref()
+            .ok_or_else(|| String::from("No redaction rules files, set REDACTION_RULES_DIR"))?;
+
+        // Only files right in the directory, not paths leading out of it
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(format!("{} isn't a redaction rules file name", name));
+        }
+
+        let rules = match fs::read_to_string(dir.join(name)) {
+            Ok(contents) => parse_yaml(&contents).map_err(|err| format!("{}: {}", name, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(format!("No redaction rules file {}", name))
+            }
+            Err(err) => return Err(format!("{}: {}", name, err)),
+        };
+
+        if rules.is_empty() {
+            return Err(format!("{} has no redaction rules", name));
+        }
+
+        if self.secret.is_none() && rules.iter().any(|(_, rule)| *rule == Rule::Hash) {
+            return Err(format!(
+                "{} uses hash, set REDACTION_SECRET to key it",
+                name
+            ));
+        }
+
+        Ok(Cow::Owned(rules))
+    }
+
+    /// Masks `entries` by the most specific of `rules` each key is under, keys under none
+    /// are exported as they are.
+    pub(crate) fn apply(
+        &self,
+        rules: &[(String, Rule)],
+        entries: Vec<(String, String)>,
+    ) -> Vec<(String, String)> {
+        let secret = self.secret.as_deref().unwrap_or_default();
+
+        entries
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let rule = rules
+                    .iter()
+                    .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+                    .max_by_key(|(prefix, _)| prefix.len())
+                    .map(|(_, rule)| *rule);
+
+                match rule {
+                    Some(rule) => rule.mask(&value, secret).map(|value| (key, value)),
+                    None => Some((key, value)),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_by_the_most_specific_rule() {
+        let redactions = Redactions {
+            rules: parse_rules("user:=hash,user:card:=partial,user:password:=drop").unwrap(),
+            secret: Some(String::from("redaction-secret")),
+            dir: None,
+        };
+
+        let entries = vec![
+            (
+                String::from("user:card:a"),
+                String::from("4111111111111111"),
+            ),
+            (String::from("user:card:b"), String::from("1234")),
+            (String::from("user:email"), String::from("a@example.com")),
+            (String::from("user:password:a"), String::from("hunter2")),
+            (String::from("public"), String::from("1")),
+        ];
+
+        let redacted = redactions.apply(&redactions.select("true").unwrap(), entries);
+
+        assert_eq!(
+            redacted,
+            vec![
+                (
+                    String::from("user:card:a"),
+                    String::from("************1111")
+                ),
+                (String::from("user:card:b"), String::from("****")),
+                (
+                    String::from("user:email"),
+                    hash("a@example.com", "redaction-secret")
+                ),
+                (String::from("public"), String::from("1")),
+            ]
+        );
+
+        assert!(parse_rules("user:=shred").is_err());
+
+        // Keyed, so the same value hashes differently under another secret
+        assert_ne!(hash("a@example.com", "other-secret"), redacted[2].1);
+        assert_eq!(
+            hash("", "key"),
+            "5d5d139563c95b5967b9bd9a8c9b233a9dedb45072794cd232dc1b74832607d0"
+        );
+    }
+
+    #[test]
+    fn reads_rules_files() {
+        let file = "# shared with analytics
+---
+\"user:card:\": partial
+'user:': hash # joinable
+session: \"drop\"
+";
+
+        assert_eq!(
+            parse_yaml(file),
+            Ok(vec![
+                (String::from("user:card:"), Rule::Partial),
+                (String::from("user:"), Rule::Hash),
+                (String::from("session"), Rule::Drop),
+            ])
+        );
+        assert_eq!(
+            parse_yaml("user: shred"),
+            Err(String::from("line 1: unknown redaction shred"))
+        );
+        assert!(parse_yaml("user:card: partial").is_ok());
+        assert!(parse_yaml("just a line").is_err());
+
+        let dir = std::env::temp_dir().join(format!("kv-redact-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("prefixes.yaml"), "\"user:\": hash\n").unwrap();
+
+        let mut redactions = Redactions {
+            rules: Vec::new(),
+            secret: None,
+            dir: Some(dir),
+        };
+
+        assert_eq!(
+            redactions.select("prefixes.yaml").unwrap_err(),
+            "prefixes.yaml uses hash, set REDACTION_SECRET to key it"
+        );
+
+        redactions.secret = Some(String::from("redaction-secret"));
+
+        assert_eq!(
+            *redactions.select("prefixes.yaml").unwrap(),
+            [(String::from("user:"), Rule::Hash)]
+        );
+        assert!(redactions.select("../prefixes.yaml").is_err());
+        assert!(redactions.select("missing.yaml").is_err());
+        assert!(redactions.select("true").is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use axum::{http::StatusCode, Json};
+use heed::RoTxn;
+use serde_json::{json, Value};
+
+use crate::AppState;
+
+/// Parses `REFERENCES`, comma separated `prefix=target_prefix` pairs. Every value under
+/// `prefix` names a key under `target_prefix` that has to exist, so with `orders:=users:`
+/// an order whose value is `42` can only be written while `users:42` is there.
+pub(crate) fn parse(config: &str) -> Result<Vec<(String, String)>, String> {
+    config
+        .split(',')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            // LMDB refuses empty keys, even just to seek to, so the check can't scan for them
+            Some((prefix, target)) if !prefix.is_empty() => {
+                Ok((prefix.to_owned(), target.to_owned()))
+            }
+            _ => Err(format!(
+                "REFERENCES: expected prefix=target_prefix, got {}",
+                pair
+            )),
+        })
+        .collect()
+}
+
+// The keys `value` written to `key` has to reference
+fn targets<'a>(
+    state: &'a AppState,
+    key: &'a str,
+    value: &'a str,
+) -> impl Iterator<Item = String> + 'a {
+    state
+        .references
+        .iter()
+        .filter(move |(prefix, _)| key.starts_with(prefix.as_str()))
+        .map(move |(_, target)| format!("{}{}", target, value))
+}
+
+/// The response to send instead of writing `value` to `key` when it would reference a key
+/// that doesn't exist as of `txn`, so keys written before it in the same transaction count.
+pub(crate) fn check(
+    state: &AppState,
+    txn: &RoTxn,
+    key: &str,
+    value: &str,
+) -> Option<(StatusCode, Json<Value>)> {
+    for target in targets(state, key, value) {
+        match state.kv.get(txn, &target) {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                return Some((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(json!({
+                        "error": format!("{} references {}, which doesn't exist", key, target),
+                    })),
+                ))
+            }
+            Err(_) => {
+                return Some((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({ "error": "Internal server error" })),
+                ))
+            }
+        }
+    }
+
+    None
+}
+
+/// Every key referencing one that doesn't exist, along with it. Writes are checked, but the
+/// keys they reference can still be deleted later.
+pub(crate) fn dangling(state: &AppState, rtxn: &RoTxn) -> heed::Result<Vec<(String, String)>> {
+    let mut dangling = Vec::new();
+
+    for (prefix, _) in &state.references {
+        for entry in state.kv.prefix_iter(rtxn, prefix)? {
+            let (key, value) = entry?;
+
+            for target in targets(state, key, value) {
+                if state.kv.get(rtxn, &target)?.is_none() {
+                    dangling.push((key.to_owned(), target));
+                }
+            }
+        }
+    }
+
+    Ok(dangling)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request},
+        Router,
+    };
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    async fn status(
+        app: &mut Router,
+        method: http::Method,
+        uri: &str,
+        body: Option<Value>,
+    ) -> StatusCode {
+        let body = match body {
+            Some(body) => Body::from(body.to_string()),
+            None => Body::empty(),
+        };
+
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .unwrap();
+
+        app.ready()
+            .await
+            .unwrap()
+            .call(request)
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn refuses_writes_referencing_missing_keys() {
+        let mut app = setup_tests().await;
+
+        let order = json!({ "key": "ref:order:1", "value": "7" });
+
+        assert_eq!(
+            status(&mut app, http::Method::PUT, "/ref:order:1", Some(order)).await,
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+
+        // Along with the key it references works
+        assert_eq!(
+            status(
+                &mut app,
+                http::Method::POST,
+                "/import",
+                Some(json!({ "ref": { "user": { "7": "alice" }, "order": { "1": "7" } } })),
+            )
+            .await,
+            StatusCode::OK
+        );
+
+        assert_eq!(
+            status(&mut app, http::Method::DELETE, "/ref:user:7", None).await,
+            StatusCode::OK
+        );
+
+        let state = crate::app_state().unwrap();
+        let rtxn = state.read_txn().unwrap();
+
+        assert_eq!(
+            dangling(&state, &rtxn).unwrap(),
+            vec![(String::from("ref:order:1"), String::from("ref:user:7"))]
+        );
+    }
+}
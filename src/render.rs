@@ -0,0 +1,222 @@
+use axum::extract::{Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{alias, AppError, AppState};
+
+// Templates are plain text with `{{ name }}` replaced by the variable `name` and
+// `{{> key }}` by the value of `key`, itself rendered first. There's no escaping or
+// logic, it's for assembling config files from fragments.
+
+// How deep keys can include keys that include keys, which also catches cycles
+const MAX_DEPTH: usize = 8;
+
+enum RenderError {
+    // The template's fault, like a variable without a value
+    Template(String),
+    // Reading a key it includes failed
+    Storage(String),
+}
+
+// Renders `template`, reading included keys with `read`
+fn render(
+    template: &str,
+    variables: &HashMap<String, String>,
+    read: &mut dyn FnMut(&str) -> Result<Option<String>, String>,
+    depth: usize,
+) -> Result<String, RenderError> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| RenderError::Template(String::from("Unclosed {{")))?;
+        let tag = after[..end].trim();
+
+        match tag.strip_prefix('>') {
+            Some(key) => {
+                let key = key.trim();
+
+                if depth >= MAX_DEPTH {
+                    return Err(RenderError::Template(format!(
+                        "Including {} goes more than {} keys deep, is it including itself?",
+                        key, MAX_DEPTH
+                    )));
+                }
+
+                let included = read(key).map_err(RenderError::Storage)?.ok_or_else(|| {
+                    RenderError::Template(format!("Included key {} not found", key))
+                })?;
+
+                rendered.push_str(&render(&included, variables, read, depth + 1)?);
+            }
+            None => {
+                let value = variables
+                    .get(tag)
+                    .ok_or_else(|| RenderError::Template(format!("No value for {}", tag)))?;
+
+                rendered.push_str(value);
+            }
+        }
+
+        rest = &after[end + 2..];
+    }
+
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
+/// `GET /:key/render`: the value of `key` as a template, with its variables taken from the
+/// query string and the keys it includes read from the same transaction.
+pub(crate) async fn render_key(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+    Query(variables): Query<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+    let rtxn = state.read_txn().unwrap();
+
+    let mut read = |key: &str| {
+        let key = alias::resolve_key(&state, &rtxn, key)?;
+
+        state
+            .kv
+            .get(&rtxn, &key)
+            .map(|value| value.map(str::to_owned))
+            .map_err(|err| err.to_string())
+    };
+
+    let template = match read(&key) {
+        Ok(Some(template)) => template,
+        Ok(None) => {
+            return Ok((
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "Key not found" })),
+            )
+                .into_response())
+        }
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            )
+                .into_response())
+        }
+    };
+
+    match render(&template, &variables, &mut read, 0) {
+        Ok(rendered) => Ok((
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            rendered,
+        )
+            .into_response()),
+        Err(RenderError::Template(err)) => Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({ "error": err })),
+        )
+            .into_response()),
+        Err(RenderError::Storage(err)) => {
+            tracing::error!(%err, %key, "failed to read keys to render");
+
+            Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            )
+                .into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::{body::Body, http, http::Request};
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    fn render_with(template: &str, keys: &[(&str, &str)]) -> Result<String, String> {
+        let variables = HashMap::from([(String::from("region"), String::from("eu"))]);
+
+        let mut read = |key: &str| {
+            Ok(keys
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| value.to_string()))
+        };
+
+        render(template, &variables, &mut read, 0).map_err(|err| match err {
+            RenderError::Template(err) | RenderError::Storage(err) => err,
+        })
+    }
+
+    #[test]
+    fn substitutes_variables_and_keys() {
+        let keys = [
+            ("db", "host = db.{{ region }}.internal\n{{> db:port }}"),
+            ("db:port", "port = 5432"),
+            ("loop", "{{> loop }}"),
+        ];
+
+        assert_eq!(
+            render_with("[database]\n{{>db}}\n", &keys).unwrap(),
+            "[database]\nhost = db.eu.internal\nport = 5432\n"
+        );
+
+        assert!(render_with("{{ missing }}", &keys).is_err());
+        assert!(render_with("{{> missing }}", &keys).is_err());
+        assert!(render_with("{{ region", &keys).is_err());
+        assert!(render_with("{{> loop }}", &keys)
+            .unwrap_err()
+            .contains("including itself"));
+    }
+
+    #[tokio::test]
+    async fn renders_stored_templates() {
+        let mut app = setup_tests().await;
+
+        for (key, value) in [
+            ("render:app", "name = {{ name }}\n{{> render:shared }}"),
+            ("render:shared", "log_level = info"),
+        ] {
+            let request = Request::builder()
+                .method(http::Method::POST)
+                .uri("/")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    json!({ "key": key, "value": value }).to_string(),
+                ))
+                .unwrap();
+            app.ready().await.unwrap().call(request).await.unwrap();
+        }
+
+        let request = Request::builder()
+            .uri("/render:app/render?name=api")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        assert_eq!(body, "name = api\nlog_level = info");
+
+        let request = Request::builder()
+            .uri("/render:app/render")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
@@ -0,0 +1,349 @@
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use serde_json::{json, Value};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::immutable::ADMIN_TOKEN_HEADER;
+
+// Typing any unambiguous start of one is enough, `g` for `get`
+const COMMANDS: [&str; 9] = [
+    "get", "set", "del", "list", "count", "export", "history", "help", "quit",
+];
+
+// Anything the commands don't cover can still be sent as is
+const METHODS: [&str; 5] = ["GET", "PUT", "POST", "PATCH", "DELETE"];
+
+const HELP: &str = "\
+get <key>              GET /<key>
+set <key> <value>      PUT /<key>
+del <key>              DELETE /<key>
+list                   GET /
+count                  GET /count
+export [prefix]        GET /export?prefix=<prefix>
+<METHOD> <path> [body] sends any request, e.g. POST /batch/get [\"a\"]
+history                lists what was typed, !<n> runs line n again
+help, quit";
+
+/// Where `kv repl` connects, from `REPL_URL`, and where it keeps what was typed, from
+/// `REPL_HISTORY`. `ADMIN_TOKEN` goes along with every request when set.
+pub(crate) struct Config {
+    url: String,
+    history: Option<PathBuf>,
+    admin_token: Option<String>,
+}
+
+impl Config {
+    pub(crate) fn from_env() -> Config {
+        let history = match std::env::var("REPL_HISTORY") {
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => std::env::var("HOME")
+                .ok()
+                .map(|home| PathBuf::from(home).join(".kv_history")),
+        };
+
+        Config {
+            url: std::env::var("REPL_URL")
+                .unwrap_or_else(|_| String::from("http://localhost:3000"))
+                .trim_end_matches('/')
+                .to_owned(),
+            history,
+            admin_token: std::env::var("ADMIN_TOKEN").ok(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Send {
+        method: Method,
+        path: String,
+        body: Option<String>,
+    },
+    History,
+    Help,
+    Quit,
+}
+
+// The command `word` starts, or what it could have meant
+fn expand(word: &str) -> Result<&'static str, String> {
+    if let Some(method) = METHODS.iter().find(|method| **method == word) {
+        return Ok(method);
+    }
+
+    let matches: Vec<&'static str> = COMMANDS
+        .iter()
+        .copied()
+        .filter(|command| command.starts_with(word))
+        .collect();
+
+    match matches[..] {
+        [command] => Ok(command),
+        [] => Err(format!("Unknown command {}, try help", word)),
+        _ => Err(format!("{} could be {}", word, matches.join(", "))),
+    }
+}
+
+// Keys go in the path, so anything but unreserved characters is percent encoded
+fn encode(key: &str) -> String {
+    key.bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn parse(line: &str) -> Result<Command, String> {
+    let (word, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let rest = rest.trim();
+
+    let send = |method: Method, path: String, body: Option<String>| {
+        Ok(Command::Send { method, path, body })
+    };
+
+    let key = || match rest {
+        "" => Err(String::from("Which key?")),
+        key => Ok(encode(key)),
+    };
+
+    match expand(word)? {
+        "get" => send(Method::GET, format!("/{}", key()?), None),
+        "set" => {
+            let (key, value) = rest
+                .split_once(' ')
+                .ok_or_else(|| String::from("set takes a key and a value"))?;
+            let body = json!({ "key": key, "value": value.trim() }).to_string();
+
+            send(Method::PUT, format!("/{}", encode(key)), Some(body))
+        }
+        "del" => send(Method::DELETE, format!("/{}", key()?), None),
+        "list" => send(Method::GET, String::from("/"), None),
+        "count" => send(Method::GET, String::from("/count"), None),
+        "export" => send(
+            Method::GET,
+            format!("/export?prefix={}", encode(rest)),
+            None,
+        ),
+        "history" => Ok(Command::History),
+        "help" => Ok(Command::Help),
+        "quit" => Ok(Command::Quit),
+        method => {
+            let (path, body) = rest.split_once(' ').unwrap_or((rest, ""));
+
+            if !path.starts_with('/') {
+                return Err(format!("{} takes a path starting with /", method));
+            }
+
+            let body = Some(body.trim()).filter(|body| !body.is_empty());
+
+            send(
+                method.parse().unwrap(),
+                path.to_owned(),
+                body.map(String::from),
+            )
+        }
+    }
+}
+
+// JSON indented, anything else as it came
+fn pretty(body: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(value) => serde_json::to_string_pretty(&value).unwrap(),
+        Err(_) => String::from_utf8_lossy(body).into_owned(),
+    }
+}
+
+async fn send(
+    client: &Client<HttpConnector>,
+    config: &Config,
+    method: Method,
+    path: &str,
+    body: Option<String>,
+) -> Result<String, String> {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(format!("{}{}", config.url, path));
+
+    if body.is_some() {
+        request = request.header(hyper::header::CONTENT_TYPE, "application/json");
+    }
+
+    if let Some(token) = &config.admin_token {
+        request = request.header(ADMIN_TOKEN_HEADER, token);
+    }
+
+    let request = request
+        .body(body.map_or_else(Body::empty, Body::from))
+        .map_err(|err| err.to_string())?;
+
+    let response = client
+        .request(request)
+        .await
+        .map_err(|err| format!("can't reach {}: {}", config.url, err))?;
+
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|err| err.to_string())?;
+
+    Ok(format!("{}\n{}", status, pretty(&body)))
+}
+
+fn load_history(config: &Config) -> Vec<String> {
+    config
+        .history
+        .as_ref()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|history| history.lines().map(String::from).collect())
+        .unwrap_or_default()
+}
+
+// Losing the history isn't worth stopping over
+fn save_history(config: &Config, line: &str) {
+    if let Some(path) = &config.history {
+        let _ = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", line));
+    }
+}
+
+/// `kv repl`: a prompt sending commands to the server at `REPL_URL` and printing the
+/// responses, for operators looking around a live instance. Returns at `quit` or the end
+/// of input.
+pub(crate) async fn run(config: Config) {
+    let client = Client::new();
+    let mut history = load_history(&config);
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    println!("Connected to {}, help lists the commands", config.url);
+
+    loop {
+        print!("kv> ");
+        let _ = io::stdout().flush();
+
+        let mut line = match lines.next_line().await {
+            Ok(Some(line)) => line.trim().to_owned(),
+            _ => break,
+        };
+
+        if line.is_empty() {
+            continue;
+        }
+
+        // `!3` runs the third line of `history` again
+        if let Some(number) = line.strip_prefix('!') {
+            match number
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| history.get(n.wrapping_sub(1)))
+            {
+                Some(previous) => {
+                    line = previous.clone();
+                    println!("{}", line);
+                }
+                None => {
+                    println!("No line {} in the history", number);
+                    continue;
+                }
+            }
+        }
+
+        history.push(line.clone());
+        save_history(&config, &line);
+
+        match parse(&line) {
+            Ok(Command::Send { method, path, body }) => {
+                match send(&client, &config, method, &path, body).await {
+                    Ok(response) | Err(response) => println!("{}", response),
+                }
+            }
+            Ok(Command::History) => {
+                for (number, line) in history.iter().enumerate() {
+                    println!("{:>4}  {}", number + 1, line);
+                }
+            }
+            Ok(Command::Help) => println!("{}", HELP),
+            Ok(Command::Quit) => break,
+            Err(err) => println!("{}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            parse("g app:name"),
+            Ok(Command::Send {
+                method: Method::GET,
+                path: String::from("/app%3Aname"),
+                body: None,
+            })
+        );
+        assert_eq!(
+            parse("set greeting hello there"),
+            Ok(Command::Send {
+                method: Method::PUT,
+                path: String::from("/greeting"),
+                body: Some(json!({ "key": "greeting", "value": "hello there" }).to_string()),
+            })
+        );
+        assert_eq!(
+            parse("POST /batch/get [\"a\"]"),
+            Ok(Command::Send {
+                method: Method::POST,
+                path: String::from("/batch/get"),
+                body: Some(String::from("[\"a\"]")),
+            })
+        );
+        assert_eq!(parse("h"), Err(String::from("h could be history, help")));
+        assert_eq!(parse("get"), Err(String::from("Which key?")));
+        assert_eq!(parse("q"), Ok(Command::Quit));
+
+        assert_eq!(pretty(b"{\"a\":1}"), "{\n  \"a\": 1\n}");
+        assert_eq!(pretty(b"not json"), "not json");
+    }
+
+    #[tokio::test]
+    async fn sends_commands_to_a_server() {
+        let app = setup_tests().await;
+
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let config = Config {
+            url: format!("http://{}", server.local_addr()),
+            history: None,
+            admin_token: None,
+        };
+
+        tokio::spawn(server);
+
+        let client = Client::new();
+
+        for (line, expected) in [
+            ("set repl:key hello", "200 OK"),
+            ("get repl:key", "\"value\": \"hello\""),
+            ("del repl:key", "200 OK"),
+            ("get repl:key", "404 Not Found"),
+        ] {
+            let Ok(Command::Send { method, path, body }) = parse(line) else {
+                panic!("{} isn't a request", line);
+            };
+
+            let response = send(&client, &config, method, &path, body).await.unwrap();
+
+            assert!(response.contains(expected), "{}: {}", line, response);
+        }
+    }
+}
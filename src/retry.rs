@@ -0,0 +1,162 @@
+use heed::{MdbError, RoTxn, RwTxn};
+use std::sync::atomic::AtomicU64;
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tracing::info_span;
+
+use crate::{metrics, profile, AppState};
+
+// Backoff doubles after every attempt, so giving up takes 10 + 20 + 40 ms at most
+const MAX_RETRIES: u32 = 3;
+const BASE_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Errors that tend to clear up on their own, like another process growing the map or
+/// every reader slot being taken for a moment.
+fn is_transient(error: &heed::Error) -> bool {
+    matches!(
+        error,
+        heed::Error::Mdb(MdbError::MapResized | MdbError::ReadersFull | MdbError::BadRslot)
+    )
+}
+
+// Transactions are begun from handlers as well as the blocking pool, so once on a worker
+// thread the runtime is told to move its other tasks elsewhere while this one sleeps.
+// Runtimes with a single thread, like the tests', have nowhere to move them to
+fn pause(backoff: Duration) {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| thread::sleep(backoff))
+        }
+        _ => thread::sleep(backoff),
+    }
+}
+
+/// Runs `operation` again with a growing pause for as long as it fails transiently,
+/// counting every retry in `retries`.
+pub(crate) fn with_retry<T>(
+    retries: &AtomicU64,
+    mut operation: impl FnMut() -> heed::Result<T>,
+) -> heed::Result<T> {
+    let mut backoff = BASE_BACKOFF;
+
+    for _ in 0..MAX_RETRIES {
+        match operation() {
+            Err(error) if is_transient(&error) => {
+                metrics::increment(retries);
+
+                pause(backoff);
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+
+    operation()
+}
+
+// Each of these gets a span under the request's, so traces tell waiting on the write lock
+// and syncing to disk apart from the handler's own work, and a line in profiled requests'
+// `Server-Timing`
+impl AppState {
+    /// Begins a write transaction, retrying when LMDB reports a transient error.
+    pub(crate) fn write_txn(&self) -> heed::Result<RwTxn<'_, '_>> {
+        let _span = info_span!("write_txn").entered();
+
+        profile::time("write_lock", || {
+            with_retry(&self.metrics.transaction_retries, || {
+                #[cfg(feature = "chaos")]
+                self.chaos.disrupt()?;
+
+                self.kv_env.write_txn()
+            })
+        })
+    }
+
+    /// Begins a read transaction, retrying when LMDB reports a transient error.
+    pub(crate) fn read_txn(&self) -> heed::Result<RoTxn<'_>> {
+        let _span = info_span!("read_txn").entered();
+
+        profile::time("read_begin", || {
+            with_retry(&self.metrics.transaction_retries, || {
+                #[cfg(feature = "chaos")]
+                self.chaos.disrupt()?;
+
+                self.kv_env.read_txn()
+            })
+        })
+    }
+
+    /// Commits `wtxn`, which isn't retried as a failed commit has already been aborted.
+    pub(crate) fn commit(&self, wtxn: RwTxn) -> heed::Result<()> {
+        let _span = info_span!("commit").entered();
+
+        profile::time("commit", || wtxn.commit())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use std::time::Instant;
+
+    #[test]
+    fn retries_transient_errors_only() {
+        let retries = AtomicU64::new(0);
+        let mut attempts = 0;
+
+        let result = with_retry(&retries, || {
+            attempts += 1;
+
+            if attempts < 3 {
+                Err(heed::Error::Mdb(MdbError::ReadersFull))
+            } else {
+                Ok(attempts)
+            }
+        });
+
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(retries.load(Ordering::Relaxed), 2);
+
+        let mut attempts = 0;
+
+        let result: heed::Result<()> = with_retry(&retries, || {
+            attempts += 1;
+
+            Err(heed::Error::Mdb(MdbError::MapFull))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        // Gives up after the last retry
+        let mut attempts = 0;
+
+        let result: heed::Result<()> = with_retry(&retries, || {
+            attempts += 1;
+
+            Err(heed::Error::Mdb(MdbError::MapResized))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(attempts, MAX_RETRIES + 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn backs_off_without_holding_up_other_tasks() {
+        // Both on the one worker, the retries first
+        let retrying = tokio::spawn(async {
+            let retries = AtomicU64::new(0);
+
+            let _ = with_retry(&retries, || -> heed::Result<()> {
+                Err(heed::Error::Mdb(MdbError::ReadersFull))
+            });
+
+            Instant::now()
+        });
+        let other = tokio::spawn(async { Instant::now() });
+
+        assert!(other.await.unwrap() < retrying.await.unwrap());
+    }
+}
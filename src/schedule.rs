@@ -0,0 +1,420 @@
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cron::Cron;
+use crate::elections::Fence;
+use crate::{
+    delete_value, hooks, immutable, now_millis, put_value, references, refuse_locked, sizes,
+    system, wait, AppError, AppState,
+};
+
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Action {
+    Put,
+    Delete,
+}
+
+/// A write to make later, stored by its ID until it's made or cancelled.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Job {
+    key: String,
+    action: Action,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    // Unix time in milliseconds it's next made at
+    run_at: u64,
+    // Recurring jobs are scheduled again by this each time they run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cron: Option<String>,
+    // Scheduled with the admin override, so immutable keys can still be changed by it
+    overrides: bool,
+    // Scheduled by a leader, so it's only made while that's still the leader
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fence: Option<Fence>,
+}
+
+fn job_json(id: &str, job: &Job) -> Value {
+    json!({
+        "id": id,
+        "key": job.key,
+        "action": job.action,
+        "value": job.value,
+        "run_at": job.run_at,
+        "cron": job.cron,
+    })
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SchedulePayload {
+    key: String,
+    action: Action,
+    value: Option<String>,
+    // Unix time in milliseconds to run once at, or a cron expression to run on, in UTC
+    at: Option<u64>,
+    cron: Option<String>,
+}
+
+/// `POST /schedule`: puts or deletes a key later, once `at` a time or recurring by `cron`,
+/// for flipping a flag at midnight without anyone up to do it.
+pub(crate) async fn create(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<SchedulePayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let bad_request =
+        |error: String| Ok((StatusCode::BAD_REQUEST, Json(json!({ "error": error }))));
+
+    let checked = match (payload.action, &payload.value) {
+        // Hooks refusing the value would refuse it when due too
+        (Action::Put, Some(value)) => hooks::run(&state, &payload.key, value)
+            .err()
+            .or_else(|| sizes::check(&state, &payload.key, value)),
+        (Action::Put, None) => return bad_request(String::from("put needs a value")),
+        (Action::Delete, None) => sizes::check_key(&payload.key),
+        (Action::Delete, Some(_)) => return bad_request(String::from("delete takes no value")),
+    };
+
+    if let Some(response) = checked {
+        return Ok(response);
+    }
+
+    let run_at = match (payload.at, &payload.cron) {
+        (Some(at), None) => at,
+        (None, Some(cron)) => match Cron::parse(cron).map(|cron| cron.next_after(now_millis())) {
+            Ok(Some(run_at)) => run_at,
+            Ok(None) => return bad_request(format!("{} never runs", cron)),
+            Err(err) => return bad_request(format!("cron: {}", err)),
+        },
+        _ => return bad_request(String::from("Give either at or cron")),
+    };
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    // Refused now rather than when it's due, with nobody there to see it
+    if let Some(response) = refuse_locked(&state, &wtxn, &payload.key, &headers) {
+        return Ok(response);
+    }
+
+    let id = uuid::Uuid::new_v4().to_string();
+
+    let job = Job {
+        key: payload.key,
+        action: payload.action,
+        value: payload.value,
+        run_at,
+        cron: payload.cron,
+        overrides: immutable::admin_override(&state, &headers),
+        // Already read by `refuse_locked`
+        fence: Fence::from_headers(&headers).ok().flatten(),
+    };
+
+    state.scheduled.put(&mut wtxn, &id, &job).unwrap();
+
+    state.commit(wtxn).unwrap();
+
+    Ok((StatusCode::CREATED, Json(job_json(&id, &job))))
+}
+
+/// `GET /schedule`: the pending jobs, soonest first.
+pub(crate) async fn list(
+    State(state): State<Arc<AppState>>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let rtxn = state.read_txn().unwrap();
+
+    let jobs = state
+        .scheduled
+        .iter(&rtxn)
+        .and_then(|jobs| jobs.collect::<heed::Result<Vec<_>>>());
+
+    let mut jobs = match jobs {
+        Ok(jobs) => jobs,
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    };
+
+    jobs.sort_by_key(|(_, job)| job.run_at);
+
+    let jobs: Vec<Value> = jobs.iter().map(|(id, job)| job_json(id, job)).collect();
+
+    Ok((StatusCode::OK, Json(json!({ "jobs": jobs }))))
+}
+
+/// `DELETE /schedule/:id`: cancels a pending job, recurring ones for good.
+pub(crate) async fn cancel(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let mut wtxn = state.write_txn().unwrap();
+
+    match state.scheduled.delete(&mut wtxn, &id) {
+        Ok(true) => {
+            state.commit(wtxn).unwrap();
+
+            Ok((StatusCode::OK, Json(json!({ "id": id }))))
+        }
+        Ok(false) => Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Job not found" })),
+        )),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+/// Makes the writes of the jobs due by `now`, returning the keys written. Recurring jobs
+/// are scheduled for their next time after `now`, so one missed while the server was down
+/// runs once rather than once for every time it missed.
+pub(crate) fn run_due(state: &AppState, now: u64) -> heed::Result<Vec<String>> {
+    let mut wtxn = state.write_txn()?;
+
+    let due: Vec<(String, Job)> = state
+        .scheduled
+        .iter(&wtxn)?
+        .filter_map(|entry| match entry {
+            Ok((id, job)) if job.run_at <= now => Some(Ok((id.to_owned(), job))),
+            Ok(_) => None,
+            Err(err) => Some(Err(err)),
+        })
+        .collect::<heed::Result<_>>()?;
+
+    let mut written = Vec::new();
+
+    for (id, mut job) in due {
+        // The key may have become immutable since, which only the admin could override
+        let locked = system::refuse_write(&job.key).is_some()
+            || (!job.overrides && immutable::is_locked(state, &wtxn, &job.key)?);
+        let fenced = match &job.fence {
+            Some(fence) => !fence.is_current(state, &wtxn)?,
+            None => false,
+        };
+        // Hooks run once it's due, so `timestamp` stamps when it was written
+        let value = job
+            .value
+            .as_deref()
+            .map(|value| hooks::run(state, &job.key, value))
+            .transpose();
+
+        let skipped = match &value {
+            _ if locked => Some("the key is locked"),
+            _ if fenced => Some("its fencing token is stale"),
+            Err(_) => Some("a write hook refused it"),
+            Ok(Some(value)) if references::check(state, &wtxn, &job.key, value).is_some() => {
+                Some("it references a missing key")
+            }
+            Ok(_) => None,
+        };
+
+        match (skipped, value) {
+            (Some(reason), _) => {
+                tracing::warn!(%id, key = %job.key, reason, "skipped scheduled write");
+            }
+            (None, Ok(Some(value))) => {
+                put_value(state, &mut wtxn, &job.key, &value)?;
+                written.push(job.key.clone());
+            }
+            (None, _) => {
+                delete_value(state, &mut wtxn, &job.key)?;
+                written.push(job.key.clone());
+            }
+        }
+
+        let next = job
+            .cron
+            .as_deref()
+            .and_then(|cron| Cron::parse(cron).ok())
+            .and_then(|cron| cron.next_after(now));
+
+        match next {
+            Some(run_at) => {
+                job.run_at = run_at;
+                state.scheduled.put(&mut wtxn, &id, &job)?;
+            }
+            None => {
+                state.scheduled.delete(&mut wtxn, &id)?;
+            }
+        }
+    }
+
+    state.commit(wtxn)?;
+
+    Ok(written)
+}
+
+/// Runs the jobs that are due every [`SCHEDULER_INTERVAL`].
+pub(crate) fn spawn_scheduler(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SCHEDULER_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let written = {
+                let state = state.clone();
+
+                tokio::task::spawn_blocking(move || {
+                    run_due(&state, now_millis()).map_err(|err| err.to_string())
+                })
+            };
+
+            match written.await.unwrap() {
+                Ok(keys) if keys.is_empty() => {}
+                Ok(keys) => {
+                    for key in &keys {
+                        wait::notify(&state, key);
+                    }
+
+                    tracing::info!(keys = keys.len(), "ran scheduled writes");
+                }
+                Err(err) => tracing::error!(%err, "failed to run scheduled writes"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elections::{FENCING_ELECTION_HEADER, FENCING_TOKEN_HEADER};
+    use crate::tests::{send_json, setup_tests};
+    use axum::{
+        body::Body,
+        http::{self, Request},
+    };
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[tokio::test]
+    async fn runs_scheduled_writes_when_due() {
+        let mut app = setup_tests().await;
+        let state = crate::app_state().unwrap();
+
+        let now = now_millis();
+
+        let (status, once) = send_json(
+            &mut app,
+            http::Method::POST,
+            "/schedule",
+            json!({ "key": "scheduled:flag", "action": "put", "value": "on", "at": now }),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (_, daily) = send_json(
+            &mut app,
+            http::Method::POST,
+            "/schedule",
+            json!({ "key": "scheduled:flag", "action": "delete", "cron": "0 0 * * *" }),
+        )
+        .await;
+
+        assert!(daily["run_at"].as_u64().unwrap() > now);
+
+        let (_, body) = send_json(&mut app, http::Method::GET, "/schedule", json!(null)).await;
+        let ids: Vec<&Value> = body["jobs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|job| &job["id"])
+            .collect();
+
+        assert!(ids.contains(&&once["id"]) && ids.contains(&&daily["id"]));
+
+        assert!(run_due(&state, now)
+            .unwrap()
+            .contains(&String::from("scheduled:flag")));
+
+        let (_, body) =
+            send_json(&mut app, http::Method::GET, "/scheduled:flag", json!(null)).await;
+
+        assert_eq!(body["value"], "on");
+
+        // Ran once and gone, while the recurring one is still waiting
+        let uri = format!("/schedule/{}", once["id"].as_str().unwrap());
+        let (status, _) = send_json(&mut app, http::Method::DELETE, &uri, json!(null)).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let uri = format!("/schedule/{}", daily["id"].as_str().unwrap());
+        let (status, _) = send_json(&mut app, http::Method::DELETE, &uri, json!(null)).await;
+
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = send_json(
+            &mut app,
+            http::Method::POST,
+            "/schedule",
+            json!({ "key": "scheduled:flag", "action": "delete", "cron": "0 0 30 2 *" }),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn skips_writes_scheduled_by_a_replaced_leader() {
+        let mut app = setup_tests().await;
+        let state = crate::app_state().unwrap();
+
+        let campaign = json!({ "candidate": "a", "ttl_secs": 30 });
+        let (_, leader) = send_json(
+            &mut app,
+            http::Method::POST,
+            "/elections/scheduling/campaign",
+            campaign,
+        )
+        .await;
+
+        let now = now_millis();
+
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/schedule")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(FENCING_ELECTION_HEADER, "scheduling")
+            .header(FENCING_TOKEN_HEADER, leader["fencing_token"].to_string())
+            .body(Body::from(
+                json!({ "key": "scheduled:fenced", "action": "put", "value": "on", "at": now })
+                    .to_string(),
+            ))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let resign = json!({ "candidate": "a" });
+        send_json(
+            &mut app,
+            http::Method::POST,
+            "/elections/scheduling/resign",
+            resign,
+        )
+        .await;
+
+        assert!(run_due(&state, now).unwrap().is_empty());
+
+        let (status, _) = send_json(
+            &mut app,
+            http::Method::GET,
+            "/scheduled:fenced",
+            json!(null),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
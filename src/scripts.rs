@@ -0,0 +1,455 @@
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use mlua::{HookTriggers, Lua, LuaOptions, LuaSerdeExt, StdLib, VmState};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use tower_http::decompression::DecompressionBody;
+
+use crate::{
+    delete_value, elections, hooks, immutable, put_value, references, sizes, system, wait,
+    AppError, AppState,
+};
+
+// Scripts run while holding the write lock, so runaway loops are cut off after this many
+// VM instructions
+const INSTRUCTION_BUDGET: u32 = 10_000_000;
+const HOOK_INTERVAL: u32 = 10_000;
+
+/// The script routes, only built with the `scripting` feature.
+pub(crate) fn routes() -> Router<Arc<AppState>, DecompressionBody<Body>> {
+    Router::new()
+        // GET /scripts/:name
+        .route(
+            "/scripts/:name",
+            get(get_script).put(put_script).delete(delete_script),
+        )
+        // POST /scripts/:name/exec
+        .route("/scripts/:name/exec", post(exec))
+}
+
+/// Creates an interpreter limited to the side-effect free parts of the standard library.
+fn sandbox() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::UTF8 | StdLib::MATH,
+        LuaOptions::default(),
+    )?;
+
+    let executed = Cell::new(0u32);
+
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(HOOK_INTERVAL),
+        move |_, _| {
+            executed.set(executed.get() + HOOK_INTERVAL);
+
+            if executed.get() > INSTRUCTION_BUDGET {
+                return Err(mlua::Error::runtime(
+                    "script exceeded its instruction budget",
+                ));
+            }
+
+            Ok(VmState::Continue)
+        },
+    )?;
+
+    Ok(lua)
+}
+
+// heed errors aren't `Send`, so they can't be wrapped as external errors
+fn storage_error(error: heed::Error) -> mlua::Error {
+    mlua::Error::runtime(format!("storage error: {}", error))
+}
+
+// What a write handler would have answered with, raised in the script instead
+fn refused((_, Json(error)): (StatusCode, Json<Value>)) -> mlua::Error {
+    mlua::Error::runtime(error["error"].as_str().unwrap_or_default())
+}
+
+// Scripts get no admin override, immutable keys are only ever read from them
+fn ensure_unlocked(state: &AppState, wtxn: &heed::RwTxn, key: &str) -> mlua::Result<()> {
+    if immutable::is_locked(state, wtxn, key).map_err(storage_error)? {
+        return Err(mlua::Error::runtime(format!("key {} is immutable", key)));
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn get_script(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let rtxn = state.read_txn().unwrap();
+
+    let value = state.scripts.get(&rtxn, &name);
+
+    match value {
+        Ok(Some(source)) => Ok((
+            StatusCode::OK,
+            Json(json!({ "name": name, "source": source })),
+        )),
+        Ok(None) => Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Script not found" })),
+        )),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+pub(crate) async fn put_script(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    source: String,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    // Compile it up front so syntax errors show up now rather than on every exec
+    let compiled = sandbox().and_then(|lua| lua.load(&source).set_name(&name).into_function());
+
+    if let Err(error) = compiled {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": error.to_string() })),
+        ));
+    }
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    let value = state.scripts.put(&mut wtxn, &name, &source);
+
+    match value {
+        Ok(_) => {
+            state.commit(wtxn).unwrap();
+
+            Ok((StatusCode::OK, Json(json!({ "name": name }))))
+        }
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+pub(crate) async fn delete_script(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let mut wtxn = state.write_txn().unwrap();
+
+    let value = state.scripts.delete(&mut wtxn, &name);
+
+    match value {
+        Ok(true) => {
+            state.commit(wtxn).unwrap();
+
+            Ok((StatusCode::OK, Json(json!({ "name": name }))))
+        }
+        Ok(false) => Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Script not found" })),
+        )),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ExecPayload {
+    #[serde(default)]
+    args: Value,
+}
+
+/// Runs a stored script inside a single write transaction.
+///
+/// The script sees the request's `args` and a `kv` table with `get`, `put` and `delete`,
+/// and whatever it returns becomes the response. Its writes are only committed when it
+/// finishes without raising an error.
+pub(crate) async fn exec(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<ExecPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let wtxn = state.write_txn().unwrap();
+
+    // Everything it writes is written by the one transaction, so fenced as one write
+    if let Some(response) = elections::check_fence(&state, &wtxn, &headers) {
+        return Ok(response);
+    }
+
+    let source = match state.scripts.get(&wtxn, &name) {
+        Ok(Some(source)) => source.to_owned(),
+        Ok(None) => {
+            return Ok((
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "Script not found" })),
+            ))
+        }
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    };
+
+    let wtxn = RefCell::new(wtxn);
+    let touched = RefCell::new(Vec::new());
+
+    let result = sandbox().and_then(|lua| {
+        lua.scope(|scope| {
+            let kv = lua.create_table()?;
+
+            kv.set(
+                "get",
+                scope.create_function(|_, key: String| {
+                    let wtxn = wtxn.borrow();
+
+                    state
+                        .kv
+                        .get(&wtxn, &key)
+                        .map(|value| value.map(str::to_owned))
+                        .map_err(storage_error)
+                })?,
+            )?;
+
+            kv.set(
+                "put",
+                scope.create_function(|_, (key, value): (String, String)| {
+                    if let Some(response) =
+                        system::refuse_write(&key).or_else(|| sizes::check_key(&key))
+                    {
+                        return Err(refused(response));
+                    }
+
+                    ensure_unlocked(&state, &wtxn.borrow(), &key)?;
+
+                    let value = hooks::run(&state, &key, &value).map_err(refused)?;
+
+                    if let Some(response) = sizes::check_value(&state, &value)
+                        .or_else(|| references::check(&state, &wtxn.borrow(), &key, &value))
+                    {
+                        return Err(refused(response));
+                    }
+
+                    let created = put_value(&state, &mut wtxn.borrow_mut(), &key, &value)
+                        .map_err(storage_error)?;
+
+                    touched.borrow_mut().push(key);
+
+                    Ok(created)
+                })?,
+            )?;
+
+            kv.set(
+                "delete",
+                scope.create_function(|_, key: String| {
+                    if let Some(response) =
+                        system::refuse_write(&key).or_else(|| sizes::check_key(&key))
+                    {
+                        return Err(refused(response));
+                    }
+
+                    ensure_unlocked(&state, &wtxn.borrow(), &key)?;
+
+                    let deleted = delete_value(&state, &mut wtxn.borrow_mut(), &key)
+                        .map_err(storage_error)?;
+
+                    touched.borrow_mut().push(key);
+
+                    Ok(deleted)
+                })?,
+            )?;
+
+            lua.globals().set("kv", kv)?;
+            lua.globals().set("args", lua.to_value(&payload.args)?)?;
+
+            let returned: mlua::Value = lua.load(&source).set_name(&name).eval()?;
+
+            lua.from_value::<Value>(returned)
+        })
+    });
+
+    match result {
+        Ok(result) => {
+            state.commit(wtxn.into_inner()).unwrap();
+
+            for key in touched.into_inner() {
+                wait::notify(&state, &key);
+            }
+
+            Ok((StatusCode::OK, Json(json!({ "result": result }))))
+        }
+        // Dropping the transaction aborts every write the script made
+        Err(error) => Ok((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": error.to_string() })),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::elections::{FENCING_ELECTION_HEADER, FENCING_TOKEN_HEADER};
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request, StatusCode},
+        Router,
+    };
+    use serde_json::{json, Value};
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    async fn put_script(app: &mut Router, name: &str, source: &str) -> StatusCode {
+        let request = Request::builder()
+            .method(http::Method::PUT)
+            .uri(format!("/scripts/{}", name))
+            .body(Body::from(source.to_owned()))
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        response.status()
+    }
+
+    async fn exec(app: &mut Router, name: &str, args: Value) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri(format!("/scripts/{}/exec", name))
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({ "args": args }).to_string()))
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_scripts() {
+        let mut app = setup_tests().await;
+
+        assert_eq!(
+            put_script(&mut app, "broken", "return (").await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn runs_against_the_store() {
+        let mut app = setup_tests().await;
+
+        let source = r#"
+            local key = args[1]
+            local current = tonumber(kv.get(key) or "0")
+            kv.put(key, tostring(current + args[2]))
+            return current + args[2]
+        "#;
+
+        assert_eq!(put_script(&mut app, "incr", source).await, StatusCode::OK);
+
+        let (status, body) = exec(&mut app, "incr", json!(["script-counter", 5])).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "result": 5 }));
+
+        let (_, body) = exec(&mut app, "incr", json!(["script-counter", 2])).await;
+
+        assert_eq!(body, json!({ "result": 7 }));
+    }
+
+    #[tokio::test]
+    async fn failed_scripts_write_nothing() {
+        let mut app = setup_tests().await;
+
+        let source = r#"
+            kv.put("script-aborted", "written")
+            error("changed my mind")
+        "#;
+
+        put_script(&mut app, "abort", source).await;
+
+        let (status, _) = exec(&mut app, "abort", json!(null)).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let request = Request::builder()
+            .uri("/script-aborted")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn runaway_scripts_are_stopped() {
+        let mut app = setup_tests().await;
+
+        put_script(&mut app, "forever", "while true do end").await;
+
+        let (status, body) = exec(&mut app, "forever", json!(null)).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .contains("instruction budget"));
+    }
+
+    #[tokio::test]
+    async fn scripts_write_only_what_handlers_would() {
+        let mut app = setup_tests().await;
+
+        put_script(&mut app, "put", "return kv.put(args[1], args[2])").await;
+        put_script(&mut app, "delete", "return kv.delete(args[1])").await;
+
+        let refusals = [
+            (
+                "put",
+                json!(["__system/metrics/kv_requests_total", "0"]),
+                "read-only",
+            ),
+            (
+                "delete",
+                json!(["__system/metrics/kv_requests_total"]),
+                "read-only",
+            ),
+            // Over MAX_VALUE_BYTES
+            ("put", json!(["script-big", "x".repeat(4097)]), "4097 bytes"),
+            ("put", json!(["", "empty"]), "can't be empty"),
+        ];
+
+        for (name, args, error) in refusals {
+            let (status, body) = exec(&mut app, name, args).await;
+
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert!(body["error"].as_str().unwrap().contains(error), "{}", body);
+        }
+
+        // Sent by a leader since replaced
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/scripts/put/exec")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(FENCING_ELECTION_HEADER, "never-held")
+            .header(FENCING_TOKEN_HEADER, "1")
+            .body(Body::from(
+                json!({ "args": ["script-fenced", "1"] }).to_string(),
+            ))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+}
@@ -0,0 +1,80 @@
+use serde::Deserialize;
+use std::fs;
+
+use crate::{put_value, AppState};
+
+#[derive(Deserialize)]
+struct SeedEntry {
+    key: String,
+    value: String,
+}
+
+/// Loads an NDJSON fixture of `{"key": ..., "value": ...}` lines given with `--seed`.
+///
+/// Nothing is written unless the database is empty, so restarting a seeded instance
+/// keeps whatever it holds by then. Returns how many keys were loaded, `None` when it
+/// wasn't empty. The whole file goes in one transaction, a bad line loads nothing.
+pub(crate) fn load(state: &AppState, path: &str) -> Result<Option<usize>, String> {
+    let fixture = fs::read_to_string(path).map_err(|err| err.to_string())?;
+
+    let mut wtxn = state.write_txn().map_err(|err| err.to_string())?;
+
+    if !state.kv.is_empty(&wtxn).map_err(|err| err.to_string())? {
+        return Ok(None);
+    }
+
+    let mut loaded = 0;
+
+    for (number, line) in fixture.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry: SeedEntry =
+            serde_json::from_str(line).map_err(|err| format!("line {}: {}", number + 1, err))?;
+
+        put_value(state, &mut wtxn, &entry.key, &entry.value).map_err(|err| err.to_string())?;
+
+        loaded += 1;
+    }
+
+    state.commit(wtxn).map_err(|err| err.to_string())?;
+
+    Ok(Some(loaded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+
+    #[tokio::test]
+    async fn seeds_an_empty_database_once() {
+        // Clears the keys left by other tests
+        let _ = setup_tests().await;
+
+        let state = crate::app_state().unwrap();
+
+        let path = "db/seed_test.ndjson";
+        fs::write(
+            path,
+            "{\"key\":\"seed-a\",\"value\":\"1\"}\n\n{\"key\":\"seed-b\",\"value\":\"2\"}\n",
+        )
+        .unwrap();
+
+        assert_eq!(load(&state, path), Ok(Some(2)));
+
+        let rtxn = state.read_txn().unwrap();
+        assert_eq!(state.kv.get(&rtxn, "seed-b").unwrap(), Some("2"));
+        drop(rtxn);
+
+        assert_eq!(load(&state, path), Ok(None));
+
+        let _ = setup_tests().await;
+
+        fs::write(path, "{\"key\":\"seed-a\",\"value\":\"1\"}\nnot json\n").unwrap();
+
+        assert!(load(&state, path).unwrap_err().starts_with("line 2:"));
+        assert!(state.kv.is_empty(&state.read_txn().unwrap()).unwrap());
+    }
+}
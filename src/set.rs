@@ -0,0 +1,271 @@
+use axum::extract::{Path, Query, State};
+use axum::{http::StatusCode, Json};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use crate::{name_prefix, record_keys, sizes, AppError, AppState};
+
+// Members are stored as `[name length][name][member]` keys with an empty value
+
+fn member_key(name: &str, member: &str) -> Vec<u8> {
+    let mut key = name_prefix(name);
+    key.extend_from_slice(member.as_bytes());
+    key
+}
+
+fn members_of(state: &AppState, rtxn: &heed::RoTxn, name: &str) -> heed::Result<BTreeSet<String>> {
+    let prefix = name_prefix(name);
+
+    state
+        .set
+        .prefix_iter(rtxn, &prefix)?
+        .map(|entry| {
+            entry.map(|(key, _)| String::from_utf8_lossy(&key[prefix.len()..]).into_owned())
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+pub(crate) struct MemberPayload {
+    member: String,
+}
+
+pub(crate) async fn add(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(payload): Json<MemberPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let key = member_key(&name, &payload.member);
+
+    if let Some(response) = sizes::check_built_key(&key, "The set name and member") {
+        return Ok(response);
+    }
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    let existing = match state.set.get(&wtxn, &key) {
+        Ok(existing) => existing,
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    };
+
+    if existing.is_some() {
+        return Ok((StatusCode::OK, Json(json!({ "member": payload.member }))));
+    }
+
+    state.set.put(&mut wtxn, &key, &()).unwrap();
+
+    state.commit(wtxn).unwrap();
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "member": payload.member })),
+    ))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct MembersQuery {
+    // Comma separated names of the sets to combine with this one
+    union: Option<String>,
+    intersection: Option<String>,
+}
+
+pub(crate) async fn members(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<MembersQuery>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    if query.union.is_some() && query.intersection.is_some() {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Only one of union or intersection can be given" })),
+        ));
+    }
+
+    let rtxn = state.read_txn().unwrap();
+
+    let mut members = match members_of(&state, &rtxn, &name) {
+        Ok(members) => members,
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    };
+
+    // Everything is read from the same transaction so the result is a consistent snapshot
+    if let Some(others) = &query.union {
+        for other in others.split(',') {
+            match members_of(&state, &rtxn, other) {
+                Ok(other) => members.extend(other),
+                Err(_) => {
+                    return Ok((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(json!({ "error": "Internal server error" })),
+                    ))
+                }
+            }
+        }
+    }
+
+    if let Some(others) = &query.intersection {
+        for other in others.split(',') {
+            let mut kept = BTreeSet::new();
+
+            for member in members {
+                match state.set.get(&rtxn, &member_key(other, &member)) {
+                    Ok(Some(_)) => {
+                        kept.insert(member);
+                    }
+                    Ok(None) => {}
+                    Err(_) => {
+                        return Ok((
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            Json(json!({ "error": "Internal server error" })),
+                        ))
+                    }
+                }
+            }
+
+            members = kept;
+        }
+    }
+
+    record_keys(members.len());
+
+    Ok((StatusCode::OK, Json(json!(members))))
+}
+
+pub(crate) async fn contains(
+    State(state): State<Arc<AppState>>,
+    Path((name, member)): Path<(String, String)>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let rtxn = state.read_txn().unwrap();
+
+    let value = state.set.get(&rtxn, &member_key(&name, &member));
+
+    match value {
+        Ok(Some(_)) => Ok((StatusCode::OK, Json(json!({ "member": member })))),
+        Ok(None) => Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Member not found" })),
+        )),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+pub(crate) async fn remove(
+    State(state): State<Arc<AppState>>,
+    Path((name, member)): Path<(String, String)>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let mut wtxn = state.write_txn().unwrap();
+
+    let value = state.set.delete(&mut wtxn, &member_key(&name, &member));
+
+    match value {
+        Ok(true) => {
+            state.commit(wtxn).unwrap();
+
+            Ok((StatusCode::OK, Json(json!({ "member": member }))))
+        }
+        Ok(false) => Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Member not found" })),
+        )),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{get_json, setup_tests};
+    use axum::{
+        body::Body,
+        http::{self, Request},
+        Router,
+    };
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    async fn add_member(app: &mut Router, name: &str, member: &str) -> StatusCode {
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri(format!("/set/{}", name))
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({ "member": member }).to_string()))
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        response.status()
+    }
+
+    #[tokio::test]
+    async fn add_contains_and_remove() {
+        let mut app = setup_tests().await;
+
+        add_member(&mut app, "membership-test", "rust").await;
+
+        // Adding twice is a no-op
+        assert_eq!(
+            add_member(&mut app, "membership-test", "rust").await,
+            StatusCode::OK
+        );
+
+        let (status, _) = get_json(&mut app, "/set/membership-test/rust").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let request = Request::builder()
+            .method(http::Method::DELETE)
+            .uri("/set/membership-test/rust")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let (status, _) = get_json(&mut app, "/set/membership-test/rust").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Too long to store along with the name
+        assert_eq!(
+            add_member(&mut app, "membership-test", &"m".repeat(500)).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn union_and_intersection() {
+        let mut app = setup_tests().await;
+
+        for member in ["a", "b", "c"] {
+            add_member(&mut app, "combine-left", member).await;
+        }
+        for member in ["b", "c", "d"] {
+            add_member(&mut app, "combine-right", member).await;
+        }
+
+        let (status, body) = get_json(&mut app, "/set/combine-left").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!(["a", "b", "c"]));
+
+        let (_, body) = get_json(&mut app, "/set/combine-left?union=combine-right").await;
+        assert_eq!(body, json!(["a", "b", "c", "d"]));
+
+        let (_, body) = get_json(&mut app, "/set/combine-left?intersection=combine-right").await;
+        assert_eq!(body, json!(["b", "c"]));
+    }
+}
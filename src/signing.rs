@@ -0,0 +1,332 @@
+use axum::body::{Body, HttpBody};
+use axum::extract::State;
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::{digest, now_millis, AppState};
+
+// Signed requests carry `Authorization: KV-HMAC-SHA256 Credential=<access key>,
+// Signature=<hex>` and the Unix time in seconds they were signed at in `X-KV-Date`. The
+// signature is the HMAC-SHA256, keyed with the access key's secret, of
+//
+//     <method>\n<path and query>\n<X-KV-Date>\n<hex SHA-256 of the body>
+//
+// the body as sent, compressed or not. A request is only good within `max_skew_secs`
+// of when it was signed, so one picked up along the way can't be replayed for long.
+
+const SCHEME: &str = "KV-HMAC-SHA256 ";
+/// When the request was signed, Unix time in seconds.
+pub(crate) const DATE_HEADER: &str = "x-kv-date";
+/// Set to the access key of requests whose signature checked out, and dropped from any
+/// other request, so handlers can trust it.
+pub(crate) const SIGNED_BY_HEADER: &str = "x-kv-signed-by";
+
+const DEFAULT_MAX_SKEW_SECS: u64 = 300;
+const DEFAULT_MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// The access keys requests can be signed with, `SIGNING_KEYS`.
+pub(crate) struct Config {
+    keys: Vec<(String, String)>,
+    max_skew_secs: u64,
+    // Signed bodies are read whole to check them, so anyone can make the server buffer
+    // this much before the signature is known to be bad
+    max_body_bytes: usize,
+}
+
+impl Config {
+    /// Reads `SIGNING_KEYS`, comma separated `access_key=secret` pairs,
+    /// `SIGNING_MAX_SKEW_SECS` and `SIGNING_MAX_BODY_BYTES`.
+    pub(crate) fn from_env() -> Result<Config, String> {
+        let keys = std::env::var("SIGNING_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((access_key, secret)) if !access_key.is_empty() && !secret.is_empty() => {
+                    Ok((access_key.to_owned(), secret.to_owned()))
+                }
+                _ => Err(format!(
+                    "SIGNING_KEYS: expected access_key=secret, got {}",
+                    pair
+                )),
+            })
+            .collect::<Result<_, _>>()?;
+
+        let max_skew_secs = match std::env::var("SIGNING_MAX_SKEW_SECS") {
+            Ok(secs) => secs
+                .parse()
+                .map_err(|_| format!("SIGNING_MAX_SKEW_SECS: expected seconds, got {}", secs))?,
+            Err(_) => DEFAULT_MAX_SKEW_SECS,
+        };
+
+        let max_body_bytes = match std::env::var("SIGNING_MAX_BODY_BYTES") {
+            Ok(bytes) => bytes
+                .parse()
+                .map_err(|_| format!("SIGNING_MAX_BODY_BYTES: expected bytes, got {}", bytes))?,
+            Err(_) => DEFAULT_MAX_BODY_BYTES,
+        };
+
+        Ok(Config {
+            keys,
+            max_skew_secs,
+            max_body_bytes,
+        })
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn string_to_sign(method: &str, path: &str, date: &str, body: &[u8]) -> String {
+    format!(
+        "{}\n{}\n{}\n{}",
+        method,
+        path,
+        date,
+        digest::hex(&Sha256::digest(body).into())
+    )
+}
+
+fn mac(secret: &str, string_to_sign: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(string_to_sign.as_bytes());
+    mac
+}
+
+// The access key a signed request was signed with, or why it can't be trusted
+fn check(
+    config: &Config,
+    headers: &HeaderMap,
+    authorization: &str,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> Result<String, &'static str> {
+    let mut credential = None;
+    let mut signature = None;
+
+    for part in authorization.split(',') {
+        match part.trim().split_once('=') {
+            Some(("Credential", value)) => credential = Some(value),
+            Some(("Signature", value)) => signature = Some(value),
+            _ => {}
+        }
+    }
+
+    let (credential, signature) = match (credential, signature.and_then(decode_hex)) {
+        (Some(credential), Some(signature)) => (credential, signature),
+        _ => return Err("Authorization needs a Credential and a hex Signature"),
+    };
+
+    let date = headers
+        .get(DATE_HEADER)
+        .and_then(|date| date.to_str().ok())
+        .ok_or("Signed requests need X-KV-Date")?;
+    let signed_at: u64 = date.parse().map_err(|_| "X-KV-Date must be Unix seconds")?;
+
+    if (now_millis() / 1000).abs_diff(signed_at) > config.max_skew_secs {
+        return Err("Request was signed too long ago, or with a clock that's off");
+    }
+
+    let secret = config
+        .keys
+        .iter()
+        .find(|(access_key, _)| access_key == credential)
+        .map(|(_, secret)| secret)
+        .ok_or("Invalid signature")?;
+
+    mac(secret, &string_to_sign(method, path, date, body))
+        .verify_slice(&signature)
+        .map_err(|_| "Invalid signature")?;
+
+    Ok(credential.to_owned())
+}
+
+// The whole of `body`, or the response to send when it's over `max` bytes
+async fn read_body(mut body: Body, max: usize) -> Result<Vec<u8>, Response> {
+    let mut read = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+
+        if read.len() + chunk.len() > max {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(json!({
+                    "error": format!(
+                        "Signed bodies can be at most {} bytes, the SIGNING_MAX_BODY_BYTES limit",
+                        max
+                    )
+                })),
+            )
+                .into_response());
+        }
+
+        read.extend_from_slice(&chunk);
+    }
+
+    Ok(read)
+}
+
+/// Checks the signature of requests signed with one of `SIGNING_KEYS`, which then go
+/// wherever the admin token does, for clients that can sign requests but can't keep a
+/// long-lived token safe. A bad signature gets a 401, unsigned requests go on as they are.
+pub(crate) async fn verify(
+    State(state): State<Arc<AppState>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let (mut parts, body) = request.into_parts();
+
+    parts.headers.remove(SIGNED_BY_HEADER);
+
+    let authorization = parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix(SCHEME))
+        .map(str::to_owned);
+
+    let authorization = match authorization {
+        Some(authorization) => authorization,
+        None => return next.run(Request::from_parts(parts, body)).await,
+    };
+
+    let body = match read_body(body, state.signing.max_body_bytes).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+
+    let path = parts
+        .uri
+        .path_and_query()
+        .map_or(parts.uri.path(), |path| path.as_str());
+
+    match check(
+        &state.signing,
+        &parts.headers,
+        &authorization,
+        parts.method.as_str(),
+        path,
+        &body,
+    ) {
+        Ok(access_key) => {
+            // Access keys come from `SIGNING_KEYS`, a header value they'd make already
+            if let Ok(access_key) = HeaderValue::from_str(&access_key) {
+                parts.headers.insert(SIGNED_BY_HEADER, access_key);
+            }
+
+            next.run(Request::from_parts(parts, Body::from(body))).await
+        }
+        Err(err) => (StatusCode::UNAUTHORIZED, Json(json!({ "error": err }))).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::immutable;
+    use crate::tests::setup_tests;
+    use axum::{http, Router};
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    fn signed(method: http::Method, uri: &str, date: u64, body: &str) -> Request<Body> {
+        let date = date.to_string();
+        let signature = mac(
+            "test-secret",
+            &string_to_sign(method.as_str(), uri, &date, body.as_bytes()),
+        )
+        .finalize()
+        .into_bytes();
+
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(
+                header::AUTHORIZATION,
+                format!(
+                    "{}Credential=test-access, Signature={}",
+                    SCHEME,
+                    digest::hex(&signature.into())
+                ),
+            )
+            .header(DATE_HEADER, date)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_owned()))
+            .unwrap()
+    }
+
+    async fn status(app: &mut Router, request: Request<Body>) -> StatusCode {
+        app.ready()
+            .await
+            .unwrap()
+            .call(request)
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn signed_requests_stand_in_for_the_admin_token() {
+        let mut app = setup_tests().await;
+
+        let now = now_millis() / 1000;
+
+        assert_eq!(
+            status(
+                &mut app,
+                signed(http::Method::GET, "/admin/expiry", now, "")
+            )
+            .await,
+            StatusCode::OK
+        );
+
+        let stale = signed(http::Method::GET, "/admin/expiry", now - 3600, "");
+
+        assert_eq!(status(&mut app, stale).await, StatusCode::UNAUTHORIZED);
+
+        // Signed for another body than the one sent
+        let mut tampered = signed(http::Method::PUT, "/admin/faults", now, "{}");
+        *tampered.body_mut() = Body::from(r#"{"route": "/:key", "error_rate": 1}"#);
+
+        assert_eq!(status(&mut app, tampered).await, StatusCode::UNAUTHORIZED);
+
+        // Too large to buffer for checking
+        let large = "x".repeat(DEFAULT_MAX_BODY_BYTES + 1);
+        let large = signed(http::Method::PUT, "/admin/faults", now, &large);
+
+        assert_eq!(status(&mut app, large).await, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Claiming to be signed without a signature gets nowhere
+        let forged = Request::builder()
+            .uri("/admin/expiry")
+            .header(SIGNED_BY_HEADER, "test-access")
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(status(&mut app, forged).await, StatusCode::FORBIDDEN);
+
+        let unsigned = Request::builder()
+            .uri("/admin/expiry")
+            .header(immutable::ADMIN_TOKEN_HEADER, "test-admin-token")
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(status(&mut app, unsigned).await, StatusCode::OK);
+    }
+}
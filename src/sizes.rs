@@ -0,0 +1,180 @@
+use axum::{http::StatusCode, Json};
+use serde_json::{json, Value};
+
+use crate::AppState;
+
+// The longest key LMDB stores in its default build, longer ones fail deep in the write
+const LMDB_MAX_KEY_BYTES: usize = 511;
+
+/// The longest tag a key can have.
+pub(crate) const MAX_TAG_BYTES: usize = 32;
+
+/// The longest key that can be written, less what the tag index adds to it for its own
+/// keys, a 4 byte length and the tag. The history adds less, a 4 byte length and 8 byte
+/// version.
+pub(crate) const MAX_KEY_BYTES: usize = LMDB_MAX_KEY_BYTES - 4 - MAX_TAG_BYTES;
+
+/// The cap on value sizes from `MAX_VALUE_BYTES`, if any.
+pub(crate) fn max_value_from_env() -> Result<Option<usize>, String> {
+    match std::env::var("MAX_VALUE_BYTES") {
+        Ok(max) => match max.parse() {
+            Ok(0) | Err(_) => Err(format!(
+                "MAX_VALUE_BYTES must be a positive number, got {}",
+                max
+            )),
+            Ok(max) => Ok(Some(max)),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// The error to answer with instead of writing `key`, if LMDB wouldn't take it.
+pub(crate) fn check_key(key: &str) -> Option<(StatusCode, Json<Value>)> {
+    if key.is_empty() {
+        return Some((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Key can't be empty" })),
+        ));
+    }
+
+    if key.len() > MAX_KEY_BYTES {
+        return Some((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": format!(
+                    "Key is {} bytes, keys can be at most {}",
+                    key.len(),
+                    MAX_KEY_BYTES
+                )
+            })),
+        ));
+    }
+
+    None
+}
+
+/// The error to answer with instead of writing `key`, built from a name and member or the
+/// like, if LMDB wouldn't take it. `what` says what it was built from.
+pub(crate) fn check_built_key(key: &[u8], what: &str) -> Option<(StatusCode, Json<Value>)> {
+    (key.len() > LMDB_MAX_KEY_BYTES).then(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": format!(
+                    "{} take {} bytes stored, at most {} fit",
+                    what,
+                    key.len(),
+                    LMDB_MAX_KEY_BYTES
+                )
+            })),
+        )
+    })
+}
+
+/// The error to answer with instead of tagging a key with `tags`, if one is too long for
+/// the index or all of them are over `MAX_VALUE_BYTES`.
+pub(crate) fn check_tags<'a>(
+    state: &AppState,
+    tags: impl IntoIterator<Item = &'a String>,
+) -> Option<(StatusCode, Json<Value>)> {
+    let mut length = 0;
+
+    for tag in tags {
+        if tag.len() > MAX_TAG_BYTES {
+            return Some((
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": format!(
+                        "Tag is {} bytes, tags can be at most {}",
+                        tag.len(),
+                        MAX_TAG_BYTES
+                    )
+                })),
+            ));
+        }
+
+        length += tag.len();
+    }
+
+    check_length(state, length)
+}
+
+/// The error to answer with instead of writing `value`, if it's over `MAX_VALUE_BYTES`.
+pub(crate) fn check_value(state: &AppState, value: &str) -> Option<(StatusCode, Json<Value>)> {
+    check_length(state, value.len())
+}
+
+/// The same for a value of `length` bytes not all here yet, like an upload's.
+pub(crate) fn check_length(state: &AppState, length: usize) -> Option<(StatusCode, Json<Value>)> {
+    let max = state.max_value_bytes?;
+
+    (length > max).then(|| {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(json!({
+                "error": format!(
+                    "Value is {} bytes, over the MAX_VALUE_BYTES limit of {}",
+                    length, max
+                )
+            })),
+        )
+    })
+}
+
+/// Both of the above, checked before a write transaction is started for them.
+pub(crate) fn check(state: &AppState, key: &str, value: &str) -> Option<(StatusCode, Json<Value>)> {
+    check_key(key).or_else(|| check_value(state, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request},
+        Router,
+    };
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    async fn put(app: &mut Router, key: &str, value: &str) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "key": key, "value": value }).to_string(),
+            ))
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_keys_and_values() {
+        let mut app = setup_tests().await;
+
+        let (status, body) = put(&mut app, &"k".repeat(MAX_KEY_BYTES + 1), "v").await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "Key is 476 bytes, keys can be at most 475");
+
+        let (status, _) = put(&mut app, "", "v").await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = put(&mut app, "sized", &"v".repeat(4097)).await;
+
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(body["error"].as_str().unwrap().contains("MAX_VALUE_BYTES"));
+
+        let (status, _) = put(&mut app, &"k".repeat(MAX_KEY_BYTES), "v").await;
+
+        assert_eq!(status, StatusCode::CREATED);
+    }
+}
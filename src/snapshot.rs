@@ -0,0 +1,161 @@
+use axum::body::{Bytes, StreamBody};
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{feed, immutable, AppState};
+
+/// `GET /admin/snapshot`: every key with its value and version as newline delimited JSON,
+/// read in one transaction, for bootstrapping a follower without copying `DB_PATH`.
+///
+/// The first line is `{"sequence": n}`, the last change feed event the snapshot includes,
+/// so the follower carries on with `GET /watch?since=n` and neither misses nor repeats a
+/// write. The transaction stays open until the last key is sent, keeping the pages it
+/// reads from being reused meanwhile.
+pub(crate) async fn snapshot(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if !immutable::admin_override(&state, &headers) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Snapshots can only be taken with the admin token" })),
+        )
+            .into_response();
+    }
+
+    // Without it there's no sequence to resume from
+    if !state.feed_enabled {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "The change feed isn't enabled" })),
+        )
+            .into_response();
+    }
+
+    let (sender, receiver) = mpsc::channel::<Result<Bytes, String>>(2);
+
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = send_snapshot(&state, &sender) {
+            // Too late for an error status, cutting the body short tells the client
+            let _ = sender.blocking_send(Err(err.to_string()));
+        }
+    });
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        StreamBody::new(ReceiverStream::new(receiver)),
+    )
+        .into_response()
+}
+
+fn send_snapshot(
+    state: &AppState,
+    sender: &mpsc::Sender<Result<Bytes, String>>,
+) -> heed::Result<()> {
+    let rtxn = state.read_txn()?;
+
+    let mut chunk = format!(
+        "{}\n",
+        json!({ "sequence": feed::last_sequence(state, &rtxn)? })
+    );
+    let mut lines = 0;
+
+    for entry in state.kv.iter(&rtxn)? {
+        let (key, value) = entry?;
+        let version = state.versions.get(&rtxn, key)?.unwrap_or(0);
+
+        chunk.push_str(&json!({ "key": key, "value": value, "version": version }).to_string());
+        chunk.push('\n');
+
+        lines += 1;
+
+        if lines == state.limits.page_size {
+            // The follower went away
+            if sender
+                .blocking_send(Ok(std::mem::take(&mut chunk).into()))
+                .is_err()
+            {
+                return Ok(());
+            }
+
+            lines = 0;
+        }
+    }
+
+    let _ = sender.blocking_send(Ok(chunk.into()));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request, StatusCode},
+    };
+    use serde_json::{json, Value};
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[tokio::test]
+    async fn streams_a_snapshot_with_its_sequence() {
+        let mut app = setup_tests().await;
+
+        for key in ["snapshot-a", "snapshot-b"] {
+            let request = Request::builder()
+                .method(http::Method::PUT)
+                .uri(format!("/{}", key))
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(json!({ "key": key, "value": "1" }).to_string()))
+                .unwrap();
+
+            app.ready().await.unwrap().call(request).await.unwrap();
+        }
+
+        let request = Request::builder()
+            .uri("/admin/snapshot")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let request = Request::builder()
+            .uri("/admin/snapshot")
+            .header(crate::immutable::ADMIN_TOKEN_HEADER, "test-admin-token")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let lines: Vec<Value> = body
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+
+        let state = crate::app_state().unwrap();
+        let rtxn = state.read_txn().unwrap();
+
+        assert_eq!(
+            lines[0]["sequence"],
+            crate::feed::last_sequence(&state, &rtxn).unwrap()
+        );
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1]["key"], "snapshot-a");
+        assert_eq!(lines[2]["value"], "1");
+        assert_eq!(
+            lines[2]["version"],
+            state.versions.get(&rtxn, "snapshot-b").unwrap().unwrap()
+        );
+    }
+}
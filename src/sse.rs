@@ -0,0 +1,51 @@
+use axum::response::sse::{Event, KeepAlive};
+use std::time::Duration;
+
+/// How server-sent event streams keep their connection and client in shape, from
+/// `SSE_HEARTBEAT_SECS` and `SSE_RETRY_MS`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Config {
+    // Idle streams get a comment this often, so proxies don't take them for dead
+    heartbeat: Duration,
+    // How long clients are told to wait before reconnecting a dropped stream
+    retry: Duration,
+}
+
+impl Config {
+    pub(crate) fn from_env() -> Result<Config, String> {
+        let var = |name: &str, default: u64| match std::env::var(name) {
+            Ok(value) => match value.parse() {
+                Ok(0) | Err(_) => Err(format!("{} must be a positive number, got {}", name, value)),
+                Ok(value) => Ok(value),
+            },
+            Err(_) => Ok(default),
+        };
+
+        Ok(Config {
+            heartbeat: Duration::from_secs(var("SSE_HEARTBEAT_SECS", 15)?),
+            retry: Duration::from_millis(var("SSE_RETRY_MS", 3000)?),
+        })
+    }
+
+    pub(crate) fn keep_alive(&self) -> KeepAlive {
+        KeepAlive::new().interval(self.heartbeat).text("heartbeat")
+    }
+
+    /// The first event of every stream, telling the client how soon to reconnect.
+    pub(crate) fn retry_hint(&self) -> Event {
+        Event::default().retry(self.retry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let config = Config::from_env().unwrap();
+
+        assert_eq!(config.heartbeat, Duration::from_secs(15));
+        assert_eq!(config.retry, Duration::from_millis(3000));
+    }
+}
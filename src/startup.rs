@@ -0,0 +1,145 @@
+use heed::{Env, EnvOpenOptions, MdbError};
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::MAX_DBS;
+
+const LOCK_FILE: &str = "lock.mdb";
+
+/// Opens the env at `db_path`, checking the directory first so a misconfigured volume
+/// fails with a message saying what to fix.
+///
+/// With `DB_PATH_WAIT_SECS` set, waits that long for the directory to show up, for
+/// volumes mounted after the container starts. With `DB_RECOVER_LOCK=true`, a lock file
+/// LMDB can't use is deleted and the open retried, which is only safe when no other
+/// process has the database open.
+pub(crate) fn open_env(db_path: &str) -> Result<Env, String> {
+    let path = Path::new(db_path);
+
+    if let Ok(wait) = std::env::var("DB_PATH_WAIT_SECS") {
+        let wait = wait.parse().map_err(|_| {
+            format!(
+                "DB_PATH_WAIT_SECS must be a number of seconds, got {}",
+                wait
+            )
+        })?;
+
+        wait_for_dir(path, Duration::from_secs(wait))?;
+    }
+
+    fs::create_dir_all(path).map_err(|err| {
+        format!(
+            "can't create DB_PATH {}: {}, check that its parent exists and is writable",
+            db_path, err
+        )
+    })?;
+
+    check_writable(path)?;
+
+    let recover_lock = std::env::var("DB_RECOVER_LOCK").is_ok_and(|value| value == "true");
+
+    match EnvOpenOptions::new().max_dbs(MAX_DBS).open(path) {
+        Ok(env) => Ok(env),
+        Err(heed::Error::Mdb(MdbError::Invalid | MdbError::VersionMismatch))
+            if recover_lock && path.join(LOCK_FILE).exists() =>
+        {
+            tracing::warn!(
+                "removing unusable {} in {} and retrying",
+                LOCK_FILE,
+                db_path
+            );
+
+            fs::remove_file(path.join(LOCK_FILE))
+                .map_err(|err| format!("can't remove the stale {}: {}", LOCK_FILE, err))?;
+
+            EnvOpenOptions::new()
+                .max_dbs(MAX_DBS)
+                .open(path)
+                .map_err(|err| open_error(db_path, err))
+        }
+        Err(err) => Err(open_error(db_path, err)),
+    }
+}
+
+fn open_error(db_path: &str, err: heed::Error) -> String {
+    match err {
+        heed::Error::Mdb(MdbError::Invalid | MdbError::VersionMismatch) => format!(
+            "can't open the database in {}: {}, if a crashed process left an unusable {} behind \
+             and nothing else has the database open, start with DB_RECOVER_LOCK=true",
+            db_path, err, LOCK_FILE
+        ),
+        err => format!("can't open the database in {}: {}", db_path, err),
+    }
+}
+
+fn wait_for_dir(path: &Path, wait: Duration) -> Result<(), String> {
+    let started = Instant::now();
+
+    while !path.is_dir() {
+        if started.elapsed() >= wait {
+            return Err(format!(
+                "DB_PATH {} didn't appear within {}s, is the volume mounted?",
+                path.display(),
+                wait.as_secs()
+            ));
+        }
+
+        thread::sleep(Duration::from_millis(250));
+    }
+
+    Ok(())
+}
+
+// LMDB needs to write both its data and lock file, find out now rather than on the first write
+pub(crate) fn check_writable(path: &Path) -> Result<(), String> {
+    let not_writable = |file: &Path, err: std::io::Error| {
+        format!(
+            "{} isn't writable by this user: {}, check the volume's ownership and permissions",
+            file.display(),
+            err
+        )
+    };
+
+    let probe = path.join(".write-check");
+
+    fs::write(&probe, b"").map_err(|err| not_writable(path, err))?;
+    fs::remove_file(&probe).map_err(|err| not_writable(path, err))?;
+
+    for file in ["data.mdb", LOCK_FILE] {
+        let file = path.join(file);
+
+        if file.exists() {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&file)
+                .map_err(|err| not_writable(&file, err))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_directories_time_out() {
+        let error = wait_for_dir(Path::new("db/never-mounted"), Duration::ZERO).unwrap_err();
+
+        assert!(error.contains("is the volume mounted?"));
+    }
+
+    #[test]
+    fn unusable_paths_fail_with_a_message() {
+        fs::create_dir_all("db").unwrap();
+        fs::write("db/not-a-directory", b"").unwrap();
+
+        let error = open_env("db/not-a-directory").err().unwrap();
+
+        assert!(error.starts_with("can't create DB_PATH db/not-a-directory"));
+    }
+}
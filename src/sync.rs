@@ -0,0 +1,432 @@
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::{Client, StatusCode, Uri};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// A delimiter no key should contain, so `/export` hands the keys back as they are
+const FLAT: &str = "\u{1f}";
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// What `kv sync` mirrors into which directory, from `SYNC_URL`, `SYNC_PREFIX`,
+/// `SYNC_DIR` and `SYNC_TEMPLATE`.
+pub(crate) struct Config {
+    url: String,
+    prefix: String,
+    dir: PathBuf,
+    // Rendered into `dir` under its own file name after every change
+    template: Option<PathBuf>,
+}
+
+impl Config {
+    pub(crate) fn from_env() -> Result<Config, String> {
+        let dir = std::env::var("SYNC_DIR")
+            .map_err(|_| String::from("SYNC_DIR must be set to the directory to write to"))?;
+
+        Ok(Config {
+            url: std::env::var("SYNC_URL")
+                .unwrap_or_else(|_| String::from("http://localhost:3000"))
+                .trim_end_matches('/')
+                .to_owned(),
+            prefix: std::env::var("SYNC_PREFIX").unwrap_or_default(),
+            dir: PathBuf::from(dir),
+            template: std::env::var("SYNC_TEMPLATE").ok().map(PathBuf::from),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct Change {
+    key: String,
+    // `None` for a delete
+    value: Option<String>,
+}
+
+/// The file a key is written to, its name less the prefix with anything that isn't safe
+/// in a file name turned into `_`.
+fn file_name(key: &str, prefix: &str) -> String {
+    let name: String = key
+        .strip_prefix(prefix)
+        .unwrap_or(key)
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+
+    // Hidden files are where the temporary copies go, and `..` is no file at all
+    match name.chars().next() {
+        Some('.') | None => format!("_{}", name),
+        _ => name,
+    }
+}
+
+/// Fills in every `{{ key }}` in `template` with the key's value, or nothing when missing.
+fn render(template: &str, values: &BTreeMap<String, String>) -> String {
+    let mut rendered = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        rendered.push_str(&rest[..start]);
+
+        let key = rest[start + 2..end].trim();
+        rendered.push_str(values.get(key).map_or("", String::as_str));
+
+        rest = &rest[end + 2..];
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+// Readers of `path` see either the old contents or the new, never half of them
+fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let name = path.file_name().unwrap().to_string_lossy();
+    let temporary = path.with_file_name(format!(".{}.tmp", name));
+
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)
+}
+
+/// Picks the id and change out of one server-sent event, skipping heartbeats and hints.
+fn parse_event(message: &str) -> Option<(u64, Change)> {
+    let mut id = None;
+    let mut data = String::new();
+
+    for line in message.lines() {
+        if let Some(value) = line.strip_prefix("id:") {
+            id = value.trim().parse().ok();
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push_str(value);
+        }
+    }
+
+    Some((id?, serde_json::from_str(&data).ok()?))
+}
+
+struct Mirror {
+    config: Config,
+    template: Option<String>,
+    values: BTreeMap<String, String>,
+}
+
+impl Mirror {
+    fn path(&self, key: &str) -> PathBuf {
+        self.config.dir.join(file_name(key, &self.config.prefix))
+    }
+
+    fn apply(&mut self, change: Change) -> io::Result<()> {
+        match change.value {
+            Some(value) => {
+                write_atomically(&self.path(&change.key), &value)?;
+                self.values.insert(change.key, value);
+            }
+            None => {
+                match fs::remove_file(self.path(&change.key)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+
+                self.values.remove(&change.key);
+            }
+        }
+
+        self.render()
+    }
+
+    // Starting over from a snapshot, files of keys that went away meanwhile are removed
+    fn reset(&mut self, snapshot: BTreeMap<String, String>) -> io::Result<()> {
+        let gone: Vec<String> = self
+            .values
+            .keys()
+            .filter(|key| !snapshot.contains_key(*key))
+            .cloned()
+            .collect();
+
+        for key in gone {
+            self.apply(Change { key, value: None })?;
+        }
+
+        for (key, value) in snapshot {
+            self.apply(Change {
+                key,
+                value: Some(value),
+            })?;
+        }
+
+        self.render()
+    }
+
+    fn render(&self) -> io::Result<()> {
+        match (&self.template, &self.config.template) {
+            (Some(template), Some(path)) => write_atomically(
+                &self.config.dir.join(path.file_name().unwrap()),
+                &render(template, &self.values),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn uri(config: &Config, path: &str, query: &[(&str, String)]) -> Result<Uri, String> {
+    let query = serde_urlencoded::to_string(query).unwrap();
+
+    format!("{}{}?{}", config.url, path, query)
+        .parse()
+        .map_err(|_| format!("SYNC_URL {} isn't a valid URL", config.url))
+}
+
+// The keys under the prefix right now, with their values
+async fn snapshot(
+    client: &Client<HttpConnector>,
+    config: &Config,
+) -> Result<BTreeMap<String, String>, String> {
+    let query = [
+        ("prefix", config.prefix.clone()),
+        ("delimiter", String::from(FLAT)),
+    ];
+
+    let response = client
+        .get(uri(config, "/export", &query)?)
+        .await
+        .map_err(|err| format!("can't reach {}: {}", config.url, err))?;
+
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if status != StatusCode::OK {
+        return Err(format!(
+            "exporting failed with {}: {}",
+            status,
+            String::from_utf8_lossy(&body)
+        ));
+    }
+
+    serde_json::from_slice(&body).map_err(|err| err.to_string())
+}
+
+// Watches the prefix until the stream ends, resuming after `last_event` when there is one
+async fn follow(
+    client: &Client<HttpConnector>,
+    mirror: &mut Mirror,
+    last_event: &mut Option<u64>,
+) -> Result<(), String> {
+    let config = &mirror.config;
+
+    let mut query = vec![("prefix", config.prefix.clone())];
+
+    if let Some(last_event) = last_event {
+        query.push(("since", last_event.to_string()));
+    }
+
+    let response = client
+        .get(uri(config, "/watch", &query)?)
+        .await
+        .map_err(|err| format!("can't reach {}: {}", config.url, err))?;
+
+    match response.status() {
+        StatusCode::OK => {}
+        StatusCode::GONE => {
+            *last_event = None;
+
+            return Err(String::from("missed changes were truncated, starting over"));
+        }
+        status => return Err(format!("watching failed with {}", status)),
+    }
+
+    // Watching starts first, changes made while the snapshot is read are then replayed
+    // on top of it and it all ends up the same
+    if last_event.is_none() {
+        let snapshot = snapshot(client, config).await?;
+
+        mirror.reset(snapshot).map_err(|err| err.to_string())?;
+    }
+
+    let mut body = response.into_body();
+    let mut buffer = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        buffer.extend_from_slice(&chunk.map_err(|err| err.to_string())?);
+
+        while let Some(end) = buffer.windows(2).position(|window| window == b"\n\n") {
+            let message: Vec<u8> = buffer.drain(..end + 2).collect();
+
+            if let Some((id, change)) = parse_event(&String::from_utf8_lossy(&message)) {
+                mirror.apply(change).map_err(|err| err.to_string())?;
+
+                *last_event = Some(id);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// `kv sync`: keeps a file per key under `SYNC_PREFIX` in `SYNC_DIR`, and optionally
+/// `SYNC_TEMPLATE` rendered from them, in step with a server's change feed. Only returns
+/// if it can't get started.
+pub(crate) async fn run(config: Config) -> Result<(), String> {
+    fs::create_dir_all(&config.dir)
+        .map_err(|err| format!("can't create SYNC_DIR {}: {}", config.dir.display(), err))?;
+
+    let template = config
+        .template
+        .as_ref()
+        .map(|path| {
+            fs::read_to_string(path)
+                .map_err(|err| format!("can't read SYNC_TEMPLATE {}: {}", path.display(), err))
+        })
+        .transpose()?;
+
+    let client = Client::new();
+
+    let mut mirror = Mirror {
+        config,
+        template,
+        values: BTreeMap::new(),
+    };
+    let mut last_event = None;
+
+    loop {
+        match follow(&client, &mut mirror, &mut last_event).await {
+            Ok(()) => tracing::warn!("watch ended, reconnecting"),
+            Err(err) => tracing::warn!("{}, reconnecting", err),
+        }
+
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request},
+    };
+    use serde_json::json;
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[test]
+    fn names_files_and_renders_templates() {
+        assert_eq!(file_name("app:db/host", "app:"), "db_host");
+        assert_eq!(file_name("app:..", "app:"), "_..");
+
+        let values = BTreeMap::from([(String::from("app:host"), String::from("db1"))]);
+
+        assert_eq!(
+            render("host={{ app:host }}\nport={{app:port}}\n", &values),
+            "host=db1\nport=\n"
+        );
+
+        assert_eq!(
+            parse_event("id:7\nevent:put\ndata:{\"key\":\"a\",\"value\":\"1\"}\n\n"),
+            Some((
+                7,
+                Change {
+                    key: String::from("a"),
+                    value: Some(String::from("1")),
+                }
+            ))
+        );
+        assert_eq!(parse_event(":heartbeat\n\n"), None);
+    }
+
+    // Polls for up to 5 seconds for `path` to hold `expected`, `None` for no file
+    async fn wait_for(path: &Path, expected: Option<&str>) {
+        for _ in 0..100 {
+            if fs::read_to_string(path).ok().as_deref() == expected {
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        panic!("{} never became {:?}", path.display(), expected);
+    }
+
+    #[tokio::test]
+    async fn mirrors_keys_into_files() {
+        let mut app = setup_tests().await;
+
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(app.clone().into_make_service());
+        let url = format!("http://{}", server.local_addr());
+
+        tokio::spawn(server);
+
+        let root = std::env::temp_dir().join(format!("kv-sync-{}", uuid::Uuid::new_v4()));
+        let template = root.join("app.conf");
+        let dir = root.join("config");
+
+        fs::create_dir_all(&root).unwrap();
+        fs::write(&template, "host={{ synced:host }}\n").unwrap();
+
+        let put = |key: &str, value: &str| {
+            Request::builder()
+                .method(http::Method::PUT)
+                .uri(format!("/{}", key))
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    json!({ "key": key, "value": value }).to_string(),
+                ))
+                .unwrap()
+        };
+
+        // Already there when syncing starts
+        app.ready()
+            .await
+            .unwrap()
+            .call(put("synced:port", "5432"))
+            .await
+            .unwrap();
+
+        let sync = tokio::spawn(run(Config {
+            url,
+            prefix: String::from("synced:"),
+            dir: dir.clone(),
+            template: Some(template),
+        }));
+
+        wait_for(&dir.join("port"), Some("5432")).await;
+
+        app.ready()
+            .await
+            .unwrap()
+            .call(put("synced:host", "db1"))
+            .await
+            .unwrap();
+
+        wait_for(&dir.join("host"), Some("db1")).await;
+        wait_for(&dir.join("app.conf"), Some("host=db1\n")).await;
+
+        let request = Request::builder()
+            .method(http::Method::DELETE)
+            .uri("/synced:host")
+            .body(Body::empty())
+            .unwrap();
+
+        app.ready().await.unwrap().call(request).await.unwrap();
+
+        wait_for(&dir.join("host"), None).await;
+        wait_for(&dir.join("app.conf"), Some("host=\n")).await;
+
+        sync.abort();
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
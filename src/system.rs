@@ -0,0 +1,88 @@
+use axum::response::{IntoResponse, Response};
+use axum::{http::StatusCode, Json};
+use serde_json::{json, Value};
+
+use crate::AppState;
+
+// Keys under here are made up on reading rather than stored, and can't be written
+const PREFIX: &str = "__system/";
+
+// Followed by the name of a counter on `GET /metrics`, like `kv_panics_total`
+const METRICS_PREFIX: &str = "__system/metrics/";
+
+/// The response to `GET /:key` of a virtual system key, or `None` for any other key.
+pub(crate) fn read(state: &AppState, key: &str) -> Option<Response> {
+    if !key.starts_with(PREFIX) {
+        return None;
+    }
+
+    let value = key
+        .strip_prefix(METRICS_PREFIX)
+        .and_then(|name| state.metrics.get(name));
+
+    let response = match value {
+        Some(value) => (
+            StatusCode::OK,
+            Json(json!({ "key": key, "value": value.to_string() })),
+        ),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Key not found" })),
+        ),
+    };
+
+    Some(response.into_response())
+}
+
+/// The response to send instead of writing `key` when it's a system key.
+pub(crate) fn refuse_write(key: &str) -> Option<(StatusCode, Json<Value>)> {
+    key.starts_with(PREFIX).then(|| {
+        (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "System keys are read-only" })),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{send, setup_tests};
+    use axum::{
+        body::Body,
+        http::{self, Request, StatusCode},
+    };
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn reads_metrics_as_keys() {
+        let mut app = setup_tests().await;
+
+        let request = Request::builder()
+            .uri("/__system%2Fmetrics%2Fkv_panics_total")
+            .body(Body::empty())
+            .unwrap();
+
+        let (status, body) = send(&mut app, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["value"].as_str().unwrap().parse::<u64>().is_ok());
+
+        let request = Request::builder()
+            .uri("/__system%2Fmetrics%2Fkv_missing_total")
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(send(&mut app, request).await.0, StatusCode::NOT_FOUND);
+
+        let request = Request::builder()
+            .method(http::Method::PUT)
+            .uri("/__system%2Fmetrics%2Fkv_panics_total")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "key": "__system/metrics/kv_panics_total", "value": "0" }).to_string(),
+            ))
+            .unwrap();
+
+        assert_eq!(send(&mut app, request).await.0, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,316 @@
+use axum::extract::{Path, Query, State};
+use axum::{http::StatusCode, Json};
+use heed::RwTxn;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use crate::fields::{self, Field};
+use crate::{name_prefix, paging, record_keys, sizes, AppError, AppState};
+
+// Each key's tags are stored with it in `tags`, and indexed in `tagged` as
+// `[tag length][tag][key]` keys with an empty value so listing a tag is a prefix scan
+
+fn index_key(tag: &str, key: &str) -> Vec<u8> {
+    let mut index_key = name_prefix(tag);
+    index_key.extend_from_slice(key.as_bytes());
+    index_key
+}
+
+/// Drops whatever tags `key` has, along with their index entries.
+pub(crate) fn clear(state: &AppState, wtxn: &mut RwTxn, key: &str) -> heed::Result<()> {
+    let tags = match state.tags.get(wtxn, key)? {
+        Some(tags) => tags,
+        None => return Ok(()),
+    };
+
+    for tag in &tags {
+        state.tagged.delete(wtxn, &index_key(tag, key))?;
+    }
+
+    state.tags.delete(wtxn, key)?;
+
+    Ok(())
+}
+
+fn set_tags(
+    state: &AppState,
+    wtxn: &mut RwTxn,
+    key: &str,
+    tags: &BTreeSet<String>,
+) -> heed::Result<()> {
+    clear(state, wtxn, key)?;
+
+    if tags.is_empty() {
+        return Ok(());
+    }
+
+    for tag in tags {
+        state.tagged.put(wtxn, &index_key(tag, key), &())?;
+    }
+
+    state.tags.put(wtxn, key, tags)
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TagsPayload {
+    tags: BTreeSet<String>,
+}
+
+/// Replaces the tags of an existing key, an empty list removes them all.
+pub(crate) async fn put_tags(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+    Json(payload): Json<TagsPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    if let Some(response) = sizes::check_tags(&state, &payload.tags) {
+        return Ok(response);
+    }
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    match state.kv.get(&wtxn, &key) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return Ok((
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "Key not found" })),
+            ))
+        }
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    }
+
+    match set_tags(&state, &mut wtxn, &key, &payload.tags) {
+        Ok(()) => {
+            state.commit(wtxn).unwrap();
+
+            Ok((
+                StatusCode::OK,
+                Json(json!({ "key": key, "tags": payload.tags })),
+            ))
+        }
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+pub(crate) async fn get_tags(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let rtxn = state.read_txn().unwrap();
+
+    let exists = state.kv.get(&rtxn, &key).map(|value| value.is_some());
+    let tags = state.tags.get(&rtxn, &key);
+
+    match (exists, tags) {
+        (Ok(true), Ok(tags)) => Ok((
+            StatusCode::OK,
+            Json(json!({ "key": key, "tags": tags.unwrap_or_default() })),
+        )),
+        (Ok(false), Ok(_)) => Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Key not found" })),
+        )),
+        _ => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+// Reads one key more than `limit`, so going over it shows
+fn tagged_keys(
+    state: &AppState,
+    rtxn: &heed::RoTxn,
+    tag: &str,
+    limit: usize,
+) -> heed::Result<Vec<String>> {
+    let prefix = name_prefix(tag);
+
+    state
+        .tagged
+        .prefix_iter(rtxn, &prefix)?
+        .take(limit + 1)
+        .map(|entry| {
+            entry.map(|(key, _)| String::from_utf8_lossy(&key[prefix.len()..]).into_owned())
+        })
+        .collect()
+}
+
+// Objects of the requested fields of each tagged key, skipping any deleted since
+fn select_keys(
+    state: &AppState,
+    rtxn: &heed::RoTxn,
+    fields: &[Field],
+    keys: &[String],
+) -> heed::Result<Vec<Value>> {
+    let mut selected = Vec::with_capacity(keys.len());
+
+    for key in keys {
+        if let Some(value) = state.kv.get(rtxn, key)? {
+            selected.push(fields::select(state, rtxn, fields, key, value)?);
+        }
+    }
+
+    Ok(selected)
+}
+
+#[derive(Deserialize)]
+pub(crate) struct KeysQuery {
+    tag: String,
+    // Comma separated, lists objects of just these instead of key names
+    fields: Option<String>,
+}
+
+pub(crate) async fn keys_with_tag(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<KeysQuery>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let fields = match query.fields.as_deref().map(fields::parse).transpose() {
+        Ok(fields) => fields,
+        Err(err) => return Ok((StatusCode::BAD_REQUEST, Json(json!({ "error": err })))),
+    };
+
+    let rtxn = state.read_txn().unwrap();
+
+    let max_keys = state.limits.max_keys;
+
+    let keys = match tagged_keys(&state, &rtxn, &query.tag, max_keys) {
+        Ok(keys) if keys.len() > max_keys => return Ok(paging::too_many_keys(max_keys)),
+        keys => keys,
+    };
+
+    if let Ok(keys) = &keys {
+        record_keys(keys.len());
+    }
+
+    let keys = keys.and_then(|keys| match &fields {
+        Some(fields) => select_keys(&state, &rtxn, fields, &keys).map(Value::from),
+        None => Ok(Value::from(keys)),
+    });
+
+    match keys {
+        Ok(keys) => Ok((
+            StatusCode::OK,
+            Json(json!({ "tag": query.tag, "keys": keys })),
+        )),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sizes;
+    use crate::tests::{send_json, setup_tests};
+    use axum::http::{self, StatusCode};
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn lists_keys_by_tag() {
+        let mut app = setup_tests().await;
+
+        for key in ["tagged-a", "tagged-b"] {
+            let body = json!({ "key": key, "value": "v" });
+            send_json(&mut app, http::Method::PUT, &format!("/{}", key), body).await;
+        }
+
+        let (status, _) = send_json(
+            &mut app,
+            http::Method::PUT,
+            "/tagged-missing/tags",
+            json!({ "tags": ["env:prod"] }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let tags = json!({ "tags": ["env:prod", "team:core"] });
+        send_json(&mut app, http::Method::PUT, "/tagged-a/tags", tags).await;
+
+        let tags = json!({ "tags": ["env:prod"] });
+        send_json(&mut app, http::Method::PUT, "/tagged-b/tags", tags).await;
+
+        let (_, body) = send_json(
+            &mut app,
+            http::Method::GET,
+            "/keys?tag=env:prod",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(body["keys"], json!(["tagged-a", "tagged-b"]));
+
+        let (_, body) = send_json(
+            &mut app,
+            http::Method::GET,
+            "/keys?tag=env:prod&fields=key,size",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(body["keys"][0], json!({ "key": "tagged-a", "size": 1 }));
+
+        // Replacing the tags drops the old ones from the index
+        let tags = json!({ "tags": ["env:dev"] });
+        send_json(&mut app, http::Method::PUT, "/tagged-a/tags", tags).await;
+
+        let (_, body) = send_json(&mut app, http::Method::GET, "/tagged-a/tags", Value::Null).await;
+        assert_eq!(body["tags"], json!(["env:dev"]));
+
+        let (_, body) = send_json(
+            &mut app,
+            http::Method::GET,
+            "/keys?tag=team:core",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(body["keys"], json!([]));
+
+        // And so does deleting the key
+        send_json(&mut app, http::Method::DELETE, "/tagged-b", Value::Null).await;
+
+        let (_, body) = send_json(
+            &mut app,
+            http::Method::GET,
+            "/keys?tag=env:prod",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(body["keys"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_tags() {
+        let mut app = setup_tests().await;
+
+        let key = "k".repeat(sizes::MAX_KEY_BYTES);
+        let body = json!({ "key": key, "value": "v" });
+        send_json(&mut app, http::Method::PUT, &format!("/{}", key), body).await;
+
+        let uri = format!("/{}/tags", key);
+
+        let tags = json!({ "tags": ["t".repeat(sizes::MAX_TAG_BYTES + 1)] });
+        let (status, _) = send_json(&mut app, http::Method::PUT, &uri, tags).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Over MAX_VALUE_BYTES all together
+        let tags: Vec<String> = (0..200).map(|tag| format!("tag-{:028}", tag)).collect();
+        let (status, _) =
+            send_json(&mut app, http::Method::PUT, &uri, json!({ "tags": tags })).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // The longest tag on the longest key still fits in the index
+        let tags = json!({ "tags": ["t".repeat(sizes::MAX_TAG_BYTES)] });
+        let (status, _) = send_json(&mut app, http::Method::PUT, &uri, tags).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
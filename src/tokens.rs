@@ -0,0 +1,468 @@
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use heed::types::{SerdeJson, Str};
+use heed::{Database, Env};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::quotas::Quota;
+use crate::{digest, immutable, now_millis, AppError, AppState};
+
+// API tokens are created at runtime, each under a name, and are accepted wherever
+// `ADMIN_TOKEN` is. Only their SHA-256 is kept, in `api_tokens` by name and in memory by
+// hash, so a copy of the database gives nobody a usable token and checking one reads
+// nothing from it. Managing them takes `ADMIN_TOKEN` itself, a leaked API token can't
+// mint more or keep itself from being revoked.
+
+/// A token as stored, without the token.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ApiToken {
+    hash: String,
+    created_at: u64,
+    #[serde(default)]
+    quota: Quota,
+}
+
+fn hash(token: &str) -> String {
+    digest::hex(&Sha256::digest(token.as_bytes()).into())
+}
+
+fn generate() -> String {
+    format!(
+        "kv_{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// The hashes of every stored token mapped to their names and quotas, loaded at startup.
+pub(crate) fn load_hashes(
+    env: &Env,
+    api_tokens: Database<Str, SerdeJson<ApiToken>>,
+) -> heed::Result<HashMap<String, (String, Quota)>> {
+    let rtxn = env.read_txn()?;
+
+    let hashes = api_tokens
+        .iter(&rtxn)?
+        .map(|entry| entry.map(|(name, token)| (token.hash, (name.to_owned(), token.quota))))
+        .collect();
+
+    hashes
+}
+
+/// The name and quota of the API token `given` is, if it is one.
+pub(crate) fn lookup(state: &AppState, given: &[u8]) -> Option<(String, Quota)> {
+    let given = std::str::from_utf8(given).ok()?;
+
+    state
+        .token_hashes
+        .read()
+        .unwrap()
+        .get(&hash(given))
+        .cloned()
+}
+
+/// Whether `given` is one of the API tokens.
+pub(crate) fn is_valid(state: &AppState, given: &[u8]) -> bool {
+    lookup(state, given).is_some()
+}
+
+fn is_root(state: &AppState, headers: &HeaderMap) -> bool {
+    match (
+        &state.admin_token,
+        headers.get(immutable::ADMIN_TOKEN_HEADER),
+    ) {
+        (Some(token), Some(given)) => immutable::is_token(token, given.as_bytes()),
+        _ => false,
+    }
+}
+
+fn forbidden() -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::FORBIDDEN,
+        Json(json!({ "error": "API tokens can only be managed with ADMIN_TOKEN" })),
+    )
+}
+
+fn internal_error() -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({ "error": "Internal server error" })),
+    )
+}
+
+// Stores a new token under `name` and returns it. Creating one, with `Some` quota, needs
+// the name to be free, rotating with `None` needs a token to replace and keeps its quota.
+// `None` when that isn't so.
+fn issue(state: &AppState, name: &str, create: Option<Quota>) -> heed::Result<Option<Value>> {
+    let mut wtxn = state.write_txn()?;
+
+    let previous = state.api_tokens.get(&wtxn, name)?;
+
+    let quota = match (&previous, create) {
+        (None, Some(quota)) => quota,
+        (Some(previous), None) => previous.quota,
+        _ => return Ok(None),
+    };
+
+    let token = generate();
+    let stored = ApiToken {
+        hash: hash(&token),
+        created_at: now_millis(),
+        quota,
+    };
+
+    state.api_tokens.put(&mut wtxn, name, &stored)?;
+    state.commit(wtxn)?;
+
+    let mut hashes = state.token_hashes.write().unwrap();
+
+    if let Some(previous) = previous {
+        hashes.remove(&previous.hash);
+    }
+
+    hashes.insert(stored.hash, (name.to_owned(), quota));
+
+    Ok(Some(json!({
+        "name": name,
+        "token": token,
+        "created_at": stored.created_at,
+        "quota": quota,
+    })))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreatePayload {
+    name: String,
+    #[serde(flatten)]
+    quota: Quota,
+}
+
+/// `POST /admin/tokens`: creates a token under a new name, with `requests_per_sec` and
+/// `bytes_per_day` quotas if given. The response is the only time the token itself is
+/// shown.
+pub(crate) async fn create(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<CreatePayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    if !is_root(&state, &headers) {
+        return Ok(forbidden());
+    }
+
+    if payload.name.is_empty() {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Token name can't be empty" })),
+        ));
+    }
+
+    match issue(&state, &payload.name, Some(payload.quota)) {
+        Ok(Some(token)) => {
+            tracing::info!(name = payload.name, "created API token");
+
+            Ok((StatusCode::CREATED, Json(token)))
+        }
+        Ok(None) => Ok((
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "A token with that name already exists" })),
+        )),
+        Err(_) => Ok(internal_error()),
+    }
+}
+
+/// `GET /admin/tokens`: the names of the tokens, when each was last issued and its quota.
+pub(crate) async fn list(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    if !is_root(&state, &headers) {
+        return Ok(forbidden());
+    }
+
+    let rtxn = state.read_txn().unwrap();
+
+    let tokens = state.api_tokens.iter(&rtxn).and_then(|tokens| {
+        tokens
+            .map(|entry| {
+                entry.map(|(name, token)| {
+                    json!({ "name": name, "created_at": token.created_at, "quota": token.quota })
+                })
+            })
+            .collect::<heed::Result<Vec<Value>>>()
+    });
+
+    match tokens {
+        Ok(tokens) => Ok((StatusCode::OK, Json(json!({ "tokens": tokens })))),
+        Err(_) => Ok(internal_error()),
+    }
+}
+
+/// `POST /admin/tokens/:name/rotate`: replaces a token with a new one, the old one stops
+/// working right away.
+pub(crate) async fn rotate(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    if !is_root(&state, &headers) {
+        return Ok(forbidden());
+    }
+
+    match issue(&state, &name, None) {
+        Ok(Some(token)) => {
+            tracing::info!(name, "rotated API token");
+
+            Ok((StatusCode::OK, Json(token)))
+        }
+        Ok(None) => Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Token not found" })),
+        )),
+        Err(_) => Ok(internal_error()),
+    }
+}
+
+/// `PUT /admin/tokens/:name/quota`: replaces a token's quota, `{}` lifting it.
+pub(crate) async fn set_quota(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(quota): Json<Quota>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    if !is_root(&state, &headers) {
+        return Ok(forbidden());
+    }
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    let mut token = match state.api_tokens.get(&wtxn, &name) {
+        Ok(Some(token)) => token,
+        Ok(None) => {
+            return Ok((
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "Token not found" })),
+            ))
+        }
+        Err(_) => return Ok(internal_error()),
+    };
+
+    token.quota = quota;
+
+    if state.api_tokens.put(&mut wtxn, &name, &token).is_err() {
+        return Ok(internal_error());
+    }
+
+    state.commit(wtxn).unwrap();
+
+    state
+        .token_hashes
+        .write()
+        .unwrap()
+        .insert(token.hash, (name.clone(), quota));
+
+    tracing::info!(name, ?quota, "set API token quota");
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "name": name, "quota": quota })),
+    ))
+}
+
+/// `DELETE /admin/tokens/:name`: revokes a token.
+pub(crate) async fn revoke(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    if !is_root(&state, &headers) {
+        return Ok(forbidden());
+    }
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    let revoked = match state.api_tokens.get(&wtxn, &name) {
+        Ok(Some(token)) => token,
+        Ok(None) => {
+            return Ok((
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "Token not found" })),
+            ))
+        }
+        Err(_) => return Ok(internal_error()),
+    };
+
+    if state.api_tokens.delete(&mut wtxn, &name).is_err() {
+        return Ok(internal_error());
+    }
+
+    state.commit(wtxn).unwrap();
+
+    state.token_hashes.write().unwrap().remove(&revoked.hash);
+    state.token_usage.lock().unwrap().remove(&name);
+
+    tracing::info!(name, "revoked API token");
+
+    Ok((StatusCode::OK, Json(json!({ "name": name }))))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::immutable;
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request, StatusCode},
+        Router,
+    };
+    use serde_json::{json, Value};
+
+    async fn send(
+        app: &mut Router,
+        method: http::Method,
+        uri: &str,
+        token: &str,
+        body: Value,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(immutable::ADMIN_TOKEN_HEADER, token)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        crate::tests::send(app, request).await
+    }
+
+    #[tokio::test]
+    async fn tokens_work_until_rotated_or_revoked() {
+        let mut app = setup_tests().await;
+
+        let name = format!("ci-{}", uuid::Uuid::new_v4());
+        let uri = format!("/admin/tokens/{}", name);
+
+        let (status, created) = send(
+            &mut app,
+            http::Method::POST,
+            "/admin/tokens",
+            "test-admin-token",
+            json!({ "name": name }),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::CREATED);
+
+        let token = created["token"].as_str().unwrap().to_owned();
+
+        let (status, _) = send(
+            &mut app,
+            http::Method::POST,
+            "/admin/tokens",
+            "test-admin-token",
+            json!({ "name": name }),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        // Stands in for the admin token, but can't manage tokens
+        let expiry = send(
+            &mut app,
+            http::Method::GET,
+            "/admin/expiry",
+            &token,
+            json!({}),
+        )
+        .await;
+
+        assert_eq!(expiry.0, StatusCode::OK);
+
+        let (status, _) = send(
+            &mut app,
+            http::Method::GET,
+            "/admin/tokens",
+            &token,
+            json!({}),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, listed) = send(
+            &mut app,
+            http::Method::GET,
+            "/admin/tokens",
+            "test-admin-token",
+            json!({}),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(listed["tokens"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|listed| listed["name"] == name.as_str() && listed.get("token").is_none()));
+
+        let (status, rotated) = send(
+            &mut app,
+            http::Method::POST,
+            &format!("{}/rotate", uri),
+            "test-admin-token",
+            json!({}),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+
+        let old = send(
+            &mut app,
+            http::Method::GET,
+            "/admin/expiry",
+            &token,
+            json!({}),
+        )
+        .await;
+
+        assert_eq!(old.0, StatusCode::FORBIDDEN);
+
+        let rotated = rotated["token"].as_str().unwrap().to_owned();
+        let new = send(
+            &mut app,
+            http::Method::GET,
+            "/admin/expiry",
+            &rotated,
+            json!({}),
+        )
+        .await;
+
+        assert_eq!(new.0, StatusCode::OK);
+
+        let (status, _) = send(
+            &mut app,
+            http::Method::DELETE,
+            &uri,
+            "test-admin-token",
+            json!({}),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+
+        let revoked = send(
+            &mut app,
+            http::Method::GET,
+            "/admin/expiry",
+            &rotated,
+            json!({}),
+        )
+        .await;
+
+        assert_eq!(revoked.0, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,193 @@
+use axum::extract::{Query, State};
+use axum::{http::StatusCode, Json};
+use heed::types::{ByteSlice, DecodeIgnore};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::{paging, record_keys, AppError, AppState};
+
+#[derive(Deserialize)]
+pub(crate) struct TreeQuery {
+    #[serde(default)]
+    prefix: String,
+    delimiter: Option<String>,
+}
+
+/// Splits the keys under `prefix` into those directly at this level and the common
+/// prefixes ("directories") up to the next delimiter, like S3's `CommonPrefixes`.
+///
+/// Stops once it has more than `limit` of them between the two.
+fn browse(
+    state: &AppState,
+    rtxn: &heed::RoTxn,
+    prefix: &str,
+    delimiter: &str,
+    limit: usize,
+) -> heed::Result<(Vec<String>, Vec<String>)> {
+    // Walked as bytes to seek to exactly the next key after one already listed
+    let kv = state.kv.remap_types::<ByteSlice, DecodeIgnore>();
+
+    let mut prefixes = Vec::new();
+    let mut keys = Vec::new();
+
+    // LMDB refuses empty keys, even just to seek to
+    let mut start = match prefix {
+        "" => None,
+        prefix => Some(prefix.as_bytes().to_vec()),
+    };
+
+    while prefixes.len() + keys.len() <= limit {
+        let bound = match &start {
+            Some(start) => Bound::Included(start.as_slice()),
+            None => Bound::Unbounded,
+        };
+
+        let next = kv
+            .range(rtxn, &(bound, Bound::Unbounded))?
+            .next()
+            .transpose()?;
+
+        let key = match next {
+            Some((key, _)) if key.starts_with(prefix.as_bytes()) => String::from_utf8_lossy(key),
+            _ => break,
+        };
+
+        match key[prefix.len()..].find(delimiter) {
+            Some(index) => {
+                let common = &key[..prefix.len() + index + delimiter.len()];
+
+                // Skip the rest of this directory by moving on to the first key past
+                // anything starting with `common`. It ends in a complete UTF-8 char, so
+                // its last byte is never 0xFF and can be bumped
+                let mut past = common.as_bytes().to_vec();
+                *past.last_mut().unwrap() += 1;
+
+                start = Some(past);
+                prefixes.push(common.to_owned());
+            }
+            None => {
+                // The smallest key sorting after this one
+                let mut past = key.as_bytes().to_vec();
+                past.push(0);
+
+                start = Some(past);
+                keys.push(key.into_owned());
+            }
+        }
+    }
+
+    Ok((prefixes, keys))
+}
+
+pub(crate) async fn tree(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<TreeQuery>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let delimiter = query.delimiter.unwrap_or_else(|| String::from("/"));
+
+    if delimiter.is_empty() {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Delimiter can't be empty" })),
+        ));
+    }
+
+    let rtxn = state.read_txn().unwrap();
+
+    let max_keys = state.limits.max_keys;
+
+    match browse(&state, &rtxn, &query.prefix, &delimiter, max_keys) {
+        Ok((prefixes, keys)) if prefixes.len() + keys.len() > max_keys => {
+            Ok(paging::too_many_keys(max_keys))
+        }
+        Ok((prefixes, keys)) => {
+            record_keys(prefixes.len() + keys.len());
+
+            Ok((
+                StatusCode::OK,
+                Json(json!({
+                    "prefix": query.prefix,
+                    "delimiter": delimiter,
+                    "prefixes": prefixes,
+                    "keys": keys,
+                })),
+            ))
+        }
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{get_json, setup_tests};
+    use axum::{
+        body::Body,
+        http::{self, Request, StatusCode},
+    };
+    use serde_json::json;
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[tokio::test]
+    async fn groups_keys_by_delimiter() {
+        let mut app = setup_tests().await;
+
+        for key in [
+            "app:db:host",
+            "app:db:port",
+            "app:name",
+            "app:web:port",
+            "other",
+        ] {
+            let request = Request::builder()
+                .method(http::Method::PUT)
+                .uri(format!("/{}", key))
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(json!({ "key": key, "value": "v" }).to_string()))
+                .unwrap();
+
+            app.ready().await.unwrap().call(request).await.unwrap();
+        }
+
+        let (status, body) = get_json(&mut app, "/tree?delimiter=:").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["prefixes"], json!(["app:"]));
+        assert_eq!(body["keys"], json!(["other"]));
+
+        let (_, body) = get_json(&mut app, "/tree?delimiter=:&prefix=app:").await;
+
+        assert_eq!(body["prefixes"], json!(["app:db:", "app:web:"]));
+        assert_eq!(body["keys"], json!(["app:name"]));
+    }
+
+    #[tokio::test]
+    async fn refuses_listings_over_the_limit() {
+        let _ = setup_tests().await;
+
+        let state = crate::app_state().unwrap();
+
+        let mut wtxn = state.write_txn().unwrap();
+        for number in 0..=state.limits.max_keys {
+            crate::put_value(&state, &mut wtxn, &format!("many:{}", number), "v").unwrap();
+        }
+        wtxn.commit().unwrap();
+
+        let mut app = crate::router(state);
+
+        let (status, body) = get_json(&mut app, "/tree?prefix=many:").await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().contains("LIST_MAX_KEYS"));
+
+        // Grouped up they fit
+        let (status, _) = get_json(&mut app, "/tree?delimiter=:").await;
+
+        assert_eq!(status, StatusCode::OK);
+    }
+}
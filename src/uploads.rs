@@ -0,0 +1,439 @@
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use heed::RwTxn;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    digest, hooks, now_millis, put_value, references, refuse_locked, sizes, wait, AppError,
+    AppState,
+};
+
+// Uploads keep their chunks in `upload_chunks` under the upload's ID followed by the chunk's
+// offset, big endian so they iterate in order, and only become the key's value once
+// finalized. Uploads nobody appended to in `ABANDONED_AFTER` are dropped.
+
+/// The offset a chunk starts at, which must be the upload's current one.
+pub(crate) const UPLOAD_OFFSET_HEADER: &str = "upload-offset";
+
+const ABANDONED_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A value being uploaded in chunks.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Upload {
+    key: String,
+    // The bytes received so far
+    offset: u64,
+    updated_at: u64,
+}
+
+fn chunk_key(id: &str, offset: u64) -> Vec<u8> {
+    let mut key = id.as_bytes().to_vec();
+    key.extend_from_slice(&offset.to_be_bytes());
+    key
+}
+
+fn internal_error() -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({ "error": "Internal server error" })),
+    )
+}
+
+fn not_found() -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({ "error": "Upload not found" })),
+    )
+}
+
+fn describe(id: &str, upload: &Upload) -> Value {
+    json!({ "id": id, "key": upload.key, "offset": upload.offset })
+}
+
+// Deletes an upload and its chunks
+fn discard(state: &AppState, wtxn: &mut RwTxn, id: &str) -> heed::Result<()> {
+    let chunks: Vec<Vec<u8>> = state
+        .upload_chunks
+        .prefix_iter(wtxn, id.as_bytes())?
+        .map(|chunk| chunk.map(|(key, _)| key.to_vec()))
+        .collect::<heed::Result<_>>()?;
+
+    for chunk in &chunks {
+        state.upload_chunks.delete(wtxn, chunk)?;
+    }
+
+    state.uploads.delete(wtxn, id).map(|_| ())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct StartPayload {
+    key: String,
+}
+
+/// `POST /uploads`: starts uploading a value too large to send in one request, or over a
+/// connection that might drop, for `key`.
+pub(crate) async fn start(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<StartPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    if let Some(response) = sizes::check_key(&payload.key) {
+        return Ok(response);
+    }
+
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    let upload = Upload {
+        key: payload.key,
+        offset: 0,
+        updated_at: now_millis(),
+    };
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    if state.uploads.put(&mut wtxn, &id, &upload).is_err() {
+        return Ok(internal_error());
+    }
+
+    state.commit(wtxn).unwrap();
+
+    Ok((StatusCode::CREATED, Json(describe(&id, &upload))))
+}
+
+/// `GET /uploads/:id`: how far an upload got, where to resume it from.
+pub(crate) async fn status(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let rtxn = state.read_txn().unwrap();
+
+    match state.uploads.get(&rtxn, &id) {
+        Ok(Some(upload)) => Ok((StatusCode::OK, Json(describe(&id, &upload)))),
+        Ok(None) => Ok(not_found()),
+        Err(_) => Ok(internal_error()),
+    }
+}
+
+/// `PATCH /uploads/:id`: appends the body to an upload, at the offset in `Upload-Offset`.
+/// An offset other than the upload's gets a 409 with the right one, so a client that
+/// lost a response knows whether its chunk made it.
+pub(crate) async fn append(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    chunk: Bytes,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let offset = headers
+        .get(UPLOAD_OFFSET_HEADER)
+        .and_then(|offset| offset.to_str().ok())
+        .and_then(|offset| offset.parse::<u64>().ok());
+
+    let offset = match offset {
+        Some(offset) => offset,
+        None => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "Appending needs Upload-Offset" })),
+            ))
+        }
+    };
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    let mut upload = match state.uploads.get(&wtxn, &id) {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return Ok(not_found()),
+        Err(_) => return Ok(internal_error()),
+    };
+
+    if offset != upload.offset {
+        return Ok((
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "Offset doesn't match the upload's", "offset": upload.offset })),
+        ));
+    }
+
+    let length = offset as usize + chunk.len();
+
+    if let Some(response) = sizes::check_length(&state, length) {
+        return Ok(response);
+    }
+
+    upload.offset = length as u64;
+    upload.updated_at = now_millis();
+
+    let appended = state
+        .upload_chunks
+        .put(&mut wtxn, &chunk_key(&id, offset), &chunk)
+        .and_then(|_| state.uploads.put(&mut wtxn, &id, &upload));
+
+    if appended.is_err() {
+        return Ok(internal_error());
+    }
+
+    state.commit(wtxn).unwrap();
+
+    Ok((StatusCode::OK, Json(describe(&id, &upload))))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct FinishPayload {
+    // Hex SHA-256 of the whole value, checked before it's written
+    sha256: String,
+}
+
+/// `POST /uploads/:id/finalize`: writes the uploaded value to its key once it matches
+/// `sha256`, all at once so nobody reads it half uploaded. A mismatch leaves the upload
+/// as it was.
+pub(crate) async fn finalize(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<FinishPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let mut wtxn = state.write_txn().unwrap();
+
+    let upload = match state.uploads.get(&wtxn, &id) {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return Ok(not_found()),
+        Err(_) => return Ok(internal_error()),
+    };
+
+    let value = state
+        .upload_chunks
+        .prefix_iter(&wtxn, id.as_bytes())
+        .and_then(|chunks| {
+            let mut value = Vec::with_capacity(upload.offset as usize);
+
+            for chunk in chunks {
+                value.extend_from_slice(chunk?.1);
+            }
+
+            Ok(value)
+        });
+
+    let value = match value {
+        Ok(value) => value,
+        Err(_) => return Ok(internal_error()),
+    };
+
+    let sha256 = digest::hex(&Sha256::digest(&value).into());
+
+    if !sha256.eq_ignore_ascii_case(&payload.sha256) {
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({ "error": "Upload doesn't match sha256", "sha256": sha256 })),
+        ));
+    }
+
+    let value = match String::from_utf8(value) {
+        Ok(value) => value,
+        Err(_) => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "Values must be UTF-8" })),
+            ))
+        }
+    };
+
+    // The hash is of what was uploaded, the hooks may still change what's written
+    let value = match hooks::run(&state, &upload.key, &value) {
+        Ok(value) => value,
+        Err(response) => return Ok(response),
+    };
+
+    if let Some(response) = sizes::check_value(&state, &value) {
+        return Ok(response);
+    }
+
+    if let Some(response) = refuse_locked(&state, &wtxn, &upload.key, &headers)
+        .or_else(|| references::check(&state, &wtxn, &upload.key, &value))
+    {
+        return Ok(response);
+    }
+
+    let written = discard(&state, &mut wtxn, &id)
+        .and_then(|_| put_value(&state, &mut wtxn, &upload.key, &value));
+
+    if written.is_err() {
+        return Ok(internal_error());
+    }
+
+    state.commit(wtxn).unwrap();
+
+    wait::notify(&state, &upload.key);
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "key": upload.key, "bytes": upload.offset })),
+    ))
+}
+
+/// `DELETE /uploads/:id`: gives up on an upload.
+pub(crate) async fn abort(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let mut wtxn = state.write_txn().unwrap();
+
+    match state.uploads.get(&wtxn, &id) {
+        Ok(Some(_)) => {}
+        Ok(None) => return Ok(not_found()),
+        Err(_) => return Ok(internal_error()),
+    }
+
+    if discard(&state, &mut wtxn, &id).is_err() {
+        return Ok(internal_error());
+    }
+
+    state.commit(wtxn).unwrap();
+
+    Ok((StatusCode::OK, Json(json!({ "id": id }))))
+}
+
+/// Drops the uploads nobody appended to since `before`, returning how many.
+pub(crate) fn sweep(state: &AppState, before: u64) -> heed::Result<usize> {
+    let mut wtxn = state.write_txn()?;
+
+    let abandoned: Vec<String> = state
+        .uploads
+        .iter(&wtxn)?
+        .filter_map(|entry| match entry {
+            Ok((id, upload)) if upload.updated_at < before => Some(Ok(id.to_owned())),
+            Ok(_) => None,
+            Err(err) => Some(Err(err)),
+        })
+        .collect::<heed::Result<_>>()?;
+
+    for id in &abandoned {
+        discard(state, &mut wtxn, id)?;
+    }
+
+    state.commit(wtxn)?;
+
+    Ok(abandoned.len())
+}
+
+/// Drops abandoned uploads every [`SWEEP_INTERVAL`].
+pub(crate) fn spawn_sweep(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let swept = {
+                let state = state.clone();
+
+                tokio::task::spawn_blocking(move || {
+                    let before = now_millis().saturating_sub(ABANDONED_AFTER.as_millis() as u64);
+
+                    sweep(&state, before).map_err(|err| err.to_string())
+                })
+            };
+
+            match swept.await.unwrap() {
+                Ok(0) => {}
+                Ok(swept) => tracing::info!(swept, "dropped abandoned uploads"),
+                Err(err) => tracing::error!(%err, "failed to drop abandoned uploads"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{send, setup_tests};
+    use axum::{
+        body::Body,
+        http::{self, Request},
+    };
+
+    fn post(uri: &str, payload: Value) -> Request<Body> {
+        Request::builder()
+            .method(http::Method::POST)
+            .uri(uri)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(payload.to_string()))
+            .unwrap()
+    }
+
+    fn append(id: &str, offset: u64, chunk: &'static str) -> Request<Body> {
+        Request::builder()
+            .method(http::Method::PATCH)
+            .uri(format!("/uploads/{}", id))
+            .header(UPLOAD_OFFSET_HEADER, offset)
+            .body(Body::from(chunk))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn uploads_in_chunks() {
+        let mut app = setup_tests().await;
+
+        let (status, upload) = send(&mut app, post("/uploads", json!({ "key": "upload:a" }))).await;
+
+        assert_eq!(status, StatusCode::CREATED);
+
+        let id = upload["id"].as_str().unwrap().to_owned();
+
+        assert_eq!(
+            send(&mut app, append(&id, 0, "hello ")).await.0,
+            StatusCode::OK
+        );
+
+        // Sent again after losing the response
+        let (status, body) = send(&mut app, append(&id, 0, "hello ")).await;
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["offset"], 6);
+
+        assert_eq!(
+            send(&mut app, append(&id, 6, "world")).await.0,
+            StatusCode::OK
+        );
+
+        let finalize = |sha256: &str| {
+            post(
+                &format!("/uploads/{}/finalize", id),
+                json!({ "sha256": sha256 }),
+            )
+        };
+
+        let (status, _) = send(&mut app, finalize(&"0".repeat(64))).await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let get = || {
+            Request::builder()
+                .uri("/upload:a")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        assert_eq!(send(&mut app, get()).await.0, StatusCode::NOT_FOUND);
+
+        let sha256 = digest::hex(&Sha256::digest(b"hello world").into());
+        let (status, body) = send(&mut app, finalize(&sha256)).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["bytes"], 11);
+
+        let (_, body) = send(&mut app, get()).await;
+
+        assert_eq!(body["value"], "hello world");
+
+        let status = Request::builder()
+            .uri(format!("/uploads/{}", id))
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(send(&mut app, status).await.0, StatusCode::NOT_FOUND);
+    }
+}
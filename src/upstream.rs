@@ -0,0 +1,337 @@
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use hyper::client::HttpConnector;
+use hyper::{header, Body, Client, Method, Request};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{cache, metrics, mirror, put_value, wait, AppState};
+
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The store behind this one, `UPSTREAM_URL`, that misses are read from and writes are
+/// made to, so the server acts as a durable cache in front of it.
+pub(crate) struct Upstream {
+    // Like `http://origin/config/{key}`, with `{key}` replaced by the percent-encoded key
+    template: String,
+    client: Client<HttpConnector>,
+}
+
+impl Upstream {
+    /// The `UPSTREAM_URL` configured, if any.
+    pub(crate) fn from_env() -> Result<Option<Upstream>, String> {
+        match std::env::var("UPSTREAM_URL") {
+            Ok(template) => Upstream::new(&template).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn new(template: &str) -> Result<Upstream, String> {
+        if !template.starts_with("http://") || !template.contains("{key}") {
+            return Err(format!(
+                "UPSTREAM_URL must be an http:// URL containing {{key}}, got {}",
+                template
+            ));
+        }
+
+        Ok(Upstream {
+            template: template.to_owned(),
+            client: Client::new(),
+        })
+    }
+
+    fn url(&self, key: &str) -> String {
+        self.template.replace("{key}", &mirror::encode_segment(key))
+    }
+
+    async fn send(&self, request: Request<Body>) -> Result<hyper::Response<Body>, String> {
+        tokio::time::timeout(UPSTREAM_TIMEOUT, self.client.request(request))
+            .await
+            .map_err(|_| String::from("timed out"))?
+            .map_err(|err| err.to_string())
+    }
+
+    // The value the upstream has for `key`, its whole response body
+    async fn fetch(&self, key: &str) -> Result<Option<String>, String> {
+        let request = Request::builder()
+            .uri(self.url(key))
+            .body(Body::empty())
+            .map_err(|err| err.to_string())?;
+
+        let response = self.send(request).await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            StatusCode::OK => {
+                let body = hyper::body::to_bytes(response.into_body())
+                    .await
+                    .map_err(|err| err.to_string())?;
+
+                String::from_utf8(body.to_vec())
+                    .map(Some)
+                    .map_err(|_| String::from("answered with a value that isn't UTF-8"))
+            }
+            status => Err(format!("answered {}", status)),
+        }
+    }
+
+    // Puts `value` upstream as the body, or deletes the key when it's gone
+    async fn write(&self, key: &str, value: Option<&str>) -> Result<(), String> {
+        let request = Request::builder().uri(self.url(key));
+
+        let request = match value {
+            Some(value) => request
+                .method(Method::PUT)
+                .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(Body::from(value.to_owned())),
+            None => request.method(Method::DELETE).body(Body::empty()),
+        }
+        .map_err(|err| err.to_string())?;
+
+        match self.send(request).await?.status() {
+            // Already gone upstream too
+            StatusCode::NOT_FOUND if value.is_none() => Ok(()),
+            status if status.is_success() => Ok(()),
+            status => Err(format!("answered {}", status)),
+        }
+    }
+}
+
+// Stores what the upstream had for `key` unless it was written here meanwhile, returning
+// the value it has and the version it's at. It's the upstream's value, so write hooks and
+// checks don't apply.
+fn cache(state: &AppState, key: &str, value: String) -> heed::Result<(String, u64)> {
+    let mut wtxn = state.write_txn()?;
+
+    let value = match state.kv.get(&wtxn, key)? {
+        Some(written) => written.to_owned(),
+        None => {
+            put_value(state, &mut wtxn, key, &value)?;
+
+            value
+        }
+    };
+
+    let version = state.versions.get(&wtxn, key)?.unwrap_or(0);
+
+    state.commit(wtxn)?;
+
+    Ok((value, version))
+}
+
+/// The response to `GET /:key` for a key that isn't here, once it's been fetched from the
+/// upstream and cached. Keys the upstream doesn't have either are still a 404.
+pub(crate) async fn read_through(
+    state: &AppState,
+    upstream: &Upstream,
+    key: &str,
+    headers: &HeaderMap,
+) -> Response {
+    let value = match upstream.fetch(key).await {
+        Ok(Some(value)) => value,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "Key not found" })),
+            )
+                .into_response()
+        }
+        Err(err) => {
+            metrics::increment(&state.metrics.upstream_failures);
+
+            tracing::warn!(key, %err, "failed to read through to the upstream");
+
+            return (
+                StatusCode::BAD_GATEWAY,
+                Json(json!({ "error": "Upstream unavailable" })),
+            )
+                .into_response();
+        }
+    };
+
+    metrics::increment(&state.metrics.upstream_reads);
+
+    let (value, version) = match cache(state, key, value) {
+        Ok(cached) => cached,
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            )
+                .into_response()
+        }
+    };
+
+    wait::notify(state, key);
+
+    if cache::not_modified(headers, version) {
+        return (
+            StatusCode::NOT_MODIFIED,
+            cache::headers(state, key, version),
+        )
+            .into_response();
+    }
+
+    (
+        StatusCode::OK,
+        cache::headers(state, key, version),
+        Json(json!({ "key": key, "value": value })),
+    )
+        .into_response()
+}
+
+/// The response to send instead of the usual one when `key` was just written here but the
+/// upstream didn't take it. It's sent as `key` is by now, so retrying the write, or any
+/// later one, brings the upstream back in step. It's sent from a task of its own, so a
+/// request dropped meanwhile, past its deadline say, still leaves the upstream in step.
+pub(crate) async fn write_through(
+    state: &Arc<AppState>,
+    key: &str,
+) -> Option<(StatusCode, Json<Value>)> {
+    state.upstream.as_ref()?;
+
+    let sent = tokio::spawn({
+        let state = state.clone();
+        let key = key.to_owned();
+
+        async move { send_write(&state, &key).await }
+    });
+
+    sent.await.unwrap_or_else(|_| Some(not_written_upstream()))
+}
+
+async fn send_write(state: &AppState, key: &str) -> Option<(StatusCode, Json<Value>)> {
+    let upstream = state.upstream.as_ref()?;
+
+    let value = {
+        let rtxn = state.read_txn().ok()?;
+
+        let value = state.kv.get(&rtxn, key).ok()?.map(str::to_owned);
+
+        value
+    };
+
+    match upstream.write(key, value.as_deref()).await {
+        Ok(()) => None,
+        Err(err) => {
+            metrics::increment(&state.metrics.upstream_failures);
+
+            tracing::warn!(key, %err, "failed to write through to the upstream");
+
+            Some(not_written_upstream())
+        }
+    }
+}
+
+fn not_written_upstream() -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::BAD_GATEWAY,
+        Json(json!({ "error": "Written here, but not upstream" })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::extract::Path;
+    use axum::routing::get;
+    use axum::Router;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn reads_misses_and_writes_through() {
+        let _ = setup_tests().await;
+
+        let state = crate::app_state().unwrap();
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let origin = Router::new().route(
+            "/config/:key",
+            get(|Path(key): Path<String>| async move {
+                match key.as_str() {
+                    "upstream/known" => Ok(String::from("from origin")),
+                    _ => Err(StatusCode::NOT_FOUND),
+                }
+            })
+            .put({
+                let received = received.clone();
+
+                move |Path(key): Path<String>, body: String| async move {
+                    received
+                        .lock()
+                        .unwrap()
+                        .push(format!("PUT {} {}", key, body));
+                }
+            }),
+        );
+
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(origin.into_make_service());
+        let template = format!("http://{}/config/{{key}}", server.local_addr());
+        tokio::spawn(server);
+
+        let upstream = Upstream::new(&template).unwrap();
+
+        let response = read_through(&state, &upstream, "upstream/known", &HeaderMap::new()).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Cached, so it's there without the upstream
+        let rtxn = state.read_txn().unwrap();
+
+        assert_eq!(
+            state.kv.get(&rtxn, "upstream/known").unwrap(),
+            Some("from origin")
+        );
+
+        drop(rtxn);
+
+        // Written here while it was being fetched, so that's what's served
+        let mut wtxn = state.write_txn().unwrap();
+        put_value(&state, &mut wtxn, "upstream/raced", "written here").unwrap();
+        state.commit(wtxn).unwrap();
+
+        let (value, version) =
+            cache(&state, "upstream/raced", String::from("from origin")).unwrap();
+
+        let rtxn = state.read_txn().unwrap();
+
+        assert_eq!(value, "written here");
+        assert_eq!(
+            state.versions.get(&rtxn, "upstream/raced").unwrap(),
+            Some(version)
+        );
+
+        drop(rtxn);
+
+        let response = read_through(&state, &upstream, "upstream/missing", &HeaderMap::new()).await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        upstream
+            .write("upstream/known", Some("changed"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![String::from("PUT upstream/known changed")]
+        );
+
+        // Nothing listens on port 9
+        let unreachable = Upstream::new("http://127.0.0.1:9/{key}").unwrap();
+
+        assert_eq!(
+            read_through(&state, &unreachable, "upstream/other", &HeaderMap::new())
+                .await
+                .status(),
+            StatusCode::BAD_GATEWAY
+        );
+
+        assert!(Upstream::new("https://origin/{key}").is_err());
+        assert!(Upstream::new("http://origin/config").is_err());
+    }
+}
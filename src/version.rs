@@ -0,0 +1,96 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::AppState;
+
+/// Bumped whenever the layout of the databases changes in a way older builds can't read.
+pub(crate) const STORAGE_FORMAT_VERSION: u32 = 1;
+
+/// What a deployment is running, so rollouts can be checked against what was shipped.
+pub(crate) async fn version(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Value>) {
+    let mut info = build_info();
+
+    info["capabilities"] = json!(capabilities(&state));
+
+    (StatusCode::OK, Json(info))
+}
+
+fn build_info() -> Value {
+    let features: Vec<&str> = env!("KV_FEATURES")
+        .split(',')
+        .filter(|feature| !feature.is_empty())
+        .collect();
+
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": env!("KV_GIT_COMMIT"),
+        // Unix time in seconds
+        "built_at": env!("KV_BUILT_AT").parse::<u64>().unwrap(),
+        "features": features,
+        "storage_format": STORAGE_FORMAT_VERSION,
+    })
+}
+
+/// The optional subsystems this server will actually serve, those compiled in and, for the
+/// ones that are off by default, also configured.
+fn capabilities(state: &AppState) -> Vec<&'static str> {
+    let compiled = [
+        ("scripting", cfg!(feature = "scripting")),
+        ("chaos", cfg!(feature = "chaos")),
+    ];
+
+    let configured = [
+        ("change_feed", state.feed_enabled),
+        ("version_history", state.history_enabled),
+        ("webhooks", !state.webhooks.is_empty()),
+        ("mirroring", state.mirror_url.is_some()),
+        ("separate_metrics_listener", state.metrics_address.is_some()),
+    ];
+
+    compiled
+        .into_iter()
+        .chain(configured)
+        .filter(|(_, enabled)| *enabled)
+        .map(|(capability, _)| capability)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+
+    #[test]
+    fn reports_the_build() {
+        let info = build_info();
+
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(info["storage_format"], STORAGE_FORMAT_VERSION);
+        assert!(info["built_at"].as_u64().unwrap() > 0);
+        assert_eq!(
+            info["features"]
+                .as_array()
+                .unwrap()
+                .contains(&json!("scripting")),
+            cfg!(feature = "scripting")
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_capabilities() {
+        let _ = setup_tests().await;
+
+        let capabilities = capabilities(&crate::app_state().unwrap());
+
+        assert!(capabilities.contains(&"change_feed"));
+        assert!(capabilities.contains(&"version_history"));
+        assert!(!capabilities.contains(&"webhooks"));
+        assert_eq!(
+            capabilities.contains(&"scripting"),
+            cfg!(feature = "scripting")
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use axum::extract::{Path, Query, State};
+use axum::{http::StatusCode, Json};
+use heed::RwTxn;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{AppError, AppState};
+
+// Waiting longer than this is left to the client re-polling
+const MAX_WAIT: Duration = Duration::from_secs(300);
+
+/// Bumps the version of `key` as part of a write, deletes count as changes too.
+///
+/// Waiters are only told about it through [`notify`] once the transaction committed.
+pub(crate) fn bump_version(state: &AppState, wtxn: &mut RwTxn, key: &str) -> heed::Result<u64> {
+    let version = state.versions.get(wtxn, key)?.unwrap_or(0) + 1;
+
+    state.versions.put(wtxn, key, &version)?;
+
+    Ok(version)
+}
+
+/// The entity tag of a key is its quoted version.
+pub(crate) fn etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// Checks an `If-Match` header, which may list several tags or `*` for any version.
+pub(crate) fn etag_matches(if_match: &str, version: u64) -> bool {
+    let etag = etag(version);
+
+    if_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+pub(crate) fn notify(state: &AppState, key: &str) {
+    state.misses.forget(key);
+    state.reads.forget(key);
+
+    // Nobody waiting is not an error
+    let _ = state.changes.send(key.to_owned());
+}
+
+/// Parses durations like `30s`, `500ms` or `2m`, a bare number is taken as seconds.
+pub(crate) fn parse_duration(input: &str) -> Option<Duration> {
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+
+    let number: u64 = number.parse().ok()?;
+
+    match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "" | "s" => Some(Duration::from_secs(number)),
+        "m" => Some(Duration::from_secs(number.checked_mul(60)?)),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct WaitQuery {
+    timeout: Option<String>,
+    since_version: Option<u64>,
+}
+
+pub(crate) async fn wait_for_change(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+    Query(query): Query<WaitQuery>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let timeout = match query.timeout.as_deref() {
+        Some(timeout) => match parse_duration(timeout) {
+            Some(timeout) => timeout.min(MAX_WAIT),
+            None => {
+                return Ok((
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({ "error": "Invalid timeout" })),
+                ))
+            }
+        },
+        None => Duration::from_secs(30),
+    };
+
+    // Subscribe before reading so a change landing in between isn't missed
+    let mut changes = state.changes.subscribe();
+
+    let mut current = match current_state(&state, &key) {
+        Ok(current) => current,
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    };
+
+    // Without a version to compare against, wait for the next change from now on
+    let since_version = query.since_version.unwrap_or(current.1);
+
+    if current.1 <= since_version {
+        let changed = tokio::time::timeout(timeout, async {
+            loop {
+                match changes.recv().await {
+                    Ok(changed) if changed == key => return true,
+                    Ok(_) => {}
+                    // Missed some notifications, the version check below sorts it out
+                    Err(RecvError::Lagged(_)) => return true,
+                    Err(RecvError::Closed) => return false,
+                }
+            }
+        })
+        .await;
+
+        if let Ok(true) = changed {
+            current = match current_state(&state, &key) {
+                Ok(current) => current,
+                Err(_) => {
+                    return Ok((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(json!({ "error": "Internal server error" })),
+                    ))
+                }
+            };
+        }
+    }
+
+    let (value, version) = current;
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "key": key,
+            "value": value,
+            "version": version,
+            "changed": version > since_version,
+        })),
+    ))
+}
+
+fn current_state(state: &AppState, key: &str) -> heed::Result<(Option<String>, u64)> {
+    let rtxn = state.read_txn()?;
+
+    let value = state.kv.get(&rtxn, key)?.map(str::to_owned);
+    let version = state.versions.get(&rtxn, key)?.unwrap_or(0);
+
+    Ok((value, version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::{
+        body::Body,
+        http::{self, Request},
+    };
+    use tower::ServiceExt; // for `oneshot`
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("45"), Some(Duration::from_secs(45)));
+        assert_eq!(parse_duration("10h"), None);
+        assert_eq!(parse_duration("s"), None);
+        assert_eq!(parse_duration("18446744073709551615m"), None);
+    }
+
+    #[tokio::test]
+    async fn times_out_without_changes() {
+        let app = setup_tests().await;
+
+        let request = Request::builder()
+            .uri("/wait-timeout-test/wait?timeout=50ms")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["changed"], false);
+    }
+
+    #[tokio::test]
+    async fn returns_once_the_key_changes() {
+        let app = setup_tests().await;
+
+        let request = Request::builder()
+            .uri("/wait-change-test/wait?timeout=5s")
+            .body(Body::empty())
+            .unwrap();
+        let waiter = tokio::spawn(app.clone().oneshot(request));
+
+        // Give the waiter a moment to start listening
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let request = Request::builder()
+            .method(http::Method::PUT)
+            .uri("/wait-change-test")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "key": "wait-change-test", "value": "updated" }).to_string(),
+            ))
+            .unwrap();
+        app.oneshot(request).await.unwrap();
+
+        let response = waiter.await.unwrap().unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["changed"], true);
+        assert_eq!(body["value"], "updated");
+
+        // Asking about an older version answers straight away
+        let version = body["version"].as_u64().unwrap();
+
+        let request = Request::builder()
+            .uri(format!(
+                "/wait-change-test/wait?timeout=5s&since_version={}",
+                version - 1
+            ))
+            .body(Body::empty())
+            .unwrap();
+        let response = setup_tests().await.oneshot(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["changed"], true);
+    }
+}
@@ -0,0 +1,438 @@
+use axum::extract::State;
+use axum::{http::StatusCode, Json};
+use heed::RwTxn;
+use hmac::{Hmac, Mac};
+use hyper::client::HttpConnector;
+use hyper::{header, Body, Client, Method, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::filter::{Filter, FilterQuery};
+use crate::{now_millis, AppError, AppState};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+// Deliveries attempted per poll, the rest wait for the next one
+const BATCH_SIZE: usize = 64;
+// Attempts before a delivery is given up on and moved to the failures
+const MAX_ATTEMPTS: u32 = 8;
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+// Sent on deliveries to endpoints with a secret, checked by `kv_client::verify_webhook`
+const TIMESTAMP_HEADER: &str = "x-kv-timestamp";
+const SIGNATURE_HEADER: &str = "x-kv-signature";
+
+// Every write queues a delivery per `WEBHOOK_URLS` endpoint in the outbox, in the same
+// transaction, under a big endian id one more than the last. Deliveries that run out of
+// attempts move to `webhook_failures` under the same id.
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Delivery {
+    url: String,
+    body: Value,
+    attempts: u32,
+    next_attempt_at: u64,
+    // Why the last attempt failed
+    last_error: Option<String>,
+}
+
+fn next_id(state: &AppState, wtxn: &RwTxn) -> heed::Result<u64> {
+    let last = state.outbox.last(wtxn)?.map(|(id, _)| id_of(id));
+    let last_failed = state.webhook_failures.last(wtxn)?.map(|(id, _)| id_of(id));
+
+    Ok(last.max(last_failed).unwrap_or(0) + 1)
+}
+
+fn id_of(key: &[u8]) -> u64 {
+    u64::from_be_bytes(key.try_into().unwrap())
+}
+
+fn enqueue_to(state: &AppState, wtxn: &mut RwTxn, url: &str, body: &Value) -> heed::Result<()> {
+    let delivery = Delivery {
+        url: url.to_owned(),
+        body: body.clone(),
+        attempts: 0,
+        next_attempt_at: now_millis(),
+        last_error: None,
+    };
+
+    state
+        .outbox
+        .put(wtxn, &next_id(state, wtxn)?.to_be_bytes(), &delivery)
+}
+
+/// Queues the write of `version` of `key` for delivery to every webhook endpoint whose
+/// filter it passes.
+pub(crate) fn enqueue(
+    state: &AppState,
+    wtxn: &mut RwTxn,
+    key: &str,
+    version: u64,
+    value: Option<&str>,
+) -> heed::Result<()> {
+    if state.webhooks.is_empty() {
+        return Ok(());
+    }
+
+    let body = json!({
+        "event": if value.is_some() { "put" } else { "delete" },
+        "key": key,
+        "value": value,
+        "version": version,
+        "written_at": now_millis(),
+    });
+
+    for endpoint in &state.webhooks {
+        if endpoint.filter.matches(key, value) {
+            enqueue_to(state, wtxn, &endpoint.url, &body)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A `WEBHOOK_URLS` endpoint.
+pub(crate) struct Endpoint {
+    url: String,
+    filter: Filter,
+    // Deliveries are signed with it when set
+    secret: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SecretQuery {
+    secret: Option<String>,
+}
+
+/// Reads the `WEBHOOK_URLS` endpoints, each with the filter and `secret` after its `#`,
+/// if any.
+pub(crate) fn endpoints_from_env() -> Result<Vec<Endpoint>, String> {
+    std::env::var("WEBHOOK_URLS")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|url| !url.is_empty())
+        .map(|url| {
+            let (url, options) = url.split_once('#').unwrap_or((url, ""));
+
+            let invalid = |err: String| format!("invalid options for webhook {}: {}", url, err);
+
+            let filter = serde_urlencoded::from_str::<FilterQuery>(options)
+                .map_err(|err| err.to_string())
+                .and_then(FilterQuery::parse)
+                .map_err(invalid)?;
+            let secret = serde_urlencoded::from_str::<SecretQuery>(options)
+                .map_err(|err| invalid(err.to_string()))?
+                .secret;
+
+            Ok(Endpoint {
+                url: url.to_owned(),
+                filter,
+                secret,
+            })
+        })
+        .collect()
+}
+
+/// The signature header of `body` sent at `timestamp`, the hex HMAC-SHA256 of
+/// `<timestamp>.<body>` keyed with the endpoint's secret.
+fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+
+    let mut signature = String::from("sha256=");
+
+    for byte in mac.finalize().into_bytes() {
+        write!(signature, "{:02x}", byte).unwrap();
+    }
+
+    signature
+}
+
+fn backoff(attempts: u32) -> Duration {
+    BASE_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempts - 1))
+        .min(MAX_BACKOFF)
+}
+
+async fn attempt(
+    client: &Client<HttpConnector>,
+    delivery: &Delivery,
+    secret: Option<&str>,
+) -> Result<(), String> {
+    let body = delivery.body.to_string();
+
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(&delivery.url)
+        .header(header::CONTENT_TYPE, "application/json");
+
+    // Signed on each attempt, so a retry isn't refused as a replay of the first one
+    if let Some(secret) = secret {
+        let timestamp = (now_millis() / 1000).to_string();
+
+        request = request
+            .header(SIGNATURE_HEADER, sign(secret, &timestamp, &body))
+            .header(TIMESTAMP_HEADER, timestamp);
+    }
+
+    let request = request
+        .body(Body::from(body))
+        .map_err(|err| err.to_string())?;
+
+    let response = tokio::time::timeout(DELIVERY_TIMEOUT, client.request(request))
+        .await
+        .map_err(|_| String::from("timed out"))?
+        .map_err(|err| err.to_string())?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("answered {}", response.status()))
+    }
+}
+
+/// Attempts the deliveries that are due, returning how many went through.
+///
+/// A failed one is retried with exponential backoff, after [`MAX_ATTEMPTS`] it's moved
+/// to the failures for `GET /admin/webhooks/failures`.
+pub(crate) async fn deliver_due(
+    state: &AppState,
+    client: &Client<HttpConnector>,
+) -> heed::Result<usize> {
+    let now = now_millis();
+
+    let due: Vec<(u64, Delivery)> = {
+        let rtxn = state.read_txn()?;
+
+        let due = state
+            .outbox
+            .iter(&rtxn)?
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .map_or(true, |(_, delivery)| delivery.next_attempt_at <= now)
+            })
+            .take(BATCH_SIZE)
+            .map(|entry| entry.map(|(id, delivery)| (id_of(id), delivery)))
+            .collect::<heed::Result<_>>()?;
+
+        due
+    };
+
+    let mut delivered = 0;
+
+    for (id, mut delivery) in due {
+        // Looked up now rather than stored in the outbox, so rotating a secret applies to
+        // deliveries already queued
+        let secret = state
+            .webhooks
+            .iter()
+            .find(|endpoint| endpoint.url == delivery.url)
+            .and_then(|endpoint| endpoint.secret.as_deref());
+
+        let outcome = attempt(client, &delivery, secret).await;
+
+        let mut wtxn = state.write_txn()?;
+
+        match outcome {
+            Ok(()) => {
+                delivered += 1;
+                state.outbox.delete(&mut wtxn, &id.to_be_bytes())?;
+            }
+            Err(err) => {
+                delivery.attempts += 1;
+                delivery.last_error = Some(err);
+
+                if delivery.attempts >= MAX_ATTEMPTS {
+                    tracing::warn!(id, url = delivery.url, "giving up on webhook delivery");
+
+                    state.outbox.delete(&mut wtxn, &id.to_be_bytes())?;
+                    state
+                        .webhook_failures
+                        .put(&mut wtxn, &id.to_be_bytes(), &delivery)?;
+                } else {
+                    delivery.next_attempt_at =
+                        now_millis() + backoff(delivery.attempts).as_millis() as u64;
+
+                    state.outbox.put(&mut wtxn, &id.to_be_bytes(), &delivery)?;
+                }
+            }
+        }
+
+        state.commit(wtxn)?;
+    }
+
+    Ok(delivered)
+}
+
+/// Delivers queued webhooks in the background, picking up whatever the outbox still
+/// held when the server last stopped.
+pub(crate) fn spawn_delivery(state: Arc<AppState>) {
+    if state.webhooks.is_empty() {
+        let rtxn = state.read_txn().unwrap();
+
+        // Deliveries queued for endpoints since removed from the config
+        if state.outbox.is_empty(&rtxn).unwrap() {
+            return;
+        }
+    }
+
+    tokio::spawn(async move {
+        let client = Client::new();
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(err) = deliver_due(&state, &client).await {
+                tracing::error!(%err, "failed to deliver webhooks");
+            }
+        }
+    });
+}
+
+fn read_failures(state: &AppState, rtxn: &heed::RoTxn) -> heed::Result<Vec<Value>> {
+    state
+        .webhook_failures
+        .iter(rtxn)?
+        .map(|entry| {
+            entry.map(|(id, delivery)| {
+                json!({
+                    "id": id_of(id),
+                    "url": delivery.url,
+                    "body": delivery.body,
+                    "attempts": delivery.attempts,
+                    "last_error": delivery.last_error,
+                })
+            })
+        })
+        .collect()
+}
+
+pub(crate) async fn failures(
+    State(state): State<Arc<AppState>>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let rtxn = state.read_txn().unwrap();
+
+    match read_failures(&state, &rtxn) {
+        Ok(failures) => Ok((StatusCode::OK, Json(json!({ "failures": failures })))),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_tests;
+    use axum::routing::post;
+    use axum::Router;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    #[test]
+    fn backs_off_exponentially() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(4), Duration::from_secs(8));
+        assert_eq!(backoff(40), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn signatures_verify_with_the_client() {
+        let timestamp = (now_millis() / 1000).to_string();
+        let body = r#"{"key":"signed"}"#;
+        let signature = sign("secret", &timestamp, body);
+
+        let verify = |secret| {
+            kv_client::verify_webhook(
+                secret,
+                Some(&timestamp),
+                Some(&signature),
+                body.as_bytes(),
+                kv_client::DEFAULT_TOLERANCE,
+            )
+        };
+
+        assert_eq!(verify("secret"), Ok(()));
+        assert_eq!(verify("other"), Err(kv_client::VerifyError::Mismatch));
+        assert_eq!(SIGNATURE_HEADER, kv_client::SIGNATURE_HEADER);
+        assert_eq!(TIMESTAMP_HEADER, kv_client::TIMESTAMP_HEADER);
+    }
+
+    #[tokio::test]
+    async fn delivers_and_gives_up_on_webhooks() {
+        let mut app = setup_tests().await;
+
+        let state = crate::app_state().unwrap();
+
+        let received = Arc::new(AtomicUsize::new(0));
+        let receiver = Router::new().route(
+            "/hook",
+            post({
+                let received = received.clone();
+
+                move || async move {
+                    received.fetch_add(1, Ordering::SeqCst);
+                }
+            }),
+        );
+
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(receiver.into_make_service());
+        let url = format!("http://{}/hook", server.local_addr());
+        tokio::spawn(server);
+
+        let mut wtxn = state.write_txn().unwrap();
+        enqueue_to(&state, &mut wtxn, &url, &json!({ "key": "hooked" })).unwrap();
+
+        // Nothing listens on port 9, and this is its last attempt
+        let failing = Delivery {
+            url: String::from("http://127.0.0.1:9/hook"),
+            body: json!({ "key": "unhooked" }),
+            attempts: MAX_ATTEMPTS - 1,
+            next_attempt_at: 0,
+            last_error: None,
+        };
+        let failing_id = next_id(&state, &wtxn).unwrap();
+        state
+            .outbox
+            .put(&mut wtxn, &failing_id.to_be_bytes(), &failing)
+            .unwrap();
+        wtxn.commit().unwrap();
+
+        let client = Client::new();
+
+        assert_eq!(deliver_due(&state, &client).await.unwrap(), 1);
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+        assert!(state.outbox.is_empty(&state.read_txn().unwrap()).unwrap());
+
+        let request = Request::builder()
+            .uri("/admin/webhooks/failures")
+            .body(axum::body::Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        let failure = body["failures"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|failure| failure["id"] == failing_id)
+            .unwrap();
+
+        assert_eq!(failure["attempts"], MAX_ATTEMPTS);
+        assert_eq!(failure["body"]["key"], "unhooked");
+    }
+}
@@ -0,0 +1,258 @@
+use axum::extract::State;
+use axum::http::{StatusCode, Uri};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{alias, digest, ephemeral, now_millis, AppError, AppState};
+
+// Wrapped values are copied into `wrapped` as they were when wrapped, under the hash of
+// their token so the database holds nothing that unwraps them. Unwrapping deletes them,
+// expired ones are also swept every `SWEEP_INTERVAL` so they don't linger unclaimed.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A value waiting to be unwrapped.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Wrapped {
+    key: String,
+    value: String,
+    expires_at: u64,
+}
+
+fn hash(token: &str) -> String {
+    digest::hex(&Sha256::digest(token.as_bytes()).into())
+}
+
+fn internal_error() -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({ "error": "Internal server error" })),
+    )
+}
+
+/// `GET /:key?wrap_ttl=`: answers with a token for `POST /unwrap` instead of the value,
+/// good for one unwrap within `wrap_ttl` seconds. Passing the token rather than the value
+/// through CI logs or chat means whoever finds it later finds it used or expired, and
+/// the one it was meant for notices when someone else unwrapped it first.
+pub(crate) fn wrap(state: &AppState, key: &str, ttl_secs: u64) -> (StatusCode, Json<Value>) {
+    if let Some(response) = ephemeral::check_ttl(ttl_secs) {
+        return response;
+    }
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    let key = match alias::resolve_key(state, &wtxn, key) {
+        Ok(key) => key,
+        Err(_) => return internal_error(),
+    };
+
+    let value = match state.kv.get(&wtxn, &key) {
+        Ok(Some(value)) => value.to_owned(),
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "Key not found" })),
+            )
+        }
+        Err(_) => return internal_error(),
+    };
+
+    let token = format!("wrap_{}", uuid::Uuid::new_v4().simple());
+    // Checked along with the TTL
+    let expires_at = ephemeral::expires_at(ttl_secs).unwrap_or(u64::MAX);
+
+    let wrapped = Wrapped {
+        key,
+        value,
+        expires_at,
+    };
+
+    if state
+        .wrapped
+        .put(&mut wtxn, &hash(&token), &wrapped)
+        .is_err()
+    {
+        return internal_error();
+    }
+
+    state.commit(wtxn).unwrap();
+
+    (
+        StatusCode::OK,
+        Json(json!({ "wrap_token": token, "expires_at": expires_at })),
+    )
+}
+
+#[derive(Deserialize)]
+struct WrapQuery {
+    wrap_ttl: Option<String>,
+}
+
+/// Whether `uri` asks `GET /:key` to wrap, which writes the token it answers with.
+pub(crate) fn wraps(uri: &Uri) -> bool {
+    uri.query()
+        .and_then(|query| serde_urlencoded::from_str::<WrapQuery>(query).ok())
+        .is_some_and(|query| query.wrap_ttl.is_some())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UnwrapPayload {
+    token: String,
+}
+
+/// `POST /unwrap`: the key and value a wrapping token stands for, once. Used, expired
+/// and unknown tokens all get the same 404.
+pub(crate) async fn unwrap(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<UnwrapPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let hash = hash(&payload.token);
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    let wrapped = match state.wrapped.get(&wtxn, &hash) {
+        Ok(wrapped) => wrapped,
+        Err(_) => return Ok(internal_error()),
+    };
+
+    if wrapped.is_some() && state.wrapped.delete(&mut wtxn, &hash).is_err() {
+        return Ok(internal_error());
+    }
+
+    state.commit(wtxn).unwrap();
+
+    match wrapped {
+        Some(wrapped) if wrapped.expires_at > now_millis() => Ok((
+            StatusCode::OK,
+            Json(json!({ "key": wrapped.key, "value": wrapped.value })),
+        )),
+        _ => Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Wrapping token is invalid, used or expired" })),
+        )),
+    }
+}
+
+/// Deletes the wrapped values not unwrapped before `now`, returning how many.
+pub(crate) fn sweep(state: &AppState, now: u64) -> heed::Result<usize> {
+    let mut wtxn = state.write_txn()?;
+
+    let expired: Vec<String> = state
+        .wrapped
+        .iter(&wtxn)?
+        .filter_map(|entry| match entry {
+            Ok((hash, wrapped)) if wrapped.expires_at <= now => Some(Ok(hash.to_owned())),
+            Ok(_) => None,
+            Err(err) => Some(Err(err)),
+        })
+        .collect::<heed::Result<_>>()?;
+
+    for hash in &expired {
+        state.wrapped.delete(&mut wtxn, hash)?;
+    }
+
+    state.commit(wtxn)?;
+
+    Ok(expired.len())
+}
+
+/// Sweeps expired wrapped values every [`SWEEP_INTERVAL`].
+pub(crate) fn spawn_sweep(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let swept = {
+                let state = state.clone();
+
+                tokio::task::spawn_blocking(move || {
+                    sweep(&state, now_millis()).map_err(|err| err.to_string())
+                })
+            };
+
+            match swept.await.unwrap() {
+                Ok(0) => {}
+                Ok(swept) => tracing::info!(swept, "swept expired wrapped values"),
+                Err(err) => tracing::error!(%err, "failed to sweep wrapped values"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{send, setup_tests};
+    use axum::{
+        body::Body,
+        http::{self, Request},
+    };
+
+    fn unwrap_request(token: &Value) -> Request<Body> {
+        Request::builder()
+            .method(http::Method::POST)
+            .uri("/unwrap")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({ "token": token }).to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn unwraps_once() {
+        let mut app = setup_tests().await;
+
+        let state = crate::app_state().unwrap();
+
+        let mut wtxn = state.write_txn().unwrap();
+        crate::put_value(&state, &mut wtxn, "wrapped:password", "hunter2").unwrap();
+        wtxn.commit().unwrap();
+
+        let request = Request::builder()
+            .uri(format!("/wrapped:password?wrap_ttl={}", u64::MAX))
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(send(&mut app, request).await.0, StatusCode::BAD_REQUEST);
+
+        let request = Request::builder()
+            .uri("/wrapped:password?wrap_ttl=60")
+            .body(Body::empty())
+            .unwrap();
+
+        let (status, wrapped) = send(&mut app, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(wrapped.get("value").is_none());
+
+        let (status, body) = send(&mut app, unwrap_request(&wrapped["wrap_token"])).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({ "key": "wrapped:password", "value": "hunter2" })
+        );
+
+        let (status, _) = send(&mut app, unwrap_request(&wrapped["wrap_token"])).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let request = Request::builder()
+            .uri("/wrapped:password?wrap_ttl=60")
+            .body(Body::empty())
+            .unwrap();
+
+        let (_, wrapped) = send(&mut app, request).await;
+        let expires_at = wrapped["expires_at"].as_u64().unwrap();
+
+        assert!(sweep(&state, expires_at).unwrap() >= 1);
+
+        let (status, _) = send(&mut app, unwrap_request(&wrapped["wrap_token"])).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
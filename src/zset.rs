@@ -0,0 +1,370 @@
+use axum::extract::{Path, Query, State};
+use axum::{http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::{name_prefix, record_keys, sizes, AppError, AppState};
+
+// Entries are stored as `[name length][name][score][member]` with an empty value, so a
+// set's members come out of LMDB ordered by score. `zset_scores` maps
+// `[name length][name][member]` back to the score for rank lookups and updates.
+
+fn entry_key(name: &str, score: f64, member: &str) -> Vec<u8> {
+    let mut key = name_prefix(name);
+    key.extend_from_slice(&encode_score(score));
+    key.extend_from_slice(member.as_bytes());
+    key
+}
+
+fn member_key(name: &str, member: &str) -> Vec<u8> {
+    let mut key = name_prefix(name);
+    key.extend_from_slice(member.as_bytes());
+    key
+}
+
+// Flip the bits so that the big endian bytes of any two scores compare like the floats do
+fn encode_score(score: f64) -> [u8; 8] {
+    let bits = score.to_bits();
+
+    let ordered = if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits | (1 << 63)
+    };
+
+    ordered.to_be_bytes()
+}
+
+fn decode_score(bytes: &[u8]) -> f64 {
+    let ordered = u64::from_be_bytes(bytes.try_into().unwrap());
+
+    let bits = if ordered >> 63 == 1 {
+        ordered & !(1 << 63)
+    } else {
+        !ordered
+    };
+
+    f64::from_bits(bits)
+}
+
+// Splits an entry key (minus its set prefix) into its score and member
+fn decode_entry(entry: &[u8]) -> (f64, String) {
+    let (score, member) = entry.split_at(8);
+
+    (
+        decode_score(score),
+        String::from_utf8_lossy(member).into_owned(),
+    )
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ZSetPayload {
+    member: String,
+    score: f64,
+}
+
+pub(crate) async fn add(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(payload): Json<ZSetPayload>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    // The longer of the two keys written
+    let entry = entry_key(&name, payload.score, &payload.member);
+
+    if let Some(response) = sizes::check_built_key(&entry, "The set name and member") {
+        return Ok(response);
+    }
+
+    let mut wtxn = state.write_txn().unwrap();
+
+    let member_key = member_key(&name, &payload.member);
+
+    let previous = match state.zset_scores.get(&wtxn, &member_key) {
+        Ok(previous) => previous,
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    };
+
+    // Drop the entry at the old score so the member only appears once in the set
+    if let Some(score) = previous {
+        state
+            .zset
+            .delete(&mut wtxn, &entry_key(&name, score, &payload.member))
+            .unwrap();
+    }
+
+    state.zset.put(&mut wtxn, &entry, &()).unwrap();
+
+    state
+        .zset_scores
+        .put(&mut wtxn, &member_key, &payload.score)
+        .unwrap();
+
+    state.commit(wtxn).unwrap();
+
+    let status = match previous {
+        Some(_) => StatusCode::OK,
+        None => StatusCode::CREATED,
+    };
+
+    Ok((
+        status,
+        Json(json!({ "member": payload.member, "score": payload.score })),
+    ))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RangeQuery {
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+pub(crate) async fn range(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<RangeQuery>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let rtxn = state.read_txn().unwrap();
+
+    let prefix = name_prefix(&name);
+
+    let mut start = prefix.clone();
+    start.extend_from_slice(&encode_score(query.min.unwrap_or(f64::NEG_INFINITY)));
+
+    let entries = state.zset.range(
+        &rtxn,
+        &(Bound::Included(start.as_slice()), Bound::Unbounded),
+    );
+
+    let entries = match entries {
+        Ok(entries) => entries,
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    };
+
+    let max = query.max.unwrap_or(f64::INFINITY);
+
+    let members: Vec<_> = entries
+        .filter_map(Result::ok)
+        .take_while(|(key, _)| key.starts_with(&prefix))
+        .map(|(key, _)| decode_entry(&key[prefix.len()..]))
+        .take_while(|(score, _)| *score <= max)
+        .map(|(score, member)| json!({ "member": member, "score": score }))
+        .collect();
+
+    record_keys(members.len());
+
+    Ok((StatusCode::OK, Json(json!(members))))
+}
+
+pub(crate) async fn rank(
+    State(state): State<Arc<AppState>>,
+    Path((name, member)): Path<(String, String)>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let rtxn = state.read_txn().unwrap();
+
+    let score = match state.zset_scores.get(&rtxn, &member_key(&name, &member)) {
+        Ok(Some(score)) => score,
+        Ok(None) => {
+            return Ok((
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "Member not found" })),
+            ))
+        }
+        Err(_) => {
+            return Ok((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            ))
+        }
+    };
+
+    let prefix = name_prefix(&name);
+    let entry = entry_key(&name, score, &member);
+
+    // The rank is the number of entries sorting before this one
+    let rank = state
+        .zset
+        .prefix_iter(&rtxn, &prefix)
+        .unwrap()
+        .filter_map(Result::ok)
+        .take_while(|(key, _)| *key < entry.as_slice())
+        .count();
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "member": member, "score": score, "rank": rank })),
+    ))
+}
+
+pub(crate) async fn remove(
+    State(state): State<Arc<AppState>>,
+    Path((name, member)): Path<(String, String)>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let mut wtxn = state.write_txn().unwrap();
+
+    let member_key = member_key(&name, &member);
+
+    match state.zset_scores.get(&wtxn, &member_key) {
+        Ok(Some(score)) => {
+            state
+                .zset
+                .delete(&mut wtxn, &entry_key(&name, score, &member))
+                .unwrap();
+            state.zset_scores.delete(&mut wtxn, &member_key).unwrap();
+
+            state.commit(wtxn).unwrap();
+
+            Ok((StatusCode::OK, Json(json!({ "member": member }))))
+        }
+        Ok(None) => Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Member not found" })),
+        )),
+        Err(_) => Ok((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Internal server error" })),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{get_json, setup_tests};
+    use axum::{
+        body::Body,
+        http::{self, Request},
+        Router,
+    };
+    use tower::Service; // for `call`
+    use tower::ServiceExt; // for `ready`
+
+    async fn add_member(app: &mut Router, name: &str, member: &str, score: f64) -> StatusCode {
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri(format!("/zset/{}", name))
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "member": member, "score": score }).to_string(),
+            ))
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        response.status()
+    }
+
+    #[test]
+    fn score_encoding_preserves_order() {
+        let scores = [f64::NEG_INFINITY, -10.5, -1.0, -0.0, 0.0, 0.25, 3.0, 1e10];
+
+        for pair in scores.windows(2) {
+            assert!(encode_score(pair[0]) <= encode_score(pair[1]));
+        }
+
+        for score in scores {
+            assert_eq!(decode_score(&encode_score(score)), score);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_members_too_long_to_store() {
+        let mut app = setup_tests().await;
+
+        assert_eq!(
+            add_member(&mut app, "long-members", &"m".repeat(500), 1.0).await,
+            StatusCode::BAD_REQUEST
+        );
+        // Just fits with the name length, name and score before it
+        assert!(
+            add_member(&mut app, "long-members", &"m".repeat(511 - 4 - 12 - 8), 1.0)
+                .await
+                .is_success()
+        );
+    }
+
+    #[tokio::test]
+    async fn range_by_score() {
+        let mut app = setup_tests().await;
+
+        // Start from a clean set, tests share the same database
+        for member in ["alice", "bob", "carol"] {
+            let request = Request::builder()
+                .method(http::Method::DELETE)
+                .uri(format!("/zset/range-test/{}", member))
+                .body(Body::empty())
+                .unwrap();
+            app.ready().await.unwrap().call(request).await.unwrap();
+        }
+
+        assert_eq!(
+            add_member(&mut app, "range-test", "alice", 30.0).await,
+            StatusCode::CREATED
+        );
+        add_member(&mut app, "range-test", "bob", -5.0).await;
+        add_member(&mut app, "range-test", "carol", 12.5).await;
+
+        // Updating a score moves the member rather than duplicating it
+        assert_eq!(
+            add_member(&mut app, "range-test", "alice", 20.0).await,
+            StatusCode::OK
+        );
+
+        let (status, body) = get_json(&mut app, "/zset/range-test").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!([
+                { "member": "bob", "score": -5.0 },
+                { "member": "carol", "score": 12.5 },
+                { "member": "alice", "score": 20.0 },
+            ])
+        );
+
+        let (_, body) = get_json(&mut app, "/zset/range-test?min=0&max=15").await;
+
+        assert_eq!(body, json!([{ "member": "carol", "score": 12.5 }]));
+    }
+
+    #[tokio::test]
+    async fn rank_and_remove() {
+        let mut app = setup_tests().await;
+
+        add_member(&mut app, "rank-test", "first", 1.0).await;
+        add_member(&mut app, "rank-test", "second", 2.0).await;
+
+        let (status, body) = get_json(&mut app, "/zset/rank-test/second").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "member": "second", "score": 2.0, "rank": 1 }));
+
+        let request = Request::builder()
+            .method(http::Method::DELETE)
+            .uri("/zset/rank-test/first")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let (_, body) = get_json(&mut app, "/zset/rank-test/second").await;
+
+        assert_eq!(body["rank"], 0);
+
+        let (status, _) = get_json(&mut app, "/zset/rank-test/first").await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}